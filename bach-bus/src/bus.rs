@@ -1,9 +1,11 @@
 use crate::packet::Packet;
 use crate::queue::Queue;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
 pub type Input = dyn FnMut(Packet) + Send + Sync;
 pub type Output = dyn FnMut() -> Option<Packet> + Send + Sync;
+/// Handle of a connection, to [`Bus::disconnect`] it.
+pub type ConnectionId = usize;
 
 pub struct BusConnection {
     i: Box<Input>,
@@ -33,7 +35,8 @@ impl BusConnection {
 
 pub struct Bus {
     cable: Queue<Packet>,
-    connections: RefCell<Vec<BusConnection>>,
+    connections: RefCell<Vec<(ConnectionId, BusConnection)>>,
+    next_id: Cell<ConnectionId>,
 }
 
 impl Default for Bus {
@@ -47,17 +50,30 @@ impl Bus {
         Bus {
             cable: Queue::new(),
             connections: RefCell::new(Vec::new()),
+            next_id: Cell::new(0),
         }
     }
 
-    pub fn connect(&self, conn: BusConnection) {
-        self.connections.borrow_mut().push(conn);
+    pub fn connect(&self, conn: BusConnection) -> ConnectionId {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.connections.borrow_mut().push((id, conn));
+        id
+    }
+
+    /// Removes a connection, it sees none of the packets still on the bus.
+    /// Returns false when it was not connected.
+    pub fn disconnect(&self, id: ConnectionId) -> bool {
+        let mut conns = self.connections.borrow_mut();
+        let len = conns.len();
+        conns.retain(|(i, _)| *i != id);
+        conns.len() != len
     }
 
     pub fn perform(&self) {
         let mut conns = self.connections.borrow_mut();
         let next = self.cable.consume();
-        for c in conns.iter_mut() {
+        for (_, c) in conns.iter_mut() {
             if let Some(out_packet) = c.perform(next) {
                 self.cable.push(out_packet);
             }
@@ -73,6 +89,16 @@ impl Bus {
         self.cable.consume()
     }
 
+    /// True when no packet is waiting to be delivered.
+    pub fn is_idle(&self) -> bool {
+        self.cable.empty()
+    }
+
+    /// Packets waiting to be delivered.
+    pub fn depth(&self) -> usize {
+        self.cable.len()
+    }
+
     pub fn con_count(&self) -> usize {
         self.connections.borrow().len()
     }
@@ -137,4 +163,37 @@ mod test {
         assert_eq!(end, endtest);
         assert!(empty.is_none());
     }
+
+    #[test]
+    fn disconnect() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let b = Bus::new();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = |seen: &Arc<AtomicUsize>| {
+            let seen = seen.clone();
+            BusConnection::new(
+                move |_| {
+                    seen.fetch_add(1, Ordering::SeqCst);
+                },
+                || -> Option<Packet> { None },
+            )
+        };
+        let first = b.connect(counter(&seen));
+        let second = b.connect(counter(&seen));
+        assert_ne!(first, second);
+        b.send(Packet::new_ng("FOO", "FAA", "FEE"));
+        b.perform();
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+
+        assert!(b.disconnect(first));
+        assert!(!b.disconnect(first));
+        assert_eq!(b.con_count(), 1);
+        b.send(Packet::new_ng("FOO", "FAA", "FEE"));
+        b.perform();
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+        // Ids are not reused.
+        assert_ne!(b.connect(counter(&seen)), first);
+    }
 }
@@ -239,9 +239,38 @@ impl From<WatchCommand> for PacketCore {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct HostCredentials(String, String);
 
+const FIRE_FLAG_DRY_RUN: u8 = 0b0000_0001;
+
+/// Options carried by a [`BackupCommand::Fire`] and handed to the module fire method.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FireOptions {
+    /// Show what the fire would do without touching the target.
+    pub dry_run: bool,
+}
+
+impl FireOptions {
+    pub fn dry_run() -> Self {
+        FireOptions { dry_run: true }
+    }
+
+    fn to_flags(self) -> u8 {
+        let mut flags = 0u8;
+        if self.dry_run {
+            flags |= FIRE_FLAG_DRY_RUN;
+        }
+        flags
+    }
+
+    fn from_flags(flags: u8) -> Self {
+        FireOptions {
+            dry_run: flags & FIRE_FLAG_DRY_RUN != 0,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BackupCommand {
-    Fire(Option<String>),
+    Fire(Option<String>, FireOptions),
     ChangeTarget(Option<String>, PathBuf),
     ChangeSource(Option<String>, PathBuf),
     HasHostCapability(Option<String>),
@@ -249,6 +278,8 @@ pub enum BackupCommand {
     ChangeHostCredentials(Option<String>, HostCredentials),
     PingHost(Option<String>),
     Print(Option<String>),
+    /// Re-read the configuration of the named module, or of every module.
+    Reload(Option<String>),
     Undef,
 }
 
@@ -274,7 +305,7 @@ impl From<PacketCore> for BackupCommand {
         };
 
         match core_header.as_ref() {
-            "FIRE" => BackupCommand::Fire(name),
+            "FIRE" => BackupCommand::Fire(name, FireOptions::from_flags(item[NAME_SIZE + 4])),
             "CHTA" => BackupCommand::ChangeTarget(
                 name,
                 PathBuf::from(core_2_string(&item[NAME_SIZE + 4..CORE_SIZE])),
@@ -296,6 +327,7 @@ impl From<PacketCore> for BackupCommand {
             ),
             "PIHO" => BackupCommand::PingHost(name),
             "PRNT" => BackupCommand::Print(name),
+            "RELO" => BackupCommand::Reload(name),
             _ => BackupCommand::Undef,
         }
     }
@@ -348,7 +380,12 @@ impl From<BackupCommand> for PacketCore {
         };
 
         match item {
-            BackupCommand::Fire(n) => write_header("FIRE", n),
+            BackupCommand::Fire(n, o) => {
+                let mut ret = write_header("FIRE", n);
+                ret[NAME_SIZE + 4] = o.to_flags();
+
+                ret
+            }
             BackupCommand::ChangeTarget(n, p) => retpaths("CHTA", n, p),
             BackupCommand::ChangeSource(n, p) => retpaths("CHSR", n, p),
             BackupCommand::HasHostCapability(n) => write_header("HAHO", n),
@@ -377,6 +414,7 @@ impl From<BackupCommand> for PacketCore {
             }
             BackupCommand::PingHost(n) => write_header("PIHO", n),
             BackupCommand::Print(n) => write_header("PRNT", n),
+            BackupCommand::Reload(n) => write_header("RELO", n),
             _ => write_header("PRNT", None),
         }
     }
@@ -967,7 +1005,10 @@ mod tests {
         let b8 = BackupCommand::from(genprint(None));
         let b9 = BackupCommand::from(genmeh());
 
-        assert_eq!(b1, BackupCommand::Fire(Some("Dummy".to_string())));
+        assert_eq!(
+            b1,
+            BackupCommand::Fire(Some("Dummy".to_string()), FireOptions::default())
+        );
         assert_eq!(
             b2,
             BackupCommand::ChangeTarget(None, PathBuf::from("/foo/bar"))
@@ -1143,7 +1184,10 @@ mod tests {
             ret
         };
 
-        let b1 = PacketCore::from(BackupCommand::Fire(Some("Dummy".to_string())));
+        let b1 = PacketCore::from(BackupCommand::Fire(
+            Some("Dummy".to_string()),
+            FireOptions::default(),
+        ));
         let b2 = PacketCore::from(BackupCommand::ChangeTarget(None, PathBuf::from("/foo/bar")));
         let b3 = PacketCore::from(BackupCommand::ChangeSource(
             Some("Dummy".to_string()),
@@ -1240,6 +1284,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn fire_options() {
+        let core = PacketCore::from(BackupCommand::Fire(
+            Some("Dummy".to_string()),
+            FireOptions::dry_run(),
+        ));
+        assert_eq!(core[NAME_SIZE + 4], 1);
+        assert_eq!(
+            BackupCommand::from(core),
+            BackupCommand::Fire(Some("Dummy".to_string()), FireOptions { dry_run: true })
+        );
+
+        let core = PacketCore::from(BackupCommand::Fire(None, FireOptions::default()));
+        assert_eq!(
+            BackupCommand::from(core),
+            BackupCommand::Fire(None, FireOptions { dry_run: false })
+        );
+    }
+
+    #[test]
+    fn reload() {
+        let core = PacketCore::from(BackupCommand::Reload(Some("Dummy".to_string())));
+        assert_eq!(&core[0..4], b"RELO");
+        assert_eq!(
+            BackupCommand::from(core),
+            BackupCommand::Reload(Some("Dummy".to_string()))
+        );
+        let core = PacketCore::from(BackupCommand::Reload(None));
+        assert_eq!(BackupCommand::from(core), BackupCommand::Reload(None));
+    }
+
     #[test]
     fn alive() {
         let p = Packet::new_alive("foo");
@@ -88,6 +88,16 @@ impl<T: Copy> InnerQueue<T> {
         }
     }
 
+    pub fn len(&self) -> usize {
+        let mut ret = 0;
+        let mut cur = self;
+        while let InnerQueue::Node(_, ref next) = cur {
+            ret += 1;
+            cur = next;
+        }
+        ret
+    }
+
     pub fn watch(&self) -> Option<T> {
         match *self {
             InnerQueue::Node(ref it, ref next) => {
@@ -124,6 +134,14 @@ impl<T: Copy> Queue<T> {
         self.inner.borrow().is_null()
     }
 
+    pub fn len(&self) -> usize {
+        self.inner.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.empty()
+    }
+
     pub fn clear(&self) {
         self.inner.borrow_mut().set_null();
     }
@@ -188,8 +206,10 @@ mod tests {
         q.push(1);
         q.push(2);
         q.push(3);
+        assert_eq!(q.len(), 3);
         q.clear();
         assert!(q.empty());
+        assert_eq!(q.len(), 0);
     }
 
     #[test]
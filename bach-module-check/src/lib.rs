@@ -0,0 +1,517 @@
+//! Conformance checks for bach module plugins.
+//!
+//! The [`Checker`] drives a module through its whole lifecycle the way the
+//! daemon does (init, alive emission, fire, output draining, stop,
+//! terminate, destroy) and reports every deviation as a [`Violation`]
+//! instead of stopping at the first one.
+use bach_bus::packet::{parse_alive, BackupCommand, FireOptions, Notification, Packet, PacketCore};
+use bach_module::outbox::DEFAULT_OUTBOX_CAPACITY;
+use bach_module::{ModResult, Module, RUN_EARLY_TERM, RUN_FIRE, RUN_IDLE};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Delay between two polls of the module under check.
+pub static POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The module panicked during `step`.
+    Panic {
+        step: String,
+        message: String,
+    },
+    /// `step` did not return in time, the remaining checks were skipped.
+    Timeout {
+        step: String,
+    },
+    /// `step` returned an error.
+    Failed {
+        step: String,
+        message: String,
+    },
+    EmptyName,
+    NoAlive,
+    /// The fire request was never picked up by the module thread.
+    NeverFired,
+    /// The run did not return to idle within the fire timeout.
+    NeverIdle,
+    OutputNeverDrains,
+    IgnoresStop,
+    IgnoresTerminate,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Panic { step, message } => {
+                write!(f, "panicked during {}: {}", step, message)
+            }
+            Violation::Timeout { step } => write!(f, "{} did not return in time", step),
+            Violation::Failed { step, message } => write!(f, "{} failed: {}", step, message),
+            Violation::EmptyName => write!(f, "module name is empty"),
+            Violation::NoAlive => write!(f, "no alive packet emitted"),
+            Violation::NeverFired => write!(f, "fire request never picked up"),
+            Violation::NeverIdle => write!(f, "run never returned to idle"),
+            Violation::OutputNeverDrains => write!(f, "output never runs out of packets"),
+            Violation::IgnoresStop => write!(f, "module thread ignores Stop"),
+            Violation::IgnoresTerminate => write!(f, "module thread ignores Terminate"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckOptions {
+    /// Time allowed to every single call into the module, and to the module
+    /// thread to end once asked to.
+    pub step_timeout: Duration,
+    pub alive_timeout: Duration,
+    pub fire_timeout: Duration,
+    pub fire_options: FireOptions,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            step_timeout: Duration::from_secs(5),
+            alive_timeout: Duration::from_secs(3 * bach_module::ALIVE_PACKET_EMISSION_TIMEOUT),
+            fire_timeout: Duration::from_secs(60),
+            fire_options: FireOptions::dry_run(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub name: String,
+    /// Steps that went through, in order.
+    pub passed: Vec<String>,
+    pub violations: Vec<Violation>,
+    /// Packets the module output during the check.
+    pub packets: Vec<Packet>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Module {}", self.name)?;
+        for s in &self.passed {
+            writeln!(f, "\t[ok] {}", s)?;
+        }
+        for v in &self.violations {
+            writeln!(f, "\t[violation] {}", v)?;
+        }
+        write!(
+            f,
+            "{} packets output, {} violations",
+            self.packets.len(),
+            self.violations.len()
+        )
+    }
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.to_string()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+pub struct Checker {
+    options: CheckOptions,
+    module: Arc<Mutex<Box<dyn Module>>>,
+    report: Report,
+}
+
+/// Ends the check when a step hung: the module stays locked by the stuck call.
+macro_rules! or_abort {
+    ($self: ident, $e: expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(v) => {
+                let hung = matches!(v, Violation::Timeout { .. });
+                $self.report.violations.push(v);
+                if hung {
+                    return $self.report;
+                }
+                return $self.finish();
+            }
+        }
+    };
+}
+
+impl Checker {
+    pub fn new(module: Box<dyn Module>, options: CheckOptions) -> Self {
+        Checker {
+            options,
+            module: Arc::new(Mutex::new(module)),
+            report: Report::default(),
+        }
+    }
+
+    /// Calls into the module from a separate thread, so that a panic or a
+    /// call that never returns ends up as a violation.
+    fn call<T, F>(&self, step: &str, f: F) -> Result<T, Violation>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Module) -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let module = self.module.clone();
+        thread::spawn(move || {
+            let m = module.lock().unwrap_or_else(|e| e.into_inner());
+            let ret = panic::catch_unwind(AssertUnwindSafe(|| f(m.as_ref())));
+            let _ = tx.send(ret);
+        });
+        match rx.recv_timeout(self.options.step_timeout) {
+            Ok(Ok(v)) => Ok(v),
+            Ok(Err(e)) => Err(Violation::Panic {
+                step: step.to_string(),
+                message: panic_message(e.as_ref()),
+            }),
+            Err(_) => Err(Violation::Timeout {
+                step: step.to_string(),
+            }),
+        }
+    }
+
+    fn checked(&self, step: &str, r: ModResult<()>) -> Result<(), Violation> {
+        r.map_err(|e| Violation::Failed {
+            step: step.to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    fn pass(&mut self, step: &str) {
+        self.report.passed.push(step.to_string());
+    }
+
+    fn status(&self) -> Result<u8, Violation> {
+        self.call("run_status", |m| m.run_status().load(Ordering::SeqCst))
+    }
+
+    fn poll_output(&mut self) -> Result<Option<Packet>, Violation> {
+        let p = self.call("output", |m| m.output())?;
+        if let Some(p) = p {
+            self.report.packets.push(p);
+        }
+        Ok(p)
+    }
+
+    /// Empties the module outbox.
+    fn drain(&mut self) -> Result<(), Violation> {
+        for _ in 0..DEFAULT_OUTBOX_CAPACITY * 2 {
+            if self.poll_output()?.is_none() {
+                return Ok(());
+            }
+        }
+        Err(Violation::OutputNeverDrains)
+    }
+
+    /// Waits for the module thread, draining the output meanwhile.
+    fn join(
+        &mut self,
+        handle: JoinHandle<ModResult<()>>,
+        step: &str,
+        ignored: Violation,
+    ) -> Result<(), Violation> {
+        let start = Instant::now();
+        while !handle.is_finished() {
+            if start.elapsed() > self.options.step_timeout {
+                return Err(ignored);
+            }
+            self.poll_output()?;
+            thread::sleep(POLL_INTERVAL);
+        }
+        match handle.join() {
+            Ok(r) => self.checked(step, r),
+            Err(e) => Err(Violation::Panic {
+                step: step.to_string(),
+                message: panic_message(e.as_ref()),
+            }),
+        }
+    }
+
+    fn check_alive(&mut self, name: &str) -> Result<(), Violation> {
+        let start = Instant::now();
+        while start.elapsed() < self.options.alive_timeout {
+            if let Some(p @ Packet::Alive(_)) = self.poll_output()? {
+                if parse_alive(p).map(|n| n.eq(name)).unwrap_or(false) {
+                    return Ok(());
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(Violation::NoAlive)
+    }
+
+    fn check_fire(&mut self, name: &str) -> Result<(), Violation> {
+        let options = self.options.fire_options;
+        let fire = Packet::BackupCom(PacketCore::from(BackupCommand::Fire(
+            Some(name.to_string()),
+            options,
+        )));
+        let seen = self.report.packets.len();
+        self.call("input", move |m| m.input(fire))?;
+
+        let start = Instant::now();
+        while self.status()? == RUN_FIRE {
+            if start.elapsed() > self.options.step_timeout {
+                return Err(Violation::NeverFired);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        loop {
+            self.poll_output()?;
+            let c = self.status()?;
+            if c == RUN_IDLE {
+                return Ok(());
+            } else if c == RUN_EARLY_TERM {
+                self.drain()?;
+                let message = self.report.packets[seen..]
+                    .iter()
+                    .rev()
+                    .find(|p| matches!(p, Packet::NotifyErr(_)))
+                    .map(|p| Notification::from(*p).message)
+                    .unwrap_or_default();
+                return Err(Violation::Failed {
+                    step: "fire".to_string(),
+                    message,
+                });
+            } else if start.elapsed() > self.options.fire_timeout {
+                return Err(Violation::NeverIdle);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Runs the whole lifecycle and returns what went wrong.
+    pub fn run(mut self) -> Report {
+        let name = or_abort!(self, self.call("name", |m| m.name()));
+        self.report.name = name.to_string();
+        if name.is_empty() {
+            self.report.violations.push(Violation::EmptyName);
+        }
+        let init = or_abort!(self, self.call("init", |m| m.init()));
+        or_abort!(self, self.checked("init", init));
+        self.pass("init");
+
+        let handle = or_abort!(self, self.call("spawn", |m| m.spawn()));
+        self.pass("spawn");
+
+        match self.check_alive(&name) {
+            Ok(()) => self.pass("alive emission"),
+            Err(v) => self.report.violations.push(v),
+        }
+
+        or_abort!(self, self.check_fire(&name));
+        self.pass("fire");
+        or_abort!(self, self.drain());
+        self.pass("output draining");
+
+        let stop = Packet::new_stop(&name);
+        or_abort!(self, self.call("input", move |m| m.input(stop)));
+        or_abort!(self, self.join(handle, "stop", Violation::IgnoresStop));
+        self.pass("stop");
+
+        or_abort!(
+            self,
+            self.call("spawn", |m| {
+                m.run_status().store(RUN_IDLE, Ordering::SeqCst);
+                m.spawn()
+            })
+            .and_then(|handle| {
+                self.call("input", |m| m.input(Packet::new_term()))?;
+                self.join(handle, "terminate", Violation::IgnoresTerminate)
+            })
+        );
+        self.pass("terminate");
+        self.finish()
+    }
+
+    fn finish(mut self) -> Report {
+        if let Err(v) = self.drain() {
+            self.report.violations.push(v);
+        }
+        match self.call("destroy", |m| m.destroy()) {
+            Ok(r) => match self.checked("destroy", r) {
+                Ok(()) => self.pass("destroy"),
+                Err(v) => self.report.violations.push(v),
+            },
+            Err(v) => self.report.violations.push(v),
+        }
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use bach_module::{ModuleFireMethod, Outbox, RUN_TERM};
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicU8};
+
+    #[derive(Default)]
+    struct Dummy {
+        ctrl: Arc<AtomicU8>,
+        out_alive: Arc<AtomicBool>,
+        out_stack: Arc<Mutex<RefCell<Outbox>>>,
+        fire_options: Arc<Mutex<RefCell<FireOptions>>>,
+        reload_pending: Arc<AtomicBool>,
+        hang_fire: bool,
+        ignore_stop: bool,
+        ignore_terminate: bool,
+        silent: bool,
+        panic_destroy: bool,
+    }
+
+    impl Module for Dummy {
+        fn name(&self) -> String {
+            "dummy".to_string()
+        }
+
+        fn init(&self) -> ModResult<()> {
+            Ok(())
+        }
+
+        fn fire(&self) -> ModuleFireMethod {
+            let hang = self.hang_fire;
+            Box::new(move |_, run_control, _, _, _| -> ModResult<()> {
+                while hang && run_control.load(Ordering::SeqCst) != RUN_TERM {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Ok(())
+            })
+        }
+
+        fn destroy(&self) -> ModResult<()> {
+            if self.panic_destroy {
+                panic!("destroyed twice");
+            }
+            Ok(())
+        }
+
+        fn inlet(&self, _: Packet) {}
+
+        fn input(&self, p: Packet) {
+            match p {
+                Packet::BackupCom(_) => self.run_status().store(RUN_FIRE, Ordering::SeqCst),
+                Packet::Stop(_) if !self.ignore_stop => {
+                    self.run_status().store(RUN_TERM, Ordering::SeqCst)
+                }
+                Packet::Terminate if !self.ignore_terminate => {
+                    self.run_status().store(RUN_TERM, Ordering::SeqCst)
+                }
+                _ => (),
+            }
+        }
+
+        fn spawn_alive_emitter(&self) -> JoinHandle<()> {
+            if !self.silent {
+                self.emit_alive_status().store(true, Ordering::SeqCst);
+            }
+            thread::spawn(|| {})
+        }
+
+        fn run_status(&self) -> &Arc<AtomicU8> {
+            &self.ctrl
+        }
+
+        fn emit_alive_status(&self) -> &Arc<AtomicBool> {
+            &self.out_alive
+        }
+
+        fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>> {
+            &self.out_stack
+        }
+
+        fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>> {
+            &self.fire_options
+        }
+
+        fn reload_pending(&self) -> &Arc<AtomicBool> {
+            &self.reload_pending
+        }
+
+        fn config_path(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    fn check(module: Dummy) -> Report {
+        Checker::new(
+            Box::new(module),
+            CheckOptions {
+                step_timeout: Duration::from_millis(500),
+                alive_timeout: Duration::from_millis(500),
+                fire_timeout: Duration::from_millis(500),
+                fire_options: FireOptions::dry_run(),
+            },
+        )
+        .run()
+    }
+
+    #[test]
+    fn conforming_module() {
+        let report = check(Dummy::default());
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.name, "dummy");
+        assert_eq!(report.passed.last().unwrap(), "destroy");
+        assert!(report
+            .packets
+            .iter()
+            .any(|p| Notification::from(*p).message.contains("Successful End")));
+    }
+
+    #[test]
+    fn violations() {
+        let report = check(Dummy {
+            silent: true,
+            panic_destroy: true,
+            ..Dummy::default()
+        });
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::NoAlive,
+                Violation::Panic {
+                    step: "destroy".to_string(),
+                    message: "destroyed twice".to_string()
+                }
+            ]
+        );
+
+        let report = check(Dummy {
+            hang_fire: true,
+            ..Dummy::default()
+        });
+        assert_eq!(report.violations, vec![Violation::NeverIdle]);
+
+        let report = check(Dummy {
+            ignore_stop: true,
+            ..Dummy::default()
+        });
+        assert_eq!(report.violations, vec![Violation::IgnoresStop]);
+        assert!(report.to_string().contains("ignores Stop"));
+
+        let report = check(Dummy {
+            ignore_terminate: true,
+            ..Dummy::default()
+        });
+        assert_eq!(report.violations, vec![Violation::IgnoresTerminate]);
+        assert_eq!(report.passed.last().unwrap(), "destroy");
+    }
+}
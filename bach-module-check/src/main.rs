@@ -0,0 +1,72 @@
+extern crate clap;
+use bach_bus::packet::FireOptions;
+use bach_module::plugin::open_plugin;
+use bach_module_check::*;
+use clap::{App, Arg};
+use std::time::Duration;
+
+fn seconds(value: Option<&str>, default: Duration) -> Result<Duration, Box<dyn std::error::Error>> {
+    match value {
+        Some(s) => Ok(Duration::from_secs(s.parse()?)),
+        None => Ok(default),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("bach-module-check")
+        .about("Checks that a bach module plugin follows the module lifecycle")
+        .arg(
+            Arg::with_name("PLUGIN")
+                .help("Module shared library to check")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .help("Configuration file handed to the module")
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .short("t")
+                .long("timeout")
+                .takes_value(true)
+                .help("Seconds allowed to each call into the module (default 5)"),
+        )
+        .arg(
+            Arg::with_name("fire-timeout")
+                .long("fire-timeout")
+                .takes_value(true)
+                .help("Seconds allowed to a fire to return to idle (default 60)"),
+        )
+        .arg(
+            Arg::with_name("real-fire")
+                .long("real-fire")
+                .takes_value(false)
+                .help("Fire for real instead of a dry run"),
+        )
+        .get_matches();
+
+    let defaults = CheckOptions::default();
+    let options = CheckOptions {
+        step_timeout: seconds(matches.value_of("timeout"), defaults.step_timeout)?,
+        fire_timeout: seconds(matches.value_of("fire-timeout"), defaults.fire_timeout)?,
+        fire_options: if matches.is_present("real-fire") {
+            FireOptions::default()
+        } else {
+            FireOptions::dry_run()
+        },
+        ..defaults
+    };
+
+    let plugin = matches.value_of("PLUGIN").unwrap_or_default();
+    let config = matches.value_of("CONFIG").map(|s| s.to_string());
+    let (lib, module) = open_plugin(plugin, &config)?;
+    let report = Checker::new(module, options).run();
+    println!("{}", report);
+
+    // Module threads may still be running, the library is only released
+    // with the process.
+    std::mem::forget(lib);
+    std::process::exit(if report.is_ok() { 0 } else { 1 });
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time source of a [`crate::Harness`].
+///
+/// The clock drives the harness deadlines and the timestamps of recorded
+/// packets. It does not reach into the module, whose own timers keep using
+/// the system time.
+pub trait Clock: Send + Sync {
+    /// Time elapsed since the clock was created.
+    fn now(&self) -> Duration;
+    /// Lets `d` pass between two polls of the bus.
+    fn sleep(&self, d: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) {
+        thread::sleep(d);
+    }
+}
+
+/// Clock that only moves when told to.
+///
+/// `sleep` advances the virtual time by the requested amount and only yields
+/// the thread for a short real delay, so "within 5s" deadlines are reached
+/// after a fixed number of polls whatever the load of the machine.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+    real_yield: Duration,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(Duration::from_secs(0))),
+            real_yield: Duration::from_millis(1),
+        }
+    }
+
+    /// Real delay spent on each `sleep`, to give module threads a chance to run.
+    pub fn with_real_yield(mut self, real_yield: Duration) -> Self {
+        self.real_yield = real_yield;
+        self
+    }
+
+    pub fn advance(&self, d: Duration) {
+        if let Ok(mut now) = self.now.lock() {
+            *now += d;
+        }
+    }
+
+    pub fn set(&self, t: Duration) {
+        if let Ok(mut now) = self.now.lock() {
+            *now = t;
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(_) => Duration::from_secs(0),
+        }
+    }
+
+    fn sleep(&self, d: Duration) {
+        self.advance(d);
+        thread::sleep(self.real_yield);
+    }
+}
@@ -0,0 +1,408 @@
+//! In-process test harness for bach modules.
+//!
+//! A [`Harness`] owns a module, connects it to a private bus next to a
+//! recorder, and gives assertions over the packets seen on that bus:
+//!
+//! ```ignore
+//! let mut h = Harness::new(Rsync::new(&Some(config)));
+//! h.init()?;
+//! h.spawn();
+//! h.fire();
+//! h.expect(Expect::notify_good().from("rsync").at_stage("Exit"))
+//!     .within(Duration::from_secs(5));
+//! h.terminate();
+//! ```
+use bach_bus::bus::{Bus, BusConnection};
+use bach_bus::packet::{BackupCommand, FireOptions, Packet, PacketCore};
+use bach_module::{ModResult, Module};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub mod clock;
+pub mod matcher;
+
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use matcher::{describe, Expect, PacketKind};
+
+/// Delay between two bus rounds while waiting for a packet.
+pub static POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Recorded {
+    /// Harness clock time at which the packet went through the bus.
+    pub at: Duration,
+    pub packet: Packet,
+}
+
+pub struct Harness<M: Module> {
+    module: Arc<Mutex<M>>,
+    bus: Bus,
+    recorded: Arc<Mutex<Vec<Recorded>>>,
+    clock: Arc<dyn Clock>,
+    handle: Option<JoinHandle<ModResult<()>>>,
+}
+
+impl<M: Module> Harness<M> {
+    pub fn new(module: M) -> Self {
+        Harness::with_clock(module, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(module: M, clock: Arc<dyn Clock>) -> Self {
+        let module = Arc::new(Mutex::new(module));
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let bus = Bus::new();
+
+        let rec = recorded.clone();
+        let rec_clock = clock.clone();
+        bus.connect(BusConnection::new(
+            move |packet| {
+                if let Ok(mut r) = rec.lock() {
+                    r.push(Recorded {
+                        at: rec_clock.now(),
+                        packet,
+                    });
+                }
+            },
+            || -> Option<Packet> { None },
+        ));
+
+        let min = module.clone();
+        let mout = module.clone();
+        bus.connect(BusConnection::new(
+            move |packet| {
+                if let Ok(m) = min.lock() {
+                    m.input(packet);
+                }
+            },
+            move || -> Option<Packet> {
+                match mout.lock() {
+                    Ok(m) => m.output(),
+                    Err(_) => None,
+                }
+            },
+        ));
+
+        Harness {
+            module,
+            bus,
+            recorded,
+            clock,
+            handle: None,
+        }
+    }
+
+    /// Runs `f` with the module under test.
+    pub fn with_module<T, F: FnOnce(&M) -> T>(&self, f: F) -> T {
+        let m = self.module.lock().expect("Module under test poisoned");
+        f(&m)
+    }
+
+    pub fn name(&self) -> String {
+        self.with_module(|m| m.name())
+    }
+
+    pub fn init(&self) -> ModResult<()> {
+        self.with_module(|m| m.init())
+    }
+
+    pub fn spawn(&mut self) {
+        if self.handle.is_none() {
+            self.handle = Some(self.with_module(|m| m.spawn()));
+        }
+    }
+
+    pub fn send(&self, p: Packet) {
+        self.bus.send(p);
+    }
+
+    pub fn fire(&self) {
+        self.fire_with(FireOptions::default());
+    }
+
+    pub fn fire_with(&self, options: FireOptions) {
+        self.send(Packet::BackupCom(PacketCore::from(BackupCommand::Fire(
+            Some(self.name()),
+            options,
+        ))));
+    }
+
+    pub fn stop(&self) {
+        self.send(Packet::new_stop(&self.name()));
+    }
+
+    /// Sends `Terminate`, flushes the bus and waits for the module thread.
+    pub fn terminate(&mut self) -> Option<ModResult<()>> {
+        self.send(Packet::new_term());
+        self.step();
+        let ret = self.handle.take().map(|h| match h.join() {
+            Ok(r) => r,
+            Err(e) => Err(e.into()),
+        });
+        self.step();
+        ret
+    }
+
+    /// Lets the bus run until nothing is left to deliver.
+    pub fn step(&self) {
+        loop {
+            self.bus.perform();
+            if self.bus.is_idle() {
+                break;
+            }
+        }
+    }
+
+    /// Keeps the bus running for `d` of harness time.
+    pub fn run_for(&self, d: Duration) {
+        let deadline = self.clock.now() + d;
+        while self.clock.now() < deadline {
+            self.step();
+            self.clock.sleep(POLL_INTERVAL);
+        }
+        self.step();
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn recorded(&self) -> Vec<Recorded> {
+        match self.recorded.lock() {
+            Ok(r) => r.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Every packet seen on the bus so far, oldest first.
+    pub fn packets(&self) -> Vec<Packet> {
+        self.recorded().into_iter().map(|r| r.packet).collect()
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut r) = self.recorded.lock() {
+            r.clear();
+        }
+    }
+
+    pub fn find(&self, expect: &Expect) -> Option<Packet> {
+        self.packets().into_iter().find(|p| expect.matches(p))
+    }
+
+    pub fn count(&self, expect: &Expect) -> usize {
+        self.packets().iter().filter(|p| expect.matches(p)).count()
+    }
+
+    /// Runs the bus until a packet matching `expect` shows up or `timeout`
+    /// of harness time has passed.
+    pub fn wait_for(&self, expect: &Expect, timeout: Duration) -> Option<Packet> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            self.step();
+            if let Some(p) = self.find(expect) {
+                return Some(p);
+            }
+            if self.clock.now() >= deadline {
+                return None;
+            }
+            self.clock.sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Starts an assertion, see [`Expectation`].
+    pub fn expect(&self, expect: Expect) -> Expectation<'_, M> {
+        Expectation {
+            harness: self,
+            expect,
+        }
+    }
+
+    /// Panics unless the recorded packets contain packets matching
+    /// `sequence`, in that order. Other packets may come in between.
+    pub fn assert_sequence(&self, sequence: &[Expect]) {
+        let packets = self.packets();
+        let mut it = packets.iter();
+        for e in sequence {
+            if !it.any(|p| e.matches(p)) {
+                panic!(
+                    "Expected {} in sequence [{}]\n{}",
+                    e,
+                    sequence
+                        .iter()
+                        .map(|e| e.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                    self.dump()
+                );
+            }
+        }
+    }
+
+    /// Panics if a packet matching `expect` was recorded.
+    pub fn assert_none(&self, expect: &Expect) {
+        if let Some(p) = self.find(expect) {
+            panic!("Unexpected {}\n{}", describe(&p), self.dump());
+        }
+    }
+
+    /// Recorded packets, one per line, for assertion failures.
+    pub fn dump(&self) -> String {
+        let mut ret = "Recorded packets:".to_string();
+        for r in self.recorded() {
+            ret.push_str(&format!(
+                "\n  [{:>8.3}s] {}",
+                r.at.as_secs_f64(),
+                describe(&r.packet)
+            ));
+        }
+        ret
+    }
+}
+
+impl<M: Module> Drop for Harness<M> {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            self.terminate();
+        }
+    }
+}
+
+/// Pending assertion on a [`Harness`].
+pub struct Expectation<'a, M: Module> {
+    harness: &'a Harness<M>,
+    expect: Expect,
+}
+
+impl<'a, M: Module> Expectation<'a, M> {
+    /// Waits for the packet and returns it, panics once `timeout` has passed.
+    pub fn within(self, timeout: Duration) -> Packet {
+        match self.harness.wait_for(&self.expect, timeout) {
+            Some(p) => p,
+            None => panic!(
+                "Expected {} within {:?}\n{}",
+                self.expect,
+                timeout,
+                self.harness.dump()
+            ),
+        }
+    }
+
+    /// Checks the packets recorded so far, without running the bus.
+    pub fn already(self) -> Packet {
+        match self.harness.find(&self.expect) {
+            Some(p) => p,
+            None => panic!("Expected {}\n{}", self.expect, self.harness.dump()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use bach_module::{ModuleFireMethod, Outbox, RUN_IDLE};
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+    struct Dummy {
+        ctrl: Arc<AtomicU8>,
+        out_alive: Arc<AtomicBool>,
+        out_stack: Arc<Mutex<RefCell<Outbox>>>,
+        fire_options: Arc<Mutex<RefCell<FireOptions>>>,
+        reload_pending: Arc<AtomicBool>,
+    }
+
+    impl Dummy {
+        fn new() -> Self {
+            Dummy {
+                ctrl: Arc::new(AtomicU8::new(0)),
+                out_alive: Arc::new(AtomicBool::new(false)),
+                out_stack: Arc::new(Mutex::new(RefCell::new(Outbox::new()))),
+                fire_options: Arc::new(Mutex::new(RefCell::new(FireOptions::default()))),
+                reload_pending: Arc::new(AtomicBool::new(false)),
+            }
+        }
+    }
+
+    impl Module for Dummy {
+        fn name(&self) -> String {
+            "dummy".to_string()
+        }
+
+        fn init(&self) -> ModResult<()> {
+            self.outlet(Packet::new_ng("dummy initialized", "dummy", "Init"));
+            Ok(())
+        }
+
+        fn fire(&self) -> ModuleFireMethod {
+            Box::new(|message_stack, run_control, _, name, _| -> ModResult<()> {
+                message_stack.lock()?.borrow_mut().push(Packet::new_ng(
+                    "Command launched",
+                    &name.lock()?.borrow(),
+                    "Run",
+                ));
+                run_control.store(RUN_IDLE, Ordering::SeqCst);
+                Ok(())
+            })
+        }
+
+        fn destroy(&self) -> ModResult<()> {
+            Ok(())
+        }
+
+        fn inlet(&self, _: Packet) {}
+
+        fn run_status(&self) -> &Arc<AtomicU8> {
+            &self.ctrl
+        }
+
+        fn emit_alive_status(&self) -> &Arc<AtomicBool> {
+            &self.out_alive
+        }
+
+        fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>> {
+            &self.out_stack
+        }
+
+        fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>> {
+            &self.fire_options
+        }
+
+        fn reload_pending(&self) -> &Arc<AtomicBool> {
+            &self.reload_pending
+        }
+
+        fn config_path(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    #[test]
+    fn fire_sequence() {
+        let clock = ManualClock::new();
+        let mut h = Harness::with_clock(Dummy::new(), Arc::new(clock.clone()));
+        h.init().unwrap();
+        h.spawn();
+        h.fire();
+        h.expect(Expect::notify_good().from("dummy").at_stage("END"))
+            .within(Duration::from_secs(5));
+        assert!(clock.now() < Duration::from_secs(5));
+        h.assert_sequence(&[
+            Expect::notify_good().at_stage("Init"),
+            Expect::notify_good().containing("launched"),
+            Expect::notify_good().containing("Successful End"),
+        ]);
+        h.assert_none(&Expect::notify_err());
+        assert_eq!(h.count(&Expect::notify_good().from("dummy")), 3);
+        assert!(h.terminate().unwrap().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected NotifyErr from dummy within")]
+    fn times_out_on_harness_clock() {
+        let clock = ManualClock::new().with_real_yield(Duration::from_micros(100));
+        let h = Harness::with_clock(Dummy::new(), Arc::new(clock));
+        h.expect(Expect::notify_err().from("dummy"))
+            .within(Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,194 @@
+use bach_bus::packet::{core_2_string, parse_alive, LoggerCommand, Notification, Packet};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    NotifyGood,
+    NotifyWarn,
+    NotifyErr,
+    NotifyCom,
+    WatchReportGood,
+    WatchReportWarn,
+    WatchReportFail,
+    WatchHold,
+    WatchCom,
+    BackupCom,
+    LoggerCom,
+    Stop,
+    Alive,
+    Terminate,
+}
+
+impl From<&Packet> for PacketKind {
+    fn from(item: &Packet) -> Self {
+        match item {
+            Packet::NotifyGood(_) => PacketKind::NotifyGood,
+            Packet::NotifyWarn(_) => PacketKind::NotifyWarn,
+            Packet::NotifyErr(_) => PacketKind::NotifyErr,
+            Packet::NotifyCom(_) => PacketKind::NotifyCom,
+            Packet::WatchReportGood(_) => PacketKind::WatchReportGood,
+            Packet::WatchReportWarn(_) => PacketKind::WatchReportWarn,
+            Packet::WatchReportFail(_) => PacketKind::WatchReportFail,
+            Packet::WatchHold => PacketKind::WatchHold,
+            Packet::WatchCom(_) => PacketKind::WatchCom,
+            Packet::BackupCom(_) => PacketKind::BackupCom,
+            Packet::LoggerCom(_) => PacketKind::LoggerCom,
+            Packet::Stop(_) => PacketKind::Stop,
+            Packet::Alive(_) => PacketKind::Alive,
+            Packet::Terminate => PacketKind::Terminate,
+        }
+    }
+}
+
+fn accepts<F: FnOnce(&str) -> bool>(wanted: &Option<String>, check: F) -> bool {
+    match wanted {
+        Some(s) => check(s),
+        None => true,
+    }
+}
+
+fn is_notification(kind: PacketKind) -> bool {
+    matches!(
+        kind,
+        PacketKind::NotifyGood | PacketKind::NotifyWarn | PacketKind::NotifyErr
+    )
+}
+
+/// Human readable form of a packet, used in assertion failures.
+pub fn describe(p: &Packet) -> String {
+    let kind = PacketKind::from(p);
+    match p {
+        _ if is_notification(kind) => {
+            let n = Notification::from(*p);
+            format!(
+                "{:?} from {} at stage {}: {}",
+                kind, n.provider, n.stage, n.message
+            )
+        }
+        Packet::LoggerCom(core) => match LoggerCommand::from(*core) {
+            LoggerCommand::Write(s) => format!("LoggerCom: {}", s),
+            _ => "LoggerCom".to_string(),
+        },
+        Packet::Alive(_) => format!("Alive from {}", parse_alive(*p).unwrap_or_default()),
+        Packet::Stop(core) => format!("Stop {}", core_2_string(core)),
+        _ => format!("{:?}", kind),
+    }
+}
+
+/// Describes the packets an assertion waits for.
+///
+/// ```ignore
+/// Expect::notify_good().from("rsync").at_stage("Exit")
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Expect {
+    kind: Option<PacketKind>,
+    provider: Option<String>,
+    stage: Option<String>,
+    message: Option<String>,
+}
+
+impl Expect {
+    pub fn any() -> Self {
+        Expect::default()
+    }
+
+    pub fn kind(kind: PacketKind) -> Self {
+        Expect {
+            kind: Some(kind),
+            ..Expect::default()
+        }
+    }
+
+    pub fn notify_good() -> Self {
+        Expect::kind(PacketKind::NotifyGood)
+    }
+
+    pub fn notify_warn() -> Self {
+        Expect::kind(PacketKind::NotifyWarn)
+    }
+
+    pub fn notify_err() -> Self {
+        Expect::kind(PacketKind::NotifyErr)
+    }
+
+    pub fn logger() -> Self {
+        Expect::kind(PacketKind::LoggerCom)
+    }
+
+    pub fn alive() -> Self {
+        Expect::kind(PacketKind::Alive)
+    }
+
+    /// Notification provider, or name carried by an alive packet.
+    pub fn from(mut self, provider: &str) -> Self {
+        self.provider = Some(provider.to_string());
+        self
+    }
+
+    pub fn at_stage(mut self, stage: &str) -> Self {
+        self.stage = Some(stage.to_string());
+        self
+    }
+
+    /// Substring of the notification message or of the logged line.
+    pub fn containing(mut self, message: &str) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn matches(&self, p: &Packet) -> bool {
+        let kind = PacketKind::from(p);
+        if let Some(k) = self.kind {
+            if k != kind {
+                return false;
+            }
+        }
+        if self.provider.is_none() && self.stage.is_none() && self.message.is_none() {
+            return true;
+        }
+
+        if is_notification(kind) {
+            let n = Notification::from(*p);
+            accepts(&self.provider, |s| n.provider.eq(s))
+                && accepts(&self.stage, |s| n.stage.eq(s))
+                && accepts(&self.message, |s| n.message.contains(s))
+        } else if let Packet::LoggerCom(core) = p {
+            match LoggerCommand::from(*core) {
+                LoggerCommand::Write(line) => {
+                    self.provider.is_none()
+                        && self.stage.is_none()
+                        && accepts(&self.message, |s| line.contains(s))
+                }
+                _ => false,
+            }
+        } else if let Packet::Alive(_) = p {
+            self.stage.is_none()
+                && self.message.is_none()
+                && accepts(&self.provider, |s| {
+                    parse_alive(*p).map(|n| n.eq(s)).unwrap_or(false)
+                })
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Some(k) => write!(f, "{:?}", k)?,
+            None => write!(f, "any packet")?,
+        }
+        if let Some(p) = &self.provider {
+            write!(f, " from {}", p)?;
+        }
+        if let Some(s) = &self.stage {
+            write!(f, " at stage {}", s)?;
+        }
+        if let Some(m) = &self.message {
+            write!(f, " containing {:?}", m)?;
+        }
+        Ok(())
+    }
+}
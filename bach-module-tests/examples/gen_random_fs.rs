@@ -0,0 +1,22 @@
+use bach_module_tests::fixtures::{generate, TreeSpec};
+use std::path::Path;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        println!("Usage: {} MAXDEPTH WIDTH PATH [SEED]", args[0]);
+        std::process::exit(1);
+    }
+
+    let spec = TreeSpec {
+        max_depth: args[1].parse()?,
+        width: args[2].parse()?,
+        ..TreeSpec::new(match args.get(4) {
+            Some(s) => s.parse()?,
+            None => 0,
+        })
+    };
+    let summary = generate(Path::new(&args[3]), &spec)?;
+    println!("{:?}", summary);
+    Ok(())
+}
@@ -0,0 +1,656 @@
+//! Seeded random directory trees, and tree comparison to check that a backup
+//! is a faithful copy of its source.
+//!
+//! ```ignore
+//! let summary = fixtures::generate(&source, &TreeSpec::new(42))?;
+//! // ... back up source into target ...
+//! fixtures::assert_trees_eq(&source, &target, &CompareOptions::default());
+//! ```
+//!
+//! The example configurations of a module name their source and target with
+//! [`SOURCE_PLACEHOLDER`] and [`TARGET_PLACEHOLDER`], a [`Fixture`] points
+//! them at a generated tree.
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::fs::{self, File, FileTimes, Permissions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Prefixes of the Unicode names: accents, combining marks, other scripts,
+/// spaces and emoji.
+static UNICODE_NAMES: [&str; 8] = [
+    "été",
+    "e\u{301}cole",
+    "日本語",
+    "Ωmega",
+    "ñandú",
+    "Ünïcödé",
+    "с пробелом",
+    "crab 🦀",
+];
+static FILE_MODES: [u32; 6] = [0o400, 0o444, 0o600, 0o640, 0o700, 0o755];
+/// Directory modes, all of them keep the tree writable by its owner.
+static DIR_MODES: [u32; 4] = [0o700, 0o711, 0o750, 0o1777];
+/// Generated modification times fall within the year after 2021-01-01.
+static BASE_MTIME: u64 = 1_609_459_200;
+static CHUNK_SIZE: usize = 64 * 1024;
+/// Replaced by the generated source tree in the example configurations.
+pub static SOURCE_PLACEHOLDER: &str = "@FIXTURE_SOURCE@";
+/// Replaced by the empty target directory in the example configurations.
+pub static TARGET_PLACEHOLDER: &str = "@FIXTURE_TARGET@";
+
+/// Shape of a generated tree. The same spec always gives the same tree.
+#[derive(Clone, Debug)]
+pub struct TreeSpec {
+    pub seed: u64,
+    pub max_depth: usize,
+    /// Entries created in every directory.
+    pub width: usize,
+    /// Upper bound of regular file sizes.
+    pub max_file_size: usize,
+    /// Size of the large files, written for real.
+    pub large_file_size: u64,
+    /// Apparent size of the sparse files, mostly made of holes.
+    pub sparse_file_size: u64,
+    pub symlinks: bool,
+    pub hard_links: bool,
+    pub odd_permissions: bool,
+    pub unicode_names: bool,
+}
+
+impl Default for TreeSpec {
+    fn default() -> Self {
+        TreeSpec {
+            seed: 0,
+            max_depth: 3,
+            width: 5,
+            max_file_size: 64 * 1024,
+            large_file_size: 4 * 1024 * 1024,
+            sparse_file_size: 64 * 1024 * 1024,
+            symlinks: true,
+            hard_links: true,
+            odd_permissions: true,
+            unicode_names: true,
+        }
+    }
+}
+
+impl TreeSpec {
+    pub fn new(seed: u64) -> Self {
+        TreeSpec {
+            seed,
+            ..TreeSpec::default()
+        }
+    }
+
+    /// A tree quick to generate and back up, for the module tests.
+    pub fn small(seed: u64) -> Self {
+        TreeSpec {
+            max_depth: 2,
+            max_file_size: 4096,
+            large_file_size: 256 * 1024,
+            sparse_file_size: 1024 * 1024,
+            ..TreeSpec::new(seed)
+        }
+    }
+}
+
+/// What [`generate`] created.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeSummary {
+    pub dirs: usize,
+    pub files: usize,
+    pub symlinks: usize,
+    pub hard_links: usize,
+    /// Apparent size of the regular files, hard links counted once.
+    pub bytes: u64,
+}
+
+struct Generator<'a> {
+    spec: &'a TreeSpec,
+    rng: ChaCha8Rng,
+    files: Vec<PathBuf>,
+    summary: TreeSummary,
+}
+
+/// Fills `root` with a random tree, creating it if needed.
+pub fn generate(root: &Path, spec: &TreeSpec) -> io::Result<TreeSummary> {
+    fs::create_dir_all(root)?;
+    let mut g = Generator {
+        spec,
+        rng: ChaCha8Rng::seed_from_u64(spec.seed),
+        files: Vec::new(),
+        summary: TreeSummary::default(),
+    };
+    g.populate(root, spec.max_depth)?;
+    Ok(g.summary)
+}
+
+impl<'a> Generator<'a> {
+    fn name(&mut self, taken: &mut HashSet<String>) -> String {
+        loop {
+            let n = self.rng.gen_range(10_000_000..100_000_000);
+            let name = if self.spec.unicode_names && self.rng.gen_bool(0.3) {
+                let prefix = UNICODE_NAMES[self.rng.gen_range(0..UNICODE_NAMES.len())];
+                format!("{} {}", prefix, n)
+            } else {
+                n.to_string()
+            };
+            if taken.insert(name.to_string()) {
+                return name;
+            }
+        }
+    }
+
+    fn populate(&mut self, dir: &Path, depth: usize) -> io::Result<()> {
+        let mut taken = HashSet::new();
+        let mut siblings = Vec::new();
+        for _ in 0..self.spec.width {
+            let name = self.name(&mut taken);
+            let path = dir.join(&name);
+            match self.rng.gen_range(0..100) {
+                0..=29 if depth > 0 => {
+                    fs::create_dir(&path)?;
+                    self.summary.dirs += 1;
+                    self.populate(&path, depth - 1)?;
+                    self.finish(&path, &DIR_MODES, 0o755)?;
+                }
+                30..=39 if self.spec.symlinks => self.symlink(&path, &siblings)?,
+                40..=49 if self.spec.hard_links && !self.files.is_empty() => {
+                    let source = &self.files[self.rng.gen_range(0..self.files.len())];
+                    fs::hard_link(source, &path)?;
+                    self.summary.hard_links += 1;
+                }
+                50..=52 => self.large_file(&path, true)?,
+                53..=54 => self.large_file(&path, false)?,
+                _ => self.file(&path)?,
+            }
+            siblings.push(name);
+        }
+        Ok(())
+    }
+
+    /// Relative link to a sibling, to the parent directory, or dangling.
+    fn symlink(&mut self, path: &Path, siblings: &[String]) -> io::Result<()> {
+        let target = match self.rng.gen_range(0..3) {
+            0 if !siblings.is_empty() => {
+                siblings[self.rng.gen_range(0..siblings.len())].to_string()
+            }
+            1 => "..".to_string(),
+            _ => format!("nowhere-{}", self.rng.gen_range(0..1000)),
+        };
+        symlink(target, path)?;
+        self.summary.symlinks += 1;
+        Ok(())
+    }
+
+    fn file(&mut self, path: &Path) -> io::Result<()> {
+        let mut content = vec![0u8; self.rng.gen_range(0..=self.spec.max_file_size)];
+        self.rng.fill_bytes(&mut content);
+        fs::write(path, &content)?;
+        self.created(path, content.len() as u64)
+    }
+
+    fn large_file(&mut self, path: &Path, sparse: bool) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let size = if sparse {
+            let size = self.spec.sparse_file_size;
+            let len = chunk.len().min(size as usize);
+            f.set_len(size)?;
+            f.seek(SeekFrom::Start(self.rng.gen_range(0..=size - len as u64)))?;
+            self.rng.fill_bytes(&mut chunk[..len]);
+            f.write_all(&chunk[..len])?;
+            size
+        } else {
+            let mut left = self.spec.large_file_size;
+            while left > 0 {
+                let len = chunk.len().min(left as usize);
+                self.rng.fill_bytes(&mut chunk[..len]);
+                f.write_all(&chunk[..len])?;
+                left -= len as u64;
+            }
+            self.spec.large_file_size
+        };
+        drop(f);
+        self.created(path, size)
+    }
+
+    fn created(&mut self, path: &Path, size: u64) -> io::Result<()> {
+        self.summary.files += 1;
+        self.summary.bytes += size;
+        self.files.push(path.to_path_buf());
+        self.finish(path, &FILE_MODES, 0o644)
+    }
+
+    /// Sets the final mode and modification time, once nothing else gets
+    /// written in `path`.
+    fn finish(&mut self, path: &Path, modes: &[u32], default: u32) -> io::Result<()> {
+        let mode = if self.spec.odd_permissions {
+            modes[self.rng.gen_range(0..modes.len())]
+        } else {
+            default
+        };
+        let mtime = SystemTime::UNIX_EPOCH
+            + Duration::from_secs(BASE_MTIME + self.rng.gen_range(0..365 * 86400));
+        File::open(path)?.set_times(FileTimes::new().set_accessed(mtime).set_modified(mtime))?;
+        fs::set_permissions(path, Permissions::from_mode(mode))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    kind: EntryKind,
+    mode: u32,
+    size: u64,
+    mtime: i64,
+    owner: (u32, u32),
+    inode: (u64, u64),
+    nlink: u64,
+    target: Option<PathBuf>,
+}
+
+/// Metadata checked by [`compare_trees`] on top of the tree shape, file
+/// contents and link targets.
+#[derive(Clone, Debug)]
+pub struct CompareOptions {
+    pub permissions: bool,
+    /// Modification times of files and directories.
+    pub mtime: bool,
+    pub ownership: bool,
+    /// Files hard linked together on one side must be on the other.
+    pub hard_links: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions {
+            permissions: true,
+            mtime: true,
+            ownership: true,
+            hard_links: true,
+        }
+    }
+}
+
+impl CompareOptions {
+    pub fn content_only() -> Self {
+        CompareOptions {
+            permissions: false,
+            mtime: false,
+            ownership: false,
+            hard_links: false,
+        }
+    }
+}
+
+/// A way the right tree differs from the left one, paths are relative to
+/// the tree roots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    Missing(PathBuf),
+    Unexpected(PathBuf),
+    Kind(PathBuf),
+    Content(PathBuf),
+    LinkTarget(PathBuf),
+    Permissions {
+        path: PathBuf,
+        left: u32,
+        right: u32,
+    },
+    Mtime {
+        path: PathBuf,
+        left: i64,
+        right: i64,
+    },
+    Ownership(PathBuf),
+    HardLinks(PathBuf),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Missing(p) => write!(f, "{:?} missing", p),
+            Difference::Unexpected(p) => write!(f, "{:?} unexpected", p),
+            Difference::Kind(p) => write!(f, "{:?} has a different file type", p),
+            Difference::Content(p) => write!(f, "{:?} content differs", p),
+            Difference::LinkTarget(p) => write!(f, "{:?} link target differs", p),
+            Difference::Permissions { path, left, right } => {
+                write!(f, "{:?} mode {:o} instead of {:o}", path, right, left)
+            }
+            Difference::Mtime { path, left, right } => {
+                write!(f, "{:?} modified at {} instead of {}", path, right, left)
+            }
+            Difference::Ownership(p) => write!(f, "{:?} owner differs", p),
+            Difference::HardLinks(p) => write!(f, "{:?} hard links differ", p),
+        }
+    }
+}
+
+fn scan(root: &Path, dir: &Path, entries: &mut BTreeMap<PathBuf, Entry>) -> io::Result<()> {
+    for e in fs::read_dir(dir)? {
+        let path = e?.path();
+        let meta = fs::symlink_metadata(&path)?;
+        let ft = meta.file_type();
+        let kind = if ft.is_dir() {
+            EntryKind::Dir
+        } else if ft.is_file() {
+            EntryKind::File
+        } else if ft.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::Other
+        };
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+        entries.insert(
+            relative,
+            Entry {
+                kind,
+                mode: meta.mode() & 0o7777,
+                size: meta.len(),
+                mtime: meta.mtime(),
+                owner: (meta.uid(), meta.gid()),
+                inode: (meta.dev(), meta.ino()),
+                nlink: meta.nlink(),
+                target: if kind == EntryKind::Symlink {
+                    Some(fs::read_link(&path)?)
+                } else {
+                    None
+                },
+            },
+        );
+        if kind == EntryKind::Dir {
+            scan(root, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn same_content(left: &Path, right: &Path) -> io::Result<bool> {
+    let mut l = File::open(left)?;
+    let mut r = File::open(right)?;
+    let mut lbuf = vec![0u8; CHUNK_SIZE];
+    let mut rbuf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = l.read(&mut lbuf)?;
+        if n == 0 {
+            return Ok(r.read(&mut rbuf[..1])? == 0);
+        }
+        r.read_exact(&mut rbuf[..n])?;
+        if lbuf[..n] != rbuf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Paths of the tree sharing the inode of `entry`.
+fn link_group(entries: &BTreeMap<PathBuf, Entry>, entry: &Entry) -> BTreeSet<PathBuf> {
+    entries
+        .iter()
+        .filter(|(_, e)| e.inode == entry.inode)
+        .map(|(p, _)| p.to_path_buf())
+        .collect()
+}
+
+/// Lists how the tree under `right` differs from the one under `left`. The
+/// roots themselves are not compared.
+pub fn compare_trees(
+    left: &Path,
+    right: &Path,
+    options: &CompareOptions,
+) -> io::Result<Vec<Difference>> {
+    let mut lentries = BTreeMap::new();
+    let mut rentries = BTreeMap::new();
+    scan(left, left, &mut lentries)?;
+    scan(right, right, &mut rentries)?;
+
+    let mut ret = Vec::new();
+    for (path, l) in &lentries {
+        let r = match rentries.get(path) {
+            Some(r) => r,
+            None => {
+                ret.push(Difference::Missing(path.to_path_buf()));
+                continue;
+            }
+        };
+        if l.kind != r.kind {
+            ret.push(Difference::Kind(path.to_path_buf()));
+            continue;
+        }
+        match l.kind {
+            EntryKind::File => {
+                if l.size != r.size || !same_content(&left.join(path), &right.join(path))? {
+                    ret.push(Difference::Content(path.to_path_buf()));
+                }
+                if options.hard_links
+                    && (l.nlink > 1 || r.nlink > 1)
+                    && link_group(&lentries, l) != link_group(&rentries, r)
+                {
+                    ret.push(Difference::HardLinks(path.to_path_buf()));
+                }
+            }
+            EntryKind::Symlink if l.target != r.target => {
+                ret.push(Difference::LinkTarget(path.to_path_buf()));
+            }
+            _ => (),
+        }
+        if l.kind == EntryKind::Symlink {
+            continue;
+        }
+        if options.permissions && l.mode != r.mode {
+            ret.push(Difference::Permissions {
+                path: path.to_path_buf(),
+                left: l.mode,
+                right: r.mode,
+            });
+        }
+        if options.mtime && l.mtime != r.mtime {
+            ret.push(Difference::Mtime {
+                path: path.to_path_buf(),
+                left: l.mtime,
+                right: r.mtime,
+            });
+        }
+        if options.ownership && l.owner != r.owner {
+            ret.push(Difference::Ownership(path.to_path_buf()));
+        }
+    }
+    for path in rentries.keys() {
+        if !lentries.contains_key(path) {
+            ret.push(Difference::Unexpected(path.to_path_buf()));
+        }
+    }
+    Ok(ret)
+}
+
+/// Panics with the list of differences unless both trees match.
+pub fn assert_trees_eq(left: &Path, right: &Path, options: &CompareOptions) {
+    let differences = compare_trees(left, right, options)
+        .unwrap_or_else(|e| panic!("Unable to compare {:?} and {:?}: {}", left, right, e));
+    if !differences.is_empty() {
+        panic!(
+            "{:?} differs from {:?}:\n\t{}",
+            right,
+            left,
+            differences
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<String>>()
+                .join("\n\t")
+        );
+    }
+}
+
+/// An example configuration copied into a directory of its own, next to a
+/// generated source tree and an empty target its placeholders point at. The
+/// directory is removed on drop.
+pub struct Fixture {
+    pub dir: PathBuf,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub config: PathBuf,
+    /// Whether the configuration backs up into the target.
+    local_target: bool,
+}
+
+impl Fixture {
+    pub fn prepare(config: &Path, spec: &TreeSpec) -> io::Result<Self> {
+        let name = config
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No configuration file"))?;
+        let dir = std::env::temp_dir().join(format!(
+            "bach-fixture-{}-{}",
+            std::process::id(),
+            name.to_string_lossy()
+        ));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        let source = dir.join("source");
+        let target = dir.join("target");
+        generate(&source, spec)?;
+        fs::create_dir_all(&target)?;
+        let content = fs::read_to_string(config)?;
+        let local_target = content.contains(TARGET_PLACEHOLDER);
+        let content = content
+            .replace(SOURCE_PLACEHOLDER, &source.to_string_lossy())
+            .replace(TARGET_PLACEHOLDER, &target.to_string_lossy());
+        let config = dir.join(name);
+        fs::write(&config, content)?;
+        Ok(Fixture {
+            dir,
+            source,
+            target,
+            config,
+            local_target,
+        })
+    }
+
+    /// Panics unless the target is a copy of the source, when the
+    /// configuration backs up into it.
+    pub fn assert_backed_up(&self, options: &CompareOptions) {
+        if self.local_target {
+            assert_trees_eq(&self.source, &self.target, options);
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let ret =
+            std::env::temp_dir().join(format!("bach-fixtures-{}-{}", std::process::id(), name));
+        if ret.exists() {
+            fs::remove_dir_all(&ret).unwrap();
+        }
+        ret
+    }
+
+    #[test]
+    fn seeded_trees() {
+        let (a, b, c) = (temp_dir("a"), temp_dir("b"), temp_dir("c"));
+        let summary = generate(&a, &TreeSpec::small(7)).unwrap();
+        assert_eq!(generate(&b, &TreeSpec::small(7)).unwrap(), summary);
+        assert!(summary.files > 0 && summary.dirs > 0);
+        assert!(summary.symlinks > 0 && summary.hard_links > 0);
+        assert_trees_eq(&a, &b, &CompareOptions::default());
+
+        generate(&c, &TreeSpec::small(8)).unwrap();
+        assert!(!compare_trees(&a, &c, &CompareOptions::content_only())
+            .unwrap()
+            .is_empty());
+
+        let mut lentries = BTreeMap::new();
+        scan(&b, &b, &mut lentries).unwrap();
+        let (path, _) = lentries
+            .iter()
+            .find(|(_, e)| e.kind == EntryKind::File && e.nlink == 1 && e.size > 0)
+            .unwrap();
+        let file = b.join(path);
+        fs::set_permissions(&file, Permissions::from_mode(0o600)).unwrap();
+        let mut content = fs::read(&file).unwrap();
+        content[0] ^= 0xff;
+        fs::write(&file, content).unwrap();
+        fs::set_permissions(&file, Permissions::from_mode(0o604)).unwrap();
+        let differences = compare_trees(&a, &b, &CompareOptions::default()).unwrap();
+        assert!(differences.contains(&Difference::Content(path.to_path_buf())));
+        assert!(differences
+            .iter()
+            .any(|d| matches!(d, Difference::Permissions { path: p, .. } if p == path)));
+        assert!(differences
+            .iter()
+            .any(|d| matches!(d, Difference::Mtime { path: p, .. } if p == path)));
+        assert_eq!(
+            compare_trees(&a, &b, &CompareOptions::content_only()).unwrap(),
+            vec![Difference::Content(path.to_path_buf())]
+        );
+
+        for d in [a, b, c] {
+            fs::remove_dir_all(d).unwrap();
+        }
+    }
+
+    #[test]
+    fn fixtures_of_example_configurations() {
+        let dir = temp_dir("configs");
+        fs::create_dir_all(&dir).unwrap();
+        let local = dir.join("example.config.1.xml");
+        fs::write(
+            &local,
+            format!(
+                "<c from=\"{}/\" to=\"{}/\"/>",
+                SOURCE_PLACEHOLDER, TARGET_PLACEHOLDER
+            ),
+        )
+        .unwrap();
+        let fixture = Fixture::prepare(&local, &TreeSpec::small(3)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&fixture.config).unwrap(),
+            format!(
+                "<c from=\"{}/\" to=\"{}/\"/>",
+                fixture.source.display(),
+                fixture.target.display()
+            )
+        );
+        assert!(fs::read_dir(&fixture.target).unwrap().next().is_none());
+        let copied = std::process::Command::new("cp")
+            .arg("-a")
+            .arg(fixture.source.join("."))
+            .arg(&fixture.target)
+            .status()
+            .unwrap();
+        assert!(copied.success());
+        fixture.assert_backed_up(&CompareOptions::default());
+        let fixture_dir = fixture.dir.to_path_buf();
+        drop(fixture);
+        assert!(!fixture_dir.exists());
+
+        // Backed up elsewhere, the empty target is not checked.
+        let remote = dir.join("example.config.2.xml");
+        fs::write(&remote, format!("<c from=\"{}/\"/>", SOURCE_PLACEHOLDER)).unwrap();
+        let fixture = Fixture::prepare(&remote, &TreeSpec::small(3)).unwrap();
+        fixture.assert_backed_up(&CompareOptions::default());
+        drop(fixture);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use bach_module::secrets::Keyring;
+use std::io::BufRead;
+
+fn usage() -> ! {
+    eprintln!("Usage: keyring <keyring.xml> <key-file> genkey|list|set <name>|remove <name>");
+    eprintln!("       set reads the secret value from stdin");
+    std::process::exit(1);
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        usage();
+    }
+    let (path, key_file, action) = (&args[1], &args[2], args[3].as_str());
+
+    if action.eq("genkey") {
+        Keyring::generate_key_file(key_file).expect("Unable to create key file");
+        return;
+    }
+
+    let mut keyring = Keyring::open(path, key_file).expect("Unable to open keyring");
+    match (action, args.get(4)) {
+        ("list", _) => {
+            for name in keyring.names() {
+                println!("{}", name);
+            }
+        }
+        ("set", Some(name)) => {
+            let mut value = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut value)
+                .expect("Unable to read secret from stdin");
+            keyring
+                .insert(name, value.trim_end_matches(&['\r', '\n'][..]))
+                .expect("Unable to store secret");
+            keyring.save().expect("Unable to save keyring");
+        }
+        ("remove", Some(name)) => {
+            keyring.remove(name);
+            keyring.save().expect("Unable to save keyring");
+        }
+        _ => usage(),
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{ModError, ModResult};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::de::DeserializeOwned;
+use std::cell::Cell;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Position in a configuration file, lines and columns counted from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    /// Position of the byte at `offset` in `text`.
+    pub fn at(text: &str, offset: usize) -> Self {
+        let mut end = offset.min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let before = &text[..end];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        Location {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Error of a configuration file, with where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub location: Location,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl From<ConfigError> for ModError {
+    fn from(item: ConfigError) -> Self {
+        ModError::located(item.location, &item.message)
+    }
+}
+
+/// Text handed to the deserializer, counting what it consumed.
+struct Tracked<'a> {
+    text: &'a [u8],
+    consumed: &'a Cell<usize>,
+}
+
+impl Read for Tracked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Tracked<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(&self.text[self.consumed.get()..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed.set(self.consumed.get() + amt);
+    }
+}
+
+/// Start of the tag `offset` is in or right after.
+fn tag_start(text: &str, offset: usize) -> usize {
+    text.get(..offset.min(text.len()))
+        .and_then(|t| t.rfind('<'))
+        .unwrap_or(0)
+}
+
+/// Deserializes the XML `text`. Malformed XML is reported at the tag the
+/// reader stopped on, the other errors at the last tag the deserializer read.
+pub fn parse_xml<T: DeserializeOwned>(text: &str) -> Result<T, ConfigError> {
+    let mut reader = Reader::from_str(text);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Eof) => break,
+            Ok(_) => buf.clear(),
+            Err(e) => {
+                return Err(ConfigError {
+                    location: Location::at(text, tag_start(text, reader.buffer_position())),
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+    let consumed = Cell::new(0);
+    let tracked = Tracked {
+        text: text.as_bytes(),
+        consumed: &consumed,
+    };
+    quick_xml::de::from_reader(tracked).map_err(|e| ConfigError {
+        location: Location::at(text, tag_start(text, consumed.get())),
+        message: e.to_string(),
+    })
+}
+
+/// Reads and deserializes the XML file at `path`.
+pub fn read_xml<T: DeserializeOwned>(path: &Path) -> ModResult<T> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(parse_xml(&text)?)
+}
+
+/// Positions of the `name` elements of the XML `text`, in document order.
+pub fn element_locations(text: &str, name: &str) -> Vec<Location> {
+    let mut ret = Vec::new();
+    let mut reader = Reader::from_str(text);
+    let mut buf = Vec::new();
+    loop {
+        let start = reader.buffer_position();
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.name() == name.as_bytes() => {
+                let offset = text[start..].find('<').map_or(start, |p| start + p);
+                ret.push(Location::at(text, offset));
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => (),
+        }
+        buf.clear();
+    }
+    ret
+}
+
+/// Shared, validated configuration of a module.
+///
+/// The configuration file is parsed once and kept in memory, so that editing
+/// the file has no effect until the module is asked to reload it. Clones share
+/// the same configuration, which lets the fire method see reloads.
+pub struct ConfigHandle<T> {
+    path: Option<PathBuf>,
+    current: Arc<Mutex<Option<Arc<T>>>>,
+}
+
+impl<T> Clone for ConfigHandle<T> {
+    fn clone(&self) -> Self {
+        ConfigHandle {
+            path: self.path.clone(),
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> ConfigHandle<T> {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        ConfigHandle {
+            path,
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.path.clone()
+    }
+
+    /// Parses the configuration file without touching the active configuration.
+    pub fn read(&self) -> ModResult<T> {
+        match &self.path {
+            Some(p) => read_xml(p),
+            None => Err(ModError::new("No configuration file")),
+        }
+    }
+
+    /// Returns the active configuration, loading it on first use.
+    pub fn get(&self) -> ModResult<Arc<T>> {
+        let mut current = self.current.lock()?;
+        if let Some(c) = current.as_ref() {
+            return Ok(c.clone());
+        }
+        let loaded = Arc::new(self.read()?);
+        current.replace(loaded.clone());
+        Ok(loaded)
+    }
+
+    /// Re-reads the configuration file and activates it if `validate` accepts
+    /// it. `validate` gets the new configuration and the active one, if any.
+    /// The active configuration is left untouched on error.
+    pub fn reload_with<F>(&self, validate: F) -> ModResult<Arc<T>>
+    where
+        F: FnOnce(&T, Option<&T>) -> ModResult<()>,
+    {
+        let candidate = self.read()?;
+        let mut current = self.current.lock()?;
+        validate(&candidate, current.as_deref())?;
+        let loaded = Arc::new(candidate);
+        current.replace(loaded.clone());
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Dummy {
+        label: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Outer {
+        #[serde(rename = "dummy")]
+        dummies: Vec<Dummy>,
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let text = "<outer>\n  <dummy label=\"a\"/>\n  <dummy>\n  </dumy>\n</outer>";
+        let e = parse_xml::<Outer>(text).unwrap_err();
+        assert_eq!(e.location, Location { line: 4, column: 3 });
+        assert!(e.message.contains("dumy"), "{}", e.message);
+
+        let text = "<outer>\n  <dummy label=\"a\"/>\n  <dummy nolabel=\"b\"/>\n</outer>";
+        let e = parse_xml::<Outer>(text).unwrap_err();
+        assert_eq!(e.location, Location { line: 3, column: 3 });
+        assert!(e.message.contains("label"), "{}", e.message);
+        let e = ModError::from(e);
+        assert_eq!(e.to_string(), "line 3, column 3: missing field `label`");
+        assert_eq!(e.location(), Some(Location { line: 3, column: 3 }));
+        assert_eq!(e.message(), "missing field `label`");
+
+        assert_eq!(
+            element_locations(text, "dummy"),
+            vec![
+                Location { line: 2, column: 3 },
+                Location { line: 3, column: 3 }
+            ]
+        );
+        assert_eq!(Location::at("é\nàb", 5), Location { line: 2, column: 2 });
+        let outer: Outer = parse_xml(r#"<outer><dummy label="x"/></outer>"#).unwrap();
+        assert_eq!(outer.dummies[0].label, "x");
+    }
+
+    #[test]
+    fn reload_keeps_previous_on_error() {
+        let path =
+            std::env::temp_dir().join(format!("bach-config-test-{}.xml", std::process::id()));
+        std::fs::write(&path, r#"<dummy label="first"/>"#).unwrap();
+        let handle: ConfigHandle<Dummy> = ConfigHandle::new(Some(path.clone()));
+        let shared = handle.clone();
+        assert_eq!(handle.get().unwrap().label, "first");
+
+        std::fs::write(&path, r#"<dummy label="second"/>"#).unwrap();
+        assert_eq!(shared.get().unwrap().label, "first");
+        assert!(handle
+            .reload_with(|_, _| Err(ModError::new("rejected")))
+            .is_err());
+        assert_eq!(shared.get().unwrap().label, "first");
+
+        std::fs::write(&path, "<dummy").unwrap();
+        assert!(handle.reload_with(|_, _| Ok(())).is_err());
+        assert_eq!(shared.get().unwrap().label, "first");
+
+        std::fs::write(&path, r#"<dummy label="third"/>"#).unwrap();
+        handle
+            .reload_with(|new, old| {
+                assert_eq!(old.unwrap().label, "first");
+                assert_eq!(new.label, "third");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(shared.get().unwrap().label, "third");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
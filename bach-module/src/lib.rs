@@ -1,4 +1,4 @@
-use bach_bus::packet::{core_2_string, BackupCommand, Packet};
+use bach_bus::packet::{core_2_string, BackupCommand, FireOptions, Packet};
 use handlebars::RenderError;
 use std::any::Any;
 use std::cell::RefCell;
@@ -8,7 +8,18 @@ use std::sync::{
     Arc, Mutex,
 };
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+pub mod config;
+pub mod outbox;
+#[cfg(feature = "modular")]
+pub mod plugin;
+pub mod runner;
+pub mod secrets;
+pub mod state;
+pub mod stats;
+
+pub use outbox::Outbox;
 
 pub static RUN_IDLE: u8 = 0;
 pub static RUN_FIRE: u8 = 1;
@@ -18,13 +29,16 @@ pub static RUN_EARLY_TERM: u8 = 4;
 pub static RUN_MODULE_SPEC1: u8 = 5;
 pub static RUN_MODULE_SPEC2: u8 = 6;
 pub static ALIVE_PACKET_EMISSION_TIMEOUT: u64 = 2;
+/// How often the alive emitter looks for the end of its module.
+pub static ALIVE_EMITTER_POLL: Duration = Duration::from_millis(50);
 
 pub type ModuleFireMethod = Box<
     dyn Fn(
-            &Arc<Mutex<RefCell<Vec<Packet>>>>,
+            &Arc<Mutex<RefCell<Outbox>>>,
             &Arc<AtomicU8>,
             &Arc<Mutex<RefCell<Option<PathBuf>>>>,
             &Arc<Mutex<RefCell<String>>>,
+            &FireOptions,
         ) -> ModResult<()>
         + Sync
         + Send,
@@ -33,12 +47,32 @@ pub type ModuleFireMethod = Box<
 #[derive(Debug, Clone)]
 pub struct ModError {
     message: String,
+    /// Where in its configuration file the error is, when it is about one.
+    location: Option<config::Location>,
 }
 
 impl ModError {
+    /// Error of a configuration file at `location`.
+    pub fn located(location: config::Location, message: &str) -> Self {
+        ModError {
+            message: message.to_string(),
+            location: Some(location),
+        }
+    }
+
+    pub fn location(&self) -> Option<config::Location> {
+        self.location
+    }
+
+    /// The message, without the location.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
     pub fn new(message: &str) -> Self {
         ModError {
             message: message.to_string(),
+            location: None,
         }
     }
 }
@@ -47,7 +81,10 @@ impl std::error::Error for ModError {}
 
 impl std::fmt::Display for ModError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}", self.message))
+        match &self.location {
+            Some(l) => f.write_fmt(format_args!("{}: {}", l, self.message)),
+            None => f.write_fmt(format_args!("{}", self.message)),
+        }
     }
 }
 
@@ -55,6 +92,7 @@ impl From<std::io::Error> for ModError {
     fn from(item: std::io::Error) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -63,6 +101,7 @@ impl From<quick_xml::DeError> for ModError {
     fn from(item: quick_xml::DeError) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -71,6 +110,7 @@ impl<T> From<std::sync::PoisonError<T>> for ModError {
     fn from(item: std::sync::PoisonError<T>) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -79,6 +119,7 @@ impl From<std::num::ParseIntError> for ModError {
     fn from(item: std::num::ParseIntError) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -87,6 +128,7 @@ impl From<std::time::SystemTimeError> for ModError {
     fn from(item: std::time::SystemTimeError) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -95,6 +137,7 @@ impl From<RenderError> for ModError {
     fn from(item: RenderError) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -103,6 +146,7 @@ impl From<regex::Error> for ModError {
     fn from(item: regex::Error) -> Self {
         ModError {
             message: item.to_string(),
+            location: None,
         }
     }
 }
@@ -111,6 +155,7 @@ impl From<Box<dyn std::any::Any + Send>> for ModError {
     fn from(_: Box<dyn std::any::Any + Send>) -> Self {
         ModError {
             message: "Join Error".to_string(),
+            location: None,
         }
     }
 }
@@ -140,18 +185,73 @@ pub trait Module: Any + Send {
     }
     fn run_status(&self) -> &Arc<AtomicU8>;
     fn emit_alive_status(&self) -> &Arc<AtomicBool>;
-    fn message_stack(&self) -> &Arc<Mutex<RefCell<Vec<Packet>>>>;
+    fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>>;
+    fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>>;
+    fn reload_pending(&self) -> &Arc<AtomicBool>;
     fn config_path(&self) -> Option<PathBuf>;
 
+    /// Re-reads and validates the module configuration. On error the module
+    /// must keep its previous configuration.
+    fn reload(&self) -> ModResult<()> {
+        Ok(())
+    }
+
+    /// Parses and validates the module configuration without activating it.
+    fn check_config(&self) -> ModResult<()> {
+        Ok(())
+    }
+
+    /// Tells the module the id the daemon knows it by, which tells apart
+    /// instances sharing a name. Modules keeping something per instance,
+    /// such as their [`state::StateStore`], must key it by this id.
+    fn set_instance_id(&self, _id: &str) {}
+
+    fn is_busy(&self) -> bool {
+        let c = self.run_status().load(Ordering::SeqCst);
+        c == RUN_FIRE || c == RUN_RUNNING
+    }
+
+    fn apply_reload(&self) {
+        self.outlet(match self.reload() {
+            Ok(()) => Packet::new_ng("Configuration reloaded", &self.name(), "Reload"),
+            Err(e) => Packet::new_ne(
+                &format!(
+                    "Configuration reload failed, keeping previous configuration : {}",
+                    e
+                ),
+                &self.name(),
+                "Reload",
+            ),
+        });
+    }
+
+    /// Reloads right away when idle, otherwise once the running fire is over.
+    fn request_reload(&self) {
+        if self.is_busy() {
+            self.reload_pending().store(true, Ordering::SeqCst);
+            self.outlet(Packet::new_ng(
+                "Reload deferred until the current run ends",
+                &self.name(),
+                "Reload",
+            ));
+        } else {
+            self.apply_reload();
+        }
+    }
+
     fn input(&self, p: Packet) {
         match p {
-            Packet::BackupCom(core) => {
-                if let BackupCommand::Fire(Some(s)) = BackupCommand::from(core) {
-                    if s.eq(&self.name()) {
-                        self.run_status().store(RUN_FIRE, Ordering::SeqCst);
+            Packet::BackupCom(core) => match BackupCommand::from(core) {
+                BackupCommand::Fire(Some(s), options) if s.eq(&self.name()) => {
+                    if let Ok(o) = self.fire_options().lock() {
+                        o.replace(options);
                     }
+                    self.run_status().store(RUN_FIRE, Ordering::SeqCst);
                 }
-            }
+                BackupCommand::Reload(None) => self.request_reload(),
+                BackupCommand::Reload(Some(s)) if s.eq(&self.name()) => self.request_reload(),
+                _ => (),
+            },
             Packet::Stop(core) => {
                 let core_name = core_2_string(&core);
                 if core_name.eq(&self.name()) {
@@ -168,6 +268,10 @@ pub trait Module: Any + Send {
     }
 
     fn output(&self) -> Option<Packet> {
+        if !self.is_busy() && self.reload_pending().swap(false, Ordering::SeqCst) {
+            self.apply_reload();
+        }
+
         if let Ok(message_stack) = self.message_stack().lock() {
             if self.emit_alive_status().load(Ordering::SeqCst) {
                 self.emit_alive_status().store(false, Ordering::SeqCst);
@@ -176,7 +280,16 @@ pub trait Module: Any + Send {
                     .push(Packet::new_alive(&self.name()));
             }
 
-            message_stack.borrow_mut().pop()
+            let dropped = message_stack.borrow_mut().take_dropped();
+            if dropped > 0 {
+                return Some(Packet::new_nw(
+                    &format!("{} messages dropped", dropped),
+                    &self.name(),
+                    "Outbox",
+                ));
+            }
+
+            message_stack.borrow_mut().pop().map(secrets::redact_packet)
         } else {
             println!(
                 "Big problem : Unable to lock message stack for module {}",
@@ -186,18 +299,27 @@ pub trait Module: Any + Send {
         }
     }
 
+    /// Raises the alive status every [`ALIVE_PACKET_EMISSION_TIMEOUT`]
+    /// seconds. The thread must end soon after the module is terminated,
+    /// [`Module::spawn`] waits for it.
     fn spawn_alive_emitter(&self) -> JoinHandle<()> {
         let emitalive = self.emit_alive_status().clone();
         let ctrlstat = self.run_status().clone();
         thread::spawn(move || {
-            let mut run = true;
-            while run {
+            let period = Duration::from_secs(ALIVE_PACKET_EMISSION_TIMEOUT);
+            let mut emitted = Instant::now();
+            loop {
                 let c = ctrlstat.load(Ordering::SeqCst);
                 if c == RUN_TERM || c == RUN_EARLY_TERM {
-                    run = false;
+                    // A last one, the module was alive up to its end.
+                    emitalive.store(true, Ordering::SeqCst);
+                    return;
+                }
+                thread::sleep(ALIVE_EMITTER_POLL);
+                if emitted.elapsed() >= period {
+                    emitalive.store(true, Ordering::SeqCst);
+                    emitted = Instant::now();
                 }
-                thread::sleep(Duration::from_secs(ALIVE_PACKET_EMISSION_TIMEOUT));
-                emitalive.store(true, Ordering::SeqCst);
             }
         })
     }
@@ -207,11 +329,14 @@ pub trait Module: Any + Send {
         let ctrlstat2 = ctrlstat.clone();
         let ctrlstat3 = ctrlstat.clone();
         let message_stack = self.message_stack().clone();
+        let fire_options = self.fire_options().clone();
         let main_method = self.fire();
         let name_arc = Arc::new(Mutex::new(RefCell::new(self.name())));
         let config_arc = Arc::new(Mutex::new(RefCell::new(self.config_path())));
-        self.spawn_alive_emitter();
+        let emitter = self.spawn_alive_emitter();
 
+        // The returned thread ends after the alive emitter, so that joining
+        // it is enough before destroying the module and unloading its code.
         thread::spawn(move || -> ModResult<()> {
             let mut run = true;
             let mut main = move || -> ModResult<()> {
@@ -221,15 +346,21 @@ pub trait Module: Any + Send {
                         run = false;
                     } else if c == RUN_FIRE {
                         ctrlstat.store(RUN_RUNNING, Ordering::SeqCst);
+                        let options = fire_options.lock()?.replace(FireOptions::default());
                         match main_method(
                             &message_stack,
                             &ctrlstat2,
                             &config_arc,
                             &name_arc.clone(),
+                            &options,
                         ) {
                             Ok(()) => {
                                 message_stack.lock()?.borrow_mut().push(Packet::new_ng(
-                                    "Successful End",
+                                    if options.dry_run {
+                                        "Successful End (dry run)"
+                                    } else {
+                                        "Successful End"
+                                    },
                                     &name_arc.lock()?.borrow(),
                                     "END",
                                 ));
@@ -250,12 +381,12 @@ pub trait Module: Any + Send {
                 Ok(())
             };
 
-            if let Err(e) = main() {
+            let res = main();
+            if res.is_err() {
                 ctrlstat3.store(RUN_EARLY_TERM, Ordering::SeqCst);
-                return Err(e);
             }
-
-            Ok(())
+            let _ = emitter.join();
+            res
         })
     }
 }
@@ -0,0 +1,132 @@
+use bach_bus::packet::Packet;
+use std::collections::VecDeque;
+
+pub static DEFAULT_OUTBOX_CAPACITY: usize = 1024;
+
+/// What an [`Outbox`] does with a packet pushed while it is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make room by discarding the oldest waiting packet.
+    DropOldest,
+    /// Discard the packet being pushed.
+    DropNewest,
+}
+
+/// Bounded FIFO of the packets a module hands to the bus.
+///
+/// Packets leave in the order they were pushed. Dropped packets are counted
+/// until [`Outbox::take_dropped`] is called, so that the module can report
+/// them with a single notification.
+#[derive(Debug)]
+pub struct Outbox {
+    queue: VecDeque<Packet>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: usize,
+    dropped_total: usize,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox::with_capacity(DEFAULT_OUTBOX_CAPACITY, OverflowPolicy::DropOldest)
+    }
+
+    pub fn with_capacity(capacity: usize, policy: OverflowPolicy) -> Self {
+        Outbox {
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            policy,
+            dropped: 0,
+            dropped_total: 0,
+        }
+    }
+
+    pub fn push(&mut self, p: Packet) {
+        if self.queue.len() >= self.capacity {
+            self.dropped += 1;
+            self.dropped_total += 1;
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.queue.pop_front();
+                }
+                OverflowPolicy::DropNewest => return,
+            }
+        }
+        self.queue.push_back(p);
+    }
+
+    pub fn pop(&mut self) -> Option<Packet> {
+        self.queue.pop_front()
+    }
+
+    /// Returns the number of packets dropped since the last call.
+    pub fn take_dropped(&mut self) -> usize {
+        std::mem::replace(&mut self.dropped, 0)
+    }
+
+    /// Number of packets dropped since the outbox was created.
+    pub fn dropped_total(&self) -> usize {
+        self.dropped_total
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Packet> {
+        self.queue.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::*;
+    use bach_bus::packet::Notification;
+
+    fn message(p: Packet) -> String {
+        Notification::from(p).message
+    }
+
+    #[test]
+    fn fifo_and_overflow() {
+        let mut outbox = Outbox::with_capacity(2, OverflowPolicy::DropOldest);
+        outbox.push(Packet::new_ng("first", "test", "Run"));
+        outbox.push(Packet::new_ng("second", "test", "Run"));
+        assert_eq!(message(outbox.pop().unwrap()), "first");
+        outbox.push(Packet::new_ng("third", "test", "Run"));
+        outbox.push(Packet::new_ng("fourth", "test", "Run"));
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.take_dropped(), 1);
+        assert_eq!(outbox.take_dropped(), 0);
+        assert_eq!(message(outbox.pop().unwrap()), "third");
+        assert_eq!(message(outbox.pop().unwrap()), "fourth");
+        assert!(outbox.pop().is_none());
+
+        let mut outbox = Outbox::with_capacity(1, OverflowPolicy::DropNewest);
+        outbox.push(Packet::new_ng("kept", "test", "Run"));
+        outbox.push(Packet::new_ng("lost", "test", "Run"));
+        outbox.push(Packet::new_ng("lost", "test", "Run"));
+        assert_eq!(outbox.dropped_total(), 2);
+        assert_eq!(outbox.take_dropped(), 2);
+        assert_eq!(message(outbox.pop().unwrap()), "kept");
+        assert!(outbox.is_empty());
+    }
+}
@@ -0,0 +1,28 @@
+//! Loading of the module plugins, shared objects exporting the constructor
+//! [`mk_create_module`](crate::mk_create_module) defines.
+use crate::{ModError, ModResult, Module};
+use libloading::{Library, Symbol};
+use std::ffi::OsStr;
+
+/// Symbol of the module constructor in a plugin.
+pub static CREATE_MODULE_SYMBOL: &[u8] = b"bach_create_module";
+
+/// Creates the module of the shared object `filename`. It is returned with
+/// the library holding its code, which must outlive the module and every
+/// thread it spawned.
+pub fn open_plugin<P: AsRef<OsStr>>(
+    filename: P,
+    config_filename: &Option<String>,
+) -> ModResult<(Library, Box<dyn Module>)> {
+    unsafe {
+        type ModGen = unsafe fn(&Option<String>) -> Box<dyn Module>;
+        let lib = Library::new(filename.as_ref()).map_err(|e| ModError::new(&e.to_string()))?;
+        let module = {
+            let cons: Symbol<ModGen> = lib
+                .get(CREATE_MODULE_SYMBOL)
+                .map_err(|e| ModError::new(&e.to_string()))?;
+            cons(config_filename)
+        };
+        Ok((lib, module))
+    }
+}
@@ -0,0 +1,427 @@
+use crate::{ModError, ModResult};
+use std::fmt;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// External command, described independently of the way it gets run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl CommandSpec {
+    pub fn new(program: &str) -> Self {
+        CommandSpec {
+            program: program.to_string(),
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg<S: AsRef<str>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_string());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for a in args {
+            self.arg(a);
+        }
+        self
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut ret = Command::new(&self.program);
+        ret.args(&self.args);
+        ret
+    }
+}
+
+impl fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for a in &self.args {
+            write!(f, " {}", a)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code, `None` when the command was killed by a signal.
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// A command started by a [`CommandRunner`].
+pub trait RunningCommand: Send {
+    /// Returns the command output once it has exited, without blocking.
+    fn try_wait(&mut self) -> ModResult<Option<CommandOutput>>;
+    fn kill(&mut self) -> ModResult<()>;
+}
+
+/// Runs external commands on behalf of a module.
+///
+/// Modules go through this trait instead of `std::process::Command` so that
+/// their tests can swap the system for a [`ScriptedRunner`]. The runner also
+/// owns the time used while waiting for commands.
+pub trait CommandRunner: Send + Sync {
+    fn spawn(&self, cmd: &CommandSpec) -> ModResult<Box<dyn RunningCommand>>;
+
+    /// Time elapsed since the runner was created.
+    fn now(&self) -> Duration;
+
+    /// Waits between two polls of a running command.
+    fn sleep(&self, d: Duration);
+
+    /// Runs `cmd` to completion.
+    fn run(&self, cmd: &CommandSpec) -> ModResult<CommandOutput> {
+        let mut child = self.spawn(cmd)?;
+        loop {
+            if let Some(out) = child.try_wait()? {
+                return Ok(out);
+            }
+            self.sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Runs commands on the local system, capturing their output.
+pub struct SystemRunner {
+    start: Instant,
+}
+
+impl Default for SystemRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemRunner {
+    pub fn new() -> Self {
+        SystemRunner {
+            start: Instant::now(),
+        }
+    }
+}
+
+struct SystemCommand {
+    child: Child,
+    stdout: Option<JoinHandle<String>>,
+    stderr: Option<JoinHandle<String>>,
+}
+
+fn read_all<R: Read + Send + 'static>(stream: Option<R>) -> Option<JoinHandle<String>> {
+    stream.map(|mut s| {
+        thread::spawn(move || {
+            let mut buffer = Vec::new();
+            let _ = s.read_to_end(&mut buffer);
+            String::from_utf8_lossy(&buffer).to_string()
+        })
+    })
+}
+
+fn join_output(handle: Option<JoinHandle<String>>) -> String {
+    handle.and_then(|h| h.join().ok()).unwrap_or_default()
+}
+
+impl RunningCommand for SystemCommand {
+    fn try_wait(&mut self) -> ModResult<Option<CommandOutput>> {
+        match self.child.try_wait()? {
+            Some(status) => Ok(Some(CommandOutput {
+                code: status.code(),
+                stdout: join_output(self.stdout.take()),
+                stderr: join_output(self.stderr.take()),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn kill(&mut self) -> ModResult<()> {
+        self.child.kill()?;
+        Ok(())
+    }
+}
+
+impl CommandRunner for SystemRunner {
+    fn spawn(&self, cmd: &CommandSpec) -> ModResult<Box<dyn RunningCommand>> {
+        let mut child = cmd
+            .to_command()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = read_all(child.stdout.take());
+        let stderr = read_all(child.stderr.take());
+        Ok(Box::new(SystemCommand {
+            child,
+            stdout,
+            stderr,
+        }))
+    }
+
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) {
+        thread::sleep(d);
+    }
+}
+
+/// Canned result of a command run by a [`ScriptedRunner`].
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedResponse {
+    code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    delay: Duration,
+    spawn_error: Option<String>,
+}
+
+impl ScriptedResponse {
+    pub fn exit(code: i32) -> Self {
+        ScriptedResponse {
+            code: Some(code),
+            ..ScriptedResponse::default()
+        }
+    }
+
+    /// The command ends as if killed by a signal.
+    pub fn signaled() -> Self {
+        ScriptedResponse::default()
+    }
+
+    /// The command cannot even be started.
+    pub fn spawn_error(message: &str) -> Self {
+        ScriptedResponse {
+            spawn_error: Some(message.to_string()),
+            ..ScriptedResponse::default()
+        }
+    }
+
+    pub fn stdout(mut self, s: &str) -> Self {
+        self.stdout = s.to_string();
+        self
+    }
+
+    pub fn stderr(mut self, s: &str) -> Self {
+        self.stderr = s.to_string();
+        self
+    }
+
+    /// Runner time the command takes before exiting.
+    pub fn delay(mut self, d: Duration) -> Self {
+        self.delay = d;
+        self
+    }
+}
+
+struct ScriptRule {
+    pattern: String,
+    response: ScriptedResponse,
+    once: bool,
+}
+
+/// Fake runner answering commands from a script, for tests.
+///
+/// Each command line is matched against the rules in the order they were
+/// added, the first rule whose pattern is contained in the command line
+/// wins. Time is virtual: [`CommandRunner::sleep`] advances it right away,
+/// so delays and timeouts of several minutes are covered in a few
+/// milliseconds.
+#[derive(Default)]
+pub struct ScriptedRunner {
+    rules: Mutex<Vec<ScriptRule>>,
+    calls: Mutex<Vec<CommandSpec>>,
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ScriptedRunner {
+    pub fn new() -> Self {
+        ScriptedRunner::default()
+    }
+
+    /// Answers every command matching `pattern` with `response`.
+    pub fn on(&self, pattern: &str, response: ScriptedResponse) -> &Self {
+        self.add_rule(pattern, response, false)
+    }
+
+    /// Answers the next command matching `pattern` with `response`.
+    pub fn once(&self, pattern: &str, response: ScriptedResponse) -> &Self {
+        self.add_rule(pattern, response, true)
+    }
+
+    fn add_rule(&self, pattern: &str, response: ScriptedResponse, once: bool) -> &Self {
+        if let Ok(mut rules) = self.rules.lock() {
+            rules.push(ScriptRule {
+                pattern: pattern.to_string(),
+                response,
+                once,
+            });
+        }
+        self
+    }
+
+    /// Commands spawned so far, oldest first.
+    pub fn calls(&self) -> Vec<CommandSpec> {
+        match self.calls.lock() {
+            Ok(c) => c.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        if let Ok(mut now) = self.now.lock() {
+            *now += d;
+        }
+    }
+}
+
+struct ScriptedCommand {
+    response: ScriptedResponse,
+    started: Duration,
+    now: Arc<Mutex<Duration>>,
+    killed: bool,
+}
+
+impl RunningCommand for ScriptedCommand {
+    fn try_wait(&mut self) -> ModResult<Option<CommandOutput>> {
+        let elapsed = *self.now.lock()? - self.started;
+        if self.killed || elapsed >= self.response.delay {
+            Ok(Some(CommandOutput {
+                code: if self.killed {
+                    None
+                } else {
+                    self.response.code
+                },
+                stdout: self.response.stdout.to_string(),
+                stderr: self.response.stderr.to_string(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn kill(&mut self) -> ModResult<()> {
+        self.killed = true;
+        Ok(())
+    }
+}
+
+impl CommandRunner for ScriptedRunner {
+    fn spawn(&self, cmd: &CommandSpec) -> ModResult<Box<dyn RunningCommand>> {
+        self.calls.lock()?.push(cmd.clone());
+        let line = cmd.to_string();
+        let response = {
+            let mut rules = self.rules.lock()?;
+            let pos = rules
+                .iter()
+                .position(|r| line.contains(&r.pattern))
+                .ok_or_else(|| ModError::new(&format!("No scripted response for {}", line)))?;
+            if rules[pos].once {
+                rules.remove(pos).response
+            } else {
+                rules[pos].response.clone()
+            }
+        };
+        if let Some(e) = &response.spawn_error {
+            return Err(ModError::new(e));
+        }
+        Ok(Box::new(ScriptedCommand {
+            response,
+            started: self.now(),
+            now: self.now.clone(),
+            killed: false,
+        }))
+    }
+
+    fn now(&self) -> Duration {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(_) => Duration::from_secs(0),
+        }
+    }
+
+    fn sleep(&self, d: Duration) {
+        self.advance(d);
+        thread::yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runner::*;
+
+    #[test]
+    fn system_runner() {
+        let runner = SystemRunner::new();
+        let mut cmd = CommandSpec::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2; exit 3"]);
+        assert_eq!(cmd.to_string(), "sh -c echo out; echo err >&2; exit 3");
+        let out = runner.run(&cmd).unwrap();
+        assert_eq!(out.code, Some(3));
+        assert_eq!(out.stdout, "out\n");
+        assert_eq!(out.stderr, "err\n");
+        assert!(!out.success());
+
+        let mut child = runner.spawn(CommandSpec::new("sleep").arg("10")).unwrap();
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().unwrap();
+        let out = runner.run(&CommandSpec::new("true")).unwrap();
+        assert!(out.success());
+        assert!(runner
+            .spawn(&CommandSpec::new("/nonexistent/bach"))
+            .is_err());
+    }
+
+    #[test]
+    fn scripted_runner() {
+        let runner = ScriptedRunner::new();
+        runner
+            .once("mount /dev/sdb1", ScriptedResponse::exit(32).stderr("busy"))
+            .on("mount", ScriptedResponse::exit(0))
+            .on(
+                "rsync",
+                ScriptedResponse::exit(0)
+                    .stdout(">f+++++++++ a")
+                    .delay(Duration::from_secs(3600)),
+            )
+            .on("ls", ScriptedResponse::spawn_error("ls not found"));
+
+        let mut mount = CommandSpec::new("mount");
+        mount.args(["/dev/sdb1", "/mnt"]);
+        assert_eq!(runner.run(&mount).unwrap().stderr, "busy");
+        assert!(runner.run(&mount).unwrap().success());
+        assert!(runner.run(&CommandSpec::new("ls")).is_err());
+        assert!(runner.run(&CommandSpec::new("df")).is_err());
+
+        let mut rsync = runner.spawn(&CommandSpec::new("rsync")).unwrap();
+        assert!(rsync.try_wait().unwrap().is_none());
+        runner.sleep(Duration::from_secs(3599));
+        assert!(rsync.try_wait().unwrap().is_none());
+        runner.sleep(Duration::from_secs(1));
+        assert_eq!(rsync.try_wait().unwrap().unwrap().stdout, ">f+++++++++ a");
+
+        let mut rsync = runner.spawn(&CommandSpec::new("rsync")).unwrap();
+        rsync.kill().unwrap();
+        assert_eq!(rsync.try_wait().unwrap().unwrap().code, None);
+        assert_eq!(runner.calls().len(), 6);
+        assert_eq!(runner.calls()[0], mount);
+    }
+}
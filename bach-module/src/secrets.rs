@@ -0,0 +1,571 @@
+use crate::{ModError, ModResult};
+use bach_bus::packet::{Packet, PacketCore};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Environment variable pointing to the secret providers definition file.
+pub static SECRETS_CONFIG_ENV: &str = "BACH_SECRETS_CONFIG";
+pub static DEFAULT_SECRETS_CONFIG: &str = "/etc/bach/secrets.xml";
+pub static DEFAULT_SECRETS_DIR: &str = "/etc/bach/secrets.d";
+pub static REDACTED: &str = "********";
+
+static KNOWN_SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Remembers `secret` so that it gets masked from logs and notifications,
+/// however short it is.
+pub fn register_redaction(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    if let Ok(mut known) = KNOWN_SECRETS.lock() {
+        if !known.iter().any(|s| s.eq(secret)) {
+            known.push(secret.to_string());
+            // Longest first, so a secret containing another one is fully masked.
+            known.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        }
+    }
+}
+
+/// Reads an optional clear text secret of a configuration, registering it for
+/// redaction as soon as the configuration is parsed. Meant for
+/// `#[serde(default, deserialize_with = "secrets::redacted")]`.
+pub fn redacted<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    let secret = Option::<String>::deserialize(d)?;
+    if let Some(s) = &secret {
+        register_redaction(s);
+    }
+    Ok(secret)
+}
+
+/// Masks every known secret in `s`.
+pub fn redact(s: &str) -> String {
+    let mut ret = s.to_string();
+    if let Ok(known) = KNOWN_SECRETS.lock() {
+        for secret in known.iter() {
+            if ret.contains(secret.as_str()) {
+                ret = ret.replace(secret.as_str(), REDACTED);
+            }
+        }
+    }
+    ret
+}
+
+fn redact_core(mut core: PacketCore) -> PacketCore {
+    if let Ok(known) = KNOWN_SECRETS.lock() {
+        for secret in known.iter() {
+            let bytes = secret.as_bytes();
+            let mut i = 0;
+            while i + bytes.len() <= core.len() {
+                if core[i..i + bytes.len()].eq(bytes) {
+                    for b in core[i..i + bytes.len()].iter_mut() {
+                        *b = b'*';
+                    }
+                    i += bytes.len();
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+    core
+}
+
+/// Masks known secrets in the text carried by notification and logger packets.
+pub fn redact_packet(p: Packet) -> Packet {
+    match p {
+        Packet::NotifyGood(c) => Packet::NotifyGood(redact_core(c)),
+        Packet::NotifyWarn(c) => Packet::NotifyWarn(redact_core(c)),
+        Packet::NotifyErr(c) => Packet::NotifyErr(redact_core(c)),
+        Packet::NotifyCom(c) => Packet::NotifyCom(redact_core(c)),
+        Packet::LoggerCom(c) => Packet::LoggerCom(redact_core(c)),
+        _ => p,
+    }
+}
+
+pub trait SecretProvider: Send + Sync {
+    fn fetch(&self, key: &str) -> ModResult<String>;
+}
+
+/// Reads secrets from environment variables named `prefix` + key.
+pub struct EnvProvider {
+    prefix: String,
+}
+
+impl EnvProvider {
+    pub fn new(prefix: &str) -> Self {
+        EnvProvider {
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl SecretProvider for EnvProvider {
+    fn fetch(&self, key: &str) -> ModResult<String> {
+        let var = format!("{}{}", self.prefix, key);
+        std::env::var(&var)
+            .map_err(|_| ModError::new(&format!("Environment variable {} is not set", var)))
+    }
+}
+
+fn check_root_only(path: &Path) -> ModResult<()> {
+    let meta = fs::metadata(path)?;
+    if meta.uid() != 0 {
+        return Err(ModError::new(&format!(
+            "Secret file {} must be owned by root",
+            path.display()
+        )));
+    }
+    if meta.mode() & 0o077 != 0 {
+        return Err(ModError::new(&format!(
+            "Secret file {} must not be readable by group or others",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Reads secrets from root-only files, one secret per file.
+/// Relative keys are looked up in `dir`, absolute keys are used as is.
+pub struct FileProvider {
+    dir: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        FileProvider {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl SecretProvider for FileProvider {
+    fn fetch(&self, key: &str) -> ModResult<String> {
+        let path = if Path::new(key).is_absolute() {
+            PathBuf::from(key)
+        } else {
+            self.dir.join(key)
+        };
+        check_root_only(&path)?;
+        let content = fs::read_to_string(&path)?;
+        Ok(content.trim_end_matches(&['\r', '\n'][..]).to_string())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> ModResult<Vec<u8>> {
+    s.trim()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|p| p.len() == 2)
+                .and_then(|p| u8::from_str_radix(p, 16).ok())
+                .ok_or_else(|| ModError::new("Invalid hexadecimal string"))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyringEntry {
+    name: String,
+    nonce: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename = "keyring")]
+struct KeyringFile {
+    #[serde(rename = "secret", default)]
+    entries: Vec<KeyringEntry>,
+}
+
+/// Local keyring: secrets are stored encrypted with ChaCha20-Poly1305 in an
+/// XML file, the 32 bytes key lives in a separate root-only key file
+/// (raw or hexadecimal).
+pub struct Keyring {
+    path: PathBuf,
+    cipher: ChaCha20Poly1305,
+    file: KeyringFile,
+}
+
+impl Keyring {
+    /// Writes a new random key to `key_file`, readable by its owner only.
+    pub fn generate_key_file<P: AsRef<Path>>(key_file: P) -> ModResult<()> {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(key_file)?;
+        f.write_all(to_hex(&key).as_bytes())?;
+        Ok(())
+    }
+
+    pub fn open<P: AsRef<Path>, K: AsRef<Path>>(path: P, key_file: K) -> ModResult<Self> {
+        check_root_only(key_file.as_ref())?;
+        let raw = fs::read(key_file.as_ref())?;
+        let key = if raw.len() == 32 {
+            raw
+        } else {
+            from_hex(&String::from_utf8_lossy(&raw))?
+        };
+        if key.len() != 32 {
+            return Err(ModError::new("Keyring key must be 32 bytes long"));
+        }
+
+        let file = if path.as_ref().exists() {
+            quick_xml::de::from_reader(BufReader::new(File::open(path.as_ref())?))?
+        } else {
+            KeyringFile::default()
+        };
+
+        Ok(Keyring {
+            path: path.as_ref().to_path_buf(),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            file,
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.file
+            .entries
+            .iter()
+            .map(|e| e.name.to_string())
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> ModResult<String> {
+        let entry = self
+            .file
+            .entries
+            .iter()
+            .find(|e| e.name.eq(name))
+            .ok_or_else(|| ModError::new(&format!("No secret {} in keyring", name)))?;
+        let nonce = from_hex(&entry.nonce)?;
+        if nonce.len() != 12 {
+            return Err(ModError::new(&format!("Bad nonce for secret {}", name)));
+        }
+        let plain = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &from_hex(&entry.value)?,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| ModError::new(&format!("Unable to decrypt secret {}", name)))?;
+        String::from_utf8(plain)
+            .map_err(|_| ModError::new(&format!("Secret {} is not valid UTF-8", name)))
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) -> ModResult<()> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let cipher = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| ModError::new(&format!("Unable to encrypt secret {}", name)))?;
+        self.file.entries.retain(|e| !e.name.eq(name));
+        self.file.entries.push(KeyringEntry {
+            name: name.to_string(),
+            nonce: to_hex(&nonce),
+            value: to_hex(&cipher),
+        });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.file.entries.retain(|e| !e.name.eq(name));
+    }
+
+    /// Writes the keyring to a temporary file renamed over the previous one,
+    /// which is kept whole if the write fails. The keyring is left readable
+    /// by its owner only, whatever the mode of the previous file.
+    pub fn save(&self) -> ModResult<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        // A leftover temporary file keeps its mode on open.
+        f.set_permissions(fs::Permissions::from_mode(0o600))?;
+        let written = quick_xml::se::to_writer(&mut f, &self.file)
+            .map_err(ModError::from)
+            .and_then(|_| Ok(f.sync_all()?));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+pub struct KeyringProvider {
+    path: PathBuf,
+    key_file: PathBuf,
+}
+
+impl KeyringProvider {
+    pub fn new<P: AsRef<Path>, K: AsRef<Path>>(path: P, key_file: K) -> Self {
+        KeyringProvider {
+            path: path.as_ref().to_path_buf(),
+            key_file: key_file.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl SecretProvider for KeyringProvider {
+    fn fetch(&self, key: &str) -> ModResult<String> {
+        Keyring::open(&self.path, &self.key_file)?.get(key)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderDefinition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub path: Option<String>,
+    #[serde(rename = "key-file")]
+    pub key_file: Option<String>,
+    pub prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename = "secrets")]
+pub struct SecretsConfig {
+    #[serde(rename = "provider", default)]
+    pub providers: Vec<ProviderDefinition>,
+}
+
+/// Named secret providers. A reference such as `vault:nas-admin` asks the
+/// provider named `vault` for the `nas-admin` secret. The `env` and `file`
+/// providers are always available unless the configuration overrides them.
+pub struct Secrets {
+    providers: HashMap<String, Box<dyn SecretProvider>>,
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        let mut ret = Secrets {
+            providers: HashMap::new(),
+        };
+        ret.register("env", Box::new(EnvProvider::new("")));
+        ret.register("file", Box::new(FileProvider::new(DEFAULT_SECRETS_DIR)));
+        ret
+    }
+
+    pub fn from_config(conf: SecretsConfig) -> ModResult<Self> {
+        let mut ret = Secrets::new();
+        for p in conf.providers {
+            let provider: Box<dyn SecretProvider> = match p.kind.as_str() {
+                "env" => Box::new(EnvProvider::new(&p.prefix.unwrap_or_default())),
+                "file" => Box::new(FileProvider::new(
+                    p.path.unwrap_or_else(|| DEFAULT_SECRETS_DIR.to_string()),
+                )),
+                "keyring" => match (p.path, p.key_file) {
+                    (Some(path), Some(key_file)) => Box::new(KeyringProvider::new(path, key_file)),
+                    _ => {
+                        return Err(ModError::new(&format!(
+                            "Keyring provider {} needs both path and key-file",
+                            p.name
+                        )))
+                    }
+                },
+                other => {
+                    return Err(ModError::new(&format!(
+                        "Unknown secret provider type {} for {}",
+                        other, p.name
+                    )))
+                }
+            };
+            ret.register(&p.name, provider);
+        }
+        Ok(ret)
+    }
+
+    pub fn from_config_file<P: AsRef<Path>>(path: P) -> ModResult<Self> {
+        let conf: SecretsConfig = quick_xml::de::from_reader(BufReader::new(File::open(path)?))?;
+        Secrets::from_config(conf)
+    }
+
+    /// Loads the providers from the file named by `BACH_SECRETS_CONFIG`, or
+    /// from the default location. Only the built-in providers are available
+    /// when there is no such file.
+    pub fn load() -> ModResult<Self> {
+        let path = match std::env::var(SECRETS_CONFIG_ENV) {
+            Ok(p) if !p.is_empty() => PathBuf::from(p),
+            _ => PathBuf::from(DEFAULT_SECRETS_CONFIG),
+        };
+        if path.exists() {
+            Secrets::from_config_file(path)
+        } else {
+            Ok(Secrets::new())
+        }
+    }
+
+    pub fn register(&mut self, name: &str, provider: Box<dyn SecretProvider>) {
+        self.providers.insert(name.to_string(), provider);
+    }
+
+    pub fn resolve(&self, reference: &str) -> ModResult<String> {
+        let (scheme, key) = reference.split_once(':').ok_or_else(|| {
+            ModError::new(&format!(
+                "Secret reference {} must look like provider:key",
+                reference
+            ))
+        })?;
+        let provider = self.providers.get(scheme).ok_or_else(|| {
+            ModError::new(&format!(
+                "Unknown secret provider {} in {}",
+                scheme, reference
+            ))
+        })?;
+        let secret = provider.fetch(key)?;
+        register_redaction(&secret);
+        Ok(secret)
+    }
+}
+
+/// Resolves `reference` with the providers configured on this host.
+pub fn resolve(reference: &str) -> ModResult<String> {
+    Secrets::load()?.resolve(reference)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bach-secrets-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn env_provider() {
+        std::env::set_var("BACH_TEST_SECRET_NAS", "s3cr3t-env");
+        let mut secrets = Secrets::new();
+        secrets.register("vault", Box::new(EnvProvider::new("BACH_TEST_SECRET_")));
+        assert_eq!(secrets.resolve("vault:NAS").unwrap(), "s3cr3t-env");
+        assert_eq!(
+            secrets.resolve("env:BACH_TEST_SECRET_NAS").unwrap(),
+            "s3cr3t-env"
+        );
+        assert!(secrets.resolve("vault:MISSING").is_err());
+        assert!(secrets.resolve("nope:NAS").is_err());
+        assert!(secrets.resolve("no-scheme").is_err());
+    }
+
+    #[test]
+    fn file_provider_requires_private_file() {
+        let dir = test_dir("file");
+        let path = dir.join("nas-admin");
+        fs::write(&path, "s3cr3t-file\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let provider = FileProvider::new(&dir);
+        if fs::metadata(&path).unwrap().uid() == 0 {
+            assert!(provider.fetch("nas-admin").is_err());
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+            assert_eq!(provider.fetch("nas-admin").unwrap(), "s3cr3t-file");
+        } else {
+            assert!(provider.fetch("nas-admin").is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keyring_roundtrip() {
+        let dir = test_dir("keyring");
+        let key_file = dir.join("keyring.key");
+        let path = dir.join("keyring.xml");
+        Keyring::generate_key_file(&key_file).unwrap();
+        if fs::metadata(&key_file).unwrap().uid() != 0 {
+            fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+
+        let mut keyring = Keyring::open(&path, &key_file).unwrap();
+        keyring.insert("nas-admin", "s3cr3t-keyring").unwrap();
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        keyring.save().unwrap();
+        let stored = fs::read_to_string(&path).unwrap();
+        assert!(!stored.contains("s3cr3t-keyring"));
+        // Loose permissions of the previous keyring are not kept.
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let conf: SecretsConfig = quick_xml::de::from_str(&format!(
+            r#"<secrets><provider name="vault" type="keyring" path="{}" key-file="{}"/></secrets>"#,
+            path.display(),
+            key_file.display()
+        ))
+        .unwrap();
+        let secrets = Secrets::from_config(conf).unwrap();
+        assert_eq!(
+            secrets.resolve("vault:nas-admin").unwrap(),
+            "s3cr3t-keyring"
+        );
+        assert!(secrets.resolve("vault:other").is_err());
+
+        let other_key = dir.join("other.key");
+        Keyring::generate_key_file(&other_key).unwrap();
+        let wrong = Keyring::open(&path, &other_key).unwrap();
+        assert!(wrong.get("nas-admin").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn redaction() {
+        register_redaction("hunter2-redact");
+        register_redaction("q7");
+        register_redaction("");
+        assert_eq!(
+            redact("ssh admin:hunter2-redact@nas q7"),
+            format!("ssh admin:{}@nas {}", REDACTED, REDACTED)
+        );
+
+        let p = redact_packet(Packet::new_ne(
+            "Login with hunter2-redact failed",
+            "rsync",
+            "Exit",
+        ));
+        let n = bach_bus::packet::Notification::from(p);
+        assert_eq!(n.message, "Login with ************** failed");
+        assert_eq!(n.provider, "rsync");
+        assert_eq!(n.stage, "Exit");
+    }
+}
@@ -0,0 +1,266 @@
+use crate::ModResult;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the directory where module states are kept.
+/// bachd exports it from the `state-dir` entry of its configuration.
+pub static STATE_DIR_ENV: &str = "BACH_STATE_DIR";
+pub static DEFAULT_STATE_DIR: &str = "/var/lib/bach";
+
+/// Key under which modules record the end of their last successful run.
+pub static LAST_SUCCESS_KEY: &str = "last-success";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StateEntry {
+    key: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename = "state")]
+struct StateFile {
+    #[serde(rename = "entry", default)]
+    entries: Vec<StateEntry>,
+}
+
+pub fn state_dir() -> PathBuf {
+    match std::env::var(STATE_DIR_ENV) {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(DEFAULT_STATE_DIR),
+    }
+}
+
+/// Starts the file names of the daemon's own stores. Module scopes always
+/// have it percent-encoded, they cannot meet them.
+pub static DAEMON_SCOPE_PREFIX: &str = "+";
+
+/// File name of the store of `scope`. Every byte but ASCII alphanumerics,
+/// `-`, `_` and `.` is percent-encoded, distinct scopes never share a file.
+fn scope_filename(scope: &str) -> String {
+    let mut encoded = String::with_capacity(scope.len());
+    for b in scope.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("{}.state.xml", encoded)
+}
+
+/// Small durable key/value store scoped to one module instance.
+///
+/// Every modification is written right away to a temporary file which is then
+/// renamed over the previous state, so a crash never leaves a truncated file.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl StateStore {
+    /// Opens the store of `scope` in the configured state directory.
+    pub fn open(scope: &str) -> ModResult<Self> {
+        StateStore::open_in(&state_dir(), scope)
+    }
+
+    pub fn open_in(dir: &Path, scope: &str) -> ModResult<Self> {
+        StateStore::open_file(dir, &scope_filename(scope), None)
+    }
+
+    /// Opens the daemon's own store `name`, taking over the file of the
+    /// former `bachd-<name>` scope. The daemon opens its stores before any
+    /// module runs, that file is still its own.
+    pub fn open_daemon(name: &str) -> ModResult<Self> {
+        StateStore::open_daemon_in(&state_dir(), name)
+    }
+
+    pub fn open_daemon_in(dir: &Path, name: &str) -> ModResult<Self> {
+        StateStore::open_file(
+            dir,
+            &format!("{}{}", DAEMON_SCOPE_PREFIX, scope_filename(name)),
+            Some(&scope_filename(&format!("bachd-{}", name))),
+        )
+    }
+
+    /// Opens the store kept in `filename`, taking over the file `legacy`.
+    fn open_file(dir: &Path, filename: &str, legacy: Option<&str>) -> ModResult<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(filename);
+        if let Some(legacy) = legacy.map(|l| dir.join(l)) {
+            if !path.exists() && legacy.exists() {
+                fs::rename(&legacy, &path)?;
+            }
+        }
+        let mut values = BTreeMap::new();
+        if path.exists() {
+            let file: StateFile = quick_xml::de::from_reader(BufReader::new(File::open(&path)?))?;
+            for e in file.entries {
+                values.insert(e.key, e.value);
+            }
+        }
+
+        Ok(StateStore { path, values })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    pub fn get_parsed<T: std::str::FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+
+    pub fn get_timestamp(&self, key: &str) -> Option<SystemTime> {
+        self.get_parsed::<u64>(key)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.values.keys().cloned().collect()
+    }
+
+    pub fn set<V: ToString>(&mut self, key: &str, value: V) -> ModResult<()> {
+        self.values.insert(key.to_string(), value.to_string());
+        self.save()
+    }
+
+    /// Stores `time` as seconds since the epoch.
+    pub fn set_timestamp(&mut self, key: &str, time: SystemTime) -> ModResult<()> {
+        let secs = time.duration_since(UNIX_EPOCH)?.as_secs();
+        self.set(key, secs)
+    }
+
+    pub fn remove(&mut self, key: &str) -> ModResult<()> {
+        if self.values.remove(key).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> ModResult<()> {
+        let content = StateFile {
+            entries: self
+                .values
+                .iter()
+                .map(|(k, v)| StateEntry {
+                    key: k.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+        };
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let mut file = File::create(&tmp)?;
+        quick_xml::se::to_writer(&mut file, &content)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Records now as the last successful run of `scope`.
+pub fn record_last_success(scope: &str) -> ModResult<()> {
+    StateStore::open(scope)?.set_timestamp(LAST_SUCCESS_KEY, SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bach-state-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn state_store_persists() {
+        let dir = test_dir("persist");
+        let mut store = StateStore::open_in(&dir, "rsync/nightly").unwrap();
+        assert!(store.get("foo").is_none());
+        store.set("foo", "bar & <baz>").unwrap();
+        store.set("count", 42).unwrap();
+        assert!(store.path().ends_with("rsync%2Fnightly.state.xml"));
+
+        let mut reopened = StateStore::open_in(&dir, "rsync/nightly").unwrap();
+        assert_eq!(reopened.get("foo"), Some("bar & <baz>"));
+        assert_eq!(reopened.get_parsed::<u64>("count"), Some(42));
+        reopened.remove("foo").unwrap();
+
+        let reopened = StateStore::open_in(&dir, "rsync/nightly").unwrap();
+        assert!(reopened.get("foo").is_none());
+        assert_eq!(reopened.keys(), vec!["count".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scopes_keep_their_own_files() {
+        let dir = test_dir("scopes");
+        StateStore::open_daemon_in(&dir, "scheduler")
+            .unwrap()
+            .set("index", "daemon")
+            .unwrap();
+        let scopes = ["a/b", "a_b", "a%2Fb", "bachd-scheduler", "+scheduler"];
+        for (i, scope) in scopes.iter().enumerate() {
+            StateStore::open_in(&dir, scope)
+                .unwrap()
+                .set("index", i)
+                .unwrap();
+        }
+        for (i, scope) in scopes.iter().enumerate() {
+            let store = StateStore::open_in(&dir, scope).unwrap();
+            assert_eq!(store.get_parsed::<usize>("index"), Some(i), "{}", scope);
+        }
+        let store = StateStore::open_daemon_in(&dir, "scheduler").unwrap();
+        assert_eq!(store.get("index"), Some("daemon"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), scopes.len() + 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn daemon_stores_take_over_their_former_files() {
+        let dir = test_dir("legacy");
+        StateStore::open_in(&dir, "bachd-scheduler")
+            .unwrap()
+            .set("last", 1)
+            .unwrap();
+        let store = StateStore::open_daemon_in(&dir, "scheduler").unwrap();
+        assert_eq!(store.get("last"), Some("1"));
+        assert!(store.path().ends_with("+scheduler.state.xml"));
+        assert!(!dir.join("bachd-scheduler.state.xml").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_store_timestamps() {
+        let dir = test_dir("stamp");
+        let mut store = StateStore::open_in(&dir, "reporter").unwrap();
+        let when = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        store.set_timestamp(LAST_SUCCESS_KEY, when).unwrap();
+
+        let store = StateStore::open_in(&dir, "reporter").unwrap();
+        assert_eq!(store.get_timestamp(LAST_SUCCESS_KEY), Some(when));
+        assert_eq!(store.get(LAST_SUCCESS_KEY), Some("1700000000"));
+        let leftovers: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().contains(".tmp-"))
+            .collect();
+        assert!(leftovers.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
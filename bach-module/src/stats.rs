@@ -0,0 +1,39 @@
+use bach_bus::packet::Packet;
+
+/// Stage of the notifications carrying a figure about the current run, as
+/// a `key=value` message. bachd turns them into metrics.
+pub static STATS_STAGE: &str = "Stats";
+
+/// Bytes sent and received by the run.
+pub static BYTES_TRANSFERRED: &str = "bytes-transferred";
+
+pub fn stat_packet(provider: &str, key: &str, value: u64) -> Packet {
+    Packet::new_ng(&format!("{}={}", key, value), provider, STATS_STAGE)
+}
+
+/// The key and value of a notification built by [`stat_packet`].
+pub fn parse_stat(stage: &str, message: &str) -> Option<(String, u64)> {
+    if stage != STATS_STAGE {
+        return None;
+    }
+    let (key, value) = message.split_once('=')?;
+    Some((key.to_string(), value.trim().parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::*;
+    use bach_bus::packet::Notification;
+
+    #[test]
+    fn stat_round_trip() {
+        let n = Notification::from(stat_packet("rsync", BYTES_TRANSFERRED, 123456));
+        assert_eq!(
+            parse_stat(&n.stage, &n.message),
+            Some((BYTES_TRANSFERRED.to_string(), 123456))
+        );
+        assert_eq!(parse_stat("Exit", "bytes-transferred=1"), None);
+        assert_eq!(parse_stat(STATS_STAGE, "bytes-transferred=many"), None);
+        assert_eq!(parse_stat(STATS_STAGE, "no value"), None);
+    }
+}
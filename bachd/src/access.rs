@@ -0,0 +1,287 @@
+//! Who may send which control command.
+//!
+//! Clients of the Unix socket are identified by the credentials the kernel
+//! attaches to the connection (`SO_PEERCRED`). Root and the user running
+//! bachd are operators; other users get the role of the configured group
+//! they belong to, or nothing. TCP clients cannot be identified that way,
+//! they present a bearer token whose SHA-256 digest is in the configuration.
+//!
+//! Plugins run with the rights of the daemon, so network clients never
+//! choose which file is loaded, and do not load, unload or replace modules
+//! at all on a listener without tokens.
+use crate::tcpmessages::Command;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// Where a control client connects from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// The Unix socket, its clients are identified by their credentials.
+    Socket,
+    /// The TCP listener or the HTTP API.
+    Network,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// May list, query statuses and read the history.
+    Reader,
+    /// May also fire, stop, reload, pause and terminate.
+    Operator,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "reader" => Some(Role::Reader),
+            "operator" => Some(Role::Operator),
+            _ => None,
+        }
+    }
+}
+
+impl Command {
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::List { .. }
+            | Command::Status { .. }
+            | Command::History { .. }
+            | Command::Metrics => Role::Reader,
+            _ => Role::Operator,
+        }
+    }
+
+    /// Why the command is refused to network clients whatever their role,
+    /// `tokens` telling whether the listener has any.
+    pub fn network_refusal(&self, tokens: bool) -> Option<&'static str> {
+        match self {
+            Command::Load { .. } | Command::Replace { file: Some(_), .. } => {
+                Some("Modules are only loaded from a file through the control socket")
+            }
+            Command::Unload { .. } | Command::Replace { .. } if !tokens => {
+                Some("Modules are only unloaded or replaced over the network with tokens")
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+pub fn effective_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// Reads a `name:password:gid:member,member` line of `/etc/group`.
+fn parse_group_line(line: &str) -> Option<Group> {
+    let fields: Vec<&str> = line.trim().split(':').collect();
+    if fields.len() < 4 || fields[0].starts_with('#') {
+        return None;
+    }
+    Some(Group {
+        name: fields[0].to_string(),
+        gid: fields[2].parse().ok()?,
+        members: fields[3]
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(String::from)
+            .collect(),
+    })
+}
+
+/// Finds a group of `/etc/group` by name or by numeric id.
+pub fn lookup_group(name_or_gid: &str) -> io::Result<Option<Group>> {
+    let content = fs::read_to_string("/etc/group")?;
+    Ok(content
+        .lines()
+        .filter_map(parse_group_line)
+        .find(|g| g.name.eq(name_or_gid) || g.gid.to_string().eq(name_or_gid)))
+}
+
+/// Name of `uid` in `/etc/passwd`.
+pub fn user_name(uid: u32) -> Option<String> {
+    let content = fs::read_to_string("/etc/passwd").ok()?;
+    content.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.get(2).and_then(|u| u.parse::<u32>().ok()) {
+            Some(u) if u == uid => Some(fields[0].to_string()),
+            _ => None,
+        }
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessPolicy {
+    pub daemon_uid: u32,
+    pub operators: Option<Group>,
+    pub readers: Option<Group>,
+}
+
+impl AccessPolicy {
+    /// Role of a peer, `user` being its user name when known, for the
+    /// supplementary group memberships.
+    pub fn role_of(&self, peer: &PeerCredentials, user: Option<&str>) -> Option<Role> {
+        if peer.uid == 0 || peer.uid == self.daemon_uid {
+            return Some(Role::Operator);
+        }
+        let member = |g: &Option<Group>| match g {
+            Some(g) => peer.gid == g.gid || user.is_some_and(|u| g.members.iter().any(|m| m.eq(u))),
+            None => false,
+        };
+        if member(&self.operators) {
+            Some(Role::Operator)
+        } else if member(&self.readers) {
+            Some(Role::Reader)
+        } else {
+            None
+        }
+    }
+}
+
+/// Hexadecimal SHA-256 digest of a token, as written in the configuration.
+pub fn token_digest(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub name: String,
+    pub role: Role,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tokens(pub Vec<Token>);
+
+impl Tokens {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The token matching `secret`. Only digests are compared, so the
+    /// comparison leaks nothing usable about the secrets.
+    pub fn find(&self, secret: &str) -> Option<&Token> {
+        let digest = token_digest(secret);
+        self.0
+            .iter()
+            .find(|t| t.sha256.eq_ignore_ascii_case(&digest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access::*;
+
+    #[test]
+    fn roles_from_credentials() {
+        let policy = AccessPolicy {
+            daemon_uid: 990,
+            operators: parse_group_line("bach:x:991:alice,bob"),
+            readers: parse_group_line("bach-ro:x:992:carol"),
+        };
+        let peer = |uid, gid| PeerCredentials { pid: 1, uid, gid };
+        assert_eq!(policy.role_of(&peer(0, 0), None), Some(Role::Operator));
+        assert_eq!(policy.role_of(&peer(990, 990), None), Some(Role::Operator));
+        assert_eq!(policy.role_of(&peer(1000, 991), None), Some(Role::Operator));
+        assert_eq!(
+            policy.role_of(&peer(1000, 100), Some("bob")),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            policy.role_of(&peer(1001, 100), Some("carol")),
+            Some(Role::Reader)
+        );
+        assert_eq!(policy.role_of(&peer(1002, 992), None), Some(Role::Reader));
+        assert_eq!(policy.role_of(&peer(1003, 100), Some("mallory")), None);
+
+        assert!(parse_group_line("# comment").is_none());
+        assert!(parse_group_line("broken:x:nan:").is_none());
+        assert!(parse_group_line("empty:x:5:").unwrap().members.is_empty());
+
+        assert_eq!(Command::Pause.required_role(), Role::Operator);
+        assert_eq!(
+            Command::History {
+                module: None,
+                limit: None
+            }
+            .required_role(),
+            Role::Reader
+        );
+    }
+
+    #[test]
+    fn tokens_by_digest() {
+        let tokens = Tokens(vec![
+            Token {
+                name: "ci".to_string(),
+                role: Role::Operator,
+                sha256: token_digest("s3cret"),
+            },
+            Token {
+                name: "grafana".to_string(),
+                role: Role::Reader,
+                sha256: token_digest("look-only").to_uppercase(),
+            },
+        ]);
+        assert_eq!(
+            token_digest("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(tokens.find("s3cret").unwrap().name, "ci");
+        assert_eq!(tokens.find("look-only").unwrap().role, Role::Reader);
+        assert!(tokens.find("S3cret").is_none());
+        assert!(tokens.find("").is_none());
+    }
+
+    #[test]
+    fn unix_peer_credentials() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let cred = peer_credentials(&a).unwrap();
+        assert_eq!(cred.uid, effective_uid());
+        assert_eq!(cred.pid, std::process::id() as i32);
+    }
+}
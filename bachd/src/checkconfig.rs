@@ -0,0 +1,380 @@
+//! `bachd check-config`: validates the daemon configuration and the module
+//! configurations it references, without starting anything.
+//!
+//! No module code runs by default: the plugins must exist and the module
+//! configurations be well-formed XML. With `--load-modules`, each module is
+//! also created to check its own configuration, which runs the constructor
+//! of its plugin. It is neither initialized nor spawned.
+use crate::daemon::DaemonConfig;
+use crate::modulemanagerconfig::ModuleDefinition;
+#[cfg(feature = "static")]
+use crate::staticmodmatcher;
+use bach_module::config::{element_locations, parse_xml, Location};
+#[cfg(feature = "modular")]
+use bach_module::plugin::open_plugin;
+use bach_module::{ModResult, Module};
+use serde::de::IgnoredAny;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub file: PathBuf,
+    pub location: Option<Location>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(l) => write!(f, "{}: {}: {}", self.file.display(), l, self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+/// Problems found so far, those of `file` unless told otherwise.
+struct Problems<'a> {
+    file: &'a Path,
+    found: Vec<Problem>,
+}
+
+impl Problems<'_> {
+    fn push(&mut self, location: Option<Location>, message: String) {
+        self.found.push(Problem {
+            file: self.file.to_path_buf(),
+            location,
+            message,
+        });
+    }
+
+    fn push_other(&mut self, file: &str, location: Option<Location>, message: String) {
+        self.found.push(Problem {
+            file: PathBuf::from(file),
+            location,
+            message,
+        });
+    }
+}
+
+/// Creates the module of `definition` and hands it to `f`.
+fn with_module<T>(definition: &ModuleDefinition, f: impl FnOnce(&dyn Module) -> T) -> ModResult<T> {
+    #[cfg(feature = "modular")]
+    {
+        let (lib, module) = open_plugin(definition.source(), &definition.config)?;
+        let ret = f(module.as_ref());
+        // Its code is in the library, the module goes first.
+        drop(module);
+        drop(lib);
+        Ok(ret)
+    }
+
+    #[cfg(feature = "static")]
+    {
+        let module = staticmodmatcher::fetch(definition.source(), &definition.config)?;
+        Ok(f(module.as_ref()))
+    }
+}
+
+/// Whether the module of `definition` can be loaded, without loading it.
+fn module_found(definition: &ModuleDefinition) -> bool {
+    #[cfg(feature = "modular")]
+    {
+        Path::new(definition.source()).is_file()
+    }
+
+    #[cfg(feature = "static")]
+    {
+        staticmodmatcher::embeds(definition.source())
+    }
+}
+
+/// Loads the module of `definition` to check its configuration. Returns its
+/// name, the default id.
+fn load_module(
+    definition: &ModuleDefinition,
+    config_found: bool,
+    location: Option<Location>,
+    problems: &mut Problems,
+) -> Option<String> {
+    let created = with_module(definition, |module| {
+        let checked = if config_found {
+            module.check_config()
+        } else {
+            Ok(())
+        };
+        (module.name(), checked)
+    });
+    match created {
+        Ok((name, checked)) => {
+            if let Err(e) = checked {
+                match &definition.config {
+                    Some(c) => problems.push_other(c, e.location(), e.message().to_string()),
+                    None => problems.push(location, e.to_string()),
+                }
+            }
+            Some(name)
+        }
+        Err(e) => {
+            problems.push(
+                location,
+                format!("Unable to load {} : {}", definition.source(), e),
+            );
+            None
+        }
+    }
+}
+
+fn check_modules(text: &str, config: &DaemonConfig, load_modules: bool, problems: &mut Problems) {
+    let locations = element_locations(text, "modules");
+    let mut ids = HashSet::new();
+    for (i, m) in config.module_manager.modules.iter().enumerate() {
+        let location = locations.get(i).copied();
+        if let Err(e) = m.get_schedule() {
+            problems.push(location, e.to_string());
+        }
+        if let Err(e) = m.restart_strategy() {
+            problems.push(location, e.to_string());
+        }
+        let config_found = match &m.config {
+            Some(c) if !Path::new(c).is_file() => {
+                problems.push(location, format!("Configuration file {} not found", c));
+                false
+            }
+            _ => true,
+        };
+        let name = if load_modules {
+            load_module(m, config_found, location, problems)
+        } else {
+            if !module_found(m) {
+                problems.push(location, format!("Module {} not found", m.source()));
+            }
+            if let (Some(c), true) = (&m.config, config_found) {
+                match fs::read_to_string(c) {
+                    Ok(t) => {
+                        if let Err(e) = parse_xml::<IgnoredAny>(&t) {
+                            problems.push_other(c, Some(e.location), e.message);
+                        }
+                    }
+                    Err(e) => problems.push_other(c, None, e.to_string()),
+                }
+            }
+            // The name is the module's to tell, the same source stands for it.
+            Some(m.source().to_string())
+        };
+        if let Some(id) = m.id.clone().or(name) {
+            if !ids.insert(id.to_string()) {
+                problems.push(
+                    location,
+                    format!(
+                        "Two modules are identified as {}, give them distinct id attributes",
+                        id
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Every problem found in the configuration at `path` and in the module
+/// configurations it references, checked by the modules themselves when
+/// `load_modules` is set.
+pub fn check(path: &Path, load_modules: bool) -> Vec<Problem> {
+    let mut problems = Problems {
+        file: path,
+        found: Vec::new(),
+    };
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            problems.push(None, e.to_string());
+            return problems.found;
+        }
+    };
+    let config: DaemonConfig = match parse_xml(&text) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(Some(e.location), e.message);
+            return problems.found;
+        }
+    };
+
+    let locations = element_locations(&text, "blackout");
+    for (i, b) in config.blackouts.iter().enumerate() {
+        if let Err(e) = b.to_blackout() {
+            problems.push(locations.get(i).copied(), e.to_string());
+        }
+    }
+    let locations = element_locations(&text, "token");
+    for (i, t) in config.tokens.iter().enumerate() {
+        if let Err(e) = t.to_token() {
+            problems.push(locations.get(i).copied(), e.to_string());
+        }
+    }
+    if let Some(tls) = &config.tls {
+        let location = element_locations(&text, "tls").first().copied();
+        if let Err(e) = config.check_tls_access() {
+            problems.push(location, e.to_string());
+        }
+        for f in [Some(&tls.cert), Some(&tls.key), tls.client_ca.as_ref()]
+            .iter()
+            .flatten()
+        {
+            if !Path::new(f).is_file() {
+                problems.push(location, format!("TLS file {} not found", f));
+            }
+        }
+    }
+    if let Err(e) = config.check_http_access() {
+        let location = element_locations(&text, "http").first().copied();
+        problems.push(location, e.to_string());
+    }
+    check_modules(&text, &config, load_modules, &mut problems);
+    problems.found
+}
+
+/// Prints the problems of the configuration at `path`. Returns the exit
+/// status of the command, 1 when there is any.
+pub fn run(path: &Path, load_modules: bool) -> i32 {
+    let problems = check(path, load_modules);
+    for p in problems.iter() {
+        eprintln!("{}", p);
+    }
+    if problems.is_empty() {
+        println!("{}: configuration is valid", path.display());
+        0
+    } else {
+        eprintln!("{}: {} problem(s) found", path.display(), problems.len());
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checkconfig::*;
+
+    #[test]
+    fn reports_located_problems() {
+        let dir = std::env::temp_dir().join(format!("bach-check-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bachd.conf.xml");
+        let module_config = dir.join("module.xml");
+        fs::write(&module_config, "<module/>").unwrap();
+        fs::write(
+            &path,
+            format!(
+                r#"<DaemonConfig>
+	<log-level>warn</log-level>
+	<blackout name="backup" from="2024-01-01T00:00" to="2023-01-01T00:00"/>
+	<module-manager respawn_duration="60">
+		<modules id="nas" cyclic="true" file="/nonexistent/librsync.so" name="nope">
+			<schedule every="often"/>
+		</modules>
+		<modules id="nas" cyclic="false" file="/nonexistent/librsync.so" name="nope" config-file="{}"/>
+		<modules cyclic="false" file="/nonexistent/libstdlogger.so" name="nope" config-file="/nonexistent/a.xml"/>
+	</module-manager>
+</DaemonConfig>"#,
+                module_config.display()
+            ),
+        )
+        .unwrap();
+        let problems = check(&path, false);
+        let at = |line, column| Some(Location { line, column });
+        let located: Vec<_> = problems.iter().map(|p| p.location).collect();
+        assert_eq!(
+            located,
+            vec![
+                at(3, 2),
+                at(5, 3),
+                at(5, 3),
+                at(8, 3),
+                at(8, 3),
+                at(9, 3),
+                at(9, 3)
+            ],
+            "{:#?}",
+            problems
+        );
+        assert!(problems[0].message.contains("ends before it starts"));
+        assert!(problems[1].message.contains("often"));
+        assert!(problems[4]
+            .message
+            .contains("Two modules are identified as nas"));
+        assert!(problems[5].message.contains("/nonexistent/a.xml not found"));
+        assert_eq!(
+            problems[6].message,
+            "Module /nonexistent/libstdlogger.so not found"
+        );
+
+        // Loading the modules, at the same places.
+        let problems = check(&path, true);
+        assert_eq!(
+            problems.iter().map(|p| p.location).collect::<Vec<_>>(),
+            located
+        );
+        assert!(problems[6].message.starts_with("Unable to load"));
+
+        // The problems of a module configuration are located in it.
+        fs::write(&module_config, "<module>\n\t<label>\n</module>").unwrap();
+        let problems = check(&path, false);
+        assert_eq!(problems.len(), 8, "{:#?}", problems);
+        assert_eq!(problems[4].file, module_config);
+        assert_eq!(problems[4].location, at(3, 1));
+
+        fs::write(
+            &path,
+            "<DaemonConfig>\n\t<module-manager respawn_duration=\"60\">\n\t</module>\n</DaemonConfig>",
+        )
+        .unwrap();
+        let problems = check(&path, false);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].location, at(3, 2));
+        assert!(problems[0]
+            .to_string()
+            .starts_with(&format!("{}: line 3, column 2: ", path.display())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_open_listeners() {
+        let dir = std::env::temp_dir().join(format!("bach-check-access-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bachd.conf.xml");
+        let cert = dir.join("cert.pem");
+        fs::write(&cert, "").unwrap();
+        let config = |extra: &str| {
+            format!(
+                r#"<DaemonConfig>
+	<tls cert="{0}" key="{0}"{1}/>
+	<http ip="0.0.0.0" port="9187"/>
+	<log-level>warn</log-level>
+	<module-manager respawn_duration="60">
+		<modules cyclic="false" file="/nonexistent/libstdlogger.so"/>
+	</module-manager>
+</DaemonConfig>"#,
+                cert.display(),
+                extra
+            )
+        };
+        fs::write(&path, config("")).unwrap();
+        let problems = check(&path, false);
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+        assert_eq!(problems[0].location, Some(Location { line: 2, column: 2 }));
+        assert!(problems[0].message.contains("needs tokens or a client-ca"));
+        assert_eq!(problems[1].location, Some(Location { line: 3, column: 2 }));
+        assert!(problems[1]
+            .message
+            .contains("HTTP API on 0.0.0.0 needs tokens"));
+
+        // A client CA closes the TLS listener, the HTTP API stays local.
+        let closed =
+            config(&format!(r#" client-ca="{}""#, cert.display())).replace("0.0.0.0", "127.0.0.1");
+        fs::write(&path, closed).unwrap();
+        let problems = check(&path, false);
+        assert_eq!(problems.len(), 1, "{:#?}", problems);
+        assert!(problems[0].message.ends_with("not found"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
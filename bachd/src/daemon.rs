@@ -1,25 +1,45 @@
-use crate::modulemanager;
-use crate::modulemanagerconfig::ModuleManagerConfig;
+use crate::access::{self, AccessPolicy, Origin, Role, Token, Tokens};
+use crate::history::{HistoryQuery, Trigger};
+use crate::http::HttpApi;
+use crate::modulemanager::{self, FireRequestState};
+use crate::modulemanagerconfig::{BlackoutDefinition, ModuleDefinition, ModuleManagerConfig};
+use crate::scheduler::Blackout;
+use crate::systemd::{Journal, Notifier};
 use crate::tcpmessages::*;
 use bach_bus::bus::Bus;
-use bach_bus::packet::{BackupCommand, Packet, PacketCore, CORE_SIZE};
+use bach_bus::packet::{FireOptions, Packet};
+use bach_module::config::parse_xml;
 use bach_module::ModError;
 use lazy_static::lazy_static;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
 use std::fs;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::net::TcpListener;
-use std::path::Path;
-use std::sync::Mutex;
+use std::net::{IpAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Configuration file read when `BACH_DEFAULT_CONFIG` is not set.
+pub static DEFAULT_CONFIG: &str = "/etc/bach/bachd.conf.xml";
+/// Runs listed by the history command when the client sets no limit.
+static HISTORY_LIST_LENGTH: usize = 20;
+/// Permissions of the control socket when the configuration sets none.
+static DEFAULT_SOCKET_MODE: &str = "660";
+/// Seconds the modules are given to stop when the configuration sets none.
+static DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+/// Seconds a module is given to stop before it is unloaded or replaced.
+static UNLOAD_TIMEOUT: u64 = 30;
 
 lazy_static! {
-    static ref MANAGER: Mutex<modulemanager::ModuleManager> = Mutex::new(
-        modulemanager::ModuleManager::from_config(DaemonConfig::load().unwrap().module_manager)
-            .unwrap()
-    );
+    /// Empty until `spawn` loads the configured modules.
+    static ref MANAGER: Mutex<modulemanager::ModuleManager> =
+        Mutex::new(modulemanager::ModuleManager::new(Duration::ZERO));
     static ref BUS: Mutex<Bus> = Mutex::new(Bus::new());
 }
 
@@ -99,32 +119,194 @@ pub struct DaemonConfigAcceptIp(String);
 pub struct DaemonConfigTcpPort(u64);
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DaemonConfigLogLevel(String);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonConfigStateDir(String);
+/// Seconds the modules are given to stop on shutdown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonConfigShutdownTimeout(u64);
+
+/// Unix socket the control clients connect to. The socket file is given
+/// to the operator group, or the reader group when there is no operator
+/// group, so `mode` must let the other group in when both are set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlSocketConfig {
+    pub path: String,
+    /// Octal permissions of the socket file.
+    pub mode: Option<String>,
+    #[serde(rename = "operator-group")]
+    pub operator_group: Option<String>,
+    #[serde(rename = "reader-group")]
+    pub reader_group: Option<String>,
+}
+
+/// Certificate and key of the TCP listener, in PEM files. Clients must
+/// present a certificate signed by `client-ca` when it is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    #[serde(rename = "client-ca")]
+    pub client_ca: Option<String>,
+}
+
+/// Bearer token of TCP clients, given by the hexadecimal SHA-256 digest of
+/// its secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenDefinition {
+    pub name: String,
+    /// `reader` or `operator`.
+    pub role: String,
+    pub sha256: String,
+}
 
+impl TokenDefinition {
+    pub fn to_token(&self) -> DaemonResult<Token> {
+        let role = Role::parse(&self.role).ok_or_else(|| {
+            DaemonError::new(
+                format!("Unknown role {} for token {}", self.role, self.name),
+                2,
+            )
+        })?;
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(DaemonError::new(
+                format!("Token {} is not a SHA-256 hexadecimal digest", self.name),
+                2,
+            ));
+        }
+        Ok(Token {
+            name: self.name.clone(),
+            role,
+            sha256: self.sha256.clone(),
+        })
+    }
+}
+
+/// Address of the HTTP API, see [`crate::http`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpConfig {
+    pub ip: String,
+    pub port: u16,
+}
+
+/// The TCP listener is only opened when `port` and `ip` are set. Its
+/// clients cannot be identified, so they get the operator role unless
+/// tokens are defined, then every request must carry one.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DaemonConfig {
-    pub port: DaemonConfigTcpPort,
-    pub ip: DaemonConfigAcceptIp,
+    pub port: Option<DaemonConfigTcpPort>,
+    pub ip: Option<DaemonConfigAcceptIp>,
+    pub tls: Option<TlsConfig>,
+    #[serde(rename = "token", default)]
+    pub tokens: Vec<TokenDefinition>,
+    #[serde(rename = "control-socket")]
+    pub control_socket: Option<ControlSocketConfig>,
+    pub http: Option<HttpConfig>,
     #[serde(rename = "log-level")]
     pub log_level: DaemonConfigLogLevel,
+    #[serde(rename = "state-dir")]
+    pub state_dir: Option<DaemonConfigStateDir>,
+    #[serde(rename = "shutdown-timeout")]
+    pub shutdown_timeout: Option<DaemonConfigShutdownTimeout>,
+    #[serde(rename = "blackout", default)]
+    pub blackouts: Vec<BlackoutDefinition>,
     #[serde(rename = "module-manager")]
     pub module_manager: ModuleManagerConfig,
 }
 
 impl DaemonConfig {
+    /// The configuration file, `BACH_DEFAULT_CONFIG` when it is set.
+    pub fn path() -> PathBuf {
+        match std::env::var("BACH_DEFAULT_CONFIG") {
+            Ok(p) if !p.is_empty() => PathBuf::from(p),
+            _ => PathBuf::from(DEFAULT_CONFIG),
+        }
+    }
+
     pub fn load() -> DaemonResult<Self> {
-        for v in std::env::vars() {
-            if v.0.eq("BACH_DEFAULT_CONFIG") && !v.1.is_empty() {
-                println!("Loading config file {}", &v.1);
-                let file = fs::File::open(Path::new(&v.1))?;
-                let ret: DaemonConfig = quick_xml::de::from_reader(BufReader::new(file))?;
+        let path = Self::path();
+        if path != Path::new(DEFAULT_CONFIG) {
+            println!("Loading config file {}", path.display());
+        }
+        Self::load_from(&path)
+    }
 
-                return Ok(ret);
+    /// Reads the configuration at `path`, telling where its errors are.
+    pub fn load_from(path: &Path) -> DaemonResult<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| DaemonError::new(format!("{}: {}", path.display(), e), 1))?;
+        parse_xml(&text).map_err(|e| DaemonError::new(format!("{}: {}", path.display(), e), 2))
+    }
+
+    pub fn blackouts(&self) -> DaemonResult<Vec<Blackout>> {
+        let blackouts = self
+            .blackouts
+            .iter()
+            .map(|b| b.to_blackout())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(blackouts)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.shutdown_timeout
+                .as_ref()
+                .map_or(DEFAULT_SHUTDOWN_TIMEOUT, |t| t.0),
+        )
+    }
+
+    /// Tokens of the TCP and HTTP clients.
+    pub fn tokens(&self) -> DaemonResult<Tokens> {
+        let tokens = self
+            .tokens
+            .iter()
+            .map(|t| t.to_token())
+            .collect::<DaemonResult<Vec<_>>>()?;
+        Ok(Tokens(tokens))
+    }
+
+    /// Refuses listeners that would let anyone in, see
+    /// [`Self::check_tls_access`] and [`Self::check_http_access`].
+    pub fn check_access(&self) -> DaemonResult<()> {
+        self.check_tls_access()?;
+        self.check_http_access()
+    }
+
+    /// A TLS listener is meant for remote clients, it needs tokens or
+    /// client certificates.
+    pub fn check_tls_access(&self) -> DaemonResult<()> {
+        match &self.tls {
+            Some(tls) if self.tokens.is_empty() && tls.client_ca.is_none() => {
+                Err(DaemonError::new(
+                    "The TLS listener needs tokens or a client-ca, any client would be an operator"
+                        .to_string(),
+                    2,
+                ))
             }
+            _ => Ok(()),
         }
+    }
 
-        let file = fs::File::open(Path::new("/etc/bach/bachd.conf.xml"))?;
-        let ret: DaemonConfig = quick_xml::de::from_reader(BufReader::new(file))?;
-        Ok(ret)
+    /// An HTTP API reachable from other hosts needs tokens. Without tokens
+    /// its clients are readers, see [`HttpApi`].
+    pub fn check_http_access(&self) -> DaemonResult<()> {
+        let http = match &self.http {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        let local = http
+            .ip
+            .parse::<IpAddr>()
+            .map_or(http.ip == "localhost", |ip| ip.is_loopback());
+        if self.tokens.is_empty() && !local {
+            return Err(DaemonError::new(
+                format!(
+                    "The HTTP API on {} needs tokens, any client could read the module statuses and history",
+                    http.ip
+                ),
+                2,
+            ));
+        }
+        Ok(())
     }
 
     pub fn save(&self, fname: &Path) -> DaemonResult<()> {
@@ -135,39 +317,570 @@ impl DaemonConfig {
     }
 }
 
-pub fn mk_tcp_connection(config: &DaemonConfig) -> DaemonResult<TcpListener> {
-    let port = config.port.0;
-    let cstr = format!("{}:{}", config.ip.0, port);
+fn load_manager() -> DaemonResult<modulemanager::ModuleManager> {
+    let config = DaemonConfig::load()?;
+    let blackouts = config.blackouts()?;
+    let mut manager = modulemanager::ModuleManager::from_config(config.module_manager)?;
+    manager.set_blackouts(blackouts);
+    Ok(manager)
+}
+
+pub fn mk_tcp_connection(config: &DaemonConfig) -> DaemonResult<Option<TcpListener>> {
+    let (ip, port) = match (&config.ip, &config.port) {
+        (Some(ip), Some(port)) => (&ip.0, port.0),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(DaemonError::new(
+                "Both ip and port must be set to listen on TCP".to_string(),
+                2,
+            ))
+        }
+    };
+    let cstr = format!("{}:{}", ip, port);
     let stream = TcpListener::bind(&cstr)?;
+    stream.set_nonblocking(true)?;
     #[cfg(feature = "debug")]
-    println!("Daemon made TCP connection with IP {} on port {}", config.ip.0, port);
+    println!("Daemon made TCP connection with IP {} on port {}", ip, port);
+
+    Ok(Some(stream))
+}
+
+/// TCP listener with its optional TLS configuration and tokens.
+pub struct TcpControl {
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    tokens: Tokens,
+}
+
+impl TcpControl {
+    pub fn bind(config: &DaemonConfig) -> DaemonResult<Option<Self>> {
+        let listener = match mk_tcp_connection(config)? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let tls = match &config.tls {
+            Some(t) => Some(crate::tls::server_config(
+                Path::new(&t.cert),
+                Path::new(&t.key),
+                t.client_ca.as_deref().map(Path::new),
+            )?),
+            None => None,
+        };
+        Ok(Some(TcpControl {
+            listener,
+            tls,
+            tokens: config.tokens()?,
+        }))
+    }
+
+    /// Accepts the pending clients. A client that cannot be set up is
+    /// dropped, the others are not affected.
+    fn accept(&self, clients: &mut Clients) {
+        let role = if self.tokens.is_empty() {
+            Some(Role::Operator)
+        } else {
+            None
+        };
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("Error: TCP connection not accepted => {}", e);
+                    return;
+                }
+            };
+            let connection = match &self.tls {
+                Some(config) => ServerConnection::new(config.clone())
+                    .map_err(std::io::Error::other)
+                    .and_then(|tls| {
+                        ControlConnection::new(StreamOwned::new(tls, stream), role, Origin::Network)
+                    }),
+                None => ControlConnection::new(stream, role, Origin::Network),
+            };
+            match connection {
+                Ok(c) => clients.push(c),
+                Err(e) => println!("Error: TCP connection dropped => {}", e),
+            }
+        }
+    }
+}
+
+fn lookup_group(name: &Option<String>) -> DaemonResult<Option<access::Group>> {
+    match name {
+        Some(name) => match access::lookup_group(name)? {
+            Some(g) => Ok(Some(g)),
+            None => Err(DaemonError::new(format!("Unknown group {}", name), 2)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Listening Unix socket, its file is removed when it is dropped.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    policy: AccessPolicy,
+}
+
+impl ControlSocket {
+    pub fn bind(config: &ControlSocketConfig) -> DaemonResult<Self> {
+        let path = PathBuf::from(&config.path);
+        let mode_str = config.mode.as_deref().unwrap_or(DEFAULT_SOCKET_MODE);
+        let mode = u32::from_str_radix(mode_str, 8).map_err(|_| {
+            DaemonError::new(format!("Invalid control socket mode {}", mode_str), 2)
+        })?;
+        let policy = AccessPolicy {
+            daemon_uid: access::effective_uid(),
+            operators: lookup_group(&config.operator_group)?,
+            readers: lookup_group(&config.reader_group)?,
+        };
+
+        // A socket left by a daemon that died is removed, one still
+        // answering belongs to a running daemon.
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(DaemonError::new(
+                    format!("{} exists and is not a socket", path.display()),
+                    1,
+                ));
+            }
+            if UnixStream::connect(&path).is_ok() {
+                return Err(DaemonError::new(
+                    format!("{} is in use by another daemon", path.display()),
+                    1,
+                ));
+            }
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        let socket = ControlSocket {
+            listener,
+            path,
+            policy,
+        };
+        if let Some(g) = socket
+            .policy
+            .operators
+            .as_ref()
+            .or(socket.policy.readers.as_ref())
+        {
+            std::os::unix::fs::chown(&socket.path, None, Some(g.gid))?;
+        }
+        fs::set_permissions(&socket.path, fs::Permissions::from_mode(mode))?;
+        socket.listener.set_nonblocking(true)?;
+
+        Ok(socket)
+    }
+
+    /// Accepts the pending clients, those without any role are dropped. A
+    /// client that cannot be set up is dropped as well, the others are not
+    /// affected.
+    fn accept(&self, clients: &mut Clients) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("Error: Control socket connection not accepted => {}", e);
+                    return;
+                }
+            };
+            let peer = match access::peer_credentials(&stream) {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("Error: Control connection dropped => {}", e);
+                    continue;
+                }
+            };
+            let user = access::user_name(peer.uid);
+            match self.policy.role_of(&peer, user.as_deref()) {
+                Some(role) => match ControlConnection::new(stream, Some(role), Origin::Socket) {
+                    Ok(c) => clients.push(c),
+                    Err(e) => println!("Error: Control connection dropped => {}", e),
+                },
+                None => println!(
+                    "Refused control connection of uid {} (pid {})",
+                    peer.uid, peer.pid
+                ),
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn internal<E: std::fmt::Display>(e: E) -> ControlError {
+    ControlError::new(ErrorCode::Internal, &e.to_string())
+}
+
+fn not_found(name: &str) -> ControlError {
+    ControlError::new(ErrorCode::NotFound, &format!("Module {} not found", name))
+}
+
+fn bad_request<E: std::fmt::Display>(e: E) -> ControlError {
+    ControlError::new(ErrorCode::BadRequest, &e.to_string())
+}
+
+/// How the daemon configuration defines the module loaded from `file` with
+/// `config`, or a definition without schedule nor group when it does not.
+fn module_definition(file: &str, config: Option<String>, id: Option<String>) -> ModuleDefinition {
+    let configured = DaemonConfig::load().ok().and_then(|c| {
+        c.module_manager
+            .modules
+            .into_iter()
+            .find(|m| m.source() == file && m.config == config && (id.is_none() || m.id == id))
+    });
+    configured.unwrap_or_else(|| ModuleDefinition::new(file, config, id))
+}
+
+/// Takes an unloaded module off the bus.
+fn disconnect_unloaded(
+    manager: &Mutex<modulemanager::ModuleManager>,
+    bus: &Mutex<Bus>,
+    module: &str,
+) -> Result<(), ControlError> {
+    let connection = manager.lock().map_err(internal)?.take_connection(module);
+    if let Some(c) = connection {
+        bus.lock().map_err(internal)?.disconnect(c);
+    }
+    Ok(())
+}
+
+/// Who waits for the reply to a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Waiter {
+    /// Request `request` of the control client numbered `client`.
+    Client { client: u64, request: u64 },
+    /// Request kept by the HTTP API under `ticket`.
+    Http { ticket: u64 },
+}
+
+/// The control clients, numbered for the replies sent after their request.
+#[derive(Default)]
+struct Clients {
+    next: u64,
+    connections: Vec<(u64, ControlConnection)>,
+}
+
+impl Clients {
+    fn push(&mut self, connection: ControlConnection) {
+        self.connections.push((self.next, connection));
+        self.next += 1;
+    }
+
+    fn reply(&mut self, client: u64, request: u64, result: Result<Reply, ControlError>) {
+        // Gone clients do not get their reply.
+        if let Some((_, c)) = self.connections.iter_mut().find(|c| c.0 == client) {
+            c.send(&Response::new(request, result));
+        }
+    }
+}
+
+/// An unload or replace waiting for the thread of its module to return.
+struct PendingUnload {
+    module: String,
+    /// For a replace, the file loaded in place of the module, its own when
+    /// `None`.
+    replace: Option<Option<String>>,
+    deadline: Instant,
+    waiter: Waiter,
+}
+
+/// The unloads and replaces in progress. The daemon loop keeps running
+/// while their modules stop, each is finished and replied to once the
+/// thread of its module returned.
+struct Unloads {
+    pending: Vec<PendingUnload>,
+    timeout: Duration,
+}
+
+impl Unloads {
+    fn new(timeout: Duration) -> Self {
+        Unloads {
+            pending: Vec::new(),
+            timeout,
+        }
+    }
 
-    Ok(stream)
+    /// Asks a module to stop, it is unloaded, or replaced by `replace`, by
+    /// [`Unloads::finish`].
+    fn start(
+        &mut self,
+        manager: &Mutex<modulemanager::ModuleManager>,
+        module: String,
+        replace: Option<Option<String>>,
+        waiter: Waiter,
+    ) -> Result<(), ControlError> {
+        if self.pending.iter().any(|p| p.module.eq(&module)) {
+            return Err(bad_request(format!(
+                "Module {} is already being unloaded",
+                module
+            )));
+        }
+        manager
+            .lock()
+            .map_err(internal)?
+            .request_unload(&module)
+            .map_err(|_| not_found(&module))?;
+        self.pending.push(PendingUnload {
+            module,
+            replace,
+            deadline: Instant::now() + self.timeout,
+            waiter,
+        });
+        Ok(())
+    }
+
+    /// Unloads or replaces the modules whose thread returned. The ones still
+    /// running past their deadline stay loaded and are supervised again.
+    /// Returns the replies to their waiters.
+    fn finish(
+        &mut self,
+        manager: &Mutex<modulemanager::ModuleManager>,
+        bus: &Mutex<Bus>,
+    ) -> DaemonResult<Vec<(Waiter, Result<Reply, ControlError>)>> {
+        let now = Instant::now();
+        let mut replies = Vec::new();
+        for p in std::mem::take(&mut self.pending) {
+            if manager.lock()?.has_stopped(&p.module) {
+                let res = match p.replace {
+                    None => manager.lock()?.unload(&p.module),
+                    Some(file) => manager.lock()?.replace(&p.module, file),
+                };
+                // Gone as well when the new module failed to load.
+                let result = disconnect_unloaded(manager, bus, &p.module)
+                    .and_then(|_| res.map_err(internal))
+                    .map(|_| Reply::Done);
+                replies.push((p.waiter, result));
+            } else if now >= p.deadline {
+                manager.lock()?.cancel_unload(&p.module);
+                let message = format!(
+                    "Module {} still running after {} seconds, not unloaded",
+                    p.module,
+                    self.timeout.as_secs()
+                );
+                replies.push((p.waiter, Err(internal(message))));
+            } else {
+                self.pending.push(p);
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Replies to the waiters left when the daemon stops.
+    fn abandon(&mut self) -> Vec<(Waiter, Result<Reply, ControlError>)> {
+        self.pending
+            .drain(..)
+            .map(|p| {
+                let message = format!("Daemon stopping, {} not unloaded", p.module);
+                (p.waiter, Err(internal(message)))
+            })
+            .collect()
+    }
 }
 
-fn process_tcp_command_list(list: TcpCommandList) -> DaemonResult<()> {
+/// Runs a control command. `run` is cleared by `Terminate`. Unloads and
+/// replaces are only started, they have no reply until [`Unloads::finish`]
+/// gives one for `waiter`.
+fn handle_command(
+    command: Command,
+    run: &mut bool,
+    unloads: &mut Unloads,
+    waiter: Waiter,
+) -> Result<Option<Reply>, ControlError> {
     #[cfg(feature = "debug")]
-    println!("TCP connection got LIST command");
-    match list {
-        TcpCommandList::Loaded => {
-            let list: Vec<String> = MANAGER.lock()?.get_module_list()?;
-            for i in list {
-                println!("\t{}", i);
+    println!("Control connection got {:?}", command);
+    let manager = || MANAGER.lock().map_err(internal);
+    let reply = match command {
+        Command::List { which } => {
+            let manager = manager()?;
+            let modules = match which {
+                ListKind::Loaded => manager.get_module_list(),
+                ListKind::Running => manager.get_spawned_list(),
+                ListKind::Queued => manager.get_queued_list(),
             }
+            .map_err(internal)?;
+            Ok(Reply::Modules { modules })
+        }
+        Command::Status { module } => {
+            let manager = manager()?;
+            let status = manager.get_status(&module);
+            if status.eq("Not found") {
+                return Err(not_found(&module));
+            }
+            Ok(Reply::Status {
+                next_fire: manager.next_fire(&module),
+                paused: manager.is_paused(),
+                module,
+                status,
+            })
+        }
+        Command::Stop { module } => {
+            let manager = manager()?;
+            if !manager.request_stop(&module) && manager.get_status(&module).eq("Not found") {
+                return Err(not_found(&module));
+            }
+            Ok(Reply::Done)
+        }
+        Command::Terminate => {
+            *run = false;
+            Ok(Reply::Done)
+        }
+        Command::Fire { module, dry_run } => {
+            let options = if dry_run {
+                FireOptions::dry_run()
+            } else {
+                FireOptions::default()
+            };
+            let state = match manager()?.request_fire(&module, options, Trigger::Manual) {
+                FireRequestState::Dispatched => FireState::Dispatched,
+                FireRequestState::Queued(position) => FireState::Queued { position },
+                FireRequestState::AlreadyQueued(position) => FireState::AlreadyQueued { position },
+                FireRequestState::NotFound => return Err(not_found(&module)),
+            };
+            Ok(Reply::Fire { state })
+        }
+        Command::Reload { module } => {
+            if manager()?.request_reload(module.as_deref()) {
+                Ok(Reply::Done)
+            } else {
+                Err(not_found(&module.unwrap_or_default()))
+            }
+        }
+        Command::Pause => {
+            manager()?.pause();
+            Ok(Reply::Done)
+        }
+        Command::Resume => {
+            manager()?.resume();
+            Ok(Reply::Done)
+        }
+        Command::History { module, limit } => {
+            let query = HistoryQuery {
+                module,
+                limit: Some(limit.unwrap_or(HISTORY_LIST_LENGTH)),
+                ..HistoryQuery::default()
+            };
+            Ok(Reply::History {
+                runs: manager()?.get_history(&query),
+            })
+        }
+        Command::Metrics => {
+            let depth = BUS.lock().map_err(internal)?.depth();
+            Ok(Reply::Metrics {
+                text: manager()?.render_metrics(depth),
+            })
+        }
+        Command::Load {
+            file,
+            config,
+            module,
+        } => {
+            let definition = module_definition(&file, config, module);
+            let module = manager()?.add_module(definition).map_err(bad_request)?;
+            modulemanager::connect_module(&MANAGER, &BUS, &module).map_err(internal)?;
+            Ok(Reply::Loaded { module })
         }
-        TcpCommandList::Running => {
-            let list: Vec<String> = MANAGER.lock()?.get_spawned_list()?;
+        Command::Unload { module } => {
+            return unloads.start(&MANAGER, module, None, waiter).map(|_| None);
+        }
+        Command::Replace { module, file } => {
+            return unloads
+                .start(&MANAGER, module, Some(file), waiter)
+                .map(|_| None);
+        }
+    };
+    reply.map(Some)
+}
 
-            for i in list {
-                println!("\t{}", i);
+/// Accepts the pending connections and answers the requests they carry.
+fn serve_clients(
+    tcp: &Option<TcpControl>,
+    socket: &Option<ControlSocket>,
+    clients: &mut Clients,
+    run: &mut bool,
+    unloads: &mut Unloads,
+) -> DaemonResult<()> {
+    if let Some(tcp) = tcp {
+        tcp.accept(clients);
+    }
+    if let Some(socket) = socket {
+        socket.accept(clients);
+    }
+    let no_tokens = Tokens::default();
+    let tokens = tcp.as_ref().map_or(&no_tokens, |t| &t.tokens);
+    for (client, c) in clients.connections.iter_mut() {
+        for request in c.poll() {
+            let id = request.id;
+            let waiter = Waiter::Client {
+                client: *client,
+                request: id,
+            };
+            let result = c
+                .authorize(&request, tokens)
+                .and_then(|_| handle_command(request.command, run, unloads, waiter));
+            if let Some(result) = result.transpose() {
+                c.send(&Response::new(id, result));
             }
         }
     }
+    clients.connections.retain(|c| !c.1.is_closed());
     Ok(())
 }
 
-fn join_and_print() -> DaemonResult<()> {
-    let vecres = MANAGER.lock()?.join_all();
+/// Reads the daemon configuration again. The blackouts and the module
+/// manager settings are applied, the modules reload their own
+/// configuration. The listeners, the state directory and the history are
+/// only set up on start.
+fn reload() -> DaemonResult<()> {
+    let config = DaemonConfig::load()?;
+    let blackouts = config.blackouts()?;
+    let mut manager = MANAGER.lock()?;
+    manager.reconfigure(config.module_manager)?;
+    manager.set_blackouts(blackouts);
+    manager.request_reload(None);
+    Ok(())
+}
+
+/// Stops the scheduled fires and terminates the modules, waiting up to
+/// `timeout` for the running fires to be cancelled before joining them.
+/// Returns the modules whose thread is left behind.
+fn shutdown(
+    manager: &Mutex<modulemanager::ModuleManager>,
+    bus: &Mutex<Bus>,
+    timeout: Duration,
+) -> DaemonResult<Vec<String>> {
+    manager.lock()?.begin_shutdown();
+    bus.lock()?.send(Packet::new_term());
+    let deadline = Instant::now() + timeout;
+    let left = loop {
+        // The bus carries the terminate packet and the last notifications.
+        bus.lock()?.perform();
+        let running = manager.lock()?.still_running();
+        if running.is_empty() {
+            break running;
+        }
+        if Instant::now() >= deadline {
+            println!(
+                "Error: Modules still running after {} seconds",
+                timeout.as_secs()
+            );
+            break running;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    join_and_print(manager)?;
+    bus.lock()?.perform();
+    Ok(left)
+}
+
+fn join_and_print(manager: &Mutex<modulemanager::ModuleManager>) -> DaemonResult<()> {
+    let vecres = manager.lock()?.join_all();
     for r in vecres {
         match r.1 {
             Ok(()) => (),
@@ -180,55 +893,309 @@ fn join_and_print() -> DaemonResult<()> {
     Ok(())
 }
 
-pub fn spawn() -> DaemonResult<()> {
-    let config: DaemonConfig = DaemonConfig::load()?;
-    let tcp = mk_tcp_connection(&config)?;
+/// What the clients connect to, at least one of them is set.
+struct Listeners {
+    tcp: Option<TcpControl>,
+    socket: Option<ControlSocket>,
+    http: Option<HttpApi>,
+}
+
+impl Listeners {
+    fn bind(config: &DaemonConfig) -> DaemonResult<Self> {
+        config.check_access()?;
+        if config.http.is_some() && config.tokens.is_empty() {
+            println!("Warning: The HTTP API has no tokens, local clients may only read");
+        }
+        let tcp = TcpControl::bind(config)?;
+        let socket = match &config.control_socket {
+            Some(c) => Some(ControlSocket::bind(c)?),
+            None => None,
+        };
+        let http = match &config.http {
+            Some(h) => Some(HttpApi::bind(
+                &format!("{}:{}", h.ip, h.port),
+                config.tokens()?,
+            )?),
+            None => None,
+        };
+        if tcp.is_none() && socket.is_none() && http.is_none() {
+            return Err(DaemonError::new(
+                "Neither a TCP port, a control socket nor an HTTP API is configured".to_string(),
+                2,
+            ));
+        }
+        Ok(Listeners { tcp, socket, http })
+    }
+}
+
+/// Signals the daemon acts on, raised by their handlers.
+struct Signals {
+    terminate: Arc<AtomicBool>,
+    hangup: Arc<AtomicBool>,
+}
+
+impl Signals {
+    fn register() -> DaemonResult<Self> {
+        // A second signal while shutting down kills the daemon right away.
+        let terminate = Arc::new(AtomicBool::new(false));
+        let hangup = Arc::new(AtomicBool::new(false));
+        for sig in [SIGTERM, SIGINT] {
+            flag::register_conditional_shutdown(sig, 1, Arc::clone(&terminate))?;
+            flag::register(sig, Arc::clone(&terminate))?;
+        }
+        flag::register(SIGHUP, Arc::clone(&hangup))?;
+        Ok(Signals { terminate, hangup })
+    }
+}
+
+fn send_replies(
+    replies: Vec<(Waiter, Result<Reply, ControlError>)>,
+    clients: &mut Clients,
+    http: &Option<HttpApi>,
+) {
+    for (waiter, result) in replies {
+        match waiter {
+            Waiter::Client { client, request } => clients.reply(client, request, result),
+            Waiter::Http { ticket } => {
+                if let Some(http) = http {
+                    http.finish(ticket, result);
+                }
+            }
+        }
+    }
+}
+
+/// Spawns the modules and serves the clients until a stop is requested.
+fn run(listeners: &Listeners, signals: &Signals, notifier: &Option<Notifier>) -> DaemonResult<()> {
     let mut run = true;
-    
-    tcp.set_nonblocking(true)?;
-    modulemanager::connect(&MANAGER, &BUS)?;
+    let mut clients = Clients::default();
+    let mut unloads = Unloads::new(Duration::from_secs(UNLOAD_TIMEOUT));
     MANAGER.lock()?.spawn_all()?;
+    // The service manager is only told about failures to notify by the
+    // missing notifications themselves, they are not worth stopping for.
+    if let Some(n) = notifier {
+        let _ = n.ready();
+    }
     loop {
+        MANAGER.lock()?.supervise();
         MANAGER.lock()?.fire_cyclic()?;
-        for tcpstream in tcp.incoming() {
-            let mut current_core = [0u8; CORE_SIZE];
-            match tcpstream {
-                Ok(mut stream) => {
-                    stream.read_exact(&mut current_core)?;
-                    match TcpCommand::from(current_core) {
-                        TcpCommand::List(list) => {
-                            process_tcp_command_list(list)?;
-                        }
-                        TcpCommand::Status(name) => {
-                            println!("{}", MANAGER.lock()?.get_status(&name));
-                        }
-                        TcpCommand::Stop(name) => {
-                            BUS.lock()?.send(Packet::new_stop(&name));
-                        }
-                        TcpCommand::Terminate => {
-                            BUS.lock()?.send(Packet::new_term());
-                            run = false;
-                            break;
-                        }
-                        TcpCommand::Fire(name) => {
-                            BUS.lock()?.send(Packet::BackupCom(PacketCore::from(
-                                BackupCommand::Fire(Some(name)),
-                            )));
-                        }
-                        _ => (),
-                    }
-                }
-                Err(_) => break,
+        MANAGER.lock()?.dispatch_queued();
+        serve_clients(
+            &listeners.tcp,
+            &listeners.socket,
+            &mut clients,
+            &mut run,
+            &mut unloads,
+        )?;
+        if let Some(http) = &listeners.http {
+            let served = http.serve(|command, ticket| {
+                handle_command(command, &mut run, &mut unloads, Waiter::Http { ticket })
+            });
+            if let Err(e) = served {
+                println!("Error: HTTP request not served => {}", e);
             }
         }
+        let replies = unloads.finish(&MANAGER, &BUS)?;
+        send_replies(replies, &mut clients, &listeners.http);
+        if signals.hangup.swap(false, Ordering::SeqCst) {
+            if let Some(n) = notifier {
+                let _ = n.reloading();
+            }
+            if let Err(e) = reload() {
+                println!("Error: Configuration not reloaded => {}", e);
+            }
+            if let Some(n) = notifier {
+                let _ = n.ready();
+            }
+        }
+        if let Some(n) = notifier {
+            let _ = n.tick(&MANAGER.lock()?.service_status());
+        }
         BUS.lock()?.perform();
-        if !run {
-            break;
+        if !run || signals.terminate.load(Ordering::SeqCst) {
+            send_replies(unloads.abandon(), &mut clients, &listeners.http);
+            return Ok(());
         }
         thread::sleep(Duration::from_millis(250));
     }
+}
 
-    join_and_print()?;
+pub fn spawn() -> DaemonResult<()> {
+    let config: DaemonConfig = DaemonConfig::load()?;
+    if let Some(dir) = &config.state_dir {
+        // Modules live in their own shared objects, the environment is the
+        // only place they all read from.
+        std::env::set_var(bach_module::state::STATE_DIR_ENV, &dir.0);
+    }
+    // Before anything is bound, a broken configuration leaves nothing behind.
+    let manager = load_manager().map_err(|e| {
+        DaemonError::new(
+            format!("{}, run bachd check-config for every problem", e.message),
+            e.code,
+        )
+    })?;
+    *MANAGER.lock()? = manager;
+    let listeners = Listeners::bind(&config)?;
+    let signals = Signals::register()?;
+    let notifier = Notifier::from_env()?;
+    if let Some(journal) = Journal::from_env() {
+        MANAGER.lock()?.set_journal(journal);
+    }
+    modulemanager::connect(&MANAGER, &BUS)?;
 
-    Ok(())
+    // The modules are shut down however the loop ends, its error comes
+    // first.
+    let ran = run(&listeners, &signals, &notifier);
+    if let Err(e) = &ran {
+        println!("Error: Shutting down => {}", e);
+    }
+    if let Some(n) = &notifier {
+        let _ = n.stopping();
+    }
+    let stopped = shutdown(&MANAGER, &BUS, config.shutdown_timeout());
+    ran.and(stopped.map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::*;
+    use crate::testing::Dummy;
+    use bach_module::{Module, RUN_TERM};
+
+    fn shared<T>(t: T) -> &'static Mutex<T> {
+        Box::leak(Box::new(Mutex::new(t)))
+    }
+
+    #[test]
+    fn shutdown_waits_for_the_modules_then_gives_up() {
+        let quick = Dummy::new("quick");
+        let mut stuck = Dummy::new("stuck");
+        stuck.ignore_terminate = true;
+        let quick_seen = quick.received.clone();
+        let stuck_status = stuck.run_status().clone();
+        let mut manager = modulemanager::ModuleManager::new(Duration::from_secs(60));
+        manager.insert(None, Box::new(quick)).unwrap();
+        manager.insert(None, Box::new(stuck)).unwrap();
+        let (manager, bus) = (shared(manager), shared(Bus::new()));
+        modulemanager::connect(manager, bus).unwrap();
+        for id in ["quick", "stuck"] {
+            modulemanager::connect_module(manager, bus, id).unwrap();
+        }
+        manager.lock().unwrap().spawn_all().unwrap();
+
+        let started = Instant::now();
+        let left = shutdown(manager, bus, Duration::from_millis(500)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert_eq!(left, vec!["stuck".to_string()]);
+        assert!(quick_seen.lock().unwrap().contains(&Packet::Terminate));
+        assert!(manager.lock().unwrap().is_paused());
+        assert!(manager
+            .lock()
+            .unwrap()
+            .get_spawned_list()
+            .unwrap()
+            .is_empty());
+        stuck_status.store(RUN_TERM, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn shutdown_returns_once_the_modules_stopped() {
+        let mut manager = modulemanager::ModuleManager::new(Duration::from_secs(60));
+        manager.insert(None, Box::new(Dummy::new("quick"))).unwrap();
+        let (manager, bus) = (shared(manager), shared(Bus::new()));
+        modulemanager::connect(manager, bus).unwrap();
+        modulemanager::connect_module(manager, bus, "quick").unwrap();
+        manager.lock().unwrap().spawn_all().unwrap();
+
+        let started = Instant::now();
+        let left = shutdown(manager, bus, Duration::from_secs(30)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(left.is_empty());
+        assert!(manager
+            .lock()
+            .unwrap()
+            .get_spawned_list()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn unloads_finish_once_the_module_stopped() {
+        let mut manager = modulemanager::ModuleManager::new(Duration::from_secs(60));
+        manager.insert(None, Box::new(Dummy::new("quick"))).unwrap();
+        let (manager, bus) = (shared(manager), shared(Bus::new()));
+        modulemanager::connect(manager, bus).unwrap();
+        modulemanager::connect_module(manager, bus, "quick").unwrap();
+        manager.lock().unwrap().spawn_all().unwrap();
+
+        let mut unloads = Unloads::new(Duration::from_secs(30));
+        let waiter = Waiter::Client {
+            client: 1,
+            request: 7,
+        };
+        unloads
+            .start(manager, "quick".to_string(), None, waiter)
+            .unwrap();
+        let err = unloads
+            .start(manager, "quick".to_string(), None, waiter)
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::BadRequest);
+        let err = unloads
+            .start(manager, "nope".to_string(), None, waiter)
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+
+        let started = Instant::now();
+        let replies = loop {
+            let replies = unloads.finish(manager, bus).unwrap();
+            if !replies.is_empty() {
+                break replies;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(replies, vec![(waiter, Ok(Reply::Done))]);
+        assert!(manager
+            .lock()
+            .unwrap()
+            .get_module_list()
+            .unwrap()
+            .is_empty());
+        assert!(unloads.finish(manager, bus).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unloads_give_up_on_modules_still_running() {
+        let mut stuck = Dummy::new("stuck");
+        stuck.ignore_stop = true;
+        let stuck_status = stuck.run_status().clone();
+        let mut manager = modulemanager::ModuleManager::new(Duration::from_secs(60));
+        manager.insert(None, Box::new(stuck)).unwrap();
+        let (manager, bus) = (shared(manager), shared(Bus::new()));
+        modulemanager::connect(manager, bus).unwrap();
+        modulemanager::connect_module(manager, bus, "stuck").unwrap();
+        manager.lock().unwrap().spawn_all().unwrap();
+
+        let mut unloads = Unloads::new(Duration::ZERO);
+        let waiter = Waiter::Http { ticket: 3 };
+        unloads
+            .start(manager, "stuck".to_string(), Some(None), waiter)
+            .unwrap();
+        let replies = unloads.finish(manager, bus).unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, waiter);
+        let err = replies[0].1.clone().unwrap_err();
+        assert_eq!(err.code, ErrorCode::Internal);
+        assert!(err.message.contains("still running"));
+        assert_eq!(
+            manager.lock().unwrap().get_spawned_list().unwrap(),
+            vec!["stuck".to_string()]
+        );
+        // It can be asked again.
+        unloads
+            .start(manager, "stuck".to_string(), None, waiter)
+            .unwrap();
+        stuck_status.store(RUN_TERM, Ordering::SeqCst);
+        manager.lock().unwrap().join_all();
+    }
 }
@@ -0,0 +1,480 @@
+//! Record of every module run.
+//!
+//! Runs are kept as JSON lines appended to a single file, one line per
+//! finished run. The file is rewritten only when the retention drops old
+//! runs.
+use bach_bus::packet::{FireOptions, Notification, Packet};
+use bach_module::{ModError, ModResult};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+pub static HISTORY_FILENAME: &str = "history.jsonl";
+
+/// Notifications kept per run, the later ones are only counted.
+pub const MAX_RUN_NOTIFICATIONS: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Schedule,
+    Manual,
+    Dependency,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failed,
+    /// The module stopped, panicked or the daemon ended during the run.
+    Interrupted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Good,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Level of a notification packet, `None` for the other packets.
+    pub fn of(packet: &Packet) -> Option<Self> {
+        match packet {
+            Packet::NotifyGood(_) => Some(Level::Good),
+            Packet::NotifyWarn(_) => Some(Level::Warn),
+            Packet::NotifyErr(_) => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunNotification {
+    pub at: DateTime<Utc>,
+    pub level: Level,
+    pub stage: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub run_id: u64,
+    pub module: String,
+    pub trigger: Trigger,
+    pub dry_run: bool,
+    pub started: DateTime<Utc>,
+    pub ended: DateTime<Utc>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub notifications: Vec<RunNotification>,
+    #[serde(default)]
+    pub dropped_notifications: usize,
+}
+
+impl std::fmt::Display for RunRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} ({:?}{}) {} -> {} : {:?}",
+            self.run_id,
+            self.module,
+            self.trigger,
+            if self.dry_run { ", dry run" } else { "" },
+            self.started.to_rfc3339(),
+            self.ended.to_rfc3339(),
+            self.outcome
+        )?;
+        if let Some(e) = &self.error {
+            write!(f, " : {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// How much history is kept. Both limits apply when both are set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_age: Option<chrono::Duration>,
+    /// Runs kept per module.
+    pub max_runs: Option<usize>,
+}
+
+/// Filter of [`History::query`], every field left to `None` matches all.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub module: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub outcome: Option<Outcome>,
+    /// Only the newest `limit` matches.
+    pub limit: Option<usize>,
+}
+
+struct ActiveRun {
+    run_id: u64,
+    trigger: Trigger,
+    dry_run: bool,
+    started: DateTime<Utc>,
+    notifications: Vec<RunNotification>,
+    dropped_notifications: usize,
+}
+
+pub struct History {
+    path: PathBuf,
+    retention: Retention,
+    runs: Vec<RunRecord>,
+    active: HashMap<String, ActiveRun>,
+    next_id: u64,
+}
+
+impl History {
+    /// Opens the history in `path`, creating it if needed. Unreadable lines,
+    /// like the last one after a crash, are dropped.
+    pub fn open(path: &Path, retention: Retention) -> ModResult<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut runs = Vec::new();
+        let mut damaged = false;
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                match serde_json::from_str::<RunRecord>(&line?) {
+                    Ok(r) => runs.push(r),
+                    Err(_) => damaged = true,
+                }
+            }
+        }
+        let next_id = runs.iter().map(|r| r.run_id).max().unwrap_or(0) + 1;
+        let mut ret = History {
+            path: path.to_path_buf(),
+            retention,
+            runs,
+            active: HashMap::new(),
+            next_id,
+        };
+        if damaged {
+            // Appending after a torn line would spoil the next record too.
+            ret.rewrite()?;
+        }
+        ret.apply_retention(Utc::now())?;
+        Ok(ret)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens a run of `module`. A run of it still open, whose end went
+    /// unseen, is closed as interrupted.
+    pub fn start(
+        &mut self,
+        module: &str,
+        trigger: Trigger,
+        options: FireOptions,
+        now: DateTime<Utc>,
+    ) -> ModResult<u64> {
+        self.finish(
+            module,
+            Outcome::Interrupted,
+            Some("End of run not seen".to_string()),
+            now,
+        )?;
+        let run_id = self.next_id;
+        self.next_id += 1;
+        self.active.insert(
+            module.to_string(),
+            ActiveRun {
+                run_id,
+                trigger,
+                dry_run: options.dry_run,
+                started: now,
+                notifications: Vec::new(),
+                dropped_notifications: 0,
+            },
+        );
+        Ok(run_id)
+    }
+
+    pub fn is_running(&self, module: &str) -> bool {
+        self.active.contains_key(module)
+    }
+
+    /// Id of the open run of `module`.
+    pub fn run_id(&self, module: &str) -> Option<u64> {
+        self.active.get(module).map(|r| r.run_id)
+    }
+
+    /// Attaches a module notification to its open run. The end of run
+    /// notifications the module loop emits close the run. Returns the
+    /// record when it did.
+    pub fn notify(&mut self, packet: Packet, now: DateTime<Utc>) -> ModResult<Option<RunRecord>> {
+        let level = match Level::of(&packet) {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let n = Notification::from(packet);
+        let run = match self.active.get_mut(&n.provider) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if run.notifications.len() < MAX_RUN_NOTIFICATIONS {
+            run.notifications.push(RunNotification {
+                at: now,
+                level,
+                stage: n.stage.to_string(),
+                message: n.message.to_string(),
+            });
+        } else {
+            run.dropped_notifications += 1;
+        }
+
+        match (level, n.stage.as_str()) {
+            (Level::Good, "END") => self.finish(&n.provider, Outcome::Success, None, now),
+            (Level::Error, "RUN") => self.finish(
+                &n.provider,
+                Outcome::Failed,
+                Some(n.message.to_string()),
+                now,
+            ),
+            _ => Ok(None),
+        }
+    }
+
+    /// Closes the open run of `module`, if any, and appends it to the file.
+    pub fn finish(
+        &mut self,
+        module: &str,
+        outcome: Outcome,
+        error: Option<String>,
+        now: DateTime<Utc>,
+    ) -> ModResult<Option<RunRecord>> {
+        let run = match self.active.remove(module) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let record = RunRecord {
+            run_id: run.run_id,
+            module: module.to_string(),
+            trigger: run.trigger,
+            dry_run: run.dry_run,
+            started: run.started,
+            ended: now,
+            outcome,
+            error,
+            notifications: run.notifications,
+            dropped_notifications: run.dropped_notifications,
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", to_json(&record)?)?;
+        file.sync_data()?;
+        self.runs.push(record.clone());
+        self.apply_retention(now)?;
+
+        Ok(Some(record))
+    }
+
+    /// Closes every open run as interrupted.
+    pub fn interrupt_all(&mut self, reason: &str, now: DateTime<Utc>) -> ModResult<()> {
+        let modules: Vec<String> = self.active.keys().cloned().collect();
+        for m in modules {
+            self.finish(&m, Outcome::Interrupted, Some(reason.to_string()), now)?;
+        }
+        Ok(())
+    }
+
+    /// Matching runs, newest first.
+    pub fn query(&self, query: &HistoryQuery) -> Vec<&RunRecord> {
+        let matches = self.runs.iter().rev().filter(|r| {
+            query.module.as_ref().is_none_or(|m| r.module.eq(m))
+                && query.since.is_none_or(|s| r.started >= s)
+                && query.outcome.is_none_or(|o| r.outcome == o)
+        });
+        match query.limit {
+            Some(l) => matches.take(l).collect(),
+            None => matches.collect(),
+        }
+    }
+
+    pub fn get(&self, run_id: u64) -> Option<&RunRecord> {
+        self.runs.iter().find(|r| r.run_id == run_id)
+    }
+
+    fn apply_retention(&mut self, now: DateTime<Utc>) -> ModResult<()> {
+        let before = self.runs.len();
+        if let Some(age) = self.retention.max_age {
+            self.runs.retain(|r| now - r.ended <= age);
+        }
+        if let Some(max) = self.retention.max_runs {
+            let mut kept: HashMap<String, usize> = HashMap::new();
+            let mut keep = vec![false; self.runs.len()];
+            for (i, r) in self.runs.iter().enumerate().rev() {
+                let count = kept.entry(r.module.to_string()).or_insert(0);
+                if *count < max {
+                    *count += 1;
+                    keep[i] = true;
+                }
+            }
+            let mut i = 0;
+            self.runs.retain(|_| {
+                i += 1;
+                keep[i - 1]
+            });
+        }
+        if self.runs.len() != before {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    fn rewrite(&self) -> ModResult<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let mut file = File::create(&tmp)?;
+        for r in self.runs.iter() {
+            writeln!(file, "{}", to_json(r)?)?;
+        }
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn to_json(record: &RunRecord) -> ModResult<String> {
+    serde_json::to_string(record).map_err(|e| ModError::new(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn test_file(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bach-history-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(HISTORY_FILENAME)
+    }
+
+    #[test]
+    fn runs_are_recorded() {
+        let path = test_file("record");
+        let mut history = History::open(&path, Retention::default()).unwrap();
+        let t0 = at("2021-06-01T02:00:00Z");
+        let t1 = at("2021-06-01T02:10:00Z");
+
+        let first = history
+            .start("rsync", Trigger::Schedule, FireOptions::default(), t0)
+            .unwrap();
+        assert!(history.is_running("rsync"));
+        // Other modules and non notifications are ignored.
+        assert!(history
+            .notify(Packet::new_ng("Started", "reporter", "RUN"), t0)
+            .unwrap()
+            .is_none());
+        assert!(history.notify(Packet::new_term(), t0).unwrap().is_none());
+        history
+            .notify(Packet::new_nw("Target almost full", "rsync", "RUN"), t0)
+            .unwrap();
+        let record = history
+            .notify(Packet::new_ng("Successful End", "rsync", "END"), t1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.outcome, Outcome::Success);
+        assert_eq!(record.notifications.len(), 2);
+        assert_eq!(record.notifications[0].level, Level::Warn);
+        assert!(!history.is_running("rsync"));
+
+        let second = history
+            .start("rsync", Trigger::Manual, FireOptions::dry_run(), t1)
+            .unwrap();
+        history
+            .notify(Packet::new_ne("Host unreachable", "rsync", "RUN"), t1)
+            .unwrap();
+        history
+            .start("reporter", Trigger::Dependency, FireOptions::default(), t1)
+            .unwrap();
+        history.interrupt_all("Daemon stopped", t1).unwrap();
+
+        // A torn last line is skipped on reopening.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"run_id\":4,\"mod").unwrap();
+        drop(file);
+
+        let history = History::open(&path, Retention::default()).unwrap();
+        let runs = history.query(&HistoryQuery::default());
+        assert_eq!(runs.len(), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        assert_eq!(runs[0].module, "reporter");
+        assert_eq!(runs[0].outcome, Outcome::Interrupted);
+        let failed = history.get(second).unwrap();
+        assert_eq!(failed.outcome, Outcome::Failed);
+        assert_eq!(failed.error.as_deref(), Some("Host unreachable"));
+        assert!(failed.dry_run);
+        assert_eq!(history.get(first).unwrap().trigger, Trigger::Schedule);
+
+        let query = HistoryQuery {
+            module: Some("rsync".to_string()),
+            outcome: Some(Outcome::Success),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(history.query(&query).len(), 1);
+        let query = HistoryQuery {
+            limit: Some(1),
+            since: Some(t1),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(history.query(&query)[0].module, "reporter");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn retention_drops_old_runs() {
+        let path = test_file("retention");
+        let retention = Retention {
+            max_age: Some(chrono::Duration::days(7)),
+            max_runs: Some(2),
+        };
+        let mut history = History::open(&path, retention).unwrap();
+        let mut t = at("2021-06-01T02:00:00Z");
+        for _ in 0..3 {
+            for m in ["rsync", "reporter"] {
+                history
+                    .start(m, Trigger::Schedule, FireOptions::default(), t)
+                    .unwrap();
+                history.finish(m, Outcome::Success, None, t).unwrap();
+            }
+            t = t + chrono::Duration::days(1);
+        }
+        assert_eq!(history.query(&HistoryQuery::default()).len(), 4);
+
+        t = at("2021-06-09T02:30:00Z");
+        history
+            .start("rsync", Trigger::Schedule, FireOptions::default(), t)
+            .unwrap();
+        history.finish("rsync", Outcome::Success, None, t).unwrap();
+        // Only the runs of the 3rd are young enough, then two per module.
+        let runs = history.query(&HistoryQuery::default());
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0].started, t);
+        assert_eq!(runs[2].started, at("2021-06-03T02:00:00Z"));
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 3);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
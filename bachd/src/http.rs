@@ -0,0 +1,439 @@
+//! Optional HTTP/JSON front of the control commands.
+//!
+//! * `GET /modules`: every loaded module with its status
+//! * `GET /modules/{name}`: status of one module
+//! * `POST /modules/{name}/fire`: fires it, `?dry_run=true` for a dry run
+//! * `POST /modules/{name}/stop`: stops it
+//! * `DELETE /modules/{name}`: unloads it
+//! * `POST /modules/{name}/replace`: loads its file again
+//! * `GET /history`: last runs, filtered by `?module=` and `?limit=`
+//! * `GET /metrics`: metrics in the Prometheus text format
+//!
+//! Replies and errors have the JSON shape of the control protocol ones.
+//! Tokens are sent as `Authorization: Bearer <token>`, they are required
+//! once any is defined, like on the TCP listener. Without tokens every
+//! client is a reader and the API must listen on a loopback address, see
+//! [`crate::daemon::DaemonConfig::check_http_access`]. Modules are only
+//! loaded from a file through the control socket.
+use crate::access::{Origin, Role, Tokens};
+use crate::tcpmessages::{authorize, Command, ControlError, ErrorCode, ListKind, Reply};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::net::SocketAddr;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// What a request asks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Route {
+    Command(Command),
+    /// `List` followed by a `Status` of each module.
+    ModuleStatuses,
+}
+
+#[derive(Serialize)]
+struct ModuleStatuses {
+    modules: Vec<Reply>,
+}
+
+fn status_code(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::BadRequest | ErrorCode::UnsupportedVersion => 400,
+        ErrorCode::Unauthorized => 401,
+        ErrorCode::Forbidden => 403,
+        ErrorCode::NotFound => 404,
+        ErrorCode::Internal => 500,
+        ErrorCode::Transport => 502,
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn bad_request(message: &str) -> ControlError {
+    ControlError::new(ErrorCode::BadRequest, message)
+}
+
+fn route(method: &Method, url: &str) -> Result<Route, ControlError> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| percent_decode(v))
+    };
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(percent_decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    let command = match (method, segments.as_slice()) {
+        (Method::Get, ["modules"]) => return Ok(Route::ModuleStatuses),
+        (Method::Get, ["modules", module]) => Command::Status {
+            module: module.to_string(),
+        },
+        (Method::Post, ["modules", module, "fire"]) => Command::Fire {
+            module: module.to_string(),
+            dry_run: match param("dry_run").as_deref() {
+                None | Some("false") | Some("0") => false,
+                Some("true") | Some("1") => true,
+                Some(v) => return Err(bad_request(&format!("Invalid dry_run {}", v))),
+            },
+        },
+        (Method::Post, ["modules", module, "stop"]) => Command::Stop {
+            module: module.to_string(),
+        },
+        (Method::Delete, ["modules", module]) => Command::Unload {
+            module: module.to_string(),
+        },
+        (Method::Post, ["modules", module, "replace"]) => Command::Replace {
+            module: module.to_string(),
+            file: None,
+        },
+        (Method::Get, ["history"]) => Command::History {
+            module: param("module"),
+            limit: match param("limit") {
+                Some(l) => Some(
+                    l.parse()
+                        .map_err(|_| bad_request(&format!("Invalid limit {}", l)))?,
+                ),
+                None => None,
+            },
+        },
+        (Method::Get, ["metrics"]) => Command::Metrics,
+        _ => {
+            return Err(ControlError::new(
+                ErrorCode::NotFound,
+                &format!("No {} {}", method, path),
+            ))
+        }
+    };
+    Ok(Route::Command(command))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn json_response<T: Serialize>(code: u16, body: &T) -> Response<io::Cursor<Vec<u8>>> {
+    let body = serde_json::to_string(body).unwrap_or_default();
+    Response::from_string(body)
+        .with_status_code(code)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn result_response(result: Result<Reply, ControlError>) -> Response<io::Cursor<Vec<u8>>> {
+    match result {
+        Ok(Reply::Metrics { text }) => Response::from_string(text)
+            .with_header(header("Content-Type", "text/plain; version=0.0.4")),
+        Ok(reply) => json_response(200, &reply),
+        Err(e) => json_response(status_code(e.code), &e),
+    }
+}
+
+pub struct HttpApi {
+    server: Server,
+    tokens: Tokens,
+    /// Requests answered later, by their ticket.
+    deferred: RefCell<Vec<(u64, Request)>>,
+    next_ticket: Cell<u64>,
+}
+
+impl HttpApi {
+    pub fn bind(address: &str, tokens: Tokens) -> io::Result<Self> {
+        let server = Server::http(address)
+            .map_err(|e| io::Error::new(io::ErrorKind::AddrNotAvailable, e.to_string()))?;
+        Ok(HttpApi {
+            server,
+            tokens,
+            deferred: RefCell::new(Vec::new()),
+            next_ticket: Cell::new(0),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers the pending requests with `handle`, without waiting for any.
+    /// `handle` is given the ticket of the request, it returns no reply for
+    /// the requests answered later with [`HttpApi::finish`].
+    pub fn serve<F>(&self, mut handle: F) -> io::Result<()>
+    where
+        F: FnMut(Command, u64) -> Result<Option<Reply>, ControlError>,
+    {
+        while let Some(request) = self.server.try_recv()? {
+            let ticket = self.next_ticket.get();
+            self.next_ticket.set(ticket + 1);
+            match self.answer(&request, ticket, &mut handle) {
+                // A client gone before its answer is its own problem.
+                Some(response) => {
+                    let _ = request.respond(response);
+                }
+                None => self.deferred.borrow_mut().push((ticket, request)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the request of `ticket` left without reply by [`HttpApi::serve`].
+    pub fn finish(&self, ticket: u64, result: Result<Reply, ControlError>) {
+        let mut deferred = self.deferred.borrow_mut();
+        if let Some(pos) = deferred.iter().position(|d| d.0 == ticket) {
+            let (_, request) = deferred.remove(pos);
+            let _ = request.respond(result_response(result));
+        }
+    }
+
+    fn token(request: &Request) -> Option<String> {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            .map(|t| t.trim().to_string())
+    }
+
+    fn answer<F>(
+        &self,
+        request: &Request,
+        ticket: u64,
+        handle: &mut F,
+    ) -> Option<Response<io::Cursor<Vec<u8>>>>
+    where
+        F: FnMut(Command, u64) -> Result<Option<Reply>, ControlError>,
+    {
+        // Any local user reaches the API, it is no control socket.
+        let role = if self.tokens.is_empty() {
+            Some(Role::Reader)
+        } else {
+            None
+        };
+        let token = Self::token(request);
+        let result = route(request.method(), request.url()).and_then(|r| {
+            let command = match &r {
+                Route::Command(c) => c.clone(),
+                Route::ModuleStatuses => Command::List {
+                    which: ListKind::Loaded,
+                },
+            };
+            authorize(
+                role,
+                token.as_deref(),
+                &command,
+                &self.tokens,
+                Origin::Network,
+            )?;
+            Ok((r, handle(command, ticket)?))
+        });
+        match result {
+            Ok((_, None)) => None,
+            Ok((Route::ModuleStatuses, Some(Reply::Modules { modules }))) => {
+                let statuses = modules
+                    .into_iter()
+                    .map(|module| handle(Command::Status { module }, ticket))
+                    .collect::<Result<Vec<_>, _>>();
+                Some(match statuses {
+                    Ok(modules) => json_response(
+                        200,
+                        &ModuleStatuses {
+                            modules: modules.into_iter().flatten().collect(),
+                        },
+                    ),
+                    Err(e) => json_response(status_code(e.code), &e),
+                })
+            }
+            Ok((_, Some(reply))) => Some(result_response(Ok(reply))),
+            Err(e) => Some(result_response(Err(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access::{token_digest, Token};
+    use crate::http::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn routes() {
+        assert_eq!(
+            route(&Method::Post, "/modules/daily%20rsync/fire?dry_run=true").unwrap(),
+            Route::Command(Command::Fire {
+                module: "daily rsync".to_string(),
+                dry_run: true
+            })
+        );
+        assert_eq!(
+            route(&Method::Get, "/history?module=rsync&limit=5").unwrap(),
+            Route::Command(Command::History {
+                module: Some("rsync".to_string()),
+                limit: Some(5)
+            })
+        );
+        assert_eq!(
+            route(&Method::Get, "/modules/").unwrap(),
+            Route::ModuleStatuses
+        );
+        assert_eq!(
+            route(&Method::Delete, "/modules/nas").unwrap(),
+            Route::Command(Command::Unload {
+                module: "nas".to_string()
+            })
+        );
+        assert_eq!(
+            route(&Method::Post, "/modules/nas/replace?file=%2Ftmp%2Fx.so").unwrap(),
+            Route::Command(Command::Replace {
+                module: "nas".to_string(),
+                file: None
+            })
+        );
+        let err = route(&Method::Post, "/modules?file=%2Ftmp%2Fx.so").unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        let err = route(&Method::Get, "/modules/rsync/fire").unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        let err = route(&Method::Get, "/history?limit=ten").unwrap_err();
+        assert_eq!(err.code, ErrorCode::BadRequest);
+        assert_eq!(percent_decode("a%2Fb%zz%"), "a/b%zz%");
+    }
+
+    fn get(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut ret = String::new();
+        stream.read_to_string(&mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn serves_commands() {
+        let tokens = Tokens(vec![
+            Token {
+                name: "prometheus".to_string(),
+                role: Role::Reader,
+                sha256: token_digest("scrape"),
+            },
+            Token {
+                name: "ops".to_string(),
+                role: Role::Operator,
+                sha256: token_digest("ops"),
+            },
+        ]);
+        let api = HttpApi::bind("127.0.0.1:0", tokens).unwrap();
+        let address = api.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let auth = "Authorization: Bearer scrape\r\n";
+            vec![
+                get(address, &format!("GET /modules HTTP/1.0\r\n{}\r\n", auth)),
+                get(address, &format!("GET /metrics HTTP/1.0\r\n{}\r\n", auth)),
+                get(address, "GET /metrics HTTP/1.0\r\n\r\n"),
+                get(
+                    address,
+                    &format!("POST /modules/rsync/stop HTTP/1.0\r\n{}\r\n", auth),
+                ),
+                get(
+                    address,
+                    &format!("GET /modules/nope HTTP/1.0\r\n{}\r\n", auth),
+                ),
+                get(
+                    address,
+                    "DELETE /modules/rsync HTTP/1.0\r\nAuthorization: Bearer ops\r\n\r\n",
+                ),
+            ]
+        });
+
+        let unloading = Cell::new(None);
+        let mut handle = |command: Command, ticket: u64| match command {
+            Command::List { .. } => Ok(Some(Reply::Modules {
+                modules: vec!["rsync".to_string()],
+            })),
+            Command::Status { module } if module == "rsync" => Ok(Some(Reply::Status {
+                module,
+                status: "Loaded".to_string(),
+                next_fire: None,
+                paused: false,
+            })),
+            Command::Status { module } => Err(ControlError::new(ErrorCode::NotFound, &module)),
+            Command::Metrics => Ok(Some(Reply::Metrics {
+                text: "bach_bus_depth 0\n".to_string(),
+            })),
+            Command::Unload { .. } => {
+                unloading.set(Some(ticket));
+                Ok(None)
+            }
+            _ => Ok(Some(Reply::Done)),
+        };
+        while !client.is_finished() {
+            api.serve(&mut handle).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+            if let Some(ticket) = unloading.take() {
+                api.finish(ticket, Ok(Reply::Done));
+            }
+        }
+        let answers = client.join().unwrap();
+        assert!(answers[0].starts_with("HTTP/1.0 200"));
+        assert!(answers[0].ends_with(
+            r#"{"modules":[{"reply":"status","module":"rsync","status":"Loaded","next_fire":null,"paused":false}]}"#
+        ));
+        assert!(answers[1].contains("text/plain; version=0.0.4"));
+        assert!(answers[1].ends_with("bach_bus_depth 0\n"));
+        assert!(answers[2].starts_with("HTTP/1.0 401"));
+        assert!(answers[3].starts_with("HTTP/1.0 403"));
+        assert!(answers[4].starts_with("HTTP/1.0 404"));
+        assert!(answers[5].starts_with("HTTP/1.0 200"));
+        assert!(answers[5].ends_with(r#"{"reply":"done"}"#));
+    }
+
+    #[test]
+    fn clients_only_read_without_tokens() {
+        let api = HttpApi::bind("127.0.0.1:0", Tokens::default()).unwrap();
+        let address = api.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            vec![
+                get(address, "GET /metrics HTTP/1.0\r\n\r\n"),
+                get(address, "POST /modules/rsync/fire HTTP/1.0\r\n\r\n"),
+                get(address, "POST /modules/rsync/stop HTTP/1.0\r\n\r\n"),
+            ]
+        });
+        let handle = |command: Command, _| match command {
+            Command::Metrics => Ok(Some(Reply::Metrics {
+                text: "bach_bus_depth 0\n".to_string(),
+            })),
+            _ => Ok(Some(Reply::Done)),
+        };
+        while !client.is_finished() {
+            api.serve(handle).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let answers = client.join().unwrap();
+        assert!(answers[0].starts_with("HTTP/1.0 200"));
+        assert!(answers[1].starts_with("HTTP/1.0 403"));
+        assert!(answers[2].starts_with("HTTP/1.0 403"));
+    }
+}
@@ -1,6 +1,17 @@
+pub mod access;
+pub mod checkconfig;
 pub mod daemon;
+pub mod history;
+pub mod http;
+pub mod metrics;
 pub mod modulemanager;
 pub mod modulemanagerconfig;
 #[cfg(feature = "static")]
 pub mod staticmodmatcher;
+pub mod scheduler;
+pub mod supervisor;
+pub mod systemd;
 pub mod tcpmessages;
+#[cfg(test)]
+mod testing;
+pub mod tls;
@@ -1,8 +1,21 @@
 #[cfg(all(feature = "modular", feature = "static"))]
 compile_error!("Static and modular features cannot be used at the same time !!!");
+use bachd::checkconfig;
 use bachd::daemon::*;
+use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    if let Some("check-config") = args.next().as_deref() {
+        // `bachd check-config [--load-modules] [PATH]`
+        let mut next = args.next();
+        let load_modules = next.as_deref() == Some("--load-modules");
+        if load_modules {
+            next = args.next();
+        }
+        let path = next.map_or_else(DaemonConfig::path, PathBuf::from);
+        std::process::exit(checkconfig::run(&path, load_modules));
+    }
     spawn()?;
     Ok(())
 }
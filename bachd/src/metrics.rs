@@ -0,0 +1,254 @@
+//! Figures of the daemon and of the runs, in the Prometheus text format.
+//!
+//! Counters start at zero with the daemon. The last success, duration and
+//! transferred bytes of each job are taken back from the history on start.
+use crate::history::{Outcome, RunRecord};
+use bach_module::stats;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct JobMetrics {
+    runs: BTreeMap<&'static str, u64>,
+    failures: u64,
+    bytes_total: u64,
+    last_success: Option<DateTime<Utc>>,
+    last_duration: Option<f64>,
+    last_bytes: Option<u64>,
+}
+
+/// State of the daemon when the metrics are rendered.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Gauges {
+    /// Loaded modules and whether they are running.
+    pub modules: Vec<(String, bool)>,
+    pub queued: usize,
+    pub paused: bool,
+    pub bus_depth: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    jobs: BTreeMap<String, JobMetrics>,
+}
+
+fn outcome_label(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Success => "success",
+        Outcome::Failed => "failed",
+        Outcome::Interrupted => "interrupted",
+    }
+}
+
+/// Sum of the transferred bytes the module reported during the run.
+fn run_bytes(record: &RunRecord) -> Option<u64> {
+    let mut ret = None;
+    for n in record.notifications.iter() {
+        if let Some((key, value)) = stats::parse_stat(&n.stage, &n.message) {
+            if key == stats::BYTES_TRANSFERRED {
+                ret = Some(ret.unwrap_or(0) + value);
+            }
+        }
+    }
+    ret
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Takes the last figures of each job from `runs`, oldest first,
+    /// without counting them.
+    pub fn restore<'a, I: IntoIterator<Item = &'a RunRecord>>(&mut self, runs: I) {
+        for r in runs {
+            self.update_last(r);
+        }
+    }
+
+    fn update_last(&mut self, record: &RunRecord) {
+        if record.dry_run {
+            return;
+        }
+        let job = self.jobs.entry(record.module.clone()).or_default();
+        job.last_duration =
+            Some((record.ended - record.started).num_milliseconds() as f64 / 1000.0);
+        if record.outcome == Outcome::Success {
+            job.last_success = Some(record.ended);
+            job.last_bytes = run_bytes(record);
+        }
+    }
+
+    /// Accounts for a run that just ended.
+    pub fn record(&mut self, record: &RunRecord) {
+        let job = self.jobs.entry(record.module.clone()).or_default();
+        *job.runs.entry(outcome_label(record.outcome)).or_insert(0) += 1;
+        if record.outcome != Outcome::Success {
+            job.failures += 1;
+        }
+        job.bytes_total += run_bytes(record).unwrap_or(0);
+        self.update_last(record);
+    }
+
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        };
+        let module = |m: &str| format!("module=\"{}\"", escape_label(m));
+        let per_job = |f: &dyn Fn(&JobMetrics) -> Option<String>| {
+            self.jobs
+                .iter()
+                .filter_map(|(m, j)| f(j).map(|v| (module(m), v)))
+                .collect::<Vec<_>>()
+        };
+
+        family(
+            "bach_module_up",
+            "gauge",
+            "Loaded modules, 1 when running.",
+            gauges
+                .modules
+                .iter()
+                .map(|(m, running)| (module(m), (*running as u8).to_string()))
+                .collect(),
+        );
+        family(
+            "bach_fire_queue_length",
+            "gauge",
+            "Fires waiting for a free slot.",
+            vec![(String::new(), gauges.queued.to_string())],
+        );
+        family(
+            "bach_scheduling_paused",
+            "gauge",
+            "1 while the scheduled fires are paused.",
+            vec![(String::new(), (gauges.paused as u8).to_string())],
+        );
+        family(
+            "bach_bus_depth",
+            "gauge",
+            "Packets waiting on the bus.",
+            vec![(String::new(), gauges.bus_depth.to_string())],
+        );
+        family(
+            "bach_job_runs_total",
+            "counter",
+            "Ended runs by outcome.",
+            self.jobs
+                .iter()
+                .flat_map(|(m, j)| {
+                    j.runs.iter().map(move |(o, n)| {
+                        (format!("{},outcome=\"{}\"", module(m), o), n.to_string())
+                    })
+                })
+                .collect(),
+        );
+        family(
+            "bach_job_failures_total",
+            "counter",
+            "Runs that failed or were interrupted.",
+            per_job(&|j| Some(j.failures.to_string())),
+        );
+        family(
+            "bach_job_transferred_bytes_total",
+            "counter",
+            "Bytes transferred by the runs.",
+            per_job(&|j| Some(j.bytes_total.to_string())),
+        );
+        family(
+            "bach_job_last_success_timestamp_seconds",
+            "gauge",
+            "End of the last successful run.",
+            per_job(&|j| j.last_success.map(|t| t.timestamp().to_string())),
+        );
+        family(
+            "bach_job_last_duration_seconds",
+            "gauge",
+            "Duration of the last run.",
+            per_job(&|j| j.last_duration.map(|d| d.to_string())),
+        );
+        family(
+            "bach_job_last_transferred_bytes",
+            "gauge",
+            "Bytes transferred by the last successful run.",
+            per_job(&|j| j.last_bytes.map(|b| b.to_string())),
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::{Level, RunNotification, Trigger};
+    use crate::metrics::*;
+    use chrono::TimeZone;
+
+    fn run(module: &str, outcome: Outcome, start: i64, secs: i64, bytes: &[u64]) -> RunRecord {
+        let started = Utc.timestamp_opt(start, 0).unwrap();
+        RunRecord {
+            run_id: 1,
+            module: module.to_string(),
+            trigger: Trigger::Schedule,
+            dry_run: false,
+            started,
+            ended: started + chrono::Duration::seconds(secs),
+            outcome,
+            error: None,
+            notifications: bytes
+                .iter()
+                .map(|b| RunNotification {
+                    at: started,
+                    level: Level::Good,
+                    stage: stats::STATS_STAGE.to_string(),
+                    message: format!("{}={}", stats::BYTES_TRANSFERRED, b),
+                })
+                .collect(),
+            dropped_notifications: 0,
+        }
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let mut metrics = Metrics::default();
+        metrics.restore(&[run("rsync", Outcome::Success, 1000, 60, &[10])]);
+        metrics.record(&run("rsync", Outcome::Failed, 2000, 5, &[]));
+        metrics.record(&run("rsync", Outcome::Success, 3000, 90, &[100, 23]));
+        metrics.record(&run("say \"hi\"", Outcome::Interrupted, 3000, 1, &[]));
+        let text = metrics.render(&Gauges {
+            modules: vec![("rsync".to_string(), true)],
+            queued: 2,
+            paused: false,
+            bus_depth: 7,
+        });
+        for line in [
+            "# TYPE bach_job_failures_total counter",
+            "bach_module_up{module=\"rsync\"} 1",
+            "bach_fire_queue_length 2",
+            "bach_bus_depth 7",
+            "bach_job_runs_total{module=\"rsync\",outcome=\"failed\"} 1",
+            "bach_job_runs_total{module=\"rsync\",outcome=\"success\"} 1",
+            "bach_job_failures_total{module=\"rsync\"} 1",
+            "bach_job_failures_total{module=\"say \\\"hi\\\"\"} 1",
+            "bach_job_transferred_bytes_total{module=\"rsync\"} 123",
+            "bach_job_last_success_timestamp_seconds{module=\"rsync\"} 3090",
+            "bach_job_last_duration_seconds{module=\"rsync\"} 90",
+            "bach_job_last_transferred_bytes{module=\"rsync\"} 123",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(!text.contains("bach_job_last_success_timestamp_seconds{module=\"say"));
+    }
+}
@@ -1,25 +1,113 @@
-use crate::modulemanagerconfig::{ModuleManagerConfig, Whence};
+use crate::history::{History, HistoryQuery, Level, Outcome, RunRecord, Trigger};
+use crate::metrics::{Gauges, Metrics};
+use crate::modulemanagerconfig::{ModuleDefinition, ModuleManagerConfig};
+use crate::scheduler::{Blackout, BlackoutPolicy, Clock, Misfire, Schedule, Scheduler};
 #[cfg(feature = "static")]
 use crate::staticmodmatcher;
-use bach_bus::bus::{Bus, BusConnection};
-use bach_bus::packet::{BackupCommand, Packet, PacketCore};
+use crate::supervisor::{Decision, Exit, RestartStrategy, Supervisor};
+use crate::systemd::Journal;
+use bach_bus::bus::{Bus, BusConnection, ConnectionId};
+use bach_bus::packet::{
+    core_2_string, BackupCommand, FireOptions, Notification, Packet, PacketCore,
+};
+#[cfg(feature = "modular")]
+use bach_module::plugin::open_plugin;
+use bach_module::state::StateStore;
 use bach_module::*;
+use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 #[cfg(feature = "modular")]
-use libloading::{Library, Symbol};
+use libloading::Library;
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 #[cfg(feature = "modular")]
 use std::ffi::OsStr;
-use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use chrono::prelude::*;
+
+/// Daemon store of the scheduled fire times.
+pub static SCHEDULER_STATE_SCOPE: &str = "scheduler";
 
 pub struct ModuleManagerContainer {
+    /// Identity of the module in the daemon: its commands, status, schedule
+    /// and history go by it. The `id` of its definition, or its name.
+    pub id: String,
     pub module: Box<dyn Module>,
+    /// Holds the code of `module`, declared after it to be dropped after it.
     #[cfg(feature = "modular")]
     pub lib: Library,
-    pub whence: Option<Whence>,
+    pub groups: Vec<String>,
+    /// Where the module was loaded from and its configuration file, which
+    /// identify it in the daemon configuration.
+    pub source: String,
+    pub config: Option<String>,
+    pub supervisor: RefCell<Supervisor>,
+}
+
+impl ModuleManagerContainer {
+    /// Hands a packet to the module. Commands name their target by id, they
+    /// are given the module's own name, or dropped when meant for another
+    /// module.
+    pub fn input(&self, packet: Packet) {
+        let packet = match packet {
+            Packet::Stop(core) => {
+                if !core_2_string(&core).eq(&self.id) {
+                    return;
+                }
+                Packet::new_stop(&self.module.name())
+            }
+            Packet::BackupCom(core) => match BackupCommand::from(core) {
+                BackupCommand::Fire(Some(target), options) => {
+                    if !target.eq(&self.id) {
+                        return;
+                    }
+                    Packet::BackupCom(PacketCore::from(BackupCommand::Fire(
+                        Some(self.module.name()),
+                        options,
+                    )))
+                }
+                BackupCommand::Reload(Some(target)) => {
+                    if !target.eq(&self.id) {
+                        return;
+                    }
+                    Packet::BackupCom(PacketCore::from(BackupCommand::Reload(Some(
+                        self.module.name(),
+                    ))))
+                }
+                _ => packet,
+            },
+            _ => packet,
+        };
+        self.module.input(packet);
+    }
+
+    /// Next packet of the module, its notifications and alive packets being
+    /// given its id as provider.
+    pub fn output(&self) -> Option<Packet> {
+        let packet = self.module.output()?;
+        let name = self.module.name();
+        if name.eq(&self.id) {
+            return Some(packet);
+        }
+        let renamed = |n: &Notification| n.provider.eq(&name);
+        Some(match packet {
+            Packet::NotifyGood(_) | Packet::NotifyWarn(_) | Packet::NotifyErr(_) => {
+                let n = Notification::from(packet);
+                if !renamed(&n) {
+                    return Some(packet);
+                }
+                match packet {
+                    Packet::NotifyGood(_) => Packet::new_ng(&n.message, &self.id, &n.stage),
+                    Packet::NotifyWarn(_) => Packet::new_nw(&n.message, &self.id, &n.stage),
+                    _ => Packet::new_ne(&n.message, &self.id, &n.stage),
+                }
+            }
+            Packet::Alive(_) => Packet::new_alive(&self.id),
+            _ => packet,
+        })
+    }
 }
 
 lazy_static! {
@@ -27,16 +115,6 @@ lazy_static! {
         Mutex::new(RefCell::new(Vec::new()));
 }
 
-#[cfg(feature = "modular")]
-macro_rules! unwind_moderror {
-    ($fn: expr) => {{
-        match $fn {
-            Ok(a) => a,
-            Err(e) => return Err(ModError::new(&e.to_string())),
-        }
-    }};
-}
-
 pub struct LastTimeSeenAlive(RefCell<Instant>);
 
 impl LastTimeSeenAlive {
@@ -47,11 +125,24 @@ impl LastTimeSeenAlive {
 
 struct ModSpwned {
     pub handle: thread::JoinHandle<ModResult<()>>,
-    pub modules_index: usize,
     pub name: String,
     pub last_time_seen_alive: LastTimeSeenAlive,
-    pub last_cycle: RefCell<Instant>,
-    pub whence: Option<Whence>,
+    /// Asked to stop, it is not restarted.
+    pub stop_requested: bool,
+    /// Stopped for missing its alive packets, its end is a failure.
+    pub unresponsive: bool,
+}
+
+impl ModSpwned {
+    fn new(handle: thread::JoinHandle<ModResult<()>>, name: String) -> Self {
+        ModSpwned {
+            handle,
+            name,
+            last_time_seen_alive: LastTimeSeenAlive(RefCell::new(Instant::now())),
+            stop_requested: false,
+            unresponsive: false,
+        }
+    }
 }
 
 pub enum ModSpawnState {
@@ -60,11 +151,33 @@ pub enum ModSpawnState {
     Error(String),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FireRequestState {
+    Dispatched,
+    Queued(usize),
+    AlreadyQueued(usize),
+    NotFound,
+}
+
 pub struct ModuleManager {
     spwned: RefCell<Vec<ModSpwned>>,
     respawn_duration: RefCell<Duration>,
-    output: RefCell<Option<Packet>>,
+    output: RefCell<VecDeque<Packet>>,
     modules: Vec<ModuleManagerContainer>,
+    max_running: Option<usize>,
+    group_limits: HashMap<String, usize>,
+    fire_queue: RefCell<VecDeque<(String, FireOptions, Trigger)>>,
+    scheduler: RefCell<Scheduler>,
+    blackouts: Vec<Blackout>,
+    deferred: RefCell<Vec<(String, DateTime<Utc>)>>,
+    paused: RefCell<bool>,
+    history: RefCell<Option<History>>,
+    metrics: RefCell<Metrics>,
+    /// Modules waiting for their restart time.
+    restarts: RefCell<Vec<(String, Instant)>>,
+    journal: Option<Journal>,
+    /// Bus connection of each module, by id.
+    bus_connections: RefCell<HashMap<String, ConnectionId>>,
 }
 
 impl ModuleManager {
@@ -72,72 +185,371 @@ impl ModuleManager {
         ModuleManager {
             spwned: RefCell::new(Vec::new()),
             respawn_duration: RefCell::new(respawn_duration),
-            output: RefCell::new(None),
+            output: RefCell::new(VecDeque::new()),
             modules: Vec::new(),
+            max_running: None,
+            group_limits: HashMap::new(),
+            fire_queue: RefCell::new(VecDeque::new()),
+            scheduler: RefCell::new(Scheduler::default()),
+            blackouts: Vec::new(),
+            deferred: RefCell::new(Vec::new()),
+            paused: RefCell::new(false),
+            history: RefCell::new(None),
+            metrics: RefCell::new(Metrics::default()),
+            restarts: RefCell::new(Vec::new()),
+            journal: None,
+            bus_connections: RefCell::new(HashMap::new()),
         }
     }
 
     pub fn from_config(conf: ModuleManagerConfig) -> ModResult<Self> {
         let mut ret = ModuleManager::new(Duration::from_secs(conf.respawn_duration));
+        match StateStore::open_daemon(SCHEDULER_STATE_SCOPE) {
+            Ok(store) => ret.set_state_store(store),
+            Err(e) => ret.output.get_mut().push_back(Packet::new_nw(
+                &format!(
+                    "Missed runs will not be caught up, no scheduler state : {}",
+                    e
+                ),
+                "Module Manager",
+                "Schedule",
+            )),
+        }
+        let history = conf.history.unwrap_or_default();
+        match History::open(&history.path(), history.retention()) {
+            Ok(h) => ret.set_history(h),
+            Err(e) => ret.output.get_mut().push_back(Packet::new_nw(
+                &format!("Runs will not be recorded, no history : {}", e),
+                "Module Manager",
+                "History",
+            )),
+        }
+        ret.set_max_running(conf.max_running);
+        for g in conf.groups {
+            ret.set_group_limit(&g.name, g.max_running);
+        }
         for m in conf.modules {
-            #[cfg(feature = "modular")]
-            ret.load(m.file, m.whence, &m.config)?;
-
-            #[cfg(feature = "static")]
-            ret.load(m.name, m.whence, &m.config)?;
+            ret.load_definition(m)?;
         }
         Ok(ret)
     }
 
+    /// Loads the module of `definition` with its schedule, restart strategy
+    /// and groups. Returns its id.
+    fn load_definition(&mut self, definition: ModuleDefinition) -> ModResult<String> {
+        let schedule = definition.get_schedule()?;
+        let restart = definition.restart_strategy()?;
+        let source = definition.source().to_string();
+        let groups = definition.groups.into_iter().map(|g| g.0).collect();
+        self.load(
+            source,
+            definition.id,
+            schedule,
+            restart,
+            groups,
+            &definition.config,
+        )
+    }
+
+    /// Sets the daemon-wide cap on concurrently running jobs. `None` means unlimited.
+    pub fn set_max_running(&mut self, max_running: Option<usize>) {
+        self.max_running = max_running;
+    }
+
+    /// Sets how many members of `group` may run at once. Groups referenced by
+    /// modules but never declared behave as plain mutexes (limit of 1).
+    pub fn set_group_limit(&mut self, group: &str, max_running: usize) {
+        self.group_limits.insert(group.to_string(), max_running);
+    }
+
+    /// Sets the time source of the scheduled fires.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.scheduler.get_mut().set_clock(clock);
+    }
+
+    /// Keeps the last scheduled fire of each module in `store`, for the
+    /// missed runs to be caught up by [`ModuleManager::spawn_all`].
+    pub fn set_state_store(&mut self, store: StateStore) {
+        self.scheduler.get_mut().set_store(store);
+    }
+
+    /// Records every run in `history`.
+    pub fn set_history(&mut self, history: History) {
+        let mut runs = history.query(&HistoryQuery::default());
+        runs.reverse();
+        self.metrics.get_mut().restore(runs);
+        self.history.replace(Some(history));
+    }
+
+    /// Logs every notification to `journal` too, with the run it belongs to.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    /// Metrics in the Prometheus text format, `bus_depth` being the packets
+    /// waiting on the bus.
+    pub fn render_metrics(&self, bus_depth: usize) -> String {
+        let spawned = self.get_spawned_list().unwrap_or_default();
+        let gauges = Gauges {
+            modules: self
+                .modules
+                .iter()
+                .map(|m| {
+                    let running = spawned.contains(&m.id);
+                    (m.id.to_string(), running)
+                })
+                .collect(),
+            queued: self.fire_queue.borrow().len(),
+            paused: self.is_paused(),
+            bus_depth,
+        };
+        self.metrics.borrow().render(&gauges)
+    }
+
+    /// Recorded runs matching `query`, newest first.
+    pub fn get_history(&self, query: &HistoryQuery) -> Vec<RunRecord> {
+        match self.history.borrow().as_ref() {
+            Some(h) => h.query(query).into_iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Hands a bus packet to the history, closing the run it ends if any.
+    pub fn record_notification(&self, packet: Packet) {
+        if let Some(journal) = &self.journal {
+            let run_id = match (self.history.borrow().as_ref(), Level::of(&packet)) {
+                (Some(h), Some(_)) => h.run_id(&Notification::from(packet).provider),
+                _ => None,
+            };
+            // The journal is a copy of the bus, losing an entry is harmless.
+            let _ = journal.log(packet, run_id);
+        }
+        let now = self.scheduler.borrow().now();
+        let res = match self.history.borrow_mut().as_mut() {
+            Some(h) => h.notify(packet, now),
+            None => Ok(None),
+        };
+        self.account_run(res);
+    }
+
+    fn finish_run(&self, mod_name: &str, reason: &str) {
+        let now = self.scheduler.borrow().now();
+        let res = match self.history.borrow_mut().as_mut() {
+            Some(h) => h.finish(
+                mod_name,
+                Outcome::Interrupted,
+                Some(reason.to_string()),
+                now,
+            ),
+            None => Ok(None),
+        };
+        self.account_run(res);
+    }
+
+    fn account_run(&self, res: ModResult<Option<RunRecord>>) {
+        if let Ok(Some(record)) = &res {
+            self.metrics.borrow_mut().record(record);
+        }
+        self.report_history_error(res);
+    }
+
+    fn report_history_error<T>(&self, res: ModResult<T>) {
+        if let Err(e) = res {
+            self.output.borrow_mut().push_back(Packet::new_ne(
+                &format!("Unable to record the run : {}", e),
+                "Module Manager",
+                "History",
+            ));
+        }
+    }
+
+    pub fn set_blackouts(&mut self, blackouts: Vec<Blackout>) {
+        self.blackouts = blackouts;
+    }
+
+    /// Applies a new configuration to the loaded modules: their schedules,
+    /// groups and restart strategies, the running limits and the respawn duration. Modules
+    /// added to or missing from `conf` are reported and left for the load
+    /// and unload commands. Nothing changes when a schedule or a restart
+    /// strategy of `conf` is invalid.
+    pub fn reconfigure(&mut self, conf: ModuleManagerConfig) -> ModResult<()> {
+        let mut definitions = Vec::new();
+        for m in conf.modules {
+            definitions.push((m.get_schedule()?, m.restart_strategy()?, m));
+        }
+        self.respawn_duration
+            .replace(Duration::from_secs(conf.respawn_duration));
+        self.max_running = conf.max_running;
+        self.group_limits = conf
+            .groups
+            .into_iter()
+            .map(|g| (g.name, g.max_running))
+            .collect();
+
+        let mut notices = Vec::new();
+        let scheduler = self.scheduler.get_mut();
+        for container in self.modules.iter_mut() {
+            let name = container.id.to_string();
+            // Definitions without an id are told apart by what they load.
+            let found = definitions.iter().position(|(_, _, m)| match &m.id {
+                Some(id) => id.eq(&name),
+                None => m.source() == container.source && m.config == container.config,
+            });
+            match found.map(|i| definitions.remove(i)) {
+                Some((schedule, restart, definition)) => {
+                    match schedule {
+                        Some((s, misfire)) => scheduler.add(&name, s, misfire),
+                        None => scheduler.remove(&name),
+                    }
+                    container.groups = definition.groups.into_iter().map(|g| g.0).collect();
+                    container.supervisor.get_mut().set_strategy(restart);
+                }
+                None => notices.push(format!(
+                    "{} is no longer configured, it stays loaded until unloaded",
+                    name
+                )),
+            }
+        }
+        for (_, _, m) in definitions {
+            notices.push(format!("{} is not loaded, load it to start it", m.source()));
+        }
+        let output = self.output.get_mut();
+        for n in notices {
+            output.push_back(Packet::new_nw(&n, "Module Manager", "Reload"));
+        }
+        output.push_back(Packet::new_ng(
+            "Configuration reloaded",
+            "Module Manager",
+            "Reload",
+        ));
+        Ok(())
+    }
+
+    /// Holds every scheduled fire until [`ModuleManager::resume`], the fires
+    /// coming due meanwhile are skipped. Manual fires still go through.
+    pub fn pause(&self) {
+        self.paused.replace(true);
+        self.notify("Scheduling paused");
+    }
+
+    pub fn resume(&self) {
+        self.paused.replace(false);
+        self.notify("Scheduling resumed");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Next time the named module fires on its own, if it is scheduled.
+    pub fn next_fire(&self, mod_name: &str) -> Option<DateTime<Utc>> {
+        self.scheduler.borrow().next_fire(mod_name)
+    }
+
+    /// `id`, or the name of `module` when there is none, if no loaded
+    /// module has it yet.
+    fn unique_id(&self, id: Option<String>, module: &dyn Module) -> ModResult<String> {
+        let id = id.unwrap_or_else(|| module.name());
+        if self.find_module(&id).is_some() {
+            return Err(ModError::new(&format!(
+                "Two modules are identified as {}, give them distinct id attributes",
+                id
+            )));
+        }
+        Ok(id)
+    }
+
+    /// Adds a module created by the tests, its code is in the daemon.
+    #[cfg(test)]
+    pub(crate) fn insert(
+        &mut self,
+        id: Option<String>,
+        module: Box<dyn Module>,
+    ) -> ModResult<String> {
+        let id = self.unique_id(id, module.as_ref())?;
+        module.set_instance_id(&id);
+        self.modules.push(ModuleManagerContainer {
+            id: id.to_string(),
+            module,
+            #[cfg(feature = "modular")]
+            lib: libloading::os::unix::Library::this().into(),
+            groups: Vec::new(),
+            source: String::new(),
+            config: None,
+            supervisor: RefCell::new(Supervisor::new(RestartStrategy::default())),
+        });
+        Ok(id)
+    }
+
     #[cfg(feature = "modular")]
     pub fn load<P: AsRef<OsStr> + std::fmt::Debug + Clone>(
         &mut self,
         filename: P,
-        cyclewhence: Option<Whence>,
+        id: Option<String>,
+        schedule: Option<(Schedule, Misfire)>,
+        restart: RestartStrategy,
+        groups: Vec<String>,
         config_filename: &Option<String>,
-    ) -> ModResult<usize> {
-        let size: usize;
-        unsafe {
-            type ModGen = unsafe fn(&Option<String>) -> Box<dyn Module>;
-            let lib = unwind_moderror!(Library::new(filename.as_ref()));
-            let cons: Symbol<ModGen> = unwind_moderror!(lib.get(b"bach_create_module"));
-            let module: Box<dyn Module> = cons(config_filename);
-            self.modules.push(ModuleManagerContainer {
-                module,
-                lib,
-                whence: cyclewhence,
-            });
-            size = self.modules.len();
+    ) -> ModResult<String> {
+        let (lib, module) = open_plugin(&filename, config_filename)?;
+        let id = match self.unique_id(id, module.as_ref()) {
+            Ok(id) => id,
+            Err(e) => {
+                // Its code is in the library, the module goes first.
+                drop(module);
+                return Err(e);
+            }
+        };
+        module.set_instance_id(&id);
+        if let Some((s, misfire)) = schedule {
+            self.scheduler.get_mut().add(&id, s, misfire);
         }
-        Ok(size)
+        self.modules.push(ModuleManagerContainer {
+            id: id.to_string(),
+            module,
+            lib,
+            groups,
+            source: filename.as_ref().to_string_lossy().to_string(),
+            config: config_filename.clone(),
+            supervisor: RefCell::new(Supervisor::new(restart)),
+        });
+        Ok(id)
     }
 
     #[cfg(feature = "static")]
     pub fn load(
         &mut self,
         name: String,
-        cyclewhence: Option<Whence>,
+        id: Option<String>,
+        schedule: Option<(Schedule, Misfire)>,
+        restart: RestartStrategy,
+        groups: Vec<String>,
         config_filename: &Option<String>,
-    ) -> ModResult<usize> {
-        let size: usize = 0;
+    ) -> ModResult<String> {
+        let module = staticmodmatcher::fetch(&name, config_filename)?;
+        let id = self.unique_id(id, module.as_ref())?;
+        module.set_instance_id(&id);
+        if let Some((s, misfire)) = schedule {
+            self.scheduler.get_mut().add(&id, s, misfire);
+        }
         self.modules.push(ModuleManagerContainer {
-            module: staticmodmatcher::fetch(&name, config_filename)?,
-            whence: cyclewhence,
+            id: id.to_string(),
+            module,
+            groups,
+            source: name,
+            config: config_filename.clone(),
+            supervisor: RefCell::new(Supervisor::new(restart)),
         });
-        Ok(size)
+        Ok(id)
     }
 
     pub fn spawn_all(&self) -> ModResult<()> {
-        for (dex, m) in self.modules.iter().enumerate() {
+        for c in self.scheduler.borrow_mut().catch_up() {
+            self.notify(&c.to_string());
+        }
+        for m in self.modules.iter() {
             m.module.init()?;
-            let spwn = ModSpwned {
-                handle: m.module.spawn(),
-                modules_index: dex,
-                name: m.module.name().to_string(),
-                last_time_seen_alive: LastTimeSeenAlive(RefCell::new(Instant::now())),
-                last_cycle: RefCell::new(Instant::now()),
-                whence: m.whence.clone(),
-            };
+            let spwn = ModSpwned::new(m.module.spawn(), m.id.to_string());
             self.spwned.borrow_mut().push(spwn);
         }
         Ok(())
@@ -146,7 +558,7 @@ impl ModuleManager {
     pub fn get_module_list(&self) -> ModResult<Vec<String>> {
         let mut ret: Vec<String> = Vec::new();
         for m in &self.modules {
-            ret.push(m.module.name().to_string());
+            ret.push(m.id.to_string());
         }
         Ok(ret)
     }
@@ -159,15 +571,59 @@ impl ModuleManager {
         Ok(ret)
     }
 
+    pub fn get_queued_list(&self) -> ModResult<Vec<String>> {
+        Ok(self
+            .fire_queue
+            .borrow()
+            .iter()
+            .map(|q| q.0.to_string())
+            .collect())
+    }
+
+    /// One line summary of the running and queued fires, for the service
+    /// manager.
+    pub fn service_status(&self) -> String {
+        let running: Vec<String> = self
+            .modules
+            .iter()
+            .filter(|m| Self::is_busy(m))
+            .map(|m| m.id.to_string())
+            .collect();
+        let mut ret = if running.is_empty() {
+            "Idle".to_string()
+        } else {
+            format!("Running {}", running.join(", "))
+        };
+        let queued = self.fire_queue.borrow().len();
+        if queued > 0 {
+            ret.push_str(&format!(", {} queued", queued));
+        }
+        if self.is_paused() {
+            ret.push_str(", scheduling paused");
+        }
+        ret
+    }
+
     pub fn get_status(&self, mod_name: &str) -> String {
+        if let Some(pos) = self.queue_position(mod_name) {
+            return format!("Queued (position {})", pos);
+        }
+
         for m in self.spwned.borrow().iter() {
             if m.name.eq(mod_name) {
                 return "Running".to_string();
             }
         }
 
+        if self.restarts.borrow().iter().any(|(n, _)| n.eq(mod_name)) {
+            return "Restarting".to_string();
+        }
+
         for m in &self.modules {
-            if m.module.name().eq(mod_name) {
+            if m.id.eq(mod_name) {
+                if m.supervisor.borrow().is_failed() {
+                    return "Failed".to_string();
+                }
                 return "Loaded".to_string();
             }
         }
@@ -175,19 +631,46 @@ impl ModuleManager {
         "Not found".to_string()
     }
 
+    /// Stops the scheduled fires and drops the queued ones, before the
+    /// modules are told to terminate.
+    pub fn begin_shutdown(&self) {
+        self.paused.replace(true);
+        for (name, _, _) in self.fire_queue.replace(VecDeque::new()) {
+            self.notify(&format!("Queued fire of {} dropped, shutting down", name));
+        }
+    }
+
+    /// Modules whose thread has not returned.
+    pub fn still_running(&self) -> Vec<String> {
+        self.spwned
+            .borrow()
+            .iter()
+            .filter(|s| !s.handle.is_finished())
+            .map(|s| s.name.to_string())
+            .collect()
+    }
+
+    /// Joins and destroys the modules whose thread has returned. The others
+    /// are reported and left behind, joining them could block forever.
     pub fn join_all(&self) -> Vec<(String, ModResult<()>)> {
         let mut res: Vec<(String, ModResult<()>)> = Vec::new();
         let mut spwned = self.spwned.replace(Vec::new());
+        for m in spwned.iter() {
+            self.finish_run(&m.name, "Daemon stopped");
+        }
 
         while !spwned.is_empty() {
             let item = spwned.pop();
             match item {
+                Some(i) if !i.handle.is_finished() => {
+                    res.push((i.name, Err(ModError::new("Still running, left behind"))));
+                }
                 Some(i) => match i.handle.join() {
                     Ok(r) => {
                         let n = i.name.to_string();
                         res.push((i.name, r));
                         for mo in &self.modules {
-                            if mo.module.name().eq(&n.to_string()) {
+                            if mo.id.eq(&n) {
                                 match mo.module.destroy() {
                                     Ok(()) => (),
                                     Err(e) => {
@@ -210,23 +693,20 @@ impl ModuleManager {
         res
     }
 
+    /// Starts the thread of a module that is not running, from an idle state.
     pub fn spawn(&self, mod_name: &str) -> ModSpawnState {
-        for (dex, m) in self.modules.iter().enumerate() {
-            if m.module.name().eq(mod_name) {
+        for m in self.modules.iter() {
+            if m.id.eq(mod_name) {
                 match m.module.init() {
                     Ok(()) => (),
                     Err(e) => {
                         return ModSpawnState::Error(e.to_string());
                     }
                 }
-                let spwn = ModSpwned {
-                    handle: m.module.spawn(),
-                    modules_index: dex,
-                    name: m.module.name(),
-                    last_time_seen_alive: LastTimeSeenAlive(RefCell::new(Instant::now())),
-                    last_cycle: RefCell::new(Instant::now()),
-                    whence: m.whence.clone(),
-                };
+                // Left terminated by the previous thread, the new one would
+                // return right away.
+                m.module.run_status().store(RUN_IDLE, Ordering::SeqCst);
+                let spwn = ModSpwned::new(m.module.spawn(), m.id.to_string());
                 self.spwned.borrow_mut().push(spwn);
                 return ModSpawnState::Spawned;
             }
@@ -234,68 +714,550 @@ impl ModuleManager {
         ModSpawnState::NotFound
     }
 
-    pub fn respawn(&self, mod_name: &str) -> Option<ModSpawnState> {
-        let mut dex = 0;
-        if let Ok(mut spawned) = self.spwned.try_borrow_mut() {
-            for m in spawned.iter() {
-                if m.name.eq(&mod_name) {
-                    break;
-                }
-                dex += 1;
+    /// Tells the named module to stop, it is not restarted afterwards.
+    /// Returns false when it is not running.
+    pub fn request_stop(&self, mod_name: &str) -> bool {
+        let mut found = false;
+        for m in self.spwned.borrow_mut().iter_mut() {
+            if m.name.eq(mod_name) {
+                m.stop_requested = true;
+                found = true;
             }
-            let torespawn = spawned.remove(dex);
-            match torespawn.handle.join() {
-                Ok(res) => match res {
-                    Ok(()) => {
-                        self.output.replace(Some(Packet::new_nw(
-                            &format!("Module {} stopped", mod_name),
-                            "Module Manager",
-                            "Respawn",
-                        )));
-                    }
-                    Err(e) => {
-                        self.output.replace(Some(Packet::new_ne(
-                            &format!("Module {} exited with error {}", mod_name, e.to_string()),
-                            "Module Manager",
-                            "Respawn",
-                        )));
-                    }
-                },
-                Err(_) => {
-                    self.output.replace(Some(Packet::new_ne(
-                        &format!("Module {} panicked", mod_name),
-                        "Module Manager",
-                        "Respawn",
-                    )));
+        }
+        if found {
+            self.output
+                .borrow_mut()
+                .push_back(Packet::new_stop(mod_name));
+        }
+        found
+    }
+
+    /// Loads and starts the module of `definition` in the running daemon.
+    /// Returns its id, for [`connect_module`] to put it on the bus.
+    pub fn add_module(&mut self, definition: ModuleDefinition) -> ModResult<String> {
+        let id = self.load_definition(definition)?;
+        if let ModSpawnState::Error(e) = self.spawn(&id) {
+            self.modules.retain(|m| !m.id.eq(&id));
+            self.forget(&id);
+            return Err(ModError::new(&format!("Unable to start {} : {}", id, e)));
+        }
+        self.output.get_mut().push_back(Packet::new_ng(
+            &format!("Module {} loaded", id),
+            "Module Manager",
+            "Load",
+        ));
+        Ok(id)
+    }
+
+    /// Asks a module to stop before it is unloaded or replaced, it is not
+    /// restarted. See [`ModuleManager::has_stopped`].
+    pub fn request_unload(&mut self, mod_name: &str) -> ModResult<()> {
+        let index = match self.modules.iter().position(|m| m.id.eq(mod_name)) {
+            Some(i) => i,
+            None => return Err(ModError::new(&format!("Module {} not found", mod_name))),
+        };
+        if let Some(s) = self
+            .spwned
+            .get_mut()
+            .iter_mut()
+            .find(|s| s.name.eq(mod_name))
+        {
+            s.stop_requested = true;
+            self.modules[index].input(Packet::new_stop(mod_name));
+        }
+        // A restart would start it again while it is waited for.
+        self.restarts.get_mut().retain(|r| !r.0.eq(mod_name));
+        Ok(())
+    }
+
+    /// Gives up on unloading a module that did not stop in time, it is
+    /// supervised again and restarted as its strategy says once it stops.
+    pub fn cancel_unload(&mut self, mod_name: &str) {
+        for s in self.spwned.get_mut().iter_mut() {
+            if s.name.eq(mod_name) {
+                s.stop_requested = false;
+            }
+        }
+    }
+
+    /// Whether the thread of a module, if any, has returned.
+    pub fn has_stopped(&self, mod_name: &str) -> bool {
+        self.spwned
+            .borrow()
+            .iter()
+            .filter(|s| s.name.eq(mod_name))
+            .all(|s| s.handle.is_finished())
+    }
+
+    /// Joins and destroys a stopped module, then drops it and closes its
+    /// shared object. Its schedule and pending fires go with it. Fails,
+    /// leaving it loaded, when its thread is still running.
+    pub fn unload(&mut self, mod_name: &str) -> ModResult<()> {
+        drop(self.take_module(mod_name)?);
+        self.forget(mod_name);
+        self.output.get_mut().push_back(Packet::new_ng(
+            &format!("Module {} unloaded", mod_name),
+            "Module Manager",
+            "Load",
+        ));
+        Ok(())
+    }
+
+    /// Unloads a stopped module and loads `file`, or its shared object
+    /// again, in its place. The new module keeps its id, configuration,
+    /// schedule, groups and restart strategy. It stays unloaded when the
+    /// new one fails.
+    pub fn replace(&mut self, mod_name: &str, file: Option<String>) -> ModResult<()> {
+        let old = self.take_module(mod_name)?;
+        let source = file.unwrap_or_else(|| old.source.to_string());
+        let config = old.config.clone();
+        let groups = old.groups.clone();
+        let strategy = old.supervisor.borrow().strategy().clone();
+        // Closed before the new one is opened, the same path would otherwise
+        // give back the old code.
+        drop(old);
+        let res = self
+            .load(
+                source,
+                Some(mod_name.to_string()),
+                None,
+                strategy,
+                groups,
+                &config,
+            )
+            .and_then(|id| match self.spawn(&id) {
+                ModSpawnState::Error(e) => {
+                    self.modules.retain(|m| !m.id.eq(&id));
+                    Err(ModError::new(&e))
                 }
+                _ => Ok(()),
+            });
+        if let Err(e) = res {
+            self.forget(mod_name);
+            return Err(ModError::new(&format!(
+                "Unable to replace {}, it is unloaded : {}",
+                mod_name, e
+            )));
+        }
+        self.output.get_mut().push_back(Packet::new_ng(
+            &format!("Module {} replaced", mod_name),
+            "Module Manager",
+            "Load",
+        ));
+        Ok(())
+    }
+
+    /// The bus connection of a module that is no longer loaded, for the
+    /// caller to disconnect it.
+    pub fn take_connection(&mut self, mod_name: &str) -> Option<ConnectionId> {
+        if self.find_module(mod_name).is_some() {
+            return None;
+        }
+        self.bus_connections.get_mut().remove(mod_name)
+    }
+
+    /// Joins the thread of a module, then takes it out of the loaded ones
+    /// once destroyed.
+    fn take_module(&mut self, mod_name: &str) -> ModResult<ModuleManagerContainer> {
+        let index = match self.modules.iter().position(|m| m.id.eq(mod_name)) {
+            Some(i) => i,
+            None => return Err(ModError::new(&format!("Module {} not found", mod_name))),
+        };
+        if !self.has_stopped(mod_name) {
+            return Err(ModError::new(&format!(
+                "Module {} still running, not unloaded",
+                mod_name
+            )));
+        }
+        if let Some(pos) = self
+            .spwned
+            .get_mut()
+            .iter()
+            .position(|s| s.name.eq(mod_name))
+        {
+            let spawned = self.spwned.get_mut().remove(pos);
+            self.finish_run(mod_name, "Module unloaded");
+            if let Ok(Err(e)) = spawned.handle.join() {
+                self.supervisor_notify(Packet::new_ne(
+                    &format!("Module {} exited, {}", mod_name, e),
+                    "Module Manager",
+                    "Load",
+                ));
             }
-            Some(self.spawn(mod_name))
-        } else {
-            None
         }
+        let container = self.modules.remove(index);
+        if let Err(e) = container.module.destroy() {
+            self.supervisor_notify(Packet::new_ne(
+                &format!("Unable to destroy {} : {}", mod_name, e),
+                "Module Manager",
+                "Load",
+            ));
+        }
+        // Its last words would be lost with it.
+        while let Some(p) = container.output() {
+            self.output.get_mut().push_back(p);
+        }
+        Ok(container)
+    }
+
+    /// Drops the schedule, restart and pending fires of an unloaded module.
+    fn forget(&mut self, mod_name: &str) {
+        self.scheduler.get_mut().remove(mod_name);
+        self.fire_queue.get_mut().retain(|q| !q.0.eq(mod_name));
+        self.deferred.get_mut().retain(|d| !d.0.eq(mod_name));
+        self.restarts.get_mut().retain(|r| !r.0.eq(mod_name));
+    }
+
+    fn supervisor_notify(&self, packet: Packet) {
+        self.output.borrow_mut().push_back(packet);
+    }
+
+    /// Watches the module threads, called from the daemon loop. The ended
+    /// ones are restarted as their strategy says, the ones not seen alive
+    /// for the respawn duration are stopped and handled as failed.
+    pub fn supervise(&self) {
+        let now = Instant::now();
+        let timeout = *self.respawn_duration.borrow();
+        let (ended, running): (Vec<ModSpwned>, Vec<ModSpwned>) = self
+            .spwned
+            .replace(Vec::new())
+            .into_iter()
+            .partition(|s| s.handle.is_finished());
+        self.spwned.replace(running);
+        for s in self.spwned.borrow_mut().iter_mut() {
+            if !s.unresponsive && s.last_time_seen_alive.0.borrow().elapsed() > timeout {
+                s.unresponsive = true;
+                self.supervisor_notify(Packet::new_ne(
+                    &format!(
+                        "Module {} not seen alive for {} seconds, stopping it",
+                        s.name,
+                        timeout.as_secs()
+                    ),
+                    "Module Manager",
+                    "Supervisor",
+                ));
+                self.supervisor_notify(Packet::new_stop(&s.name));
+            }
+        }
+        for s in ended {
+            self.handle_exit(s, now);
+        }
+
+        let due: Vec<String> = self
+            .restarts
+            .borrow()
+            .iter()
+            .filter(|(_, at)| *at <= now)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        self.restarts.borrow_mut().retain(|(_, at)| *at > now);
+        for name in due {
+            match self.spawn(&name) {
+                ModSpawnState::Spawned => self.supervisor_notify(Packet::new_nw(
+                    &format!("Module {} restarted", name),
+                    "Module Manager",
+                    "Supervisor",
+                )),
+                ModSpawnState::Error(e) => self.supervise_exit(&name, &Exit::Failed(e), false, now),
+                ModSpawnState::NotFound => (),
+            }
+        }
+    }
+
+    fn handle_exit(&self, spawned: ModSpwned, now: Instant) {
+        let status = match self.find_module(&spawned.name) {
+            Some(c) => c.module.run_status().load(Ordering::SeqCst),
+            None => RUN_TERM,
+        };
+        let name = spawned.name;
+        // A failed run ends the thread once its error is out, the history
+        // closes the run on it. Errors and panics leave the run open.
+        let exit = match spawned.handle.join() {
+            Ok(Err(e)) => {
+                self.finish_run(&name, "Module exited");
+                Exit::Failed(e.to_string())
+            }
+            Err(_) => {
+                self.finish_run(&name, "Module panicked");
+                Exit::Failed("panicked".to_string())
+            }
+            Ok(Ok(())) if spawned.unresponsive => Exit::Failed("not seen alive".to_string()),
+            Ok(Ok(())) if status == RUN_EARLY_TERM => Exit::Failed("run failed".to_string()),
+            Ok(Ok(())) => Exit::Stopped,
+        };
+        match &exit {
+            Exit::Stopped => self.supervisor_notify(Packet::new_nw(
+                &format!("Module {} stopped", name),
+                "Module Manager",
+                "Supervisor",
+            )),
+            Exit::Failed(e) => self.supervisor_notify(Packet::new_ne(
+                &format!("Module {} exited, {}", name, e),
+                "Module Manager",
+                "Supervisor",
+            )),
+        }
+        self.supervise_exit(&name, &exit, spawned.stop_requested, now);
+    }
+
+    fn supervise_exit(&self, name: &str, exit: &Exit, requested: bool, now: Instant) {
+        let container = match self.find_module(name) {
+            Some(c) => c,
+            None => return,
+        };
+        let mut supervisor = container.supervisor.borrow_mut();
+        match supervisor.exited(exit, requested, now) {
+            Decision::Restart { at } => {
+                self.supervisor_notify(Packet::new_nw(
+                    &format!(
+                        "Module {} restarting in {} seconds",
+                        name,
+                        (at - now).as_secs()
+                    ),
+                    "Module Manager",
+                    "Supervisor",
+                ));
+                self.restarts.borrow_mut().push((name.to_string(), at));
+            }
+            Decision::Leave => (),
+            Decision::GiveUp => {
+                let strategy = supervisor.strategy();
+                self.supervisor_notify(Packet::new_ne(
+                    &format!(
+                        "Module {} restarted {} times within {} seconds, marked failed",
+                        name,
+                        strategy.max_restarts,
+                        strategy.window.as_secs()
+                    ),
+                    "Module Manager",
+                    "Supervisor",
+                ));
+            }
+        }
+    }
+
+    fn notify(&self, message: &str) {
+        self.output
+            .borrow_mut()
+            .push_back(Packet::new_nw(message, "Module Manager", "Schedule"));
+    }
+
+    /// The blackout holding the fires of `container` at `now`, and its end.
+    fn blackout_for(
+        &self,
+        container: &ModuleManagerContainer,
+        now: DateTime<Utc>,
+    ) -> Option<(&Blackout, DateTime<Utc>)> {
+        self.blackouts
+            .iter()
+            .filter(|b| b.applies_to(&container.groups))
+            .find_map(|b| b.period.end_if_within(now).map(|end| (b, end)))
     }
 
     pub fn fire_cyclic(&self) -> ModResult<()> {
-        let now: chrono::DateTime<chrono::Local> = chrono::Local::now();
-        let stamp = now.timestamp() as i64;
-        let offset = now.offset().fix().local_minus_utc() as i64;
-        let timestamp = (stamp + offset) as u64;
-        for m in self.spwned.borrow().iter() {
-            match &m.whence {
-                Some(w) => {
-                    if timestamp == w.get_whence()? {
-                        let namec = m.name.to_string();
-                        self.output.replace(Some(Packet::BackupCom(PacketCore::from(
-                            BackupCommand::Fire(Some(namec)),
-                        ))));
-                        m.last_cycle.replace(Instant::now());
+        let now = self.scheduler.borrow().now();
+        let mut due = self.scheduler.borrow_mut().due();
+        self.deferred.borrow_mut().retain(|(name, until)| {
+            if *until <= now {
+                due.push(name.to_string());
+            }
+            *until > now
+        });
+
+        for name in due {
+            if self.is_paused() {
+                self.notify(&format!(
+                    "Scheduled fire of {} skipped, scheduling is paused",
+                    name
+                ));
+                continue;
+            }
+            let blackout = self
+                .find_module(&name)
+                .and_then(|m| self.blackout_for(m, now));
+            match blackout {
+                Some((b, end)) if b.policy == BlackoutPolicy::Defer => {
+                    self.notify(&format!(
+                        "Scheduled fire of {} deferred to {}, blackout {}",
+                        name,
+                        end.to_rfc3339(),
+                        b.name
+                    ));
+                    let mut deferred = self.deferred.borrow_mut();
+                    if !deferred.iter().any(|d| d.0.eq(&name)) {
+                        deferred.push((name, end));
                     }
                 }
-                None => (),
+                Some((b, end)) => {
+                    self.notify(&format!(
+                        "Scheduled fire of {} skipped, blackout {} until {}",
+                        name,
+                        b.name,
+                        end.to_rfc3339()
+                    ));
+                }
+                None => {
+                    self.request_fire(&name, FireOptions::default(), Trigger::Schedule);
+                }
             }
         }
+
+        // Caught up runs go one at a time, each waits for the previous to end,
+        // and wait for the blackouts too.
+        let pending = self.scheduler.borrow_mut().take_pending(|name| {
+            !self.is_paused()
+                && self.queue_position(name).is_none()
+                && self
+                    .find_module(name)
+                    .is_some_and(|m| !Self::is_busy(m) && self.blackout_for(m, now).is_none())
+        });
+        for name in pending {
+            self.request_fire(&name, FireOptions::default(), Trigger::Schedule);
+        }
+
+        for e in self.scheduler.borrow_mut().take_errors() {
+            self.output
+                .borrow_mut()
+                .push_back(Packet::new_ne(&e, "Module Manager", "Schedule"));
+        }
         Ok(())
     }
+
+    fn find_module(&self, mod_name: &str) -> Option<&ModuleManagerContainer> {
+        self.modules.iter().find(|m| m.id.eq(mod_name))
+    }
+
+    fn is_busy(container: &ModuleManagerContainer) -> bool {
+        let c = container.module.run_status().load(Ordering::SeqCst);
+        c == RUN_FIRE || c == RUN_RUNNING
+    }
+
+    fn queue_position(&self, mod_name: &str) -> Option<usize> {
+        self.fire_queue
+            .borrow()
+            .iter()
+            .position(|q| q.0.eq(mod_name))
+            .map(|p| p + 1)
+    }
+
+    fn running_count(&self) -> usize {
+        self.modules.iter().filter(|m| Self::is_busy(m)).count()
+    }
+
+    fn group_running_count(&self, group: &str) -> usize {
+        self.modules
+            .iter()
+            .filter(|m| m.groups.iter().any(|g| g.eq(group)) && Self::is_busy(m))
+            .count()
+    }
+
+    fn has_free_slot(&self, container: &ModuleManagerContainer) -> bool {
+        if Self::is_busy(container) {
+            return false;
+        }
+
+        if let Some(max) = self.max_running {
+            if self.running_count() >= max {
+                return false;
+            }
+        }
+
+        container.groups.iter().all(|g| {
+            let limit = self.group_limits.get(g).cloned().unwrap_or(1);
+            self.group_running_count(g) < limit
+        })
+    }
+
+    fn dispatch(&self, container: &ModuleManagerContainer, options: FireOptions, trigger: Trigger) {
+        let now = self.scheduler.borrow().now();
+        let res = match self.history.borrow_mut().as_mut() {
+            Some(h) => h.start(&container.id, trigger, options, now),
+            None => Ok(0),
+        };
+        self.report_history_error(res);
+        container.input(Packet::BackupCom(PacketCore::from(BackupCommand::Fire(
+            Some(container.id.to_string()),
+            options,
+        ))));
+    }
+
+    /// Fires a module right away if its groups and the global limit allow it,
+    /// otherwise puts it at the end of the fire queue.
+    pub fn request_fire(
+        &self,
+        mod_name: &str,
+        options: FireOptions,
+        trigger: Trigger,
+    ) -> FireRequestState {
+        if let Some(pos) = self.queue_position(mod_name) {
+            return FireRequestState::AlreadyQueued(pos);
+        }
+
+        let container = match self.find_module(mod_name) {
+            Some(c) => c,
+            None => return FireRequestState::NotFound,
+        };
+
+        if self.fire_queue.borrow().is_empty() && self.has_free_slot(container) {
+            self.dispatch(container, options, trigger);
+            return FireRequestState::Dispatched;
+        }
+
+        self.fire_queue
+            .borrow_mut()
+            .push_back((mod_name.to_string(), options, trigger));
+        let pos = self.fire_queue.borrow().len();
+        self.output.borrow_mut().push_back(Packet::new_ng(
+            &format!("Fire of {} queued (position {})", mod_name, pos),
+            "Module Manager",
+            "Queue",
+        ));
+        FireRequestState::Queued(pos)
+    }
+
+    /// Asks the named module, or every module when `mod_name` is `None`, to
+    /// reload its configuration. Returns false when no module matched.
+    pub fn request_reload(&self, mod_name: Option<&str>) -> bool {
+        let command = || {
+            Packet::BackupCom(PacketCore::from(BackupCommand::Reload(
+                mod_name.map(String::from),
+            )))
+        };
+        match mod_name {
+            Some(name) => match self.find_module(name) {
+                Some(container) => {
+                    container.input(command());
+                    true
+                }
+                None => false,
+            },
+            None => {
+                for container in self.modules.iter() {
+                    container.input(command());
+                }
+                true
+            }
+        }
+    }
+
+    /// Walks the fire queue in order and dispatches every module that got a free slot.
+    pub fn dispatch_queued(&self) {
+        let queued: Vec<(String, FireOptions, Trigger)> =
+            self.fire_queue.borrow().iter().cloned().collect();
+        for (name, options, trigger) in queued {
+            match self.find_module(&name) {
+                Some(container) => {
+                    if self.has_free_slot(container) {
+                        self.fire_queue.borrow_mut().retain(|q| !q.0.eq(&name));
+                        self.dispatch(container, options, trigger);
+                    }
+                }
+                None => {
+                    self.fire_queue.borrow_mut().retain(|q| !q.0.eq(&name));
+                }
+            }
+        }
+    }
 }
 
 pub fn connect(
@@ -305,19 +1267,12 @@ pub fn connect(
     bus.lock()?.connect(BusConnection::new(
         move |packet| match shared_self.try_lock() {
             Ok(sup) => {
+                sup.record_notification(packet);
                 if let Packet::Alive(n) = packet {
                     let name = bach_bus::packet::core_2_string(&n[5..bach_bus::packet::CORE_SIZE]);
                     for m in sup.spwned.borrow().iter() {
                         if m.name.eq(&name) {
                             m.last_time_seen_alive.update();
-                        } else if m
-                            .last_time_seen_alive
-                            .0
-                            .borrow()
-                            .elapsed()
-                            .gt(&sup.respawn_duration.borrow())
-                        {
-                            sup.respawn(&name);
                         }
                     }
                 }
@@ -333,7 +1288,7 @@ pub fn connect(
         },
         move || -> Option<Packet> {
             match shared_self.try_lock() {
-                Ok(sup) => sup.output.replace(None),
+                Ok(sup) => sup.output.borrow_mut().pop_front(),
                 Err(e) => {
                     let bus = bus.lock().unwrap();
                     bus.send(Packet::new_ne(
@@ -347,17 +1302,208 @@ pub fn connect(
         },
     ));
 
-    let lock = shared_self.lock()?;
-    let len = lock.modules.len();
-    drop(lock);
-    for i in 0..len {
-        bus.lock()?.connect(BusConnection::new(
-            move |packet| {
-                shared_self.lock().unwrap().modules[i].module.input(packet);
-            },
-            move || -> Option<Packet> { shared_self.lock().unwrap().modules[i].module.output() },
-        ));
+    let ids = shared_self.lock()?.get_module_list()?;
+    for id in ids {
+        connect_module(shared_self, bus, &id)?;
     }
 
     Ok(())
 }
+
+/// Puts a loaded module on the bus. The connection goes by the module's id,
+/// it outlives a replacement of the module and does nothing once it is
+/// unloaded.
+pub fn connect_module(
+    shared_self: &'static Mutex<ModuleManager>,
+    bus: &'static Mutex<Bus>,
+    id: &str,
+) -> ModResult<()> {
+    let (input_id, output_id) = (id.to_string(), id.to_string());
+    let connection = bus.lock()?.connect(BusConnection::new(
+        move |packet| {
+            if let Some(m) = shared_self.lock().unwrap().find_module(&input_id) {
+                m.input(packet);
+            }
+        },
+        move || -> Option<Packet> {
+            shared_self
+                .lock()
+                .unwrap()
+                .find_module(&output_id)
+                .and_then(|m| m.output())
+        },
+    ));
+    shared_self
+        .lock()?
+        .bus_connections
+        .get_mut()
+        .insert(id.to_string(), connection);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::modulemanager::*;
+    use crate::testing::Dummy;
+
+    #[test]
+    fn unique_ids() {
+        let mut manager = ModuleManager::new(Duration::from_secs(60));
+        let logger = Dummy::new("logger");
+        let told = logger.instance_id.clone();
+        assert_eq!(manager.insert(None, Box::new(logger)).unwrap(), "logger");
+        assert_eq!(told.lock().unwrap().as_deref(), Some("logger"));
+
+        let e = manager
+            .insert(None, Box::new(Dummy::new("logger")))
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("Two modules are identified as logger"));
+        let audit = Dummy::new("logger");
+        let told = audit.instance_id.clone();
+        assert_eq!(
+            manager
+                .insert(Some("audit".to_string()), Box::new(audit))
+                .unwrap(),
+            "audit"
+        );
+        assert_eq!(told.lock().unwrap().as_deref(), Some("audit"));
+        assert!(manager
+            .insert(Some("logger".to_string()), Box::new(Dummy::new("other")))
+            .is_err());
+        assert_eq!(manager.get_module_list().unwrap(), vec!["logger", "audit"]);
+    }
+
+    #[test]
+    fn containers_translate_ids_and_names() {
+        let mut manager = ModuleManager::new(Duration::from_secs(60));
+        let module = Dummy::new("logger");
+        let received = module.received.clone();
+        let outbox = module.message_stack().clone();
+        manager
+            .insert(Some("audit".to_string()), Box::new(module))
+            .unwrap();
+        let container = manager.find_module("audit").unwrap();
+        let fire = |target: &str| {
+            Packet::BackupCom(PacketCore::from(BackupCommand::Fire(
+                Some(target.to_string()),
+                FireOptions::dry_run(),
+            )))
+        };
+        let reload = |target: &str| {
+            Packet::BackupCom(PacketCore::from(BackupCommand::Reload(Some(
+                target.to_string(),
+            ))))
+        };
+
+        // Commands name the id, the module is given its own name. Those
+        // for a module named like it are not its own.
+        container.input(Packet::new_stop("audit"));
+        container.input(Packet::new_stop("logger"));
+        container.input(fire("audit"));
+        container.input(fire("logger"));
+        container.input(reload("audit"));
+        container.input(reload("logger"));
+        container.input(Packet::new_term());
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                Packet::new_stop("logger"),
+                fire("logger"),
+                reload("logger"),
+                Packet::new_term()
+            ]
+        );
+
+        let sent = |container: &ModuleManagerContainer, packet| {
+            outbox.lock().unwrap().borrow_mut().push(packet);
+            container.output()
+        };
+        assert_eq!(
+            sent(container, Packet::new_ng("Done", "logger", "END")),
+            Some(Packet::new_ng("Done", "audit", "END"))
+        );
+        assert_eq!(
+            sent(container, Packet::new_ne("Failed", "logger", "RUN")),
+            Some(Packet::new_ne("Failed", "audit", "RUN"))
+        );
+        assert_eq!(
+            sent(container, Packet::new_alive("logger")),
+            Some(Packet::new_alive("audit"))
+        );
+        // Packets relayed for others keep their provider.
+        assert_eq!(
+            sent(container, Packet::new_nw("Slow", "rsync", "RUN")),
+            Some(Packet::new_nw("Slow", "rsync", "RUN"))
+        );
+        assert_eq!(container.output(), None);
+    }
+
+    /// The logger plugin built with the workspace.
+    #[cfg(feature = "modular")]
+    fn stdlogger_plugin() -> std::path::PathBuf {
+        let exe = std::env::current_exe().unwrap();
+        let path = exe.parent().unwrap().join("../libstdlogger.so");
+        assert!(
+            path.is_file(),
+            "{} not found, build the workspace first",
+            path.display()
+        );
+        path
+    }
+
+    #[cfg(feature = "modular")]
+    fn stop(manager: &mut ModuleManager, id: &str) {
+        manager.request_unload(id).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !manager.has_stopped(id) {
+            assert!(Instant::now() < deadline, "{} did not stop", id);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "modular")]
+    fn unload_and_replace_plugins() {
+        let plugin = stdlogger_plugin();
+        let mut manager = ModuleManager::new(Duration::from_secs(60));
+        for id in ["log-a", "log-b"] {
+            let definition =
+                ModuleDefinition::new(&plugin.to_string_lossy(), None, Some(id.to_string()));
+            assert_eq!(manager.add_module(definition).unwrap(), id);
+        }
+        assert_eq!(manager.get_spawned_list().unwrap(), vec!["log-a", "log-b"]);
+
+        // Still running, it is left loaded.
+        assert!(manager.unload("log-a").is_err());
+        stop(&mut manager, "log-a");
+        // Neither its thread nor its alive emitter, which run code of the
+        // library, hold its status any more.
+        let module = &manager.find_module("log-a").unwrap().module;
+        assert_eq!(Arc::strong_count(module.run_status()), 1);
+        manager.unload("log-a").unwrap();
+        assert_eq!(manager.get_module_list().unwrap(), vec!["log-b"]);
+        assert!(manager.request_unload("log-a").is_err());
+
+        stop(&mut manager, "log-b");
+        manager.replace("log-b", None).unwrap();
+        assert_eq!(manager.get_module_list().unwrap(), vec!["log-b"]);
+        assert_eq!(manager.get_spawned_list().unwrap(), vec!["log-b"]);
+        assert!(!manager.has_stopped("log-b"));
+        stop(&mut manager, "log-b");
+        assert!(manager
+            .replace("log-b", Some("/nonexistent/libstdlogger.so".to_string()))
+            .is_err());
+        assert!(manager.get_module_list().unwrap().is_empty());
+
+        let notices: Vec<String> = manager
+            .output
+            .get_mut()
+            .drain(..)
+            .map(|p| Notification::from(p).message)
+            .collect();
+        assert!(notices.contains(&"Module log-a unloaded".to_string()));
+        assert!(notices.contains(&"Module log-b replaced".to_string()));
+    }
+}
@@ -1,83 +1,175 @@
+use crate::history::{Retention, HISTORY_FILENAME};
+use crate::scheduler::{
+    parse_duration, parse_time, Blackout, BlackoutPolicy, Misfire, Period, Schedule,
+};
+use crate::supervisor::{RestartPolicy, RestartStrategy};
 use bach_module::{ModError, ModResult};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
-use chrono::prelude::*;
+use std::time::Duration;
 
+/// Wall-clock fields of the former schedule format, still accepted.
+/// A `year`, `month` or `day` of 0 means every one.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Whence {
+    pub year: Option<String>,
+    pub month: Option<String>,
+    pub day: Option<String>,
     pub hour: String,
     pub min: String,
 }
 
-fn get_month_len_as_secs(month: u64) -> u64 {
-    let now = Local::now();
-    match month {
-        1 => 2678400,
-        2 => {
-            if now.year() % 4 == 0 {
-                2505600
-            } else {
-                2419200
+impl Whence {
+    pub fn to_cron(&self) -> String {
+        let field = |f: &Option<String>| match f.as_deref() {
+            None | Some("0") | Some("") => "*".to_string(),
+            Some(s) => s.to_string(),
+        };
+        let mut ret = format!(
+            "{} {} {} {} *",
+            self.min,
+            self.hour,
+            field(&self.day),
+            field(&self.month)
+        );
+        if let Some(y) = self.year.as_deref() {
+            if !y.eq("0") && !y.is_empty() {
+                ret.push_str(&format!(" {}", y));
             }
         }
-        3 => 2678400,
-        4 => 2592000,
-        5 => 2678400,
-        6 => 2592000,
-        7 => 2678400,
-        8 => 2678400,
-        9 => 2592000,
-        10 => 2678400,
-        11 => 2592000,
-        12 => 2678400,
-        _ => 2678400,
+        ret
     }
 }
 
-impl Whence {
-    pub fn get_whence(&self) -> ModResult<u64> {
-        let now: chrono::DateTime<chrono::Local> = chrono::Local::now();
-        let yse = now.year() as u64 - 1970;
-        let nyb = yse / 4;
-        let nyn = yse - nyb;
-        let yearstamp = nyb * 366 * 24 * 60 * 60 + nyn * 365 * 24 * 60 * 60;
-        let mut ret = yearstamp;
-
-        for i in 0..now.month() - 1 {
-            ret += get_month_len_as_secs(i as u64 + 1);
-        }
-
-        for _ in 1..now.day() + 1 {
-            ret += 24 * 60 * 60;
-        }
+/// `<schedule cron="0 2 * * *" timezone="Europe/Paris" misfire="all" grace="86400"/>`,
+/// the time zone defaults to the local one. Instead of `cron`, `every="6h"`
+/// fires at a fixed interval and `at="2026-11-01T03:00"` fires once.
+/// `jitter="20m"` delays each fire by a random amount up to that duration.
+/// `misfire` tells what to do with the runs missed while bachd was down:
+/// `once` (default), `skip` or `all`, the latter for the runs younger than
+/// `grace` seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleDefinition {
+    pub cron: Option<String>,
+    pub every: Option<String>,
+    pub at: Option<String>,
+    pub jitter: Option<String>,
+    pub timezone: Option<String>,
+    pub misfire: Option<String>,
+    pub grace: Option<u64>,
+}
 
-        if self.hour.eq("*") {
-            ret = 3600;
-        } else {
-            let h: u64 = self.hour.parse()?;
-            let secs = h*3600;
-            ret += secs;
-        }
+/// `<blackout name="nas-upgrade" from="2026-11-01T08:00" to="2026-11-01T20:00"/>`
+/// or, recurring, `<blackout name="closing" cron="0 18 28 * *" duration="3d"/>`
+/// (`every` is accepted too). Scheduled fires landing in it are skipped, or
+/// fired when it ends with `policy="defer"`. A `group` restricts it to the
+/// members of that resource group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlackoutDefinition {
+    pub name: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub cron: Option<String>,
+    pub every: Option<String>,
+    pub duration: Option<String>,
+    pub timezone: Option<String>,
+    pub group: Option<String>,
+    pub policy: Option<String>,
+}
 
-        if self.min.eq("*") {
-            ret = 60;
-        } else {
-            let m: u64 = self.min.parse()?;
-            for _ in 0..m {
-                ret += 60;
+impl BlackoutDefinition {
+    pub fn to_blackout(&self) -> ModResult<Blackout> {
+        let timezone = self.timezone.as_deref();
+        let invalid = |what: &str| {
+            ModError::new(&format!(
+                "Blackout {} {}, expected from and to, or cron or every with a duration",
+                self.name, what
+            ))
+        };
+        let period = match (&self.from, &self.to, &self.cron, &self.every) {
+            (Some(from), Some(to), None, None) => {
+                let (from, to) = (parse_time(from, timezone)?, parse_time(to, timezone)?);
+                if to <= from {
+                    return Err(invalid("ends before it starts"));
+                }
+                Period::Absolute { from, to }
             }
-        }
+            (None, None, Some(_), None) | (None, None, None, Some(_)) => {
+                let length = match &self.duration {
+                    Some(d) => parse_duration(d)?,
+                    None => return Err(invalid("has no duration")),
+                };
+                let starts = match (&self.cron, &self.every) {
+                    (Some(c), _) => Schedule::cron(c, timezone)?,
+                    (_, Some(e)) => Schedule::interval(parse_duration(e)?)?,
+                    _ => unreachable!(),
+                };
+                Period::Recurring { starts, length }
+            }
+            _ => return Err(invalid("has no valid period")),
+        };
+        Ok(Blackout {
+            name: self.name.to_string(),
+            period,
+            group: self.group.clone(),
+            policy: BlackoutPolicy::parse(self.policy.as_deref())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMembership(pub String);
 
-        Ok(ret)
+/// `<restart policy="on-failure" backoff="1s" max-backoff="5m"
+/// max-restarts="5" window="10m"/>`, the values shown being the defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestartDefinition {
+    /// `never`, `on-failure` or `always`.
+    pub policy: Option<String>,
+    pub backoff: Option<String>,
+    #[serde(rename = "max-backoff")]
+    pub max_backoff: Option<String>,
+    #[serde(rename = "max-restarts")]
+    pub max_restarts: Option<usize>,
+    pub window: Option<String>,
+}
+
+impl RestartDefinition {
+    pub fn to_strategy(&self) -> ModResult<RestartStrategy> {
+        let duration = |text: &Option<String>, default: Duration| match text {
+            Some(t) => parse_duration(t)?
+                .to_std()
+                .map_err(|_| ModError::new(&format!("Invalid duration {:?}", t))),
+            None => Ok(default),
+        };
+        let default = RestartStrategy::default();
+        Ok(RestartStrategy {
+            policy: match &self.policy {
+                Some(p) => RestartPolicy::parse(p)?,
+                None => default.policy,
+            },
+            backoff: duration(&self.backoff, default.backoff)?,
+            max_backoff: duration(&self.max_backoff, default.max_backoff)?,
+            max_restarts: self.max_restarts.unwrap_or(default.max_restarts),
+            window: duration(&self.window, default.window)?,
+        })
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModuleDefinition {
+    /// Unique identity of the module, its name by default. Needed to load
+    /// the same module twice.
+    pub id: Option<String>,
+    /// Fires on its own, by its `schedule` or `whence` which it must have.
     pub cyclic: bool,
     pub whence: Option<Whence>,
+    pub schedule: Option<ScheduleDefinition>,
+    pub restart: Option<RestartDefinition>,
+    #[serde(rename = "group", default)]
+    pub groups: Vec<GroupMembership>,
     #[cfg(feature = "modular")]
     pub file: String,
     #[cfg(feature = "static")]
@@ -86,9 +178,146 @@ pub struct ModuleDefinition {
     pub config: Option<String>,
 }
 
+impl ScheduleDefinition {
+    pub fn to_schedule(&self) -> ModResult<Schedule> {
+        let timezone = self.timezone.as_deref();
+        let schedule = match (&self.cron, &self.every, &self.at) {
+            (Some(c), None, None) => Schedule::cron(c, timezone)?,
+            (None, Some(e), None) => Schedule::interval(parse_duration(e)?)?,
+            (None, None, Some(a)) => Schedule::once(a, timezone)?,
+            _ => {
+                return Err(ModError::new(
+                    "A schedule needs exactly one of cron, every or at",
+                ))
+            }
+        };
+        match &self.jitter {
+            Some(j) => Ok(schedule.with_jitter(parse_duration(j)?)),
+            None => Ok(schedule),
+        }
+    }
+}
+
+impl ModuleDefinition {
+    /// Loads `source` with `config`, unscheduled and in no group.
+    pub fn new(source: &str, config: Option<String>, id: Option<String>) -> Self {
+        ModuleDefinition {
+            id,
+            cyclic: false,
+            whence: None,
+            schedule: None,
+            restart: None,
+            groups: Vec::new(),
+            #[cfg(feature = "modular")]
+            file: source.to_string(),
+            #[cfg(feature = "static")]
+            name: source.to_string(),
+            config,
+        }
+    }
+
+    /// Shared object the module is loaded from.
+    #[cfg(feature = "modular")]
+    pub fn source(&self) -> &str {
+        &self.file
+    }
+
+    /// Built-in module name.
+    #[cfg(feature = "static")]
+    pub fn source(&self) -> &str {
+        &self.name
+    }
+
+    pub fn restart_strategy(&self) -> ModResult<RestartStrategy> {
+        match &self.restart {
+            Some(r) => r.to_strategy(),
+            None => Ok(RestartStrategy::default()),
+        }
+    }
+
+    /// When the module fires on its own, `schedule` taking precedence over
+    /// `whence`. Only cyclic modules have one.
+    pub fn get_schedule(&self) -> ModResult<Option<(Schedule, Misfire)>> {
+        let schedule = match (&self.schedule, &self.whence) {
+            (Some(s), _) => Some((
+                s.to_schedule()?,
+                Misfire::parse(s.misfire.as_deref(), s.grace)?,
+            )),
+            (None, Some(w)) => Some((Schedule::cron(&w.to_cron(), None)?, Misfire::default())),
+            (None, None) => None,
+        };
+        let module = self.id.as_deref().unwrap_or_else(|| self.source());
+        match (self.cyclic, &schedule) {
+            (true, None) => Err(ModError::new(&format!(
+                "Module {} is cyclic without a schedule, give it one or set cyclic=\"false\"",
+                module
+            ))),
+            (false, Some(_)) => Err(ModError::new(&format!(
+                "Module {} has a schedule but is not cyclic, set cyclic=\"true\" for it to fire",
+                module
+            ))),
+            _ => Ok(schedule),
+        }
+    }
+}
+
+/// A named resource shared by several modules (a NAS, a database host...).
+/// At most `max_running` members of the group may be firing at the same time,
+/// the other fire requests are queued.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceGroup {
+    pub name: String,
+    #[serde(rename = "max-running")]
+    pub max_running: usize,
+}
+
+pub static DEFAULT_HISTORY_RETENTION_DAYS: u64 = 90;
+
+/// `<history retention-days="30" max-runs="100"/>`, where the run history is
+/// kept and for how long. `file` defaults to `history.jsonl` in the state
+/// directory, `retention-days` to 90, `max-runs` per module to no limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    pub file: Option<String>,
+    #[serde(rename = "retention-days")]
+    pub retention_days: Option<u64>,
+    #[serde(rename = "max-runs")]
+    pub max_runs: Option<usize>,
+}
+
+impl HistoryConfig {
+    pub fn path(&self) -> PathBuf {
+        match &self.file {
+            Some(f) => PathBuf::from(f),
+            None => bach_module::state::state_dir().join(HISTORY_FILENAME),
+        }
+    }
+
+    pub fn retention(&self) -> Retention {
+        let days = self
+            .retention_days
+            .unwrap_or(DEFAULT_HISTORY_RETENTION_DAYS);
+        Retention {
+            max_age: if days == 0 {
+                None
+            } else {
+                Some(chrono::Duration::days(days as i64))
+            },
+            max_runs: self.max_runs,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModuleManagerConfig {
+    /// Seconds a module may go without sending an alive packet, it is
+    /// stopped and handled as failed past them.
     pub respawn_duration: u64,
+    #[serde(rename = "max-running")]
+    pub max_running: Option<usize>,
+    #[serde(rename = "group", default)]
+    pub groups: Vec<ResourceGroup>,
+    pub history: Option<HistoryConfig>,
     pub modules: Vec<ModuleDefinition>,
 }
 
@@ -109,3 +338,167 @@ impl ModuleManagerConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::modulemanagerconfig::*;
+
+    #[test]
+    fn resource_groups_from_xml() {
+        let xml = r#"<module-manager respawn_duration="60" max-running="2">
+            <group name="nas" max-running="1"/>
+            <modules id="nas-rsync" cyclic="true" file="librsync.so" name="rsync" config-file="a.xml">
+                <group>nas</group>
+            </modules>
+            <modules cyclic="false" file="libstdlogger.so" name="stdlogger">
+            </modules>
+        </module-manager>"#;
+        let conf: ModuleManagerConfig = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(conf.max_running, Some(2));
+        assert_eq!(conf.groups.len(), 1);
+        assert_eq!(conf.groups[0].name, "nas");
+        assert_eq!(conf.groups[0].max_running, 1);
+        assert_eq!(conf.modules.len(), 2);
+        assert_eq!(conf.modules[0].groups.len(), 1);
+        assert_eq!(conf.modules[0].groups[0].0, "nas");
+        assert!(conf.modules[1].groups.is_empty());
+        assert_eq!(conf.modules[0].id.as_deref(), Some("nas-rsync"));
+        assert!(conf.modules[1].id.is_none());
+        assert!(conf.history.is_none());
+    }
+
+    #[test]
+    fn schedules_from_xml() {
+        let xml = r#"<module-manager respawn_duration="60">
+            <history retention-days="0" max-runs="50"/>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+                <whence year="0" month="0" day="1" hour="2" min="30"/>
+            </modules>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+                <whence hour="*" min="0"/>
+                <schedule cron="0 3 * * 1-5" timezone="Europe/Paris" misfire="all" grace="3600"/>
+            </modules>
+            <modules cyclic="false" file="libstdlogger.so" name="stdlogger">
+            </modules>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+                <schedule every="6h"/>
+            </modules>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+                <schedule at="2026-11-01T03:00" timezone="UTC"/>
+            </modules>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+                <schedule cron="0 2 * * *" timezone="UTC" jitter="20m"/>
+            </modules>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+                <schedule every="1h" at="2026-11-01T03:00"/>
+            </modules>
+            <modules cyclic="true" file="librsync.so" name="rsync">
+            </modules>
+            <modules id="nightly" cyclic="false" file="librsync.so" name="rsync">
+                <schedule every="1d"/>
+            </modules>
+        </module-manager>"#;
+        let conf: ModuleManagerConfig = quick_xml::de::from_str(xml).unwrap();
+        let retention = conf.history.unwrap().retention();
+        assert_eq!(retention.max_age, None);
+        assert_eq!(retention.max_runs, Some(50));
+        assert_eq!(
+            conf.modules[0].whence.as_ref().unwrap().to_cron(),
+            "30 2 1 * *"
+        );
+        assert_eq!(
+            conf.modules[0]
+                .get_schedule()
+                .unwrap()
+                .unwrap()
+                .0
+                .to_string(),
+            "cron 30 2 1 * *"
+        );
+        let (schedule, misfire) = conf.modules[1].get_schedule().unwrap().unwrap();
+        assert_eq!(schedule.to_string(), "cron 0 3 * * 1-5 (Europe/Paris)");
+        assert_eq!(
+            misfire,
+            Misfire::RunAll {
+                grace: chrono::Duration::hours(1)
+            }
+        );
+        assert!(conf.modules[2].get_schedule().unwrap().is_none());
+        let schedules: Vec<String> = conf.modules[3..6]
+            .iter()
+            .map(|m| m.get_schedule().unwrap().unwrap().0.to_string())
+            .collect();
+        assert_eq!(
+            schedules,
+            vec![
+                "every 6h",
+                "once at 2026-11-01T03:00:00+00:00",
+                "cron 0 2 * * * (UTC) with up to 20m delay"
+            ]
+        );
+        assert!(conf.modules[6].get_schedule().is_err());
+        let unscheduled = conf.modules[7].get_schedule().unwrap_err().to_string();
+        assert!(unscheduled.contains("is cyclic without a schedule"));
+        let not_cyclic = conf.modules[8].get_schedule().unwrap_err().to_string();
+        assert_eq!(
+            not_cyclic,
+            "Module nightly has a schedule but is not cyclic, set cyclic=\"true\" for it to fire"
+        );
+    }
+
+    #[test]
+    fn blackouts_from_xml() {
+        let xml = r#"<blackouts>
+            <blackout name="upgrade" from="2026-11-01T08:00" to="2026-11-01T20:00" timezone="UTC" group="nas" policy="defer"/>
+            <blackout name="closing" cron="0 18 28 * *" duration="3d"/>
+            <blackout name="nightly" every="1d"/>
+            <blackout name="backwards" from="2026-11-01T08:00" to="2026-11-01T07:00"/>
+        </blackouts>"#;
+        #[derive(Deserialize)]
+        struct Blackouts {
+            blackout: Vec<BlackoutDefinition>,
+        }
+        let conf: Blackouts = quick_xml::de::from_str(xml).unwrap();
+        let upgrade = conf.blackout[0].to_blackout().unwrap();
+        assert_eq!(upgrade.group.as_deref(), Some("nas"));
+        assert_eq!(upgrade.policy, BlackoutPolicy::Defer);
+        let closing = conf.blackout[1].to_blackout().unwrap();
+        assert!(closing.group.is_none());
+        assert_eq!(closing.policy, BlackoutPolicy::Skip);
+        assert!(conf.blackout[2].to_blackout().is_err());
+        assert!(conf.blackout[3].to_blackout().is_err());
+    }
+
+    #[test]
+    fn restart_strategies_from_xml() {
+        let xml = r#"<module-manager respawn_duration="60">
+            <modules cyclic="false" file="librsync.so">
+                <restart policy="always" backoff="10s" max-restarts="3" window="1h"/>
+            </modules>
+            <modules cyclic="false" file="libstdlogger.so"/>
+            <modules cyclic="false" file="libreporter.so">
+                <restart policy="sometimes"/>
+            </modules>
+            <modules cyclic="false" file="libreporter.so">
+                <restart backoff="soon"/>
+            </modules>
+        </module-manager>"#;
+        let conf: ModuleManagerConfig = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(
+            conf.modules[0].restart_strategy().unwrap(),
+            RestartStrategy {
+                policy: RestartPolicy::Always,
+                backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(300),
+                max_restarts: 3,
+                window: Duration::from_secs(3600),
+            }
+        );
+        assert_eq!(
+            conf.modules[1].restart_strategy().unwrap(),
+            RestartStrategy::default()
+        );
+        assert!(conf.modules[2].restart_strategy().is_err());
+        assert!(conf.modules[3].restart_strategy().is_err());
+    }
+}
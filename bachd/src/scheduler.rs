@@ -0,0 +1,942 @@
+//! Cron-style scheduling of module fires.
+//!
+//! Each scheduled module gets its next fire time computed ahead; a job is due
+//! as soon as the clock reaches that time, however late the daemon loop
+//! ticks. With a state store, the last fire time of each job survives
+//! restarts and the runs missed while the daemon was down are caught up
+//! according to the [`Misfire`] policy of the job.
+use bach_module::state::StateStore;
+use bach_module::{ModError, ModResult};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Time source of the [`Scheduler`].
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock that only moves when told to, for tests.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        if let Ok(mut n) = self.now.lock() {
+            *n = now;
+        }
+    }
+
+    pub fn advance(&self, d: chrono::Duration) {
+        if let Ok(mut n) = self.now.lock() {
+            *n = *n + d;
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        match self.now.lock() {
+            Ok(n) => *n,
+            Err(e) => *e.into_inner(),
+        }
+    }
+}
+
+/// Time zone the wall-clock fields of a schedule are read in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zone {
+    Local,
+    Named(Tz),
+}
+
+impl Zone {
+    pub fn parse(name: Option<&str>) -> ModResult<Self> {
+        match name {
+            None => Ok(Zone::Local),
+            Some(s) if s.eq_ignore_ascii_case("local") => Ok(Zone::Local),
+            Some(s) => Tz::from_str(s)
+                .map(Zone::Named)
+                .map_err(|_| ModError::new(&format!("Unknown time zone {}", s))),
+        }
+    }
+}
+
+fn cron_after<Z: TimeZone>(
+    schedule: &cron::Schedule,
+    zone: &Z,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&after.with_timezone(zone))
+        .next()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Reads durations like `90`, `90s`, `20m`, `6h`, `1d` or `1h30m`, a bare
+/// number being seconds.
+pub fn parse_duration(text: &str) -> ModResult<chrono::Duration> {
+    let invalid = || ModError::new(&format!("Invalid duration {:?}", text));
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(secs) = text.parse::<i64>() {
+        return Ok(chrono::Duration::seconds(secs));
+    }
+
+    let mut secs = 0i64;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 24 * 3600,
+            _ => return Err(invalid()),
+        };
+        let n: i64 = number.parse().map_err(|_| invalid())?;
+        secs = n
+            .checked_mul(unit)
+            .and_then(|v| secs.checked_add(v))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(chrono::Duration::seconds(secs))
+}
+
+/// Writes a duration back in the form [`parse_duration`] reads.
+fn format_duration(d: &chrono::Duration) -> String {
+    let mut secs = d.num_seconds();
+    let mut ret = String::new();
+    for (unit, suffix) in [(24 * 3600, 'd'), (3600, 'h'), (60, 'm'), (1, 's')] {
+        if secs >= unit {
+            ret.push_str(&format!("{}{}", secs / unit, suffix));
+            secs %= unit;
+        }
+    }
+    if ret.is_empty() {
+        ret.push_str("0s");
+    }
+    ret
+}
+
+/// Seed of the random delays, the same for a host from one start to the
+/// next and different from one host to another.
+fn host_seed() -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(h) if !h.trim().is_empty() => {
+            let mut hasher = DefaultHasher::new();
+            h.trim().hash(&mut hasher);
+            hasher.finish()
+        }
+        _ => rand::random(),
+    }
+}
+
+/// Reads either an RFC 3339 date or a `2026-11-01T03:00[:00]` wall-clock
+/// time in `timezone`.
+pub fn parse_time(at: &str, timezone: Option<&str>) -> ModResult<DateTime<Utc>> {
+    let at = at.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(at) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(at, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(at, "%Y-%m-%dT%H:%M"))
+        .map_err(|e| ModError::new(&format!("Invalid date {:?}: {}", at, e)))?;
+    let at_utc = match Zone::parse(timezone)? {
+        Zone::Local => Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc)),
+        Zone::Named(tz) => tz
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc)),
+    };
+    at_utc.ok_or_else(|| ModError::new(&format!("{} does not exist in the time zone", at)))
+}
+
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    /// `min hour day-of-month month day-of-week [year]`, or one of the
+    /// `@daily` like shortcuts.
+    Cron {
+        expression: String,
+        schedule: Box<cron::Schedule>,
+        zone: Zone,
+    },
+    /// Every `every`, counted from the Unix epoch so the fire times do not
+    /// move with restarts.
+    Interval { every: chrono::Duration },
+    /// A single fire.
+    Once { at: DateTime<Utc> },
+    /// Each fire of `inner` delayed by up to `max`. The delay of a given
+    /// occurrence only depends on `seed` and the occurrence itself.
+    Jittered {
+        inner: Box<Schedule>,
+        max: chrono::Duration,
+        seed: u64,
+    },
+}
+
+/// Translates a crontab day-of-week field, where 0 and 7 are Sunday and 1 is
+/// Monday, to the cron crate's, where Sunday is 1 and Saturday 7. Names are
+/// left alone.
+fn crontab_weekdays(field: &str) -> Result<String, String> {
+    let day = |n: &str| -> Result<u32, String> {
+        match n.parse::<u32>() {
+            Ok(d) if d <= 7 => Ok(d % 7 + 1),
+            _ => Err(format!("{:?} is not a day of the week", n)),
+        }
+    };
+    let numeric = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    let mut items = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(i) => (&item[..i], Some(&item[i..])),
+            None => (item, None),
+        };
+        let bounds: Vec<&str> = range.split('-').collect();
+        if range == "*" || !bounds.iter().all(|b| numeric(b)) {
+            items.push(item.to_string());
+            continue;
+        }
+        let translated = match bounds.as_slice() {
+            [d] => day(d)?.to_string(),
+            [from, to] => {
+                let (from, to) = (day(from)?, day(to)?);
+                if to == 1 && from > 1 {
+                    // The range ends on Sunday, which now comes first.
+                    if step.is_some() {
+                        return Err(format!("write {:?} with 0-6 days", item));
+                    }
+                    items.push("1".to_string());
+                    format!("{}-7", from)
+                } else if to == 1 {
+                    // 0-7, the whole week.
+                    "1-7".to_string()
+                } else {
+                    format!("{}-{}", from, to)
+                }
+            }
+            _ => return Err(format!("{:?} is not a day of the week range", range)),
+        };
+        items.push(format!("{}{}", translated, step.unwrap_or("")));
+    }
+    Ok(items.join(","))
+}
+
+impl Schedule {
+    pub fn cron(expression: &str, timezone: Option<&str>) -> ModResult<Self> {
+        let expression = expression.trim();
+        let fields = expression.split_whitespace().count();
+        let full = if expression.starts_with('@') {
+            expression.to_string()
+        } else if fields == 5 || fields == 6 {
+            // The cron crate wants the seconds first, jobs fire on the minute.
+            let mut parts: Vec<String> =
+                expression.split_whitespace().map(str::to_string).collect();
+            parts[4] = crontab_weekdays(&parts[4]).map_err(|e| {
+                ModError::new(&format!("Invalid cron expression {:?}: {}", expression, e))
+            })?;
+            format!("0 {}", parts.join(" "))
+        } else {
+            return Err(ModError::new(&format!(
+                "Cron expression {:?} must have 5 or 6 fields",
+                expression
+            )));
+        };
+        let schedule = cron::Schedule::from_str(&full).map_err(|e| {
+            ModError::new(&format!("Invalid cron expression {:?}: {}", expression, e))
+        })?;
+        Ok(Schedule::Cron {
+            expression: expression.to_string(),
+            schedule: Box::new(schedule),
+            zone: Zone::parse(timezone)?,
+        })
+    }
+
+    pub fn interval(every: chrono::Duration) -> ModResult<Self> {
+        if every <= chrono::Duration::zero() {
+            return Err(ModError::new("A schedule interval must be positive"));
+        }
+        Ok(Schedule::Interval { every })
+    }
+
+    /// `at` is read by [`parse_time`].
+    pub fn once(at: &str, timezone: Option<&str>) -> ModResult<Self> {
+        Ok(Schedule::Once {
+            at: parse_time(at, timezone)?,
+        })
+    }
+
+    pub fn with_jitter(self, max: chrono::Duration) -> Self {
+        self.with_jitter_seed(max, host_seed())
+    }
+
+    pub fn with_jitter_seed(self, max: chrono::Duration, seed: u64) -> Self {
+        if max <= chrono::Duration::zero() {
+            return self;
+        }
+        Schedule::Jittered {
+            inner: Box::new(self),
+            max,
+            seed,
+        }
+    }
+
+    /// First fire time strictly after `after`, `None` once the schedule is over.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Cron { schedule, zone, .. } => match zone {
+                Zone::Local => cron_after(schedule, &Local, after),
+                Zone::Named(tz) => cron_after(schedule, tz, after),
+            },
+            Schedule::Interval { every } => {
+                let every = every.num_seconds();
+                let secs = after.timestamp().div_euclid(every) * every + every;
+                Some(Utc.timestamp(secs, 0))
+            }
+            Schedule::Once { at } => Some(*at).filter(|at| *at > after),
+            Schedule::Jittered { inner, max, seed } => {
+                // Occurrences up to `max` before `after` may still be delayed past it.
+                let mut base = inner.next_after(after - *max - chrono::Duration::seconds(1))?;
+                loop {
+                    let mut rng = StdRng::seed_from_u64(*seed ^ base.timestamp() as u64);
+                    let fire =
+                        base + chrono::Duration::seconds(rng.gen_range(0..=max.num_seconds()));
+                    if fire > after {
+                        return Some(fire);
+                    }
+                    base = inner.next_after(base)?;
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Cron {
+                expression, zone, ..
+            } => match zone {
+                Zone::Local => write!(f, "cron {}", expression),
+                Zone::Named(tz) => write!(f, "cron {} ({})", expression, tz.name()),
+            },
+            Schedule::Interval { every } => write!(f, "every {}", format_duration(every)),
+            Schedule::Once { at } => write!(f, "once at {}", at.to_rfc3339()),
+            Schedule::Jittered { inner, max, .. } => {
+                write!(f, "{} with up to {} delay", inner, format_duration(max))
+            }
+        }
+    }
+}
+
+/// What to do with the runs a job missed while the daemon was down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Misfire {
+    /// Fire once, however many runs were missed.
+    #[default]
+    RunOnce,
+    Skip,
+    /// Fire once per missed run, for the runs younger than `grace`.
+    RunAll {
+        grace: chrono::Duration,
+    },
+}
+
+pub static DEFAULT_MISFIRE_GRACE: i64 = 24 * 3600;
+
+impl Misfire {
+    /// Reads the `misfire` and `grace` (seconds) attributes of a schedule.
+    pub fn parse(policy: Option<&str>, grace: Option<u64>) -> ModResult<Self> {
+        match policy.map(|p| p.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("once") => Ok(Misfire::RunOnce),
+            Some("skip") => Ok(Misfire::Skip),
+            Some("all") => Ok(Misfire::RunAll {
+                grace: chrono::Duration::seconds(
+                    grace.map(|g| g as i64).unwrap_or(DEFAULT_MISFIRE_GRACE),
+                ),
+            }),
+            Some(p) => Err(ModError::new(&format!(
+                "Unknown misfire policy {}, expected once, skip or all",
+                p
+            ))),
+        }
+    }
+}
+
+/// Counting missed runs stops there, a schedule firing every minute would
+/// otherwise have half a million of them after a year down.
+const MAX_MISSED: usize = 1000;
+
+/// Decision taken at startup for a job that missed runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CatchUp {
+    pub name: String,
+    pub missed: usize,
+    pub last_fire: DateTime<Utc>,
+    pub fires: usize,
+}
+
+impl std::fmt::Display for CatchUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} missed {}{} run(s) since {}, ",
+            self.name,
+            if self.missed >= MAX_MISSED {
+                "at least "
+            } else {
+                ""
+            },
+            self.missed,
+            self.last_fire.to_rfc3339()
+        )?;
+        match self.fires {
+            0 => write!(f, "skipped"),
+            1 => write!(f, "firing once"),
+            n => write!(f, "firing {} times", n),
+        }
+    }
+}
+
+fn last_fire_key(name: &str) -> String {
+    format!("last-fire.{}", name)
+}
+
+/// What happens to a scheduled fire landing in a blackout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlackoutPolicy {
+    #[default]
+    Skip,
+    /// Fire when the blackout ends.
+    Defer,
+}
+
+impl BlackoutPolicy {
+    pub fn parse(policy: Option<&str>) -> ModResult<Self> {
+        match policy.map(|p| p.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("skip") => Ok(BlackoutPolicy::Skip),
+            Some("defer") => Ok(BlackoutPolicy::Defer),
+            Some(p) => Err(ModError::new(&format!(
+                "Unknown blackout policy {}, expected skip or defer",
+                p
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Period {
+    Absolute {
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    },
+    /// Starts at every fire of `starts` and lasts `length`.
+    Recurring {
+        starts: Schedule,
+        length: chrono::Duration,
+    },
+}
+
+impl Period {
+    /// End of the period if `t` falls in it.
+    pub fn end_if_within(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Period::Absolute { from, to } => Some(*to).filter(|_| *from <= t && t < *to),
+            Period::Recurring { starts, length } => starts
+                .next_after(t - *length)
+                .filter(|start| *start <= t)
+                .map(|start| start + *length),
+        }
+    }
+}
+
+/// Time range during which scheduled fires are held, for every module or
+/// only for the members of `group`.
+#[derive(Clone, Debug)]
+pub struct Blackout {
+    pub name: String,
+    pub period: Period,
+    pub group: Option<String>,
+    pub policy: BlackoutPolicy,
+}
+
+impl Blackout {
+    pub fn applies_to(&self, groups: &[String]) -> bool {
+        match &self.group {
+            None => true,
+            Some(g) => groups.iter().any(|m| m.eq(g)),
+        }
+    }
+}
+
+struct Job {
+    name: String,
+    schedule: Schedule,
+    misfire: Misfire,
+    next: Option<DateTime<Utc>>,
+    pending: usize,
+}
+
+pub struct Scheduler {
+    clock: Arc<dyn Clock>,
+    jobs: Vec<Job>,
+    store: Option<StateStore>,
+    errors: Vec<String>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new(Arc::new(SystemClock))
+    }
+}
+
+impl Scheduler {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Scheduler {
+            clock,
+            jobs: Vec::new(),
+            store: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Keeps the last fire time of every job in `store`.
+    pub fn set_store(&mut self, store: StateStore) {
+        self.store = Some(store);
+    }
+
+    /// Changes the time source, next fire times are computed again from it.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+        let now = self.clock.now();
+        for j in self.jobs.iter_mut() {
+            j.next = j.schedule.next_after(now);
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Schedules `name`, replacing its previous schedule if any. The missed
+    /// runs the previous schedule still had to catch up are kept.
+    pub fn add(&mut self, name: &str, schedule: Schedule, misfire: Misfire) {
+        let pending = self
+            .jobs
+            .iter()
+            .find(|j| j.name.eq(name))
+            .map_or(0, |j| j.pending);
+        self.remove(name);
+        let next = schedule.next_after(self.clock.now());
+        self.jobs.push(Job {
+            name: name.to_string(),
+            schedule,
+            misfire,
+            next,
+            pending,
+        });
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.jobs.retain(|j| !j.name.eq(name));
+    }
+
+    pub fn next_fire(&self, name: &str) -> Option<DateTime<Utc>> {
+        self.jobs
+            .iter()
+            .find(|j| j.name.eq(name))
+            .and_then(|j| j.next)
+    }
+
+    /// Returns the jobs whose fire time has come and moves them to their
+    /// next occurrence. Occurrences that went by during a single late tick
+    /// give a single fire.
+    pub fn due(&mut self) -> Vec<String> {
+        let now = self.clock.now();
+        let mut ret = Vec::new();
+        let mut fired = Vec::new();
+        for j in self.jobs.iter_mut() {
+            if let Some(next) = j.next {
+                if next <= now {
+                    ret.push(j.name.to_string());
+                    fired.push((j.name.to_string(), next));
+                    j.next = j.schedule.next_after(now);
+                }
+            }
+        }
+        for (name, at) in fired {
+            self.record(&name, at);
+        }
+        ret
+    }
+
+    fn record(&mut self, name: &str, at: DateTime<Utc>) {
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.set_timestamp(&last_fire_key(name), SystemTime::from(at)) {
+                self.errors.push(format!(
+                    "Unable to record the last fire of {} : {}",
+                    name, e
+                ));
+            }
+        }
+    }
+
+    /// Errors met while persisting fire times since the last call.
+    pub fn take_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Looks for the runs missed since the recorded last fire of each job
+    /// and applies its misfire policy. The fires it decides are handed out
+    /// by [`Scheduler::take_pending`]. Jobs that never fired are left alone.
+    pub fn catch_up(&mut self) -> Vec<CatchUp> {
+        let now = self.clock.now();
+        let mut ret = Vec::new();
+        let mut decided = Vec::new();
+        for j in self.jobs.iter_mut() {
+            let last_fire = match self
+                .store
+                .as_ref()
+                .and_then(|s| s.get_timestamp(&last_fire_key(&j.name)))
+            {
+                Some(t) => DateTime::<Utc>::from(t),
+                None => continue,
+            };
+
+            let mut missed = Vec::new();
+            let mut t = last_fire;
+            while missed.len() < MAX_MISSED {
+                match j.schedule.next_after(t) {
+                    Some(n) if n <= now => {
+                        missed.push(n);
+                        t = n;
+                    }
+                    _ => break,
+                }
+            }
+            let latest = match missed.last() {
+                Some(l) => *l,
+                None => continue,
+            };
+
+            j.pending = match j.misfire {
+                Misfire::RunOnce => 1,
+                Misfire::Skip => 0,
+                Misfire::RunAll { grace } => missed.iter().filter(|m| now - **m <= grace).count(),
+            };
+            ret.push(CatchUp {
+                name: j.name.to_string(),
+                missed: missed.len(),
+                last_fire,
+                fires: j.pending,
+            });
+            decided.push((j.name.to_string(), latest));
+        }
+        // The decision is taken, a crash now must not replay it.
+        for (name, latest) in decided {
+            self.record(&name, latest);
+        }
+        ret
+    }
+
+    /// Hands out one caught up fire of each job for which `ready` holds.
+    pub fn take_pending<F: Fn(&str) -> bool>(&mut self, ready: F) -> Vec<String> {
+        let mut ret = Vec::new();
+        for j in self.jobs.iter_mut() {
+            if j.pending > 0 && ready(&j.name) {
+                j.pending -= 1;
+                ret.push(j.name.to_string());
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduler::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn cron_next_fire() {
+        let s = Schedule::cron("30 2 * * *", Some("Europe/Paris")).unwrap();
+        assert_eq!(
+            s.next_after(at("2021-06-01T12:00:00Z")),
+            Some(at("2021-06-02T00:30:00Z"))
+        );
+        // 02:30 does not exist in Paris on the night clocks go forward.
+        assert_eq!(
+            s.next_after(at("2021-03-27T12:00:00Z")),
+            Some(at("2021-03-29T00:30:00Z"))
+        );
+
+        let s = Schedule::cron("0 0 29 2 * 2024", Some("UTC")).unwrap();
+        assert_eq!(
+            s.next_after(at("2021-01-01T00:00:00Z")),
+            Some(at("2024-02-29T00:00:00Z"))
+        );
+        assert_eq!(s.next_after(at("2024-03-01T00:00:00Z")), None);
+
+        assert!(Schedule::cron("0 2 * *", None).is_err());
+        assert!(Schedule::cron("0 25 * * *", None).is_err());
+        assert!(Schedule::cron("0 2 * * *", Some("Mars/Olympus")).is_err());
+        assert!(Schedule::cron("@daily", Some("UTC")).is_ok());
+
+        // Crontab days of the week: 0 and 7 are Sunday, 1 is Monday.
+        // 2021-06-05 is a Saturday.
+        let next = |e: &str| {
+            Schedule::cron(e, Some("UTC"))
+                .unwrap()
+                .next_after(at("2021-06-05T12:00:00Z"))
+        };
+        assert_eq!(next("0 2 * * 1"), Some(at("2021-06-07T02:00:00Z")));
+        assert_eq!(next("0 2 * * 1-5"), Some(at("2021-06-07T02:00:00Z")));
+        assert_eq!(next("0 2 * * 0"), Some(at("2021-06-06T02:00:00Z")));
+        assert_eq!(next("0 2 * * 7"), Some(at("2021-06-06T02:00:00Z")));
+        assert_eq!(next("0 2 * * 5-7"), Some(at("2021-06-06T02:00:00Z")));
+        assert_eq!(next("0 2 * * 2,4"), Some(at("2021-06-08T02:00:00Z")));
+        assert_eq!(next("0 2 * * MON"), Some(at("2021-06-07T02:00:00Z")));
+        let s = Schedule::cron("0 2 * * 1-5", Some("UTC")).unwrap();
+        assert_eq!(
+            s.next_after(at("2021-06-11T12:00:00Z")),
+            Some(at("2021-06-14T02:00:00Z"))
+        );
+        assert!(Schedule::cron("0 2 * * 8", None).is_err());
+    }
+
+    #[test]
+    fn late_ticks_still_fire() {
+        let clock = ManualClock::new(at("2021-06-01T01:59:00Z"));
+        let mut scheduler = Scheduler::new(Arc::new(clock.clone()));
+        scheduler.add(
+            "nightly",
+            Schedule::cron("0 2 * * *", Some("UTC")).unwrap(),
+            Misfire::default(),
+        );
+        scheduler.add(
+            "hourly",
+            Schedule::cron("15 * * * *", Some("UTC")).unwrap(),
+            Misfire::default(),
+        );
+        assert!(scheduler.due().is_empty());
+        assert_eq!(
+            scheduler.next_fire("nightly"),
+            Some(at("2021-06-01T02:00:00Z"))
+        );
+
+        // The tick lands seconds after the fire time.
+        clock.advance(chrono::Duration::seconds(63));
+        assert_eq!(scheduler.due(), vec!["nightly".to_string()]);
+        assert!(scheduler.due().is_empty());
+        assert_eq!(
+            scheduler.next_fire("nightly"),
+            Some(at("2021-06-02T02:00:00Z"))
+        );
+
+        // Several hourly occurrences behind, fired once.
+        clock.advance(chrono::Duration::hours(3));
+        assert_eq!(scheduler.due(), vec!["hourly".to_string()]);
+        assert_eq!(
+            scheduler.next_fire("hourly"),
+            Some(at("2021-06-01T05:15:00Z"))
+        );
+    }
+
+    #[test]
+    fn missed_runs_after_downtime() {
+        let dir = std::env::temp_dir().join(format!("bach-scheduler-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let clock = ManualClock::new(at("2021-06-01T01:00:00Z"));
+        let daily = || Schedule::cron("0 2 * * *", Some("UTC")).unwrap();
+
+        let mut scheduler = Scheduler::new(Arc::new(clock.clone()));
+        scheduler.set_store(StateStore::open_in(&dir, "scheduler").unwrap());
+        scheduler.add("once", daily(), Misfire::RunOnce);
+        scheduler.add("skip", daily(), Misfire::Skip);
+        scheduler.add(
+            "all",
+            daily(),
+            Misfire::parse(Some("all"), Some(3 * 24 * 3600)).unwrap(),
+        );
+        scheduler.add("new", daily(), Misfire::RunOnce);
+        // Nothing recorded yet, nothing to catch up.
+        assert!(scheduler.catch_up().is_empty());
+        clock.advance(chrono::Duration::hours(1));
+        assert_eq!(scheduler.due().len(), 4);
+        drop(scheduler);
+
+        // Down from the 1st of June run to the 7th, six runs went by.
+        clock.set(at("2021-06-07T12:00:00Z"));
+        let mut scheduler = Scheduler::new(Arc::new(clock.clone()));
+        let mut store = StateStore::open_in(&dir, "scheduler").unwrap();
+        store.remove(&last_fire_key("new")).unwrap();
+        scheduler.set_store(store);
+        scheduler.add("once", daily(), Misfire::RunOnce);
+        scheduler.add("skip", daily(), Misfire::Skip);
+        scheduler.add(
+            "all",
+            daily(),
+            Misfire::parse(Some("all"), Some(3 * 24 * 3600)).unwrap(),
+        );
+        scheduler.add("new", daily(), Misfire::RunOnce);
+        let decisions = scheduler.catch_up();
+        assert_eq!(decisions.len(), 3);
+        assert_eq!(
+            decisions[0].to_string(),
+            "once missed 6 run(s) since 2021-06-01T02:00:00+00:00, firing once"
+        );
+        assert_eq!(decisions[1].fires, 0);
+        // 5th, 6th and 7th are within the 3 days of grace.
+        assert_eq!(decisions[2].fires, 3);
+        assert!(scheduler.catch_up().is_empty());
+
+        assert_eq!(
+            scheduler.take_pending(|n| !n.eq("all")),
+            vec!["once".to_string()]
+        );
+        assert!(scheduler.take_pending(|_| false).is_empty());
+        // Rescheduled by a reload, it still owes its missed runs.
+        scheduler.add(
+            "all",
+            Schedule::cron("30 2 * * *", Some("UTC")).unwrap(),
+            Misfire::RunOnce,
+        );
+        assert_eq!(scheduler.next_fire("all"), Some(at("2021-06-08T02:30:00Z")));
+        for _ in 0..3 {
+            assert_eq!(scheduler.take_pending(|_| true), vec!["all".to_string()]);
+        }
+        assert!(scheduler.take_pending(|_| true).is_empty());
+        assert!(scheduler.take_errors().is_empty());
+
+        assert!(Misfire::parse(Some("twice"), None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interval_once_and_jitter() {
+        let s = Schedule::interval(parse_duration("6h").unwrap()).unwrap();
+        assert_eq!(
+            s.next_after(at("2021-06-01T05:59:59Z")),
+            Some(at("2021-06-01T06:00:00Z"))
+        );
+        assert_eq!(
+            s.next_after(at("2021-06-01T06:00:00Z")),
+            Some(at("2021-06-01T12:00:00Z"))
+        );
+        assert!(Schedule::interval(chrono::Duration::zero()).is_err());
+
+        let s = Schedule::once("2026-11-01T03:00", Some("Europe/Paris")).unwrap();
+        assert_eq!(
+            s.next_after(at("2026-01-01T00:00:00Z")),
+            Some(at("2026-11-01T02:00:00Z"))
+        );
+        assert_eq!(s.next_after(at("2026-11-01T02:00:00Z")), None);
+        assert!(Schedule::once("tomorrow", None).is_err());
+
+        let max = parse_duration("20m").unwrap();
+        let nightly = || Schedule::cron("0 2 * * *", Some("UTC")).unwrap();
+        let s = nightly().with_jitter_seed(max, 42);
+        let fire = s.next_after(at("2021-06-01T00:00:00Z")).unwrap();
+        assert!(fire >= at("2021-06-01T02:00:00Z") && fire <= at("2021-06-01T02:20:00Z"));
+        // Asking again from any time before the delayed fire gives it back.
+        assert_eq!(s.next_after(at("2021-06-01T02:00:00Z")), Some(fire));
+        assert_eq!(
+            s.next_after(fire - chrono::Duration::seconds(1)),
+            Some(fire)
+        );
+        let next = s.next_after(fire).unwrap();
+        assert!(next >= at("2021-06-02T02:00:00Z") && next <= at("2021-06-02T02:20:00Z"));
+
+        // Another host draws other delays.
+        let delays: Vec<_> = (0..10u64)
+            .map(|seed| {
+                nightly()
+                    .with_jitter_seed(max, seed)
+                    .next_after(at("2021-06-01T00:00:00Z"))
+            })
+            .collect();
+        assert!(delays.iter().any(|d| *d != delays[0]));
+
+        assert_eq!(
+            parse_duration("1h30m").unwrap(),
+            chrono::Duration::minutes(90)
+        );
+        assert_eq!(parse_duration("90").unwrap(), chrono::Duration::seconds(90));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("10m5").is_err());
+        assert_eq!(format_duration(&chrono::Duration::minutes(90)), "1h30m");
+    }
+
+    #[test]
+    fn blackout_periods() {
+        let upgrade = Period::Absolute {
+            from: parse_time("2026-11-01T08:00", Some("Europe/Paris")).unwrap(),
+            to: parse_time("2026-11-01T20:00:00+01:00", None).unwrap(),
+        };
+        assert_eq!(upgrade.end_if_within(at("2026-11-01T06:59:59Z")), None);
+        assert_eq!(
+            upgrade.end_if_within(at("2026-11-01T07:00:00Z")),
+            Some(at("2026-11-01T19:00:00Z"))
+        );
+        assert_eq!(upgrade.end_if_within(at("2026-11-01T19:00:00Z")), None);
+
+        // The last two days of every month but December.
+        let closing = Period::Recurring {
+            starts: Schedule::cron("0 0 30 1-11 *", Some("UTC")).unwrap(),
+            length: parse_duration("2d").unwrap(),
+        };
+        assert_eq!(closing.end_if_within(at("2021-06-29T23:59:00Z")), None);
+        assert_eq!(
+            closing.end_if_within(at("2021-07-01T12:00:00Z")),
+            Some(at("2021-07-02T00:00:00Z"))
+        );
+        assert_eq!(closing.end_if_within(at("2021-07-02T00:00:00Z")), None);
+
+        let blackout = Blackout {
+            name: "nas upgrade".to_string(),
+            period: upgrade,
+            group: Some("nas".to_string()),
+            policy: BlackoutPolicy::parse(Some("defer")).unwrap(),
+        };
+        assert!(blackout.applies_to(&["db".to_string(), "nas".to_string()]));
+        assert!(!blackout.applies_to(&[]));
+        assert!(BlackoutPolicy::parse(Some("later")).is_err());
+    }
+}
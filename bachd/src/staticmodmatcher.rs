@@ -3,6 +3,11 @@ use reporter::Reporter;
 use rsync::Rsync;
 use stdlogger::StdLogger;
 
+/// Whether the module `name` was embedded at compile time.
+pub fn embeds(name: &str) -> bool {
+    matches!(name, "stdlogger" | "rsync" | "reporter")
+}
+
 pub fn fetch(name: &str, config: &Option<String>) -> ModResult<Box<dyn Module>> {
     match name {
         "stdlogger" => Ok(Box::new(StdLogger::new(config))),
@@ -0,0 +1,223 @@
+//! Restart strategies of the module threads.
+use bach_module::{ModError, ModResult};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// When an ended module thread is started again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    /// After an error, a panic, a failed run or an alive timeout.
+    #[default]
+    OnFailure,
+    /// Whenever it ends without being asked to stop.
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn parse(policy: &str) -> ModResult<Self> {
+        match policy.trim().to_ascii_lowercase().as_str() {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            p => Err(ModError::new(&format!(
+                "Unknown restart policy {}, expected never, on-failure or always",
+                p
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestartStrategy {
+    pub policy: RestartPolicy,
+    /// Delay of the first restart, doubled by each restart within `window`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Restarts allowed within `window`, the module is failed past them.
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for RestartStrategy {
+    fn default() -> Self {
+        RestartStrategy {
+            policy: RestartPolicy::default(),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            max_restarts: 5,
+            window: Duration::from_secs(600),
+        }
+    }
+}
+
+/// How a module thread ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Exit {
+    Stopped,
+    Failed(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Restart {
+        at: Instant,
+    },
+    /// Left stopped, as the policy says.
+    Leave,
+    /// Too many restarts within the window, the module is failed.
+    GiveUp,
+}
+
+/// Restarts of a module, following its strategy.
+#[derive(Clone, Debug)]
+pub struct Supervisor {
+    strategy: RestartStrategy,
+    restarts: VecDeque<Instant>,
+    failed: bool,
+}
+
+impl Supervisor {
+    pub fn new(strategy: RestartStrategy) -> Self {
+        Supervisor {
+            strategy,
+            restarts: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    pub fn strategy(&self) -> &RestartStrategy {
+        &self.strategy
+    }
+
+    pub fn set_strategy(&mut self, strategy: RestartStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Decides what follows the end of the module thread at `now`.
+    /// `requested` tells it was asked to stop, it is then never restarted.
+    pub fn exited(&mut self, exit: &Exit, requested: bool, now: Instant) -> Decision {
+        let restart = match (self.strategy.policy, exit) {
+            _ if requested => false,
+            (RestartPolicy::Never, _) => false,
+            (RestartPolicy::OnFailure, Exit::Stopped) => false,
+            _ => true,
+        };
+        if !restart {
+            return Decision::Leave;
+        }
+        while let Some(first) = self.restarts.front() {
+            if now.duration_since(*first) < self.strategy.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.strategy.max_restarts {
+            self.failed = true;
+            return Decision::GiveUp;
+        }
+        let delay = self
+            .strategy
+            .backoff
+            .checked_mul(1 << self.restarts.len().min(31))
+            .unwrap_or(self.strategy.max_backoff)
+            .min(self.strategy.max_backoff);
+        self.restarts.push_back(now);
+        self.failed = false;
+        Decision::Restart { at: now + delay }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::supervisor::*;
+
+    fn failed() -> Exit {
+        Exit::Failed("boom".to_string())
+    }
+
+    #[test]
+    fn policies() {
+        let now = Instant::now();
+        let mut never = Supervisor::new(RestartStrategy {
+            policy: RestartPolicy::Never,
+            ..RestartStrategy::default()
+        });
+        assert_eq!(never.exited(&failed(), false, now), Decision::Leave);
+
+        let mut on_failure = Supervisor::new(RestartStrategy::default());
+        assert_eq!(
+            on_failure.exited(&Exit::Stopped, false, now),
+            Decision::Leave
+        );
+        assert_eq!(on_failure.exited(&failed(), true, now), Decision::Leave);
+        assert!(matches!(
+            on_failure.exited(&failed(), false, now),
+            Decision::Restart { .. }
+        ));
+
+        let mut always = Supervisor::new(RestartStrategy {
+            policy: RestartPolicy::Always,
+            ..RestartStrategy::default()
+        });
+        assert!(matches!(
+            always.exited(&Exit::Stopped, false, now),
+            Decision::Restart { .. }
+        ));
+        assert_eq!(always.exited(&Exit::Stopped, true, now), Decision::Leave);
+
+        assert_eq!(
+            RestartPolicy::parse(" On-Failure ").unwrap(),
+            RestartPolicy::OnFailure
+        );
+        assert!(RestartPolicy::parse("sometimes").is_err());
+    }
+
+    #[test]
+    fn backoff_and_crash_loop() {
+        let start = Instant::now();
+        let mut supervisor = Supervisor::new(RestartStrategy {
+            backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(5),
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            ..RestartStrategy::default()
+        });
+        let mut delays = Vec::new();
+        for i in 0..3 {
+            let now = start + Duration::from_secs(i);
+            match supervisor.exited(&failed(), false, now) {
+                Decision::Restart { at } => delays.push(at - now),
+                d => panic!("unexpected {:?}", d),
+            }
+        }
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_secs(2),
+                Duration::from_secs(4),
+                Duration::from_secs(5)
+            ]
+        );
+        assert!(!supervisor.is_failed());
+        assert_eq!(
+            supervisor.exited(&failed(), false, start + Duration::from_secs(10)),
+            Decision::GiveUp
+        );
+        assert!(supervisor.is_failed());
+
+        // Once the first restarts are out of the window, it may go again.
+        let later = start + Duration::from_secs(61);
+        assert_eq!(
+            supervisor.exited(&failed(), false, later),
+            Decision::Restart {
+                at: later + Duration::from_secs(4)
+            }
+        );
+        assert!(!supervisor.is_failed());
+    }
+}
@@ -0,0 +1,282 @@
+//! Service manager integration: the `sd_notify` protocol for `Type=notify`
+//! units and the native journald protocol for structured log entries.
+use crate::history::Level;
+use bach_bus::packet::{Notification, Packet};
+use std::cell::RefCell;
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+/// Socket of the journald native protocol.
+pub static JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Half the watchdog timeout of `usec`, if the watchdog is meant for `pid`.
+fn keepalive_interval(
+    usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(p) = watchdog_pid {
+        if p.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Connection to the `NOTIFY_SOCKET` of the service manager.
+pub struct Notifier {
+    socket: UnixDatagram,
+    keepalive: Option<Duration>,
+    last_keepalive: RefCell<Instant>,
+    status: RefCell<String>,
+}
+
+impl Notifier {
+    /// `path` is a file system path, or an abstract name after `@`.
+    pub fn connect(path: &str, keepalive: Option<Duration>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        match path.strip_prefix('@') {
+            Some(name) => socket.connect_addr(&SocketAddr::from_abstract_name(name)?)?,
+            None => socket.connect(path)?,
+        }
+        Ok(Notifier {
+            socket,
+            keepalive,
+            last_keepalive: RefCell::new(Instant::now()),
+            status: RefCell::new(String::new()),
+        })
+    }
+
+    /// The notifier of the service manager that started the daemon, if
+    /// any. The variables are removed so the modules' children do not
+    /// notify in the daemon's name.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match env::var("NOTIFY_SOCKET") {
+            Ok(p) if !p.is_empty() => p,
+            _ => return Ok(None),
+        };
+        let keepalive = keepalive_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        for v in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(v);
+        }
+        Ok(Some(Notifier::connect(&path, keepalive)?))
+    }
+
+    /// Sends newline separated `KEY=VALUE` assignments.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send(state.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    pub fn reloading(&self) -> io::Result<()> {
+        self.notify("RELOADING=1")
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Called from the main loop: sends the watchdog keepalive when it is
+    /// due and `status` when it changed.
+    pub fn tick(&self, status: &str) -> io::Result<()> {
+        if let Some(keepalive) = self.keepalive {
+            if self.last_keepalive.borrow().elapsed() >= keepalive {
+                self.last_keepalive.replace(Instant::now());
+                self.notify("WATCHDOG=1")?;
+            }
+        }
+        if !self.status.borrow().eq(status) {
+            self.status.replace(status.to_string());
+            self.notify(&format!("STATUS={}", status))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `journal_stream`, a `JOURNAL_STREAM` value, names the device
+/// and inode of `metadata`.
+fn is_journal_stream(journal_stream: &str, metadata: &std::fs::Metadata) -> bool {
+    match journal_stream.split_once(':') {
+        Some((dev, ino)) => dev.parse() == Ok(metadata.dev()) && ino.parse() == Ok(metadata.ino()),
+        None => false,
+    }
+}
+
+fn append_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Structured entries sent to journald.
+pub struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Journal { socket })
+    }
+
+    /// The journal, when the standard output of the daemon goes to it.
+    pub fn from_env() -> Option<Self> {
+        let journal_stream = env::var("JOURNAL_STREAM").ok()?;
+        let stdout = std::fs::metadata("/proc/self/fd/1").ok()?;
+        if !is_journal_stream(&journal_stream, &stdout) {
+            return None;
+        }
+        Journal::connect(JOURNAL_SOCKET).ok()
+    }
+
+    /// Logs a notification, with the run it belongs to if any. Other
+    /// packets are ignored.
+    pub fn log(&self, packet: Packet, run_id: Option<u64>) -> io::Result<()> {
+        let level = match Level::of(&packet) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        let (priority, severity) = match level {
+            Level::Good => ("6", "good"),
+            Level::Warn => ("4", "warn"),
+            Level::Error => ("3", "error"),
+        };
+        let n = Notification::from(packet);
+        let mut buf = Vec::new();
+        append_field(&mut buf, "MESSAGE", &n.to_string());
+        append_field(&mut buf, "PRIORITY", priority);
+        append_field(&mut buf, "SEVERITY", severity);
+        append_field(&mut buf, "MODULE", &n.provider);
+        append_field(&mut buf, "STAGE", &n.stage);
+        if let Some(id) = run_id {
+            append_field(&mut buf, "RUN_ID", &id.to_string());
+        }
+        append_field(&mut buf, "SYSLOG_IDENTIFIER", "bachd");
+        self.socket.send(&buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systemd::*;
+
+    fn socket(name: &str) -> (std::path::PathBuf, UnixDatagram) {
+        let path =
+            env::temp_dir().join(format!("bach-systemd-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (path, socket)
+    }
+
+    fn recv(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = [0; 4096];
+        let len = socket.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn notify_socket() {
+        let (path, manager) = socket("notify");
+        let notifier =
+            Notifier::connect(path.to_str().unwrap(), Some(Duration::from_millis(0))).unwrap();
+        notifier.ready().unwrap();
+        assert_eq!(recv(&manager), b"READY=1");
+        notifier.tick("Idle").unwrap();
+        assert_eq!(recv(&manager), b"WATCHDOG=1");
+        assert_eq!(recv(&manager), b"STATUS=Idle");
+        let notifier = Notifier::connect(path.to_str().unwrap(), None).unwrap();
+        notifier.tick("Idle").unwrap();
+        notifier.tick("Idle").unwrap();
+        notifier.stopping().unwrap();
+        // The status is only sent again when it changes.
+        assert_eq!(recv(&manager), b"STATUS=Idle");
+        assert_eq!(recv(&manager), b"STOPPING=1");
+        std::fs::remove_file(&path).unwrap();
+
+        let name = format!("bach-systemd-test-{}", std::process::id());
+        let manager =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        Notifier::connect(&format!("@{}", name), None)
+            .unwrap()
+            .reloading()
+            .unwrap();
+        assert_eq!(recv(&manager), b"RELOADING=1");
+    }
+
+    #[test]
+    fn watchdog_interval() {
+        assert_eq!(
+            keepalive_interval(Some("60000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            keepalive_interval(Some("60000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(keepalive_interval(Some("60000000"), Some("7"), 42), None);
+        assert_eq!(keepalive_interval(Some("0"), None, 42), None);
+        assert_eq!(keepalive_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn journal_fields() {
+        let (path, journald) = socket("journal");
+        let journal = Journal::connect(path.to_str().unwrap()).unwrap();
+        journal
+            .log(
+                Packet::new_ne("No route\nto host", "rsync", "RUN"),
+                Some(12),
+            )
+            .unwrap();
+        journal.log(Packet::new_alive("rsync"), None).unwrap();
+        journal
+            .log(Packet::new_ng("Done", "rsync", "END"), None)
+            .unwrap();
+        let message = "rsync:No route\nto host at stage RUN";
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        expected.extend_from_slice(message.as_bytes());
+        expected.push(b'\n');
+        expected.extend_from_slice(
+            b"PRIORITY=3\nSEVERITY=error\nMODULE=rsync\nSTAGE=RUN\nRUN_ID=12\nSYSLOG_IDENTIFIER=bachd\n",
+        );
+        assert_eq!(recv(&journald), expected);
+        let entry = String::from_utf8(recv(&journald)).unwrap();
+        assert!(entry.starts_with("MESSAGE=rsync:Done at stage END\nPRIORITY=6\nSEVERITY=good\n"));
+        assert!(!entry.contains("RUN_ID"));
+        std::fs::remove_file(&path).unwrap();
+
+        let dir = std::fs::metadata(path.parent().unwrap()).unwrap();
+        let stream = format!("{}:{}", dir.dev(), dir.ino());
+        assert!(is_journal_stream(&stream, &dir));
+        assert!(!is_journal_stream("1:2", &dir));
+        assert!(!is_journal_stream("", &dir));
+    }
+}
@@ -1,60 +1,863 @@
-use bach_bus::packet::{core_2_string, PacketCore, CORE_SIZE};
+//! Control protocol of bachd.
+//!
+//! Every message is a frame made of a 4 bytes big endian length followed by
+//! that many bytes of JSON. The client sends [`Request`]s and gets one
+//! [`Response`] per request, carrying the same `id`, on the same connection.
+//! The same protocol is spoken over TCP and over the Unix control socket.
+use crate::access::{Origin, Role, Tokens};
+use crate::history::RunRecord;
+use chrono::{DateTime, Utc};
+use rustls::{ClientConfig, ClientConnection, ServerConnection, StreamOwned};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[macro_export]
-macro_rules! str_to_core {
-    ($x: ident) => {{
-        let ret = [0u8; CORE_SIZE];
-        let bytes = $x.as_bytes();
-        let len = bytes.len();
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+const FRAME_HEADER_SIZE: usize = 4;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
 
-        for i in 0..len {
-            ret[i] = bytes[i];
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListKind {
+    Running,
+    Loaded,
+    Queued,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    List {
+        which: ListKind,
+    },
+    Status {
+        module: String,
+    },
+    Stop {
+        module: String,
+    },
+    Terminate,
+    Fire {
+        module: String,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    /// Every module when `module` is `None`.
+    Reload {
+        module: Option<String>,
+    },
+    Pause,
+    Resume,
+    History {
+        module: Option<String>,
+        limit: Option<usize>,
+    },
+    /// Metrics in the Prometheus text format.
+    Metrics,
+    /// Loads and starts a module. Its schedule, groups and restart strategy
+    /// are taken from the daemon configuration when it defines it.
+    Load {
+        file: String,
+        config: Option<String>,
+        /// Id of the module, its name by default.
+        module: Option<String>,
+    },
+    /// Stops a module and unloads it.
+    Unload {
+        module: String,
+    },
+    /// Unloads a module and loads `file`, or its own file again, in its place.
+    Replace {
+        module: String,
+        file: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    pub version: u32,
+    pub id: u64,
+    /// Bearer token, only looked at on TCP connections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum FireState {
+    Dispatched,
+    Queued { position: usize },
+    AlreadyQueued { position: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "kebab-case")]
+pub enum Reply {
+    Modules {
+        modules: Vec<String>,
+    },
+    Status {
+        module: String,
+        status: String,
+        next_fire: Option<DateTime<Utc>>,
+        paused: bool,
+    },
+    Fire {
+        #[serde(flatten)]
+        state: FireState,
+    },
+    History {
+        runs: Vec<RunRecord>,
+    },
+    Metrics {
+        text: String,
+    },
+    Loaded {
+        module: String,
+    },
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    BadRequest,
+    UnsupportedVersion,
+    NotFound,
+    Internal,
+    /// A token is required and none valid was given.
+    Unauthorized,
+    /// The client is not allowed to send this command.
+    Forbidden,
+    /// The connection to the daemon failed, only raised client side.
+    Transport,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControlError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ControlError {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        ControlError {
+            code,
+            message: message.to_string(),
         }
+    }
+}
 
-        ret
-    }};
+impl std::error::Error for ControlError {}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
 }
 
-pub enum TcpCommandList {
-    Running,
-    Loaded,
+impl From<io::Error> for ControlError {
+    fn from(item: io::Error) -> Self {
+        ControlError::new(ErrorCode::Transport, &item.to_string())
+    }
 }
 
-pub enum TcpCommand {
-    List(TcpCommandList),
-    Status(String),
-    Stop(String),
-    Terminate,
-    Fire(String),
-    Undef,
-}
-
-impl From<PacketCore> for TcpCommand {
-    fn from(item: PacketCore) -> Self {
-        let header = core_2_string(&item[0..4]);
-        match header.as_str() {
-            "LIST" => {
-                let subcom = core_2_string(&item[4..CORE_SIZE]);
-                match subcom.as_str() {
-                    "running" => TcpCommand::List(TcpCommandList::Running),
-                    "loaded" => TcpCommand::List(TcpCommandList::Loaded),
-                    _ => TcpCommand::Undef,
+/// Exactly one of `ok` and `error` is set.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+    pub version: u32,
+    pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ok: Option<Reply>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ControlError>,
+}
+
+impl Response {
+    pub fn new(id: u64, result: Result<Reply, ControlError>) -> Self {
+        let (ok, error) = match result {
+            Ok(r) => (Some(r), None),
+            Err(e) => (None, Some(e)),
+        };
+        Response {
+            version: PROTOCOL_VERSION,
+            id,
+            ok,
+            error,
+        }
+    }
+
+    pub fn into_result(self) -> Result<Reply, ControlError> {
+        match (self.ok, self.error) {
+            (_, Some(e)) => Err(e),
+            (Some(r), None) => Ok(r),
+            (None, None) => Err(ControlError::new(
+                ErrorCode::BadRequest,
+                "Response without result",
+            )),
+        }
+    }
+}
+
+fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    let payload =
+        serde_json::to_vec(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message larger than a frame",
+        ));
+    }
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend(payload);
+    Ok(frame)
+}
+
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, message: &T) -> io::Result<()> {
+    w.write_all(&encode(message)?)?;
+    w.flush()
+}
+
+/// Reads one frame, `None` when the peer closed the connection in between
+/// two frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> io::Result<Option<T>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut got = 0;
+    while got < FRAME_HEADER_SIZE {
+        match r.read(&mut header[got..])? {
+            0 if got == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => got += n,
+        }
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame too large",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    serde_json::from_slice(&payload)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Cuts the bytes received so far into frames.
+#[derive(Debug, Default)]
+pub struct FrameBuffer {
+    buf: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Next complete frame payload. An oversized frame is an error, the
+    /// stream cannot be resynchronised after it.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ControlError> {
+        if self.buf.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&self.buf[..FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(ControlError::new(
+                ErrorCode::BadRequest,
+                &format!("Frame of {} bytes, at most {} allowed", len, MAX_FRAME_SIZE),
+            ));
+        }
+        if self.buf.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let payload = self.buf[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+        self.buf.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(payload))
+    }
+}
+
+/// Refuses `command` when it needs more than `role`, or than the role of
+/// `token` when `role` is `None`, or when it may not come from `origin`.
+pub fn authorize(
+    role: Option<Role>,
+    token: Option<&str>,
+    command: &Command,
+    tokens: &Tokens,
+    origin: Origin,
+) -> Result<(), ControlError> {
+    let role = match (role, token) {
+        (Some(role), _) => role,
+        (None, Some(secret)) => match tokens.find(secret) {
+            Some(t) => t.role,
+            None => return Err(ControlError::new(ErrorCode::Unauthorized, "Invalid token")),
+        },
+        (None, None) => return Err(ControlError::new(ErrorCode::Unauthorized, "Token required")),
+    };
+    let required = command.required_role();
+    if required > role {
+        return Err(ControlError::new(
+            ErrorCode::Forbidden,
+            &format!("{:?} role required", required),
+        ));
+    }
+    match origin {
+        Origin::Network => match command.network_refusal(!tokens.is_empty()) {
+            Some(reason) => Err(ControlError::new(ErrorCode::Forbidden, reason)),
+            None => Ok(()),
+        },
+        Origin::Socket => Ok(()),
+    }
+}
+
+/// A stream the control protocol can be carried on.
+pub trait ControlStream: Read + Write + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ControlStream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl ControlStream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl ControlStream for StreamOwned<ServerConnection, TcpStream> {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.sock.set_nonblocking(nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+}
+
+impl ControlStream for StreamOwned<ClientConnection, TcpStream> {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.sock.set_nonblocking(nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+}
+
+/// Daemon side of a client connection. It never blocks on reads, so the
+/// daemon loop can serve several clients and keep scheduling.
+pub struct ControlConnection {
+    stream: Box<dyn ControlStream>,
+    frames: FrameBuffer,
+    closed: bool,
+    role: Option<Role>,
+    origin: Origin,
+}
+
+impl ControlConnection {
+    /// `role` is what the client was granted when it connected, `None`
+    /// when every request must carry a token.
+    pub fn new<S: ControlStream + 'static>(
+        stream: S,
+        role: Option<Role>,
+        origin: Origin,
+    ) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(ControlConnection {
+            stream: Box::new(stream),
+            frames: FrameBuffer::default(),
+            closed: false,
+            role,
+            origin,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// Refuses requests needing more than the role of the client, or of
+    /// the token of the request when the connection has none.
+    pub fn authorize(&self, request: &Request, tokens: &Tokens) -> Result<(), ControlError> {
+        authorize(
+            self.role,
+            request.token.as_deref(),
+            &request.command,
+            tokens,
+            self.origin,
+        )
+    }
+
+    /// Requests received since the last call. Malformed ones are answered
+    /// with an error right away.
+    pub fn poll(&mut self) -> Vec<Request> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => self.frames.push(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+
+        let mut ret = Vec::new();
+        loop {
+            let payload = match self.frames.next_frame() {
+                Ok(Some(p)) => p,
+                Ok(None) => break,
+                Err(e) => {
+                    self.send(&Response::new(0, Err(e)));
+                    self.closed = true;
+                    break;
                 }
+            };
+            match parse_request(&payload) {
+                Ok(r) => ret.push(r),
+                Err((id, e)) => self.send(&Response::new(id, Err(e))),
+            }
+        }
+        ret
+    }
+
+    /// Sends `response`, the connection is closed if it cannot be.
+    pub fn send(&mut self, response: &Response) {
+        let res = self
+            .stream
+            .set_nonblocking(false)
+            .and_then(|_| write_frame(&mut self.stream, response))
+            .and_then(|_| self.stream.set_nonblocking(true));
+        if res.is_err() {
+            self.closed = true;
+        }
+    }
+}
+
+fn parse_request(payload: &[u8]) -> Result<Request, (u64, ControlError)> {
+    let value: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|e| (0, ControlError::new(ErrorCode::BadRequest, &e.to_string())))?;
+    let id = value.get("id").and_then(|i| i.as_u64()).unwrap_or(0);
+    match value.get("version").and_then(|v| v.as_u64()) {
+        Some(v) if v == PROTOCOL_VERSION as u64 => (),
+        v => {
+            return Err((
+                id,
+                ControlError::new(
+                    ErrorCode::UnsupportedVersion,
+                    &format!(
+                        "Protocol version {:?} requested, {} supported",
+                        v, PROTOCOL_VERSION
+                    ),
+                ),
+            ))
+        }
+    }
+    serde_json::from_value(value)
+        .map_err(|e| (id, ControlError::new(ErrorCode::BadRequest, &e.to_string())))
+}
+
+/// Client side of the control protocol, one command at a time.
+pub struct ControlClient {
+    stream: Box<dyn ControlStream>,
+    next_id: u64,
+    token: Option<String>,
+}
+
+impl ControlClient {
+    fn with_stream<S: ControlStream + 'static>(stream: S) -> Self {
+        ControlClient {
+            stream: Box::new(stream),
+            next_id: 1,
+            token: None,
+        }
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        Ok(Self::with_stream(TcpStream::connect(address)?))
+    }
+
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::with_stream(UnixStream::connect(path)?))
+    }
+
+    /// TLS over TCP, `host` being the name the daemon certificate is
+    /// checked against.
+    pub fn connect_tls<A: ToSocketAddrs>(
+        address: A,
+        host: &str,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<Self> {
+        let conn = ClientConnection::new(config, crate::tls::server_name(host)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self::with_stream(StreamOwned::new(
+            conn,
+            TcpStream::connect(address)?,
+        )))
+    }
+
+    /// Sent along with every following request.
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    pub fn call(&mut self, command: Command) -> Result<Reply, ControlError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(
+            &mut self.stream,
+            &Request {
+                version: PROTOCOL_VERSION,
+                id,
+                token: self.token.clone(),
+                command,
+            },
+        )?;
+        let response: Response = match read_frame(&mut self.stream)? {
+            Some(r) => r,
+            None => {
+                return Err(ControlError::new(
+                    ErrorCode::Transport,
+                    "Connection closed by the daemon",
+                ))
             }
-            "STAT" => {
-                let name = core_2_string(&item[4..CORE_SIZE]);
-                TcpCommand::Status(name)
+        };
+        if response.id != id {
+            return Err(ControlError::new(
+                ErrorCode::Transport,
+                &format!(
+                    "Response to request {} while waiting for {}",
+                    response.id, id
+                ),
+            ));
+        }
+        response.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access::{token_digest, Token};
+    use crate::tcpmessages::*;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    #[test]
+    fn messages_wire_format() {
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            id: 7,
+            token: None,
+            command: Command::Fire {
+                module: "rsync".to_string(),
+                dry_run: true,
+            },
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"id":7,"command":"fire","module":"rsync","dry_run":true}"#
+        );
+        let parsed = parse_request(br#"{"version":1,"id":8,"command":"fire","module":"rsync"}"#);
+        assert_eq!(
+            parsed.unwrap().command,
+            Command::Fire {
+                module: "rsync".to_string(),
+                dry_run: false
             }
-            "STOP" => {
-                let name = core_2_string(&item[4..CORE_SIZE]);
-                TcpCommand::Stop(name)
+        );
+        let parsed = parse_request(
+            br#"{"version":1,"id":8,"command":"load","file":"/usr/lib/bach/librsync.so","module":"nas"}"#,
+        );
+        assert_eq!(
+            parsed.unwrap().command,
+            Command::Load {
+                file: "/usr/lib/bach/librsync.so".to_string(),
+                config: None,
+                module: Some("nas".to_string())
             }
-            "TERM" => TcpCommand::Terminate,
-            "FIRE" => {
-                let name = core_2_string(&item[4..CORE_SIZE]);
-                TcpCommand::Fire(name)
+        );
+        let parsed = parse_request(br#"{"version":2,"id":9,"command":"pause"}"#);
+        assert_eq!(parsed.unwrap_err().1.code, ErrorCode::UnsupportedVersion);
+        let parsed = parse_request(br#"{"version":1,"id":10,"command":"explode"}"#);
+        let (id, e) = parsed.unwrap_err();
+        assert_eq!((id, e.code), (10, ErrorCode::BadRequest));
+
+        let response = Response::new(
+            7,
+            Ok(Reply::Fire {
+                state: FireState::Queued { position: 2 },
+            }),
+        );
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"version":1,"id":7,"ok":{"reply":"fire","state":"queued","position":2}}"#
+        );
+
+        let mut frames = FrameBuffer::default();
+        let bytes = encode(&response).unwrap();
+        frames.push(&bytes[..3]);
+        assert_eq!(frames.next_frame().unwrap(), None);
+        frames.push(&bytes[3..]);
+        frames.push(&bytes);
+        assert!(frames.next_frame().unwrap().is_some());
+        assert!(frames.next_frame().unwrap().is_some());
+        assert_eq!(frames.next_frame().unwrap(), None);
+        frames.push(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert!(frames.next_frame().is_err());
+    }
+
+    #[test]
+    fn several_commands_per_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn =
+                ControlConnection::new(stream, Some(Role::Operator), Origin::Network).unwrap();
+            let mut served = 0;
+            while !conn.is_closed() {
+                for r in conn.poll() {
+                    let result = match r.command {
+                        Command::Status { module } if module.eq("rsync") => Ok(Reply::Status {
+                            module,
+                            status: "Loaded".to_string(),
+                            next_fire: None,
+                            paused: false,
+                        }),
+                        Command::Status { module } => Err(ControlError::new(
+                            ErrorCode::NotFound,
+                            &format!("Module {} not found", module),
+                        )),
+                        _ => Ok(Reply::Done),
+                    };
+                    conn.send(&Response::new(r.id, result));
+                    served += 1;
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            served
+        });
+
+        let mut client = ControlClient::connect(address).unwrap();
+        assert_eq!(client.call(Command::Pause).unwrap(), Reply::Done);
+        match client.call(Command::Status {
+            module: "rsync".to_string(),
+        }) {
+            Ok(Reply::Status { status, .. }) => assert_eq!(status, "Loaded"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let err = client
+            .call(Command::Status {
+                module: "nope".to_string(),
+            })
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+
+        // Garbage gets an error back and the connection stays usable.
+        client.stream.write_all(&3u32.to_be_bytes()).unwrap();
+        client.stream.write_all(b"{{{").unwrap();
+        let response: Response = read_frame(&mut client.stream).unwrap().unwrap();
+        assert_eq!(response.error.unwrap().code, ErrorCode::BadRequest);
+        assert_eq!(client.call(Command::Resume).unwrap(), Reply::Done);
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn reader_role_is_refused_operator_commands() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut conn = ControlConnection::new(server, Some(Role::Reader), Origin::Socket).unwrap();
+        let mut client = ControlClient::with_stream(client);
+        let server = std::thread::spawn(move || {
+            while !conn.is_closed() {
+                for r in conn.poll() {
+                    let result = conn.authorize(&r, &Tokens::default()).map(|_| Reply::Done);
+                    conn.send(&Response::new(r.id, result));
+                }
+                std::thread::sleep(Duration::from_millis(5));
             }
-            _ => TcpCommand::Undef,
+        });
+        let err = client.call(Command::Terminate).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden);
+        let list = client.call(Command::List {
+            which: ListKind::Loaded,
+        });
+        assert_eq!(list.unwrap(), Reply::Done);
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn network_clients_do_not_load_files() {
+        let operator = Some(Role::Operator);
+        let tokens = Tokens(vec![Token {
+            name: "ops".to_string(),
+            role: Role::Operator,
+            sha256: token_digest("ops"),
+        }]);
+        let load = Command::Load {
+            file: "/tmp/libevil.so".to_string(),
+            config: None,
+            module: None,
+        };
+        let unload = Command::Unload {
+            module: "nas".to_string(),
+        };
+        let replace = |file: Option<&str>| Command::Replace {
+            module: "nas".to_string(),
+            file: file.map(str::to_string),
+        };
+        let pause = Command::Pause;
+        let none = Tokens::default();
+        for (command, tokens) in [(&load, &none), (&load, &tokens), (&unload, &none)] {
+            let err = authorize(operator, None, command, tokens, Origin::Network).unwrap_err();
+            assert_eq!(err.code, ErrorCode::Forbidden);
         }
+        let err = authorize(
+            None,
+            Some("ops"),
+            &replace(Some("/tmp/libevil.so")),
+            &tokens,
+            Origin::Network,
+        )
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden);
+        let err = authorize(operator, None, &replace(None), &none, Origin::Network).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden);
+        assert!(authorize(None, Some("ops"), &unload, &tokens, Origin::Network).is_ok());
+        assert!(authorize(None, Some("ops"), &replace(None), &tokens, Origin::Network).is_ok());
+        assert!(authorize(operator, None, &pause, &none, Origin::Network).is_ok());
+        for command in [&load, &unload, &replace(Some("/tmp/libevil.so"))] {
+            assert!(authorize(operator, None, command, &none, Origin::Socket).is_ok());
+        }
+    }
+
+    /// Self-signed CA, a server certificate for localhost and a client
+    /// certificate, all written as PEM in a fresh directory.
+    fn tls_files() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bachd-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        for (name, purpose, sans) in [
+            (
+                "server",
+                ExtendedKeyUsagePurpose::ServerAuth,
+                vec!["localhost".to_string()],
+            ),
+            ("client", ExtendedKeyUsagePurpose::ClientAuth, vec![]),
+        ] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(sans).unwrap();
+            params.extended_key_usages = vec![purpose];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn tls_with_client_certificates_and_tokens() {
+        let dir = tls_files();
+        let server_config = crate::tls::server_config(
+            &dir.join("server.pem"),
+            &dir.join("server.key"),
+            Some(&dir.join("ca.pem")),
+        )
+        .unwrap();
+        let tokens = Tokens(vec![Token {
+            name: "grafana".to_string(),
+            role: Role::Reader,
+            sha256: token_digest("look-only"),
+        }]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let tls = ServerConnection::new(server_config.clone()).unwrap();
+                let mut conn =
+                    ControlConnection::new(StreamOwned::new(tls, stream), None, Origin::Network)
+                        .unwrap();
+                while !conn.is_closed() {
+                    for r in conn.poll() {
+                        let result = conn.authorize(&r, &tokens).map(|_| Reply::Done);
+                        conn.send(&Response::new(r.id, result));
+                    }
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+        });
+
+        let config = crate::tls::client_config(
+            &dir.join("ca.pem"),
+            Some((&dir.join("client.pem"), &dir.join("client.key"))),
+        )
+        .unwrap();
+        let mut client = ControlClient::connect_tls(address, "localhost", config).unwrap();
+        let list = Command::List {
+            which: ListKind::Running,
+        };
+        let err = client.call(list.clone()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Unauthorized);
+        client.set_token(Some("guess".to_string()));
+        let err = client.call(list.clone()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Unauthorized);
+        client.set_token(Some("look-only".to_string()));
+        assert_eq!(client.call(list.clone()).unwrap(), Reply::Done);
+        let err = client.call(Command::Pause).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden);
+        drop(client);
+
+        // Without a client certificate the daemon ends the handshake.
+        let config = crate::tls::client_config(&dir.join("ca.pem"), None).unwrap();
+        let mut client = ControlClient::connect_tls(address, "localhost", config).unwrap();
+        client.set_token(Some("look-only".to_string()));
+        let err = client.call(list).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Transport);
+        drop(client);
+
+        server.join().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,99 @@
+//! Module doubles for the tests of the daemon.
+use bach_bus::packet::{core_2_string, FireOptions, Packet};
+use bach_module::*;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Module whose fires do nothing, it keeps the packets it is given.
+#[derive(Default)]
+pub struct Dummy {
+    pub name: String,
+    /// Keeps running when the daemon terminates, only a stop ends it.
+    pub ignore_terminate: bool,
+    /// Keeps running when it is asked to stop.
+    pub ignore_stop: bool,
+    pub received: Arc<Mutex<Vec<Packet>>>,
+    /// What the daemon told it with [`Module::set_instance_id`].
+    pub instance_id: Arc<Mutex<Option<String>>>,
+    ctrl: Arc<AtomicU8>,
+    out_alive: Arc<AtomicBool>,
+    out_stack: Arc<Mutex<RefCell<Outbox>>>,
+    fire_options: Arc<Mutex<RefCell<FireOptions>>>,
+    reload_pending: Arc<AtomicBool>,
+}
+
+impl Dummy {
+    pub fn new(name: &str) -> Self {
+        Dummy {
+            name: name.to_string(),
+            ..Dummy::default()
+        }
+    }
+}
+
+impl Module for Dummy {
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn init(&self) -> ModResult<()> {
+        Ok(())
+    }
+
+    fn fire(&self) -> ModuleFireMethod {
+        Box::new(|_, _, _, _, _| -> ModResult<()> { Ok(()) })
+    }
+
+    fn destroy(&self) -> ModResult<()> {
+        Ok(())
+    }
+
+    fn inlet(&self, _: Packet) {}
+
+    fn input(&self, p: Packet) {
+        self.received.lock().unwrap().push(p);
+        let stop = match p {
+            Packet::Terminate => !self.ignore_terminate,
+            Packet::Stop(core) => !self.ignore_stop && core_2_string(&core).eq(&self.name),
+            _ => false,
+        };
+        if stop {
+            self.run_status().store(RUN_TERM, Ordering::SeqCst);
+        }
+    }
+
+    fn set_instance_id(&self, id: &str) {
+        self.instance_id.lock().unwrap().replace(id.to_string());
+    }
+
+    fn spawn_alive_emitter(&self) -> JoinHandle<()> {
+        thread::spawn(|| {})
+    }
+
+    fn run_status(&self) -> &Arc<AtomicU8> {
+        &self.ctrl
+    }
+
+    fn emit_alive_status(&self) -> &Arc<AtomicBool> {
+        &self.out_alive
+    }
+
+    fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>> {
+        &self.out_stack
+    }
+
+    fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>> {
+        &self.fire_options
+    }
+
+    fn reload_pending(&self) -> &Arc<AtomicBool> {
+        &self.reload_pending
+    }
+
+    fn config_path(&self) -> Option<PathBuf> {
+        None
+    }
+}
@@ -1,14 +1,57 @@
 extern crate clap;
 use clap::{App, Arg, SubCommand};
 extern crate r_i18n;
+use bachd::tcpmessages::{Command, ControlClient, FireState, ListKind, Reply};
+use bachd::tls;
 use r_i18n::{I18n, I18nConfig};
 use std::fs::File;
 use std::io::prelude::*;
-use std::mem::take;
-use std::net::TcpStream;
+use std::path::Path;
 
 static LOCALE_DIR: &str = "translations";
 static LOCALES: [&str; 2] = ["en", "fr"];
+static DEFAULT_ADDRESS: &str = "127.0.0.1:6060";
+static DEFAULT_SOCKET: &str = "/run/bach/bachd.sock";
+
+fn print_reply(reply: Reply) {
+    match reply {
+        Reply::Modules { modules } => {
+            for m in modules {
+                println!("\t{}", m);
+            }
+        }
+        Reply::Status {
+            module,
+            status,
+            next_fire,
+            paused,
+        } => {
+            println!("{}: {}", module, status);
+            if let Some(n) = next_fire {
+                println!(
+                    "\tnext fire {}{}",
+                    n.to_rfc3339(),
+                    if paused { " (scheduling paused)" } else { "" }
+                );
+            }
+        }
+        Reply::Fire { state } => match state {
+            FireState::Dispatched => println!("Fired"),
+            FireState::Queued { position } => println!("Queued (position {})", position),
+            FireState::AlreadyQueued { position } => {
+                println!("Already queued (position {})", position)
+            }
+        },
+        Reply::History { runs } => {
+            for r in runs {
+                println!("\t{}", r);
+            }
+        }
+        Reply::Metrics { text } => print!("{}", text),
+        Reply::Loaded { module } => println!("Loaded as {}", module),
+        Reply::Done => (),
+    }
+}
 
 macro_rules! setup_locale {
     ($conf: ident, $locale: ident) => {
@@ -57,6 +100,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help(locale.t("firesubdescname").as_str().unwrap_or(""))
                 .required(true)
                 .index(1),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .short("n")
+                .long("dry-run")
+                .takes_value(false)
+                .help(locale.t("firesubdescdryrun").as_str().unwrap_or("")),
         );
     let stopsub = SubCommand::with_name("stop")
         .about(locale.t("stopsubdesc").as_str().unwrap_or(""))
@@ -66,23 +116,209 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(true)
                 .index(1),
         );
+    let reloadsub = SubCommand::with_name("reload")
+        .about(locale.t("reloadsubdesc").as_str().unwrap_or(""))
+        .arg(
+            Arg::with_name("NAME")
+                .help(locale.t("reloadsubdescname").as_str().unwrap_or(""))
+                .index(1),
+        );
+    let historysub = SubCommand::with_name("history")
+        .about(locale.t("historysubdesc").as_str().unwrap_or(""))
+        .arg(
+            Arg::with_name("NAME")
+                .help(locale.t("historysubdescname").as_str().unwrap_or(""))
+                .index(1),
+        );
+    let loadsub = SubCommand::with_name("load")
+        .about(locale.t("loadsubdesc").as_str().unwrap_or(""))
+        .arg(
+            Arg::with_name("FILE")
+                .help(locale.t("loadsubdescfile").as_str().unwrap_or(""))
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .help(locale.t("loadsubdescconfig").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("id")
+                .long("id")
+                .takes_value(true)
+                .help(locale.t("loadsubdescid").as_str().unwrap_or("")),
+        );
+    let unloadsub = SubCommand::with_name("unload")
+        .about(locale.t("unloadsubdesc").as_str().unwrap_or(""))
+        .arg(
+            Arg::with_name("NAME")
+                .help(locale.t("unloadsubdescname").as_str().unwrap_or(""))
+                .required(true)
+                .index(1),
+        );
+    let replacesub = SubCommand::with_name("replace")
+        .about(locale.t("replacesubdesc").as_str().unwrap_or(""))
+        .arg(
+            Arg::with_name("NAME")
+                .help(locale.t("replacesubdescname").as_str().unwrap_or(""))
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("FILE")
+                .help(locale.t("replacesubdescfile").as_str().unwrap_or(""))
+                .index(2),
+        );
+    let metricssub =
+        SubCommand::with_name("metrics").about(locale.t("metricsdesc").as_str().unwrap_or(""));
+    let pausesub =
+        SubCommand::with_name("pause").about(locale.t("pausedesc").as_str().unwrap_or(""));
+    let resumesub =
+        SubCommand::with_name("resume").about(locale.t("resumedesc").as_str().unwrap_or(""));
     let termsub =
         SubCommand::with_name("terminate").about(locale.t("termdesc").as_str().unwrap_or(""));
     let matches = App::new("Bach Shell")
         .version("0.1.0")
         .author("Dorian Vuolo")
         .about(locale.t("desc").as_str().unwrap_or(""))
+        .arg(
+            Arg::with_name("address")
+                .short("a")
+                .long("address")
+                .takes_value(true)
+                .help(locale.t("addressdesc").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .short("s")
+                .long("socket")
+                .takes_value(true)
+                .conflicts_with("address")
+                .help(locale.t("socketdesc").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .takes_value(true)
+                .conflicts_with("socket")
+                .help(locale.t("tlscadesc").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires_all(&["tls-ca", "tls-key"])
+                .help(locale.t("tlscertdesc").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help(locale.t("tlskeydesc").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("server-name")
+                .long("server-name")
+                .takes_value(true)
+                .requires("tls-ca")
+                .help(locale.t("servernamedesc").as_str().unwrap_or("")),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .env("BACH_TOKEN")
+                .hide_env_values(true)
+                .help(locale.t("tokendesc").as_str().unwrap_or("")),
+        )
         .subcommand(listsub.clone())
         .subcommand(statussub.clone())
         .subcommand(firesub.clone())
         .subcommand(stopsub.clone())
+        .subcommand(reloadsub.clone())
+        .subcommand(historysub.clone())
+        .subcommand(loadsub.clone())
+        .subcommand(unloadsub.clone())
+        .subcommand(replacesub.clone())
+        .subcommand(metricssub.clone())
+        .subcommand(pausesub.clone())
+        .subcommand(resumesub.clone())
         .subcommand(termsub.clone())
         .get_matches();
 
     let switch_to_shell = matches.subcommand_name().is_none();
+    // The socket is preferred, TCP is used when asked for or when the
+    // default socket does not exist.
+    let tcp_asked = matches.is_present("address") || matches.is_present("tls-ca");
+    let socket = match (tcp_asked, matches.value_of("socket")) {
+        (false, Some(s)) => Some(s),
+        (false, None) if Path::new(DEFAULT_SOCKET).exists() => Some(DEFAULT_SOCKET),
+        _ => None,
+    };
+    let address = matches.value_of("address").unwrap_or(DEFAULT_ADDRESS);
 
     if switch_to_shell {
     } else {
+        let name = |sub: &clap::ArgMatches| sub.value_of("NAME").unwrap_or("").to_string();
+        let command = match matches.subcommand() {
+            ("list", Some(sub)) => Command::List {
+                which: if sub.is_present("running") {
+                    ListKind::Running
+                } else {
+                    ListKind::Loaded
+                },
+            },
+            ("status", Some(sub)) => Command::Status { module: name(sub) },
+            ("fire", Some(sub)) => Command::Fire {
+                module: name(sub),
+                dry_run: sub.is_present("dry-run"),
+            },
+            ("stop", Some(sub)) => Command::Stop { module: name(sub) },
+            ("reload", Some(sub)) => Command::Reload {
+                module: sub.value_of("NAME").map(String::from),
+            },
+            ("history", Some(sub)) => Command::History {
+                module: sub.value_of("NAME").map(String::from),
+                limit: None,
+            },
+            ("load", Some(sub)) => Command::Load {
+                file: sub.value_of("FILE").unwrap_or("").to_string(),
+                config: sub.value_of("config").map(String::from),
+                module: sub.value_of("id").map(String::from),
+            },
+            ("unload", Some(sub)) => Command::Unload { module: name(sub) },
+            ("replace", Some(sub)) => Command::Replace {
+                module: name(sub),
+                file: sub.value_of("FILE").map(String::from),
+            },
+            ("metrics", Some(_)) => Command::Metrics,
+            ("pause", Some(_)) => Command::Pause,
+            ("resume", Some(_)) => Command::Resume,
+            ("terminate", Some(_)) => Command::Terminate,
+            _ => return Ok(()),
+        };
+        let mut client = match (socket, matches.value_of("tls-ca")) {
+            (Some(path), _) => ControlClient::connect_unix(path)?,
+            (None, Some(ca)) => {
+                let identity = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+                    (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+                    _ => None,
+                };
+                let config = tls::client_config(Path::new(ca), identity)?;
+                // The host part of the address unless told otherwise.
+                let host = matches
+                    .value_of("server-name")
+                    .unwrap_or_else(|| address.rsplit_once(':').map_or(address, |(h, _)| h));
+                ControlClient::connect_tls(address, host, config)?
+            }
+            (None, None) => ControlClient::connect(address)?,
+        };
+        client.set_token(matches.value_of("token").map(String::from));
+        print_reply(client.call(command)?);
     }
 
     Ok(())
@@ -1,5 +1,6 @@
 use bach_bus::packet::*;
 use bach_module::*;
+use bach_module::{config::ConfigHandle, state};
 use std::cell::RefCell;
 use std::fs::{self, File};
 use std::io::{prelude::*, BufReader, LineWriter};
@@ -42,8 +43,12 @@ fn translate_severity(sev: String) -> String {
 pub struct Reporter {
     ctrl: Arc<AtomicU8>,
     out_alive: Arc<AtomicBool>,
-    config_file: Option<PathBuf>,
-    out_stack: Arc<Mutex<RefCell<Vec<Packet>>>>,
+    config: ConfigHandle<ReporterConfig>,
+    out_stack: Arc<Mutex<RefCell<Outbox>>>,
+    fire_options: Arc<Mutex<RefCell<FireOptions>>>,
+    reload_pending: Arc<AtomicBool>,
+    /// Given by the daemon, keys the state of this instance.
+    instance_id: Arc<Mutex<RefCell<Option<String>>>>,
 }
 
 impl Reporter {
@@ -53,39 +58,36 @@ impl Reporter {
         Reporter {
             ctrl: Arc::new(AtomicU8::new(0)),
             out_alive: Arc::new(AtomicBool::new(false)),
-            config_file: config_filename.clone().map(PathBuf::from),
-            out_stack: Arc::new(Mutex::new(RefCell::new(Vec::new()))),
+            config: ConfigHandle::new(config_filename.clone().map(PathBuf::from)),
+            out_stack: Arc::new(Mutex::new(RefCell::new(Outbox::new()))),
+            fire_options: Arc::new(Mutex::new(RefCell::new(FireOptions::default()))),
+            reload_pending: Arc::new(AtomicBool::new(false)),
+            instance_id: Arc::new(Mutex::new(RefCell::new(None))),
         }
     }
 }
 
 impl Module for Reporter {
     fn name(&self) -> String {
-        if let Some(config_file) = &self.config_file {
-            let conf: ReporterConfig =
-                match quick_xml::de::from_reader(BufReader::new(match File::open(&config_file) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        return format!(
-                            "{} : {}",
-                            config_file.to_str().unwrap_or("NOT FOUND"),
-                            e.to_string()
-                        );
-                    }
-                })) {
-                    Ok(conf) => conf,
-                    Err(e) => return e.to_string(),
-                };
-
-            conf.name
+        if let Some(config_file) = &self.config.path() {
+            match self.config.get() {
+                Ok(conf) => conf.name.to_string(),
+                Err(e) => format!(
+                    "{} : {}",
+                    config_file.to_str().unwrap_or("NOT FOUND"),
+                    e.to_string()
+                ),
+            }
         } else {
             "undefined".to_string()
         }
     }
 
     fn fire(&self) -> ModuleFireMethod {
+        let config = self.config.clone();
+        let instance_id = self.instance_id.clone();
         Box::new(
-            |message_stack, run_control, config_path, name| -> ModResult<()> {
+            move |message_stack, run_control, config_path, name, options| -> ModResult<()> {
                 let check_level = |conf: &ReporterConfig, severity: &str| -> bool {
                     if conf.level.eq("debug") {
                         true
@@ -99,9 +101,8 @@ impl Module for Reporter {
                 };
 
                 bach_module::wait_for_running_status(run_control);
-                if let Some(path) = config_path.lock()?.borrow().as_ref() {
-                    let conf: ReporterConfig =
-                        quick_xml::de::from_reader(BufReader::new(File::open(path)?))?;
+                if config_path.lock()?.borrow().is_some() {
+                    let conf = config.get()?;
                     let fname = tmp_format(&conf.name);
                     let tmpfile = File::open(&fname)?;
                     let rawlines = BufReader::new(tmpfile).lines();
@@ -110,14 +111,38 @@ impl Module for Reporter {
                         lines.push(l);
                     }
                     let mail_and_severity =
-                        gen_mail(lines, &conf.clone().template.map(PathBuf::from))?;
+                        gen_mail(lines, &conf.template.clone().map(PathBuf::from))?;
+
+                    if options.dry_run {
+                        if let Ok(stack) = message_stack.lock() {
+                            stack.borrow_mut().push(Packet::LoggerCom(PacketCore::from(
+                                LoggerCommand::Write(format!("[dry run] {}", mail_and_severity.0)),
+                            )));
+                            stack.borrow_mut().push(Packet::new_ng(
+                                &format!(
+                                    "Dry run: {} report rendered, {}",
+                                    translate_severity(mail_and_severity.1.clone()),
+                                    if check_level(&conf, &mail_and_severity.1) {
+                                        "mail not sent"
+                                    } else {
+                                        "below configured level, mail would not be sent"
+                                    }
+                                ),
+                                &name.lock()?.borrow().to_string(),
+                                "fire",
+                            ));
+                        }
+                        return Ok(());
+                    }
 
+                    let mut sent = true;
                     if check_level(&conf, &mail_and_severity.1) {
                         let stat = conf
                             .mailcmd(mail_and_severity.0, translate_severity(mail_and_severity.1))?
                             .status()?;
 
                         if !stat.success() {
+                            sent = false;
                             if let Ok(stack) = message_stack.lock() {
                                 stack.borrow_mut().push(Packet::new_ne(
                                     "Reporter could not send mail",
@@ -128,6 +153,19 @@ impl Module for Reporter {
                         }
                     }
                     fs::remove_file(&tmp_format(&conf.name))?;
+                    if sent {
+                        let namecc = name.lock()?.borrow().to_string();
+                        let scope = instance_id.lock()?.borrow().clone();
+                        if let Err(e) =
+                            state::record_last_success(scope.as_deref().unwrap_or(&namecc))
+                        {
+                            message_stack.lock()?.borrow_mut().push(Packet::new_nw(
+                                &format!("Unable to record last success: {}", e),
+                                &namecc,
+                                "State",
+                            ));
+                        }
+                    }
                 }
                 Ok(())
             },
@@ -136,9 +174,9 @@ impl Module for Reporter {
 
     fn init(&self) -> ModResult<()> {
         #[cfg(feature = "debug")]
-        println!("Initializing reporter with {:?}", &self.config_file);
-        if let Some(config_file) = &self.config_file {
-            match quick_xml::de::from_reader(BufReader::new(File::open(config_file)?)) {
+        println!("Initializing reporter with {:?}", &self.config.path());
+        if let Some(config_file) = &self.config.path() {
+            match self.config.get() {
                 Ok(conf) => {
                     self.outlet(if self.name().contains("error") {
                         Packet::new_ne("ERROR", &self.name(), "Init")
@@ -166,9 +204,8 @@ impl Module for Reporter {
     }
 
     fn destroy(&self) -> ModResult<()> {
-        if let Some(config_file) = &self.config_file {
-            let conf: ReporterConfig =
-                quick_xml::de::from_reader(BufReader::new(File::open(config_file)?))?;
+        if self.config.path().is_some() {
+            let conf = self.config.get()?;
             fs::remove_file(&tmp_format(&conf.name))?;
         }
 
@@ -180,17 +217,51 @@ impl Module for Reporter {
     }
 
     fn config_path(&self) -> Option<PathBuf> {
-        self.config_file.clone()
+        self.config.path()
+    }
+
+    fn reload(&self) -> ModResult<()> {
+        self.config.reload_with(|new, old| {
+            new.validate()?;
+            if let Some(old) = old {
+                if !new.name.eq(&old.name) {
+                    return Err(ModError::new(&format!(
+                        "name cannot change from {} to {} without a restart",
+                        old.name, new.name
+                    )));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn check_config(&self) -> ModResult<()> {
+        self.config.read()?.validate()
+    }
+
+    fn set_instance_id(&self, id: &str) {
+        if let Ok(i) = self.instance_id.lock() {
+            i.replace(Some(id.to_string()));
+        }
     }
 
     fn emit_alive_status(&self) -> &Arc<AtomicBool> {
         &self.out_alive
     }
 
-    fn message_stack(&self) -> &Arc<Mutex<RefCell<Vec<Packet>>>> {
+    fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>> {
         &self.out_stack
     }
 
+    fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>> {
+        &self.fire_options
+    }
+
+    fn reload_pending(&self) -> &Arc<AtomicBool> {
+        &self.reload_pending
+    }
+
     fn inlet(&self, p: Packet) {
         let is_provider = move |conf: &ReporterConfig, n: &Notification| {
             for s in &conf.source {
@@ -202,9 +273,8 @@ impl Module for Reporter {
             false
         };
         let filter = move |p: Packet, prefix: &str| -> ModResult<()> {
-            if let Some(config_file) = &self.config_file {
-                let conf: ReporterConfig =
-                    quick_xml::de::from_reader(BufReader::new(File::open(config_file)?))?;
+            if self.config.path().is_some() {
+                let conf = self.config.get()?;
                 let notif = Notification::from(p);
                 if is_provider(&conf, &notif) {
                     let file = fs::OpenOptions::new()
@@ -221,9 +291,8 @@ impl Module for Reporter {
         };
 
         let init_file_wrap = move || -> ModResult<()> {
-            if let Some(config_file) = &self.config_file {
-                let conf: ReporterConfig =
-                    quick_xml::de::from_reader(BufReader::new(File::open(config_file)?))?;
+            if self.config.path().is_some() {
+                let conf = self.config.get()?;
                 if !std::path::Path::new(&tmp_format(&conf.name)).exists() {
                     init_tmp_file(&conf)?;
                 }
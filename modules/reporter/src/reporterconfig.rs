@@ -48,4 +48,21 @@ impl ReporterConfig {
     pub fn mailcmd(&self, mailbody: String, overall: String) -> ModResult<Command> {
         self.mail_cmd.to_cmd(mailbody, overall)
     }
+
+    /// Checks what deserialization alone cannot catch.
+    pub fn validate(&self) -> ModResult<()> {
+        if !["debug", "warning", "error"].contains(&self.level.as_str()) {
+            return Err(ModError::new(&format!(
+                "Unknown level {}, expected debug, warning or error",
+                self.level
+            )));
+        }
+        if let Some(template) = &self.template {
+            if !std::path::Path::new(template).exists() {
+                return Err(ModError::new(&format!("Template {} not found", template)));
+            }
+        }
+        self.mailcmd(String::new(), String::new())?;
+        Ok(())
+    }
 }
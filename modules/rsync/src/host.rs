@@ -1,4 +1,5 @@
 extern crate pnet;
+use bach_module::{secrets, ModError, ModResult};
 use pnet::packet::icmp::echo_reply::EchoReplyPacket;
 use pnet::packet::icmp::echo_request::MutableEchoRequestPacket;
 use pnet::packet::icmp::IcmpTypes::EchoRequest;
@@ -19,7 +20,13 @@ pub struct Host {
     ip: Ipv4Addr,
     port: u16,
     user: String,
-    password: String,
+    /// Clear text password, kept for older configurations; prefer `password-ref`.
+    /// It is redacted from the logs once the configuration is read.
+    #[serde(default, deserialize_with = "secrets::redacted")]
+    password: Option<String>,
+    /// Secret reference such as `vault:nas-admin`, see `bach_module::secrets`.
+    #[serde(rename = "password-ref")]
+    password_ref: Option<String>,
     #[serde(rename = "ignore-ping")]
     ignore_ping: Option<bool>,
 }
@@ -54,12 +61,26 @@ fn create_icmp_p<'a>(
 
 impl Host {
     pub fn new(name: &str, ip: Ipv4Addr, user: &str, password: &str) -> Self {
+        secrets::register_redaction(password);
         Host {
             name: name.to_string(),
             ip,
             user: user.to_string(),
             port: 22,
-            password: password.to_string(),
+            password: Some(password.to_string()),
+            password_ref: None,
+            ignore_ping: None,
+        }
+    }
+
+    pub fn with_password_ref(name: &str, ip: Ipv4Addr, user: &str, password_ref: &str) -> Self {
+        Host {
+            name: name.to_string(),
+            ip,
+            user: user.to_string(),
+            port: 22,
+            password: None,
+            password_ref: Some(password_ref.to_string()),
             ignore_ping: None,
         }
     }
@@ -72,8 +93,20 @@ impl Host {
         self.user.to_string()
     }
 
-    pub fn password(&self) -> String {
-        self.password.to_string()
+    pub fn password_ref(&self) -> Option<String> {
+        self.password_ref.clone()
+    }
+
+    /// Resolves the host password, the reference winning over the clear text value.
+    pub fn password(&self) -> ModResult<String> {
+        match (&self.password_ref, &self.password) {
+            (Some(r), _) => secrets::resolve(r),
+            (None, Some(p)) => Ok(p.to_string()),
+            (None, None) => Err(ModError::new(&format!(
+                "No password nor password-ref for host {}",
+                self.name
+            ))),
+        }
     }
 
     pub fn ip(&self) -> &Ipv4Addr {
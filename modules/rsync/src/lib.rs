@@ -2,48 +2,68 @@ use ansi_term::Colour::Blue;
 #[cfg(test)]
 use ansi_term::Colour::Purple;
 use bach_bus::packet::*;
+use bach_module::runner::{CommandOutput, CommandRunner, RunningCommand, SystemRunner};
 use bach_module::*;
+use bach_module::{config::ConfigHandle, secrets, state, stats};
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::{prelude::*, BufReader};
 use std::path::PathBuf;
-use std::process::Stdio;
 use std::sync::{
     atomic::{AtomicBool, AtomicU8, Ordering},
     Arc, Mutex,
 };
-use std::time::{Duration, Instant};
+use std::time::Duration;
 extern crate bach_module_tests;
 use bach_module_tests::*;
 
 pub mod host;
 pub mod rsynconfig;
 
+/// Maximum number of itemized changes forwarded to the loggers on a dry run.
+const MAX_DRY_RUN_LINES: usize = 100;
+
 use rsynconfig::*;
 
 #[derive(BachModuleStdTests)]
 pub struct Rsync {
     ctrl: Arc<AtomicU8>,
     out_alive: Arc<AtomicBool>,
-    config_file: Option<PathBuf>,
-    out_stack: Arc<Mutex<RefCell<Vec<Packet>>>>,
+    config: ConfigHandle<RsynConfig>,
+    out_stack: Arc<Mutex<RefCell<Outbox>>>,
+    fire_options: Arc<Mutex<RefCell<FireOptions>>>,
+    reload_pending: Arc<AtomicBool>,
+    runner: Arc<dyn CommandRunner>,
+    /// Given by the daemon, keys the state of this instance.
+    instance_id: Arc<Mutex<RefCell<Option<String>>>>,
 }
 
 impl Rsync {
     pub fn new(config_filename: &Option<String>) -> Self {
+        Rsync::with_runner(config_filename, Arc::new(SystemRunner::new()))
+    }
+
+    /// Module running rsync, ssh and mount through `runner` instead of the system.
+    pub fn with_runner(config_filename: &Option<String>, runner: Arc<dyn CommandRunner>) -> Self {
         #[cfg(feature = "debug")]
-        println!("Rsync module instanciated with {:?}", &config_filename.clone());
+        println!(
+            "Rsync module instanciated with {:?}",
+            &config_filename.clone()
+        );
         Rsync {
             ctrl: Arc::new(AtomicU8::new(0)),
             out_alive: Arc::new(AtomicBool::new(false)),
-            config_file: config_filename.clone().map(PathBuf::from),
-            out_stack: Arc::new(Mutex::new(RefCell::new(Vec::new()))),
+            config: ConfigHandle::new(config_filename.clone().map(PathBuf::from)),
+            out_stack: Arc::new(Mutex::new(RefCell::new(Outbox::new()))),
+            fire_options: Arc::new(Mutex::new(RefCell::new(FireOptions::default()))),
+            reload_pending: Arc::new(AtomicBool::new(false)),
+            runner,
+            instance_id: Arc::new(Mutex::new(RefCell::new(None))),
         }
     }
 }
 
 fn clog(format: String, onlytest: bool) {
     let nowstr = chrono::Local::now().to_rfc2822();
+    let format = secrets::redact(&format);
     if onlytest {
         #[cfg(test)]
         println!("[{}] {}", Purple.paint(nowstr), Purple.paint(format));
@@ -54,7 +74,7 @@ fn clog(format: String, onlytest: bool) {
 
 fn push_write_command(
     format: String,
-    message_stack: &Arc<Mutex<RefCell<Vec<Packet>>>>,
+    message_stack: &Arc<Mutex<RefCell<Outbox>>>,
 ) -> ModResult<()> {
     message_stack
         .lock()?
@@ -67,12 +87,14 @@ fn push_write_command(
 
 fn perform_checks(
     item: &RsynConfigItem,
-    stack: &Arc<Mutex<RefCell<Vec<Packet>>>>,
+    runner: &dyn CommandRunner,
+    stack: &Arc<Mutex<RefCell<Outbox>>>,
     label: &str,
 ) -> bool {
-    let check_target = item.check_target();
-    let check_device = item.check_device();
+    let check_target = item.check_target(runner);
+    let check_device = item.check_device(runner);
     let check_host = item.check_host_ping();
+    let check_credentials = item.check_credentials();
     let lock_generr = move |format: String| -> bool {
         clog(format.clone(), false);
         if let Ok(cell) = stack.lock() {
@@ -88,6 +110,12 @@ fn perform_checks(
         lock_generr(format!("Target device {} not testable", item.get_desc()))
     } else if !check_host {
         lock_generr(format!("Target {} host not reachable", item.get_desc()))
+    } else if let Err(e) = check_credentials {
+        lock_generr(format!(
+            "Target {} credentials not available : {}",
+            item.get_desc(),
+            e
+        ))
     } else if !check_target.unwrap_or(false) {
         lock_generr(format!("Target {} not reachable", item.get_desc()))
     } else if !check_device.unwrap_or(false) {
@@ -101,10 +129,10 @@ fn process_rsync_exit_code(
     item: &RsynConfigItem,
     code: Option<i32>,
     stderr: &str,
-    stack: &Arc<Mutex<RefCell<Vec<Packet>>>>,
+    stack: &Arc<Mutex<RefCell<Outbox>>>,
     label: &str,
-) {
-    let lock_genwarn = move |format: &str| {
+) -> bool {
+    let lock_genwarn = move |format: &str| -> bool {
         if let Ok(cell) = stack.lock() {
             cell.borrow_mut().push(Packet::new_nw(
                 &format!(
@@ -117,9 +145,10 @@ fn process_rsync_exit_code(
                 "Exit",
             ));
         }
+        true
     };
 
-    let lock_generr = move |format: &str| {
+    let lock_generr = move |format: &str| -> bool {
         if let Ok(cell) = stack.lock() {
             cell.borrow_mut().push(Packet::new_ne(
                 &format!(
@@ -132,9 +161,10 @@ fn process_rsync_exit_code(
                 "Exit",
             ));
         }
+        false
     };
 
-    let lock_gengood = move |format: &str| {
+    let lock_gengood = move |format: &str| -> bool {
         if let Ok(cell) = stack.lock() {
             cell.borrow_mut().push(Packet::new_ng(
                 &format!("Target {} : {}", item.get_desc(), format),
@@ -142,6 +172,7 @@ fn process_rsync_exit_code(
                 "Exit",
             ));
         }
+        true
     };
 
     if code.is_none() {
@@ -178,11 +209,12 @@ fn process_rsync_exit_code(
 
 fn do_mount(
     item: &RsynConfigItem,
-    stackc: &Arc<Mutex<RefCell<Vec<Packet>>>>,
+    runner: &dyn CommandRunner,
+    stackc: &Arc<Mutex<RefCell<Outbox>>>,
     namecc: &str,
 ) -> ModResult<bool> {
     clog("Doing Mount".to_string(), true);
-    let mount = item.mount_target();
+    let mount = item.mount_target(runner);
     if !mount.unwrap_or(false) {
         stackc.lock()?.borrow_mut().push(Packet::new_ne(
             &format!("Unable to mount target {}", item.get_desc()),
@@ -199,10 +231,11 @@ fn do_mount(
 
 fn do_umount(
     item: &RsynConfigItem,
-    stackc: &Arc<Mutex<RefCell<Vec<Packet>>>>,
+    runner: &dyn CommandRunner,
+    stackc: &Arc<Mutex<RefCell<Outbox>>>,
     namecc: String,
 ) -> ModResult<()> {
-    match item.umount_target() {
+    match item.umount_target(runner) {
         Ok(b) => {
             if !b {
                 stackc.lock()?.borrow_mut().push(Packet::new_nw(
@@ -237,125 +270,205 @@ fn do_umount(
 }
 
 fn wait_or_kill(
+    runner: &dyn CommandRunner,
     run_control: &Arc<AtomicU8>,
-    child: &Arc<Mutex<std::process::Child>>,
+    child: &mut Box<dyn RunningCommand>,
     timeout: Option<u64>,
-) -> ModResult<Option<(std::process::ExitStatus, String)>> {
-    let start = Instant::now();
+) -> ModResult<Option<CommandOutput>> {
+    let start = runner.now();
     let runc = Arc::downgrade(run_control);
 
     let stat = loop {
-        if let Ok(mut chlock) = child.try_lock() {
-            if let Some(st) = chlock.try_wait()? {
-                let mut buffer = String::new();
-                if let Some(ref mut stream) = &mut chlock.stderr {
-                    stream.read_to_string(&mut buffer)?;
-                }
-                break Some((st, buffer));
-            }
+        if let Some(out) = child.try_wait()? {
+            break Some(out);
+        }
 
-            if let Some(timeout) = timeout {
-                if start.elapsed().gt(&Duration::from_secs(timeout * 60)) {
-                    chlock.kill()?;
-                    break None;
-                }
+        if let Some(timeout) = timeout {
+            if (runner.now() - start).gt(&Duration::from_secs(timeout * 60)) {
+                child.kill()?;
+                break None;
             }
+        }
 
-            if let Some(ctrlc) = runc.upgrade() {
-                let c = ctrlc.load(Ordering::SeqCst);
-                if c == bach_module::RUN_TERM || c == bach_module::RUN_EARLY_TERM {
-                    chlock.kill()?;
-                    break None;
-                }
-            } else {
-                chlock.kill()?;
+        if let Some(ctrlc) = runc.upgrade() {
+            let c = ctrlc.load(Ordering::SeqCst);
+            if c == bach_module::RUN_TERM || c == bach_module::RUN_EARLY_TERM {
+                child.kill()?;
                 break None;
             }
-
-            std::thread::sleep(Duration::from_millis(100));
+        } else {
+            child.kill()?;
+            break None;
         }
+
+        runner.sleep(Duration::from_millis(100));
     };
     Ok(stat)
 }
 
+fn do_dry_run(
+    item: &RsynConfigItem,
+    runner: &dyn CommandRunner,
+    run_control: &Arc<AtomicU8>,
+    stack: &Arc<Mutex<RefCell<Outbox>>>,
+    label: &str,
+) -> ModResult<()> {
+    let cmd = item.to_dry_run_spec();
+    let mut child = runner.spawn(&cmd)?;
+    clog(format!("Spawning dry run {}", cmd), true);
+    let w = wait_or_kill(runner, run_control, &mut child, item.timeout)?;
+
+    match &w {
+        Some(out) if out.success() => {
+            let changes: Vec<&str> = out.stdout.lines().collect();
+            for change in changes.iter().take(MAX_DRY_RUN_LINES) {
+                push_write_command(format!("[dry run] {} : {}", item.get_desc(), change), stack)?;
+            }
+            if changes.len() > MAX_DRY_RUN_LINES {
+                push_write_command(
+                    format!(
+                        "[dry run] {} : {} more changes not shown",
+                        item.get_desc(),
+                        changes.len() - MAX_DRY_RUN_LINES
+                    ),
+                    stack,
+                )?;
+            }
+            stack.lock()?.borrow_mut().push(Packet::new_ng(
+                &format!(
+                    "Target {} : dry run, {} planned changes",
+                    item.get_desc(),
+                    changes.len()
+                ),
+                label,
+                "Dry run",
+            ));
+        }
+        _ => {
+            report_rsync_end(item, &w, stack, label);
+        }
+    }
+
+    Ok(())
+}
+
+/// Bytes sent plus received, read from the summary `--stats` makes rsync print.
+fn transferred_bytes(stdout: &str) -> Option<u64> {
+    let totals: Vec<u64> = stdout
+        .lines()
+        .filter_map(|l| {
+            let value = l
+                .strip_prefix("Total bytes sent:")
+                .or_else(|| l.strip_prefix("Total bytes received:"))?;
+            // Thousands separators depend on the rsync version and locale.
+            let digits: String = value.chars().filter(|c| c.is_ascii_digit()).collect();
+            digits.parse().ok()
+        })
+        .collect();
+    if totals.is_empty() {
+        None
+    } else {
+        Some(totals.iter().sum())
+    }
+}
+
+/// Reports the outcome of an rsync run, `None` meaning it was killed.
+fn report_rsync_end(
+    item: &RsynConfigItem,
+    w: &Option<CommandOutput>,
+    stack: &Arc<Mutex<RefCell<Outbox>>>,
+    label: &str,
+) -> bool {
+    match w {
+        Some(out) => process_rsync_exit_code(item, out.code, &out.stderr, stack, label),
+        None => process_rsync_exit_code(item, Some(-1), "", stack, label),
+    }
+}
+
 impl Module for Rsync {
     fn name(&self) -> String {
         #[cfg(feature = "debug")]
-        println!("Getting name from {:?}", &self.config_file);
-        if let Some(cfg) = &self.config_file.clone() {
-            let conf: RsynConfig =
-                match quick_xml::de::from_reader(BufReader::new(match File::open(cfg) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        return format!(
-                            "{} : {}",
-                            cfg.to_str().unwrap_or("NOT FOUND"),
-                            e.to_string()
-                        )
-                    }
-                })) {
-                    Ok(conf) => conf,
-                    Err(e) => return format!("Config file {:?} error {}", cfg, e.to_string()),
-                };
-
-            conf.label
+        println!("Getting name from {:?}", &self.config.path());
+        if let Some(cfg) = &self.config.path() {
+            match self.config.get() {
+                Ok(conf) => conf.label.to_string(),
+                Err(e) => format!("Config file {:?} error {}", cfg, e),
+            }
         } else {
             "undefined".to_string()
         }
     }
 
     fn fire(&self) -> ModuleFireMethod {
+        let config = self.config.clone();
+        let runner = self.runner.clone();
+        let instance_id = self.instance_id.clone();
         Box::new(
-            |message_stack, run_control, config_path, name| -> ModResult<()> {
+            move |message_stack, run_control, config_path, name, options| -> ModResult<()> {
                 bach_module::wait_for_running_status(run_control);
-                if let Some(path) = config_path.lock()?.borrow().as_ref() {
+                if config_path.lock()?.borrow().is_some() {
                     clog("Fire Rsync Start".to_string(), true);
-                    let config: RsynConfig =
-                        quick_xml::de::from_reader(BufReader::new(File::open(path.as_path())?))?;
+                    let config = config.get()?;
+                    let mut all_ok = true;
 
-                    for item in config.synchros {
+                    for item in config.synchros.iter() {
                         let namecc = name.lock()?.borrow().to_string();
                         clog(format!("Config name: {}", namecc), true);
-                        if perform_checks(&item, message_stack, &namecc)
-                            && do_mount(&item, message_stack, &namecc)?
+                        let runner = runner.as_ref();
+                        if options.dry_run {
+                            if perform_checks(item, runner, message_stack, &namecc) {
+                                do_dry_run(item, runner, run_control, message_stack, &namecc)?;
+                            }
+                            continue;
+                        }
+                        if perform_checks(item, runner, message_stack, &namecc)
+                            && do_mount(item, runner, message_stack, &namecc)?
                         {
                             clog("Passed checks".to_string(), true);
-                            let mut cmd = item.to_cmd();
-                            let child = Arc::new(Mutex::new(
-                                cmd.stdout(Stdio::null()).stderr(Stdio::piped()).spawn()?,
-                            ));
+                            let cmd = item.to_spec();
+                            let mut child = runner.spawn(&cmd)?;
                             run_control.store(bach_module::RUN_RUNNING, Ordering::SeqCst);
-                            clog(format!("Spawning {:?}", cmd), true);
+                            clog(format!("Spawning {}", cmd), true);
 
                             push_write_command(
                                 format!(
-                                    "Command {:?} successfully launched on target {}",
+                                    "Command {} successfully launched on target {}",
                                     &cmd,
                                     item.get_desc()
                                 ),
                                 message_stack,
                             )?;
 
-                            let w = wait_or_kill(run_control, &child, item.timeout)?;
-                            let stderr = match &w {
-                                Some(p) => p.1.to_string(),
-                                None => "".to_string(),
-                            };
-
-                            process_rsync_exit_code(
-                                &item,
-                                match &w {
-                                    Some(proc1) => proc1.0.code(),
-                                    None => Some(-1),
-                                },
-                                &stderr,
-                                message_stack,
+                            let w = wait_or_kill(runner, run_control, &mut child, item.timeout)?;
+                            all_ok &= report_rsync_end(item, &w, message_stack, &namecc);
+                            if let Some(bytes) =
+                                w.as_ref().and_then(|o| transferred_bytes(&o.stdout))
+                            {
+                                message_stack.lock()?.borrow_mut().push(stats::stat_packet(
+                                    &namecc,
+                                    stats::BYTES_TRANSFERRED,
+                                    bytes,
+                                ));
+                            }
+                            runner.sleep(Duration::from_secs(1));
+                            do_umount(item, runner, message_stack, namecc)?;
+                        } else {
+                            all_ok = false;
+                        }
+                        runner.sleep(Duration::from_secs(10));
+                    }
+                    if all_ok && !options.dry_run {
+                        let namecc = name.lock()?.borrow().to_string();
+                        let scope = instance_id.lock()?.borrow().clone();
+                        if let Err(e) =
+                            state::record_last_success(scope.as_deref().unwrap_or(&namecc))
+                        {
+                            message_stack.lock()?.borrow_mut().push(Packet::new_nw(
+                                &format!("Unable to record last success: {}", e),
                                 &namecc,
-                            );
-                            std::thread::sleep(std::time::Duration::from_secs(1));
-                            do_umount(&item, message_stack, namecc)?;
+                                "State",
+                            ));
                         }
-                        std::thread::sleep(std::time::Duration::from_secs(10));
                     }
                     run_control.store(bach_module::RUN_IDLE, Ordering::SeqCst);
                 } else {
@@ -375,17 +488,54 @@ impl Module for Rsync {
     }
 
     fn config_path(&self) -> Option<PathBuf> {
-        self.config_file.as_ref().cloned()
+        self.config.path()
+    }
+
+    fn reload(&self) -> ModResult<()> {
+        self.config.reload_with(|new, old| {
+            if let Some(old) = old {
+                if !new.label.eq(&old.label) {
+                    return Err(ModError::new(&format!(
+                        "label cannot change from {} to {} without a restart",
+                        old.label, new.label
+                    )));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn check_config(&self) -> ModResult<()> {
+        // Resolved passwords are registered for redaction as well.
+        for item in self.config.read()?.synchros.iter() {
+            item.check_credentials()?;
+        }
+        Ok(())
+    }
+
+    fn set_instance_id(&self, id: &str) {
+        if let Ok(i) = self.instance_id.lock() {
+            i.replace(Some(id.to_string()));
+        }
     }
 
     fn emit_alive_status(&self) -> &Arc<AtomicBool> {
         &self.out_alive
     }
 
-    fn message_stack(&self) -> &Arc<Mutex<RefCell<Vec<Packet>>>> {
+    fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>> {
         &self.out_stack
     }
 
+    fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>> {
+        &self.fire_options
+    }
+
+    fn reload_pending(&self) -> &Arc<AtomicBool> {
+        &self.reload_pending
+    }
+
     fn init(&self) -> ModResult<()> {
         #[cfg(feature = "debug")]
         println!("Initializing Rsync Module");
@@ -412,3 +562,208 @@ impl Module for Rsync {
 
 #[cfg(feature = "modular")]
 mk_create_module!(Rsync, Rsync::new);
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use bach_module::runner::{CommandSpec, ScriptedResponse, ScriptedRunner};
+
+    fn directory_item() -> RsynConfigItem {
+        quick_xml::de::from_str(
+            r#"<synchro use-host-name="false" day-by-day="false" delete="false" timeout="5"><type directory="/tmp"/><source>/var/log</source></synchro>"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn exit_codes() {
+        let item = directory_item();
+        let stack = Arc::new(Mutex::new(RefCell::new(Outbox::new())));
+        let cases: &[(Option<i32>, bool, &str)] = &[
+            (None, false, "not supposed to return nothing"),
+            (Some(-1), false, "killed before end"),
+            (Some(0), true, "Ok"),
+            (Some(1), false, "Syntax or usage error"),
+            (Some(2), false, "Incompatible protocol"),
+            (Some(3), false, "I/O Files selection error"),
+            (Some(4), false, "Unsupported action"),
+            (Some(5), false, "Client/Server startup error"),
+            (Some(6), false, "Could not open log file"),
+            (Some(10), false, "I/O socket error"),
+            (Some(11), false, "I/O file error"),
+            (Some(12), false, "Data flow error"),
+            (Some(13), false, "Diagnostic error"),
+            (Some(14), false, "IPC error"),
+            (Some(20), false, "Killed by user"),
+            (Some(21), false, "Waitpid() failed"),
+            (Some(22), false, "Buffer allocation error"),
+            (Some(23), true, "Partial transfer"),
+            (Some(24), true, "Partial transfer"),
+            (Some(25), true, "Max delete limit reached"),
+            (Some(30), false, "Timeout rx/tx error"),
+            (Some(31), false, "Connection timeout error"),
+            (Some(127), false, "executable is corrupted"),
+            (Some(255), false, "Ssh disconnected"),
+            (Some(42), false, "Unexpected return code"),
+        ];
+        for (code, ok, message) in cases {
+            assert_eq!(
+                process_rsync_exit_code(&item, *code, "stderr", &stack, "test"),
+                *ok,
+                "{:?}",
+                code
+            );
+            let p = stack.lock().unwrap().borrow_mut().pop().unwrap();
+            let n = Notification::from(p);
+            assert!(n.message.contains(message), "{:?} : {}", code, n.message);
+            assert_eq!(n.stage, "Exit");
+            match (code, p) {
+                (Some(0), Packet::NotifyGood(_)) => (),
+                (Some(23..=25), Packet::NotifyWarn(_)) => (),
+                (Some(0) | Some(23..=25), _) => panic!("{:?} reported as {:?}", code, p),
+                (_, Packet::NotifyErr(_)) => (),
+                _ => panic!("{:?} reported as {:?}", code, p),
+            }
+        }
+    }
+
+    #[test]
+    fn wait_for_rsync() {
+        let runner = ScriptedRunner::new();
+        runner.on(
+            "rsync",
+            ScriptedResponse::exit(23)
+                .stderr("file vanished")
+                .delay(Duration::from_secs(120)),
+        );
+        let run_control = Arc::new(AtomicU8::new(RUN_RUNNING));
+        let rsync = CommandSpec::new("rsync");
+
+        let mut child = runner.spawn(&rsync).unwrap();
+        let out = wait_or_kill(&runner, &run_control, &mut child, Some(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(out.code, Some(23));
+        assert_eq!(out.stderr, "file vanished");
+
+        let mut child = runner.spawn(&rsync).unwrap();
+        let start = runner.now();
+        assert!(wait_or_kill(&runner, &run_control, &mut child, Some(1))
+            .unwrap()
+            .is_none());
+        assert!(runner.now() - start > Duration::from_secs(60));
+        assert_eq!(child.try_wait().unwrap().unwrap().code, None);
+
+        run_control.store(RUN_TERM, Ordering::SeqCst);
+        let mut child = runner.spawn(&rsync).unwrap();
+        assert!(wait_or_kill(&runner, &run_control, &mut child, None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn dry_run_through_runner() {
+        let item = directory_item();
+        let runner = ScriptedRunner::new();
+        runner.on(
+            "--dry-run",
+            ScriptedResponse::exit(0).stdout(">f+++++++++ syslog\n>f.st...... messages\n"),
+        );
+        let stack = Arc::new(Mutex::new(RefCell::new(Outbox::new())));
+        let run_control = Arc::new(AtomicU8::new(RUN_RUNNING));
+        do_dry_run(&item, &runner, &run_control, &stack, "test").unwrap();
+        let mut outbox: Vec<Packet> = stack.lock().unwrap().borrow().iter().copied().collect();
+        let n = Notification::from(outbox.pop().unwrap());
+        assert!(n.message.contains("2 planned changes"));
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(
+            runner.calls()[0].to_string(),
+            "rsync -a --dry-run --itemize-changes /var/log /tmp"
+        );
+    }
+
+    #[test]
+    fn stats_summary() {
+        let stdout = "Number of files: 1,204 (reg: 1,100, dir: 104)\n\
+                      Total file size: 52,428,800 bytes\n\
+                      Total bytes sent: 1,234,567\n\
+                      Total bytes received: 2.345\n\n\
+                      sent 1,234,567 bytes  received 2,345 bytes  82,460.80 bytes/sec\n";
+        assert_eq!(transferred_bytes(stdout), Some(1_236_912));
+        assert_eq!(transferred_bytes("Total bytes sent: 12\n"), Some(12));
+        assert_eq!(transferred_bytes(">f+++++++++ syslog\n"), None);
+    }
+
+    #[test]
+    fn reload_config() {
+        let path =
+            std::env::temp_dir().join(format!("bach-rsync-reload-{}.xml", std::process::id()));
+        let write_conf = |label: &str, source: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    r#"<rsync-config label="{}"><synchro use-host-name="false" day-by-day="false" delete="false"><type directory="/tmp"/><source>{}</source></synchro></rsync-config>"#,
+                    label, source
+                ),
+            )
+            .unwrap();
+        };
+        let reload = Packet::BackupCom(PacketCore::from(BackupCommand::Reload(Some(
+            "reload-test".to_string(),
+        ))));
+        write_conf("reload-test", "/var/log");
+        let module = Rsync::new(&Some(path.to_str().unwrap().to_string()));
+        assert_eq!(module.name(), "reload-test");
+
+        write_conf("renamed", "/var/log");
+        module.input(reload);
+        let n = Notification::from(module.output().unwrap());
+        assert!(n.message.contains("keeping previous configuration"));
+        assert_eq!(module.name(), "reload-test");
+
+        write_conf("reload-test", "/usr/share");
+        module.run_status().store(RUN_RUNNING, Ordering::SeqCst);
+        module.input(reload);
+        let n = Notification::from(module.output().unwrap());
+        assert!(n.message.contains("deferred"));
+        assert!(module.output().is_none());
+        assert_eq!(
+            module.config.get().unwrap().synchros[0].source.0,
+            "/var/log"
+        );
+
+        module.run_status().store(RUN_IDLE, Ordering::SeqCst);
+        let n = Notification::from(module.output().unwrap());
+        assert_eq!(n.message, "Configuration reloaded");
+        assert_eq!(
+            module.config.get().unwrap().synchros[0].source.0,
+            "/usr/share"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn output_order_and_drops() {
+        let module = Rsync::new(&None);
+        module.outlet(Packet::new_ng("Command launched", "test", "Run"));
+        module.outlet(Packet::new_ng("Successful End", "test", "END"));
+        assert_eq!(
+            Notification::from(module.output().unwrap()).message,
+            "Command launched"
+        );
+        assert_eq!(
+            Notification::from(module.output().unwrap()).message,
+            "Successful End"
+        );
+
+        for i in 0..bach_module::outbox::DEFAULT_OUTBOX_CAPACITY + 3 {
+            module.outlet(Packet::new_ng(&format!("message {}", i), "test", "Run"));
+        }
+        let n = Notification::from(module.output().unwrap());
+        assert_eq!(n.message, "3 messages dropped");
+        assert_eq!(
+            Notification::from(module.output().unwrap()).message,
+            "message 3"
+        );
+    }
+}
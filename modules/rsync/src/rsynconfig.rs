@@ -1,12 +1,17 @@
 use crate::host::Host;
+use bach_module::runner::{CommandRunner, CommandSpec};
 use bach_module::ModResult;
 use chrono::{prelude::*, Local, Weekday};
 use serde::{Deserialize, Serialize};
-use std::io::prelude::*;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
+use std::process::Command;
+use std::time::Duration;
+
+/// Time left to the system after a successful mount, before writing to the target.
+const MOUNT_SETTLE_DELAY: Duration = Duration::from_secs(10);
+/// A mount command still running after this long is given up.
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(600);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Label(pub String);
@@ -110,34 +115,62 @@ impl RsynConfigItem {
     }
 
     pub fn to_cmd(&self) -> Command {
-        let mut ret = if self.source_host.is_some() {
-            Command::new("ssh")
-        } else {
-            Command::new("rsync")
-        };
+        self.to_spec().to_command()
+    }
+
+    /// Same command as [`RsynConfigItem::to_cmd`] but only listing what would change.
+    pub fn to_dry_run_cmd(&self) -> Command {
+        self.to_dry_run_spec().to_command()
+    }
+
+    pub fn to_spec(&self) -> CommandSpec {
+        self.build_spec(false)
+    }
 
-        match &self.source_host {
+    pub fn to_dry_run_spec(&self) -> CommandSpec {
+        self.build_spec(true)
+    }
+
+    fn remote(&self, h: &Host) -> String {
+        format!(
+            "{}@{}",
+            h.user(),
+            if self.use_host_name {
+                h.name()
+            } else {
+                h.ip().to_string()
+            }
+        )
+    }
+
+    /// Runs `program` locally, or through ssh on the target host if there is one.
+    fn on_target(&self, program: &str) -> CommandSpec {
+        match &self.host {
             Some(h) => {
-                ret.arg(&format!(
-                    "{}@{}",
-                    h.user(),
-                    if self.use_host_name {
-                        h.name()
-                    } else {
-                        h.ip().to_string()
-                    }
-                ))
-                .arg("rsync");
+                let mut ret = CommandSpec::new("ssh");
+                ret.arg(self.remote(h)).arg(program);
+                ret
             }
-            None => (),
+            None => CommandSpec::new(program),
         }
+    }
+
+    fn build_spec(&self, dry_run: bool) -> CommandSpec {
+        let mut ret = match &self.source_host {
+            Some(h) => {
+                let mut ret = CommandSpec::new("ssh");
+                ret.arg(self.remote(h)).arg("rsync");
+                ret
+            }
+            None => CommandSpec::new("rsync"),
+        };
 
         match &self.host {
             Some(h) => {
-                ret.args(&["-a", "-z", "-e", "ssh"]);
+                ret.args(["-a", "-z", "-e", "ssh"]);
             }
             None => {
-                ret.args(&["-a"]);
+                ret.args(["-a"]);
             }
         }
 
@@ -145,23 +178,24 @@ impl RsynConfigItem {
             ret.arg("--delete");
         }
 
+        if dry_run {
+            ret.args(["--dry-run", "--itemize-changes"]);
+        } else {
+            ret.arg("--stats");
+        }
+
         match &self.exclude {
             Some(e) => {
-                ret.arg(&format!("--exclude-from={}", e.0));
+                ret.arg(format!("--exclude-from={}", e.0));
             }
             None => (),
         }
-        ret.arg(&self.source.0.to_string());
+        ret.arg(&self.source.0);
         match &self.host {
             Some(h) => {
-                ret.arg(&format!(
-                    "{}@{}:{}",
-                    h.user(),
-                    if self.use_host_name {
-                        h.name()
-                    } else {
-                        h.ip().to_string()
-                    },
+                ret.arg(format!(
+                    "{}:{}",
+                    self.remote(h),
                     self.genpathstr(&self.ttype.to_enum(), self.day_by_day)
                 ));
             }
@@ -182,7 +216,18 @@ impl RsynConfigItem {
         }
     }
 
-    pub fn check_mounted(&self) -> ModResult<bool> {
+    /// Makes sure the host credentials can be resolved, hosts without any
+    /// password configured rely on ssh keys.
+    pub fn check_credentials(&self) -> ModResult<()> {
+        for h in self.host.iter().chain(self.source_host.iter()) {
+            if h.password_ref().is_some() {
+                h.password()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_mounted(&self, runner: &dyn CommandRunner) -> ModResult<bool> {
         #[cfg(test)]
         println!("Checking if target is mounted");
         match &self.ttype.to_enum() {
@@ -193,37 +238,12 @@ impl RsynConfigItem {
                     path.truncate(path.len() - 1);
                 }
 
-                let mut cmd = if self.host.is_some() {
-                    Command::new("ssh")
-                } else {
-                    Command::new("df")
-                };
-
-                match &self.host {
-                    Some(h) => {
-                        cmd.args(&[
-                            &format!(
-                                "{}@{}",
-                                h.user(),
-                                if self.use_host_name {
-                                    h.name()
-                                } else {
-                                    h.ip().to_string()
-                                }
-                            ),
-                            "df",
-                            "-h",
-                        ]);
-                    }
-                    None => {
-                        cmd.arg("-h");
-                    }
-                }
+                let mut cmd = self.on_target("df");
+                cmd.arg("-h");
                 #[cfg(test)]
-                println!("Spawning {:?}", &cmd);
-                let child = cmd.stdout(Stdio::piped()).spawn()?;
-                for line in std::io::BufReader::new(child.stdout.unwrap()).lines() {
-                    let s = line?.to_string();
+                println!("Spawning {}", &cmd);
+                let out = runner.run(&cmd)?;
+                for s in out.stdout.lines() {
                     #[cfg(test)]
                     println!("Scanning line {} for path {}", &s, &path);
                     if s.contains(&path) {
@@ -237,7 +257,7 @@ impl RsynConfigItem {
         }
     }
 
-    pub fn mount_target(&self) -> ModResult<bool> {
+    pub fn mount_target(&self, runner: &dyn CommandRunner) -> ModResult<bool> {
         match &self.ttype.to_enum() {
             TargetType::Directory(_) => Ok(true),
             TargetType::Mount(e) => {
@@ -249,30 +269,10 @@ impl RsynConfigItem {
                 if !run {
                     return Ok(true);
                 }
-                if self.check_mounted()? {
+                if self.check_mounted(runner)? {
                     Ok(true)
                 } else {
-                    let mut cmd = if self.host.is_some() {
-                        Command::new("ssh")
-                    } else {
-                        Command::new("mount")
-                    };
-
-                    match &self.host {
-                        Some(h) => {
-                            cmd.arg(format!(
-                                "{}@{}",
-                                h.user(),
-                                if self.use_host_name {
-                                    h.name()
-                                } else {
-                                    h.ip().to_string()
-                                }
-                            ))
-                            .arg("mount");
-                        }
-                        None => (),
-                    }
+                    let mut cmd = self.on_target("mount");
                     if e.oloop {
                         let options = match e.offset {
                             Some(val) => format!("{},offset={}", "loop", val),
@@ -282,52 +282,32 @@ impl RsynConfigItem {
                     }
                     cmd.arg(&e.device).arg(&e.path);
                     #[cfg(test)]
-                    println!("Mount command : {:?}", &cmd);
-                    let mut child = cmd.spawn()?;
-                    let start = Instant::now();
+                    println!("Mount command : {}", &cmd);
+                    let mut child = runner.spawn(&cmd)?;
+                    let start = runner.now();
                     loop {
-                        if let Some(stat) = child.try_wait()? {
-                            std::thread::sleep(Duration::from_secs(10));
-                            return Ok(stat.success());
-                        } else if start.elapsed().gt(&Duration::from_secs(600)) {
-                            return Err(bach_module::ModError::new(&format!("Mount command {:?} didn't returned after 10 minutes, should check target, aborting job", &cmd)));
+                        if let Some(out) = child.try_wait()? {
+                            runner.sleep(MOUNT_SETTLE_DELAY);
+                            return Ok(out.success());
+                        } else if (runner.now() - start).gt(&MOUNT_TIMEOUT) {
+                            child.kill()?;
+                            return Err(bach_module::ModError::new(&format!("Mount command {} didn't returned after 10 minutes, should check target, aborting job", &cmd)));
                         }
-                        std::thread::sleep(Duration::from_millis(100));
+                        runner.sleep(Duration::from_millis(100));
                     }
                 }
             }
         }
     }
 
-    pub fn umount_target(&self) -> Result<bool, Box<dyn std::error::Error>> {
+    pub fn umount_target(&self, runner: &dyn CommandRunner) -> ModResult<bool> {
         match &self.ttype.to_enum() {
             TargetType::Directory(_) => Ok(true),
             TargetType::Mount(e) => {
                 if e.unmount {
-                    let mut cmd = if self.host.is_some() {
-                        Command::new("ssh")
-                    } else {
-                        Command::new("umount")
-                    };
-
-                    match &self.host {
-                        Some(h) => {
-                            cmd.arg(format!(
-                                "{}@{}",
-                                h.user(),
-                                if self.use_host_name {
-                                    h.name()
-                                } else {
-                                    h.ip().to_string()
-                                }
-                            ))
-                            .arg("umount");
-                        }
-                        None => (),
-                    }
+                    let mut cmd = self.on_target("umount");
                     cmd.arg(&e.path);
-                    let stat = cmd.status()?;
-                    Ok(stat.success())
+                    Ok(runner.run(&cmd)?.success())
                 } else {
                     Ok(true)
                 }
@@ -335,51 +315,24 @@ impl RsynConfigItem {
         }
     }
 
-    pub fn check_device(&self) -> Result<bool, Box<dyn std::error::Error>> {
+    pub fn check_device(&self, runner: &dyn CommandRunner) -> ModResult<bool> {
         match &self.ttype.to_enum() {
             TargetType::Directory(_) => Ok(true),
             TargetType::Mount(e) => {
-                let mut cmd = match &self.host {
-                    Some(h) => Command::new("ssh")
-                        .arg(&format!(
-                            "{}@{}",
-                            h.user(),
-                            if self.use_host_name {
-                                h.name()
-                            } else {
-                                h.ip().to_string()
-                            }
-                        ))
-                        .arg("ls")
-                        .arg(&e.device)
-                        .spawn()?,
-                    None => Command::new("ls").arg(&e.device).spawn()?,
-                };
-                Ok(cmd.wait()?.success())
+                let mut cmd = self.on_target("ls");
+                cmd.arg(&e.device);
+                Ok(runner.run(&cmd)?.success())
             }
         }
     }
 
-    pub fn check_target(&self) -> Result<bool, Box<dyn std::error::Error>> {
+    pub fn check_target(&self, runner: &dyn CommandRunner) -> ModResult<bool> {
         match &self.ttype.to_enum() {
             TargetType::Directory(e) => match &self.host {
                 Some(h) => {
-                    let mut cmd = Command::new("ssh")
-                        .arg(&format!(
-                            "{}@{}",
-                            h.user(),
-                            if self.use_host_name {
-                                h.name()
-                            } else {
-                                h.ip().to_string()
-                            }
-                        ))
-                        .arg("ls")
-                        .arg(&self.genpathstr(&self.ttype.to_enum(), self.day_by_day))
-                        .stderr(Stdio::piped())
-                        .stdout(Stdio::piped())
-                        .spawn()?;
-                    Ok(cmd.wait()?.success())
+                    let mut cmd = self.on_target("ls");
+                    cmd.arg(self.genpathstr(&self.ttype.to_enum(), self.day_by_day));
+                    Ok(runner.run(&cmd)?.success())
                 }
                 None => {
                     let p = PathBuf::from(e);
@@ -411,11 +364,11 @@ pub fn gen_dummy_configs(outfile: PathBuf) -> Result<(), Box<dyn std::error::Err
             mount: None,
         },
         source: Source("/usr/bin".to_string()),
-        source_host: Some(Host::new(
+        source_host: Some(Host::with_password_ref(
             "db",
             Ipv4Addr::new(192, 168, 10, 130),
             "root",
-            "passwd",
+            "file:db-root",
         )),
         exclude: None,
         host: None,
@@ -442,11 +395,11 @@ pub fn gen_dummy_configs(outfile: PathBuf) -> Result<(), Box<dyn std::error::Err
         source: Source("/var/log".to_string()),
         source_host: None,
         exclude: Some(Exclude("/etc/exclude".to_string())),
-        host: Some(Host::new(
+        host: Some(Host::with_password_ref(
             "nas",
             Ipv4Addr::new(192, 168, 10, 123),
             "admin",
-            "password",
+            "vault:nas-admin",
         )),
         use_host_name: false,
         day_by_day: true,
@@ -466,6 +419,34 @@ pub fn gen_dummy_configs(outfile: PathBuf) -> Result<(), Box<dyn std::error::Err
 #[cfg(test)]
 mod test {
     use crate::rsynconfig::*;
+    use bach_module::runner::{ScriptedResponse, ScriptedRunner};
+
+    fn mount_item(host: Option<Host>, check_only: Option<bool>) -> RsynConfigItem {
+        RsynConfigItem {
+            ttype: SerdeTargetType {
+                directory: None,
+                mount: Some(MountPoint {
+                    device: "/dev/sdb1".to_string(),
+                    path: "/mnt/backup/".to_string(),
+                    oloop: true,
+                    offset: Some(1048576),
+                    unmount: true,
+                    check_only,
+                }),
+            },
+            source: Source("/var/log".to_string()),
+            exclude: None,
+            host,
+            source_host: None,
+            use_host_name: true,
+            day_by_day: false,
+            stamp_name: None,
+            author: None,
+            timeout: None,
+            delete: false,
+        }
+    }
+
     #[test]
     fn rsynconf_to_cmd() {
         let confitem = RsynConfigItem {
@@ -498,5 +479,112 @@ mod test {
         };
         let cmd = confitem.to_cmd();
         println!("{:?}", cmd);
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect();
+        assert!(!args.contains(&"--dry-run".to_string()));
+        assert!(args.contains(&"--stats".to_string()));
+
+        let cmd = confitem.to_dry_run_cmd();
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect();
+        assert!(args.contains(&"--dry-run".to_string()));
+        assert!(args.contains(&"--itemize-changes".to_string()));
+        assert!(!args.contains(&"--stats".to_string()));
+        assert_eq!(
+            args.last().unwrap(),
+            &format!(
+                "admin@192.168.10.123:{}",
+                confitem.genpathstr(&confitem.ttype.to_enum(), true)
+            )
+        );
+    }
+
+    #[test]
+    fn host_password_ref() {
+        std::env::set_var("BACH_TEST_NAS_ADMIN", "nas-s3cr3t");
+        let host: Host = quick_xml::de::from_str(
+            r#"<host name="nas" ip="192.168.10.123" port="22" user="admin" password-ref="env:BACH_TEST_NAS_ADMIN"/>"#,
+        )
+        .unwrap();
+        assert_eq!(host.password().unwrap(), "nas-s3cr3t");
+        assert_eq!(
+            bach_module::secrets::redact("admin:nas-s3cr3t"),
+            "admin:********"
+        );
+
+        let host: Host = quick_xml::de::from_str(
+            r#"<host name="nas" ip="192.168.10.123" port="22" user="admin" password-ref="env:BACH_TEST_UNSET"/>"#,
+        )
+        .unwrap();
+        assert!(host.password().is_err());
+    }
+
+    #[test]
+    fn inline_passwords_are_redacted_once_read() {
+        let _: Host = quick_xml::de::from_str(
+            r#"<host name="nas" ip="192.168.10.123" port="22" user="admin" password="Xq"/>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            bach_module::secrets::redact("sshpass -p Xq"),
+            "sshpass -p ********"
+        );
+        let host: Host = quick_xml::de::from_str(
+            r#"<host name="nas" ip="192.168.10.123" port="22" user="admin"/>"#,
+        )
+        .unwrap();
+        assert!(host.password().is_err());
+    }
+
+    #[test]
+    fn mount_target() {
+        let runner = ScriptedRunner::new();
+        runner.on("df", ScriptedResponse::exit(0).stdout("/dev/sda1 50G /\n"));
+        runner.once("mount", ScriptedResponse::exit(0));
+        runner.once("mount", ScriptedResponse::exit(32));
+        let item = mount_item(None, None);
+        assert!(item.mount_target(&runner).unwrap());
+        assert!(!item.mount_target(&runner).unwrap());
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 4);
+        assert_eq!(calls[0].to_string(), "df -h");
+        assert_eq!(
+            calls[1].to_string(),
+            "mount -o loop,offset=1048576 /dev/sdb1 /mnt/backup/"
+        );
+        assert!(runner.now() >= MOUNT_SETTLE_DELAY * 2);
+
+        let runner = ScriptedRunner::new();
+        runner.on(
+            "df",
+            ScriptedResponse::exit(0).stdout("/dev/sdb1 1T /mnt/backup\n"),
+        );
+        let nas = Host::new("nas", Ipv4Addr::new(192, 168, 10, 123), "admin", "password");
+        let item = mount_item(Some(nas), None);
+        assert!(item.mount_target(&runner).unwrap());
+        assert_eq!(runner.calls().len(), 1);
+        assert_eq!(runner.calls()[0].to_string(), "ssh admin@nas df -h");
+
+        let runner = ScriptedRunner::new();
+        assert!(mount_item(None, Some(true)).mount_target(&runner).unwrap());
+        assert!(runner.calls().is_empty());
+
+        runner.on("df", ScriptedResponse::exit(0));
+        runner.on(
+            "mount",
+            ScriptedResponse::exit(0).delay(Duration::from_secs(3600)),
+        );
+        let e = mount_item(None, None).mount_target(&runner).unwrap_err();
+        assert!(e.to_string().contains("didn't returned after 10 minutes"));
+
+        let runner = ScriptedRunner::new();
+        runner.on("umount", ScriptedResponse::exit(1));
+        assert!(!mount_item(None, None).umount_target(&runner).unwrap());
+        runner.on("ls", ScriptedResponse::spawn_error("no such file"));
+        assert!(mount_item(None, None).check_device(&runner).is_err());
     }
 }
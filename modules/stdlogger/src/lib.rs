@@ -16,7 +16,9 @@ use bach_module_tests::*;
 pub struct StdLogger {
     ctrl: Arc<AtomicU8>,
     out_alive: Arc<AtomicBool>,
-    message_stack: Arc<Mutex<RefCell<Vec<Packet>>>>,
+    message_stack: Arc<Mutex<RefCell<Outbox>>>,
+    fire_options: Arc<Mutex<RefCell<FireOptions>>>,
+    reload_pending: Arc<AtomicBool>,
 }
 
 impl Module for StdLogger {
@@ -29,7 +31,7 @@ impl Module for StdLogger {
     }
 
     fn fire(&self) -> ModuleFireMethod {
-        Box::new(|_, run_control, _, _| -> ModResult<()> {
+        Box::new(|_, run_control, _, _, _| -> ModResult<()> {
             run_control.store(bach_module::RUN_IDLE, Ordering::SeqCst);
             Ok(())
         })
@@ -47,10 +49,18 @@ impl Module for StdLogger {
         &self.out_alive
     }
 
-    fn message_stack(&self) -> &Arc<Mutex<RefCell<Vec<Packet>>>> {
+    fn message_stack(&self) -> &Arc<Mutex<RefCell<Outbox>>> {
         &self.message_stack
     }
 
+    fn fire_options(&self) -> &Arc<Mutex<RefCell<FireOptions>>> {
+        &self.fire_options
+    }
+
+    fn reload_pending(&self) -> &Arc<AtomicBool> {
+        &self.reload_pending
+    }
+
     fn inlet(&self, p: Packet) {
         let now = chrono::Local::now();
         let nowstr = now.to_rfc2822();
@@ -95,10 +105,35 @@ impl StdLogger {
         StdLogger {
             ctrl: Arc::new(AtomicU8::new(0)),
             out_alive: Arc::new(AtomicBool::new(false)),
-            message_stack: Arc::new(Mutex::new(RefCell::new(Vec::new()))),
+            message_stack: Arc::new(Mutex::new(RefCell::new(Outbox::new()))),
+            fire_options: Arc::new(Mutex::new(RefCell::new(FireOptions::default()))),
+            reload_pending: Arc::new(AtomicBool::new(false)),
         }
     }
 }
 
 #[cfg(feature = "modular")]
 mk_create_module!(StdLogger, StdLogger::new);
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use bach_module_harness::{Expect, Harness};
+    use std::time::Duration;
+
+    #[test]
+    fn fire_ends_successfully() {
+        let mut h = Harness::new(StdLogger::new(&None));
+        h.init().unwrap();
+        h.spawn();
+        h.send(Packet::new_nw("something to print", "test", "Run"));
+        h.fire();
+        h.expect(Expect::notify_good().from("StdLogger").at_stage("END"))
+            .within(Duration::from_secs(5));
+        h.assert_sequence(&[
+            Expect::notify_warn().from("test"),
+            Expect::notify_good().containing("Successful End"),
+        ]);
+        assert!(h.terminate().unwrap().is_ok());
+    }
+}